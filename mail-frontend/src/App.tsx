@@ -20,6 +20,8 @@ interface User {
 }
 
 interface Email {
+  id: number
+  alias: string | null
   sender: string
   subject: string
   preview: string
@@ -36,11 +38,22 @@ function App() {
   // Check for auth callback on mount
   useEffect(() => {
     const params = new URLSearchParams(window.location.search)
-    const urlToken = params.get('token')
-    const urlUser = params.get('user')
+    const loginCode = params.get('code')
 
-    if (urlToken) {
-      handleTokenLogin(urlToken, urlUser)
+    if (loginCode) {
+      // Login redirects carry a one-time code, trade it for the token
+      window.history.replaceState({}, document.title, '/')
+      fetch(`${API_URL}/auth/exchange`, {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({ code: loginCode })
+      })
+        .then(res => res.ok ? res.json() : Promise.reject(res.status))
+        .then(data => {
+          localStorage.setItem('refresh_token', data.refresh_token)
+          handleTokenLogin(data.token, JSON.stringify(data.user))
+        })
+        .catch(() => console.error('Failed to exchange login code'))
     } else {
       const storedToken = localStorage.getItem('token')
       const storedUser = localStorage.getItem('user')
@@ -55,7 +68,7 @@ function App() {
 
     if (userStr) {
       try {
-        const userData = JSON.parse(decodeURIComponent(userStr))
+        const userData = JSON.parse(userStr)
         setUser(userData)
         localStorage.setItem('user', JSON.stringify(userData))
       } catch (e) {
@@ -86,21 +99,52 @@ function App() {
     window.location.href = `${API_URL}/auth/sso?redirect_to=${redirectUrl}`
   }
 
-  const handleConnectGmail = () => {
-    if (user) {
-      const redirectUrl = encodeURIComponent(window.location.origin)
-      window.location.href = `${API_URL}/connect/gmail?user_id=${user.id}&redirect_to=${redirectUrl}`
+  // The consent URL is bound to the logged-in user, so it is fetched with the token first
+  const handleConnectGmail = async () => {
+    if (!user || !token) return
+    const redirectUrl = encodeURIComponent(window.location.origin)
+    const res = await authFetch(`/connect/gmail?redirect_to=${redirectUrl}`)
+    if (!res.ok) {
+      console.error('Failed to start Gmail connection')
+      return
     }
+    const data = await res.json()
+    window.location.href = data.url
+  }
+
+  // Access tokens are short-lived: on 401, rotate the refresh token once and retry
+  const authFetch = async (path: string, init: RequestInit = {}) => {
+    const send = (accessToken: string | null) => fetch(`${API_URL}${path}`, {
+      ...init,
+      headers: { ...init.headers, 'Authorization': `Bearer ${accessToken}` }
+    })
+
+    const res = await send(token)
+    const refreshToken = localStorage.getItem('refresh_token')
+    if (res.status !== 401 || !refreshToken) return res
+
+    const refreshed = await fetch(`${API_URL}/auth/refresh`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ refresh_token: refreshToken })
+    })
+    if (!refreshed.ok) {
+      handleLogout()
+      return res
+    }
+
+    const data = await refreshed.json()
+    setToken(data.token)
+    localStorage.setItem('token', data.token)
+    localStorage.setItem('refresh_token', data.refresh_token)
+    return send(data.token)
   }
 
   const handleCreateTempAlias = async () => {
     if (!user || !token) return
     try {
       setLoading(true)
-      const res = await fetch(`${API_URL}/temp-mail`, {
-        method: 'POST',
-        headers: { 'Authorization': `Bearer ${token}` }
-      })
+      const res = await authFetch('/temp-mail', { method: 'POST' })
       const data = await res.json()
       if (data.alias) {
         const updatedUser = { ...user, temp_alias: data.alias }
@@ -118,10 +162,7 @@ function App() {
     if (!user || !token) return
     if (!confirm('Delete this alias? You will stop receiving mail for it.')) return
     try {
-      await fetch(`${API_URL}/temp-mail`, {
-        method: 'DELETE',
-        headers: { 'Authorization': `Bearer ${token}` }
-      })
+      await authFetch('/temp-mail', { method: 'DELETE' })
       const updatedUser = { ...user, temp_alias: undefined }
       setUser(updatedUser)
       // We manually clear it from UI, waiting for re-login is optional but better UX directly
@@ -137,9 +178,7 @@ function App() {
     setLoading(true)
     try {
       // Trigger external sync (optional, fails silently if not connected)
-      await fetch(`${API_URL}/sync/${user.id}`, {
-        headers: { 'Authorization': `Bearer ${token}` }
-      }).catch(() => { })
+      await authFetch(`/sync/${user.id}`).catch(() => { })
 
       // Fetch local DB emails (includes alias emails)
       await handleFetchEmails()
@@ -153,13 +192,11 @@ function App() {
     if (!user || !token) return
     try {
       // Use get_all_emails route for everyone
-      const res = await fetch(`${API_URL}/emails/${user.id}`, {
-        headers: { 'Authorization': `Bearer ${token}` }
-      })
+      const res = await authFetch(`/emails/${user.id}`)
       const data = await res.json()
 
-      if (Array.isArray(data)) {
-        setEmails(data)
+      if (Array.isArray(data.emails)) {
+        setEmails(data.emails)
       } else if (data.sender) {
         setEmails([data])
       }
@@ -169,10 +206,24 @@ function App() {
   }
 
   const handleLogout = () => {
+    // End the server-side session too, so the refresh token can't be reused
+    // WorkOS logins also get a logout_url that clears the AuthKit session
+    if (token) {
+      fetch(`${API_URL}/auth/logout`, {
+        method: 'POST',
+        headers: { 'Authorization': `Bearer ${token}` }
+      })
+        .then(res => res.json())
+        .then(data => {
+          if (data.logout_url) window.location.href = data.logout_url
+        })
+        .catch(() => { })
+    }
     setUser(null)
     setToken(null)
     setEmails([])
     localStorage.removeItem('token')
+    localStorage.removeItem('refresh_token')
     localStorage.removeItem('user')
   }
 
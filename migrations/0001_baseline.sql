@@ -0,0 +1,165 @@
+-- Mail server schema as of the switch to versioned migrations.
+-- Every statement is idempotent: databases set up by the old boot-time ALTERs adopt it as is.
+
+-- Users (with IMAP and OAuth credentials)
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    email TEXT UNIQUE NOT NULL,
+    -- IMAP credentials (fallback)
+    imap_server TEXT,
+    imap_port INTEGER DEFAULT 993,
+    imap_password TEXT,              -- Encrypted (enc:v1:...)
+    -- OAuth credentials
+    auth_provider TEXT,              -- 'google', 'microsoft', 'workos', or null for IMAP
+    access_token TEXT,               -- Encrypted (enc:v1:...)
+    refresh_token TEXT,              -- Encrypted (enc:v1:...)
+    token_expires_at TIMESTAMP,
+    secrets_key_id TEXT,             -- Master key the secrets above are sealed with
+    sync_cursor TEXT,                -- Provider sync cursor (history id, delta link, IMAP uid)
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+-- Disposable aliases
+CREATE TABLE IF NOT EXISTS temp_aliases (
+    alias TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+-- Emails
+CREATE TABLE IF NOT EXISTS emails (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id),
+    message_id TEXT,
+    sender TEXT NOT NULL,
+    subject TEXT,
+    body_preview TEXT,
+    body_text TEXT,
+    body_html TEXT,
+    otp TEXT,                        -- One-time code detected in the message
+    received_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    UNIQUE(user_id, message_id)
+);
+
+CREATE TABLE IF NOT EXISTS email_attachments (
+    id BIGSERIAL PRIMARY KEY,
+    email_id BIGINT NOT NULL REFERENCES emails(id) ON DELETE CASCADE,
+    filename TEXT,
+    content_type TEXT,
+    size_bytes INTEGER NOT NULL,
+    content BYTEA
+);
+
+-- Background sync schedule, leases and last result per account
+CREATE TABLE IF NOT EXISTS account_sync (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    interval_secs INTEGER NOT NULL DEFAULT 300,
+    next_sync_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    lease_owner TEXT,                -- replica currently syncing this account
+    lease_expires_at TIMESTAMP,
+    last_sync_at TIMESTAMP,
+    last_status TEXT,                -- 'ok', 'partial' or 'error'
+    last_error TEXT,
+    last_count INTEGER
+);
+
+-- In-flight OAuth flows: single-use state ids and their PKCE verifiers
+CREATE TABLE IF NOT EXISTS oauth_states (
+    id TEXT PRIMARY KEY,             -- jti of the signed state
+    pkce_verifier TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+-- One-time codes the frontend trades for a JWT after a login redirect
+CREATE TABLE IF NOT EXISTS auth_exchange_codes (
+    code TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    profile TEXT NOT NULL,           -- JSON user profile returned with the token
+    expires_at TIMESTAMP NOT NULL
+);
+
+-- API keys for CI and scripts (only the bcrypt hash of a key is stored)
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    prefix TEXT UNIQUE NOT NULL,     -- 'mk_' + 8 chars, shown in listings and used for lookup
+    key_hash TEXT NOT NULL,
+    scopes TEXT[] NOT NULL DEFAULT '{}', -- 'mail:read', 'aliases:manage', 'webhooks:manage'
+    expires_at TIMESTAMP,
+    last_used_at TIMESTAMP,
+    revoked_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+-- Login sessions: rotating refresh tokens (bcrypt hash of the current one) and revocation
+CREATE TABLE IF NOT EXISTS auth_sessions (
+    id TEXT PRIMARY KEY,             -- `sid` claim of access tokens
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    refresh_hash TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP,
+    last_refreshed_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+-- Organizations (teams) and their members
+CREATE TABLE IF NOT EXISTS organizations (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    workos_organization_id TEXT UNIQUE, -- Its SSO users join automatically...
+    sso_role TEXT NOT NULL DEFAULT 'member' CHECK (sso_role IN ('admin', 'member', 'read-only')), -- ...with this role
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS organization_members (
+    org_id TEXT NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'admin', 'member', 'read-only')),
+    created_at TIMESTAMP DEFAULT NOW(),
+    PRIMARY KEY (org_id, user_id)
+);
+
+-- WorkOS sessions behind logins; our sessions are refreshed through them and end with them
+CREATE TABLE IF NOT EXISTS workos_sessions (
+    id TEXT PRIMARY KEY,             -- WorkOS session id (`sid` of WorkOS access tokens)
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    refresh_token TEXT NOT NULL,     -- Encrypted (enc:v1:...)
+    revoked_at TIMESTAMP,
+    last_refreshed_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT NOW()
+);
+
+-- Shared aliases, their mail and organization API keys (NULL: personal)
+ALTER TABLE temp_aliases ADD COLUMN IF NOT EXISTS org_id TEXT REFERENCES organizations(id) ON DELETE CASCADE;
+ALTER TABLE emails ADD COLUMN IF NOT EXISTS org_id TEXT REFERENCES organizations(id) ON DELETE CASCADE;
+ALTER TABLE api_keys ADD COLUMN IF NOT EXISTS org_id TEXT REFERENCES organizations(id) ON DELETE CASCADE;
+
+-- Logins backed by a WorkOS session
+ALTER TABLE auth_sessions ADD COLUMN IF NOT EXISTS workos_session_id TEXT REFERENCES workos_sessions(id) ON DELETE CASCADE;
+ALTER TABLE auth_exchange_codes ADD COLUMN IF NOT EXISTS workos_session_id TEXT REFERENCES workos_sessions(id) ON DELETE CASCADE;
+
+-- Columns added to tables of older databases over time
+ALTER TABLE users DROP COLUMN IF EXISTS temp_alias;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS auth_provider TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS access_token TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS refresh_token TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS token_expires_at TIMESTAMP;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS secrets_key_id TEXT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS sync_cursor TEXT;
+ALTER TABLE emails ADD COLUMN IF NOT EXISTS otp TEXT;
+ALTER TABLE emails ADD COLUMN IF NOT EXISTS body_text TEXT;
+ALTER TABLE emails ADD COLUMN IF NOT EXISTS body_html TEXT;
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS workos_organization_id TEXT UNIQUE;
+ALTER TABLE organizations ADD COLUMN IF NOT EXISTS sso_role TEXT NOT NULL DEFAULT 'member' CHECK (sso_role IN ('admin', 'member', 'read-only'));
+
+-- Performance indexes
+CREATE INDEX IF NOT EXISTS idx_rate_limit ON emails (user_id, received_at DESC);
+CREATE INDEX IF NOT EXISTS idx_user_auth ON users (auth_provider);
+CREATE INDEX IF NOT EXISTS idx_account_sync_due ON account_sync (next_sync_at);
+CREATE INDEX IF NOT EXISTS idx_org_members_user ON organization_members (user_id);
+CREATE INDEX IF NOT EXISTS idx_temp_aliases_org ON temp_aliases (org_id) WHERE org_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_emails_org ON emails (org_id) WHERE org_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_api_keys_org ON api_keys (org_id) WHERE org_id IS NOT NULL;
+CREATE INDEX IF NOT EXISTS idx_auth_sessions_workos ON auth_sessions (workos_session_id) WHERE workos_session_id IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Which alias each email was addressed to, and whether it has been read
+ALTER TABLE emails ADD COLUMN alias TEXT;         -- NULL: sent to the mailbox itself (sync, user id address)
+ALTER TABLE emails ADD COLUMN read_at TIMESTAMP;  -- NULL: unread
+
+-- Shared inboxes are listed newest first, like personal ones (idx_rate_limit)
+DROP INDEX IF EXISTS idx_emails_org;
+CREATE INDEX idx_emails_org_received ON emails (org_id, received_at DESC) WHERE org_id IS NOT NULL;
@@ -0,0 +1,3 @@
+-- The original message, served as message/rfc822 and parsed for its full headers.
+-- NULL for webhook deliveries, which arrive already parsed, and for mail stored before this.
+ALTER TABLE emails ADD COLUMN raw_message BYTEA;
@@ -0,0 +1,10 @@
+-- Full-text search over subject, sender and body, weighted in that order
+ALTER TABLE emails ADD COLUMN recipients TEXT;    -- To addresses, comma separated (searched by to:)
+
+ALTER TABLE emails ADD COLUMN search tsvector GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', coalesce(subject, '')), 'A') ||
+    setweight(to_tsvector('english', translate(sender, '@.', '  ')), 'B') ||  -- "billing@stripe.com" finds "stripe"
+    setweight(to_tsvector('english', coalesce(body_text, body_preview, '')), 'C')
+) STORED;
+
+CREATE INDEX idx_emails_search ON emails USING GIN (search);
@@ -0,0 +1,14 @@
+-- Conversation threading. message_id stays the dedupe key (a provider id for synced mail),
+-- so the Message-ID header gets its own column.
+ALTER TABLE emails ADD COLUMN header_message_id TEXT;
+ALTER TABLE emails ADD COLUMN in_reply_to TEXT;
+ALTER TABLE emails ADD COLUMN reference_ids TEXT[] NOT NULL DEFAULT '{}';  -- References header, oldest first
+ALTER TABLE emails ADD COLUMN thread_subject TEXT;                         -- Subject without Re:/Fwd:, lowercased
+ALTER TABLE emails ADD COLUMN thread_id BIGINT;                            -- Id of an email of the thread
+
+-- Mail stored before threading is a thread of its own
+UPDATE emails SET thread_id = id;
+
+CREATE INDEX idx_emails_thread ON emails (thread_id);
+CREATE INDEX idx_emails_header_message_id ON emails (header_message_id);
+CREATE INDEX idx_emails_in_reply_to ON emails (in_reply_to);
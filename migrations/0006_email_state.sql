@@ -0,0 +1,7 @@
+-- Per-message state besides read_at: a star and user-defined labels
+ALTER TABLE emails ADD COLUMN starred BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE emails ADD COLUMN labels TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX idx_emails_labels ON emails USING GIN (labels);
+-- Unread counts per alias
+CREATE INDEX idx_emails_unread ON emails (user_id, alias) WHERE read_at IS NULL;
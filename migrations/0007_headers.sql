@@ -0,0 +1,3 @@
+-- Every header of a message in order, as [name, value] pairs with folded values unfolded.
+-- NULL for mail stored before this: its headers are read from raw_message instead.
+ALTER TABLE emails ADD COLUMN headers JSONB;
@@ -0,0 +1,3 @@
+-- DKIM results per signature, as verified on arrival (SMTP, or webhooks forwarding the raw message).
+-- NULL when the message was not checked, e.g. mail synced from a provider.
+ALTER TABLE emails ADD COLUMN dkim JSONB;
@@ -0,0 +1,3 @@
+-- WorkOS organization a login went through, for SSO logins. Linking an organization to a
+-- WorkOS organization requires a session that signed in through it.
+ALTER TABLE workos_sessions ADD COLUMN organization_id TEXT;
@@ -0,0 +1,8 @@
+-- Refresh tokens a session has rotated out, as SHA-256 of the secret. One of these coming back
+-- means the token leaked, and the session is revoked. Any other wrong token is just refused.
+CREATE TABLE auth_session_used_tokens (
+    session_id TEXT NOT NULL REFERENCES auth_sessions(id) ON DELETE CASCADE,
+    token_hash TEXT NOT NULL,
+    used_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (session_id, token_hash)
+);
@@ -0,0 +1,4 @@
+-- API keys are checked against a SHA-256 digest: their secrets are random, so a slow hash
+-- only costs a bcrypt round per request. Older keys keep their bcrypt hash until first used.
+ALTER TABLE api_keys ADD COLUMN key_digest TEXT;
+ALTER TABLE api_keys ALTER COLUMN key_hash DROP NOT NULL;
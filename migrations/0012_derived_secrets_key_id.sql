@@ -0,0 +1,24 @@
+-- users.secrets_key_id is derived from the sealed columns themselves, so no writer can leave it
+-- stale: the key every secret of the row is sealed with, NULL when they differ, are plaintext,
+-- or there are none.
+CREATE FUNCTION users_secrets_key_id() RETURNS trigger AS $$
+DECLARE
+    ids TEXT[];
+BEGIN
+    SELECT array_agg(DISTINCT CASE WHEN v LIKE 'enc:v1:%' THEN split_part(v, ':', 3) END)
+    INTO ids
+    FROM unnest(ARRAY[NEW.imap_password, NEW.access_token, NEW.refresh_token]) AS v
+    WHERE v IS NOT NULL;
+
+    NEW.secrets_key_id := CASE WHEN cardinality(ids) = 1 THEN ids[1] END;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER users_secrets_key_id
+    BEFORE INSERT OR UPDATE OF imap_password, access_token, refresh_token, secrets_key_id ON users
+    FOR EACH ROW EXECUTE FUNCTION users_secrets_key_id();
+
+-- Recompute it for existing rows
+UPDATE users SET secrets_key_id = NULL
+WHERE imap_password IS NOT NULL OR access_token IS NOT NULL OR refresh_token IS NOT NULL OR secrets_key_id IS NOT NULL;
@@ -0,0 +1,40 @@
+-- One row per conversation with its latest message, so thread listings page through an
+-- index instead of grouping the whole inbox. Kept by a trigger on emails.
+CREATE TABLE email_threads (
+    id BIGINT PRIMARY KEY,           -- thread_id of its emails
+    user_id TEXT NOT NULL,
+    org_id TEXT,
+    last_received_at TIMESTAMP NOT NULL
+);
+CREATE INDEX idx_email_threads_personal ON email_threads (user_id, last_received_at DESC, id DESC) WHERE org_id IS NULL;
+CREATE INDEX idx_email_threads_shared ON email_threads (org_id, last_received_at DESC, id DESC) WHERE org_id IS NOT NULL;
+
+CREATE FUNCTION email_threads_sync() RETURNS trigger AS $$
+BEGIN
+    -- The thread an email left (or lost a message from) may be older now, or gone
+    IF TG_OP <> 'INSERT' AND OLD.thread_id IS NOT NULL THEN
+        DELETE FROM email_threads t
+        WHERE t.id = OLD.thread_id AND NOT EXISTS (SELECT 1 FROM emails WHERE thread_id = OLD.thread_id);
+        UPDATE email_threads
+        SET last_received_at = (SELECT MAX(received_at) FROM emails WHERE thread_id = OLD.thread_id)
+        WHERE id = OLD.thread_id;
+    END IF;
+
+    IF TG_OP <> 'DELETE' AND NEW.thread_id IS NOT NULL THEN
+        INSERT INTO email_threads (id, user_id, org_id, last_received_at)
+        VALUES (NEW.thread_id, NEW.user_id, NEW.org_id, NEW.received_at)
+        ON CONFLICT (id) DO UPDATE SET last_received_at = GREATEST(email_threads.last_received_at, EXCLUDED.last_received_at);
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER email_threads_sync
+    AFTER INSERT OR DELETE OR UPDATE OF thread_id, received_at ON emails
+    FOR EACH ROW EXECUTE FUNCTION email_threads_sync();
+
+INSERT INTO email_threads (id, user_id, org_id, last_received_at)
+SELECT DISTINCT ON (thread_id) thread_id, user_id, org_id, MAX(received_at) OVER (PARTITION BY thread_id)
+FROM emails
+WHERE thread_id IS NOT NULL
+ORDER BY thread_id;
@@ -0,0 +1,236 @@
+use actix_web::{dev::Payload, http::StatusCode, web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures::future::LocalBoxFuture;
+use sqlx::PgPool;
+use crate::core::api_keys::{self, Scope};
+use crate::core::jwt;
+use crate::core::orgs::{self, Role};
+use crate::core::sessions;
+
+/// Why a request was refused
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken(String),
+    Forbidden,
+    MissingScope(Scope),
+    SessionRequired,
+    NotMember,
+    InsufficientRole(Role),
+    Internal(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "Missing Authorization: Bearer <token>"),
+            AuthError::InvalidToken(e) => write!(f, "{}", e),
+            AuthError::Forbidden => write!(f, "Token does not match user_id"),
+            AuthError::MissingScope(scope) => write!(f, "API key lacks the '{}' scope", scope.as_str()),
+            AuthError::SessionRequired => write!(f, "This endpoint requires a login session, not an API key"),
+            AuthError::NotMember => write!(f, "Not a member of this organization"),
+            AuthError::InsufficientRole(role) => write!(f, "Requires the '{}' role in this organization", role.as_str()),
+            AuthError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden
+            | AuthError::MissingScope(_)
+            | AuthError::SessionRequired
+            | AuthError::NotMember
+            | AuthError::InsufficientRole(_) => StatusCode::FORBIDDEN,
+            AuthError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header(("WWW-Authenticate", "Bearer"));
+        }
+        response.json(self.to_string())
+    }
+}
+
+/// The caller, as proven by the `Authorization: Bearer <token>` header.
+/// The token is either a login JWT or an API key (`mk_...`).
+/// Taking this as a handler argument makes the route require authentication.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: String,
+    pub api_key_scopes: Option<Vec<Scope>>, // None for login sessions, which may do everything
+    pub session_id: Option<String>,         // Login session of a JWT, if it has one
+    pub api_key_id: Option<String>,         // The API key used, if any
+    pub api_key_org: Option<String>,        // Organization an API key is bound to
+}
+
+impl AuthenticatedUser {
+    /// Ensure an API key was granted `scope` (login sessions always pass)
+    pub fn ensure_scope(&self, scope: Scope) -> Result<(), AuthError> {
+        match &self.api_key_scopes {
+            Some(scopes) if !scopes.iter().any(|granted| granted.covers(scope)) => Err(AuthError::MissingScope(scope)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ensure the caller logged in, for endpoints API keys must not reach (e.g. managing keys)
+    pub fn ensure_session(&self) -> Result<(), AuthError> {
+        match self.api_key_scopes {
+            Some(_) => Err(AuthError::SessionRequired),
+            None => Ok(()),
+        }
+    }
+
+    /// Ensure the caller is the owner of `user_id`'s data (organization keys never are)
+    pub fn ensure_owns(&self, user_id: &str) -> Result<(), AuthError> {
+        if self.user_id == user_id && self.api_key_org.is_none() {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden)
+        }
+    }
+
+    /// Whether the session or API key the caller authenticated with still works.
+    /// Checked again by requests that last, like event streams.
+    pub async fn still_valid(&self, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        match (&self.session_id, &self.api_key_id) {
+            (Some(session_id), _) => sessions::is_active(pool, session_id).await,
+            (None, Some(key_id)) => api_keys::is_active(pool, key_id).await,
+            (None, None) => Ok(true), // Tokens without a session can't be revoked early
+        }
+    }
+
+    /// Ensure the caller is a member of `org_id` with at least `min` role, returning their role.
+    /// Organization keys carry their creator's current role, so they lose access with them.
+    pub async fn ensure_org_role(&self, pool: &PgPool, org_id: &str, min: Role) -> Result<Role, AuthError> {
+        if self.api_key_org.as_deref().is_some_and(|org| org != org_id) {
+            return Err(AuthError::NotMember);
+        }
+
+        let role = orgs::role_of(pool, org_id, &self.user_id)
+            .await
+            .map_err(|e| AuthError::Internal(format!("DB error: {}", e)))?
+            .ok_or(AuthError::NotMember)?;
+
+        if role < min {
+            return Err(AuthError::InsufficientRole(min));
+        }
+        Ok(role)
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let token = req.headers().get("Authorization")
+            .and_then(|h| h.to_str().ok())
+            .and_then(jwt::extract_bearer_token)
+            .map(str::to_string);
+        let pool = req.app_data::<web::Data<PgPool>>().cloned();
+
+        Box::pin(async move {
+            let token = token.ok_or(AuthError::MissingToken)?;
+            let pool = || pool.clone().ok_or_else(|| AuthError::Internal("Database not configured".to_string()));
+
+            if !token.starts_with(api_keys::KEY_PREFIX) {
+                let claims = jwt::validate_token(&token).map_err(AuthError::InvalidToken)?;
+
+                // Tokens of a revoked (logged out) session stop working right away
+                if let Some(sid) = &claims.sid {
+                    let active = sessions::is_active(pool()?.get_ref(), sid)
+                        .await
+                        .map_err(|e| AuthError::Internal(format!("DB error: {}", e)))?;
+                    if !active {
+                        return Err(AuthError::InvalidToken("Session has been revoked".to_string()));
+                    }
+                }
+
+                return Ok(AuthenticatedUser {
+                    user_id: claims.sub,
+                    api_key_scopes: None,
+                    session_id: claims.sid,
+                    api_key_id: None,
+                    api_key_org: None,
+                });
+            }
+
+            match api_keys::authenticate(pool()?.get_ref(), &token).await {
+                Ok(Some(owner)) => Ok(AuthenticatedUser {
+                    user_id: owner.user_id,
+                    api_key_scopes: Some(owner.scopes),
+                    session_id: None,
+                    api_key_id: Some(owner.id),
+                    api_key_org: owner.org_id,
+                }),
+                Ok(None) => Err(AuthError::InvalidToken("Invalid API key".to_string())),
+                Err(e) => Err(AuthError::Internal(e)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_user(scopes: &[Scope]) -> AuthenticatedUser {
+        AuthenticatedUser {
+            user_id: "user_1".to_string(),
+            api_key_scopes: Some(scopes.to_vec()),
+            session_id: None,
+            api_key_id: Some("key_1".to_string()),
+            api_key_org: None,
+        }
+    }
+
+    #[test]
+    fn api_keys_are_limited_to_their_scopes() {
+        let user = key_user(&[Scope::ReadMail]);
+
+        assert!(user.ensure_scope(Scope::ReadMail).is_ok());
+        assert!(matches!(user.ensure_scope(Scope::ManageAliases), Err(AuthError::MissingScope(Scope::ManageAliases))));
+        assert!(matches!(user.ensure_session(), Err(AuthError::SessionRequired)));
+    }
+
+    #[test]
+    fn organizing_mail_does_not_allow_deleting_it() {
+        let organizer = key_user(&[Scope::OrganizeMail]);
+        assert!(organizer.ensure_scope(Scope::OrganizeMail).is_ok());
+        assert!(matches!(organizer.ensure_scope(Scope::ManageMail), Err(AuthError::MissingScope(Scope::ManageMail))));
+
+        // Keys made before mail:organize existed keep marking mail read
+        assert!(key_user(&[Scope::ManageMail]).ensure_scope(Scope::OrganizeMail).is_ok());
+    }
+
+    #[test]
+    fn sessions_may_do_everything() {
+        let user = AuthenticatedUser { user_id: "user_1".to_string(), api_key_scopes: None, session_id: None, api_key_id: None, api_key_org: None };
+
+        assert!(user.ensure_scope(Scope::ManageAliases).is_ok());
+        assert!(user.ensure_session().is_ok());
+        assert_eq!(user.ensure_owns("user_2").unwrap_err().status_code(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn organization_keys_never_reach_personal_data() {
+        let user = AuthenticatedUser { api_key_org: Some("org_1".to_string()), ..key_user(&[Scope::ReadMail]) };
+
+        assert!(matches!(user.ensure_owns("user_1"), Err(AuthError::Forbidden)));
+    }
+
+    #[actix_web::test]
+    async fn organization_keys_stay_in_their_organization() {
+        let user = AuthenticatedUser { api_key_org: Some("org_1".to_string()), ..key_user(&[Scope::ReadMail]) };
+        // Never connects: the key's organization is checked before membership
+        let pool = sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+
+        let result = user.ensure_org_role(&pool, "org_2", Role::ReadOnly).await;
+        assert!(matches!(result, Err(AuthError::NotMember)));
+    }
+}
@@ -1,13 +1,34 @@
-use actix_web::{web, HttpRequest, HttpResponse};
-use sqlx::{PgPool, Row};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use sqlx::PgPool;
 use serde::{Deserialize, Serialize};
-use crate::core::imap_client::{fetch_latest_email, ImapCredentials};
 use crate::core::oauth;
 use crate::core::jwt;
+use crate::core::sessions;
 use crate::core::workos_auth;
+use crate::core::workos_sessions;
+use crate::core::sync;
+use crate::core::crypto::{self, KeyRing};
+use crate::core::oauth_state;
+use crate::core::redirects;
+use crate::core::exchange_code;
+use crate::core::api_keys::{self, Scope};
+use crate::core::orgs::{self, MemberError, Role};
+use crate::core::ingest::{self, ParsedEmail};
+use crate::core::search;
+use crate::core::threads;
+use crate::core::events::{self, EventHub};
+use crate::core::headers;
+use crate::core::dkim::DkimSignature;
+use crate::core::dns::Resolver;
+use crate::api::auth::AuthenticatedUser;
+use crate::db::{
+    EmailCursor, EmailDetail, EmailQuery, EmailRow, ImapAccount, Inbox, Repos, SealedTokens, StateChange, ThreadQuery, ThreadRow,
+};
 
 #[derive(Serialize)]
 pub struct EmailResponse {
+    id: i64,
+    alias: Option<String>,
     sender: String,
     subject: String,
     preview: String,
@@ -15,6 +36,20 @@ pub struct EmailResponse {
     received_at: String,
 }
 
+impl From<EmailRow> for EmailResponse {
+    fn from(row: EmailRow) -> Self {
+        EmailResponse {
+            id: row.id,
+            alias: row.alias,
+            sender: row.sender,
+            subject: row.subject.unwrap_or_default(),
+            preview: row.body_preview.unwrap_or_default(),
+            otp: row.otp,
+            received_at: row.received_at.to_string(),
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct CreateUserRequest {
     id: String,
@@ -40,38 +75,45 @@ pub struct SyncResponse {
 
 #[derive(Deserialize)]
 pub struct AuthQuery {
-    user_id: String,
+    user_id: Option<String>, // Optional; the flow is always for the caller, this must name them if given
 }
 
 #[derive(Deserialize)]
 pub struct CallbackQuery {
     code: String,
-    state: String,  // Contains user_id and provider
+    state: String,  // Signed state from oauth_state::issue
 }
 
-/// Create a new user with IMAP credentials
+/// Encrypt freshly issued OAuth tokens for storage on the user row
+fn seal_tokens(keys: &KeyRing, user_id: &str, tokens: &oauth::OAuthTokens) -> Result<(String, Option<String>), String> {
+    let access_token = keys.seal(&tokens.access_token, &crypto::context(user_id, "access_token"))?;
+    let refresh_token = keys.seal_column(user_id, "refresh_token", tokens.refresh_token.as_deref())?;
+    Ok((access_token, refresh_token))
+}
+
+/// Set IMAP credentials for the logged-in user (requires Bearer token)
 pub async fn create_user(
-    pool: web::Data<PgPool>,
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    keys: web::Data<KeyRing>,
     body: web::Json<CreateUserRequest>,
 ) -> HttpResponse {
-    let result = sqlx::query(
-        r#"
-        INSERT INTO users (id, email, imap_server, imap_port, imap_password)
-        VALUES ($1, $2, $3, $4, $5)
-        ON CONFLICT (id) DO UPDATE SET
-            email = EXCLUDED.email,
-            imap_server = EXCLUDED.imap_server,
-            imap_port = EXCLUDED.imap_port,
-            imap_password = EXCLUDED.imap_password
-        "#
-    )
-    .bind(&body.id)
-    .bind(&body.email)
-    .bind(&body.imap_server)
-    .bind(body.imap_port.unwrap_or(993))
-    .bind(&body.imap_password)
-    .execute(pool.get_ref())
-    .await;
+    // Ensure user can only set their own credentials
+    if let Err(e) = auth.ensure_owns(&body.id).and_then(|_| auth.ensure_session()) {
+        return e.error_response();
+    }
+
+    let imap_password = match keys.seal_column(&body.id, "imap_password", body.imap_password.as_deref()) {
+        Ok(sealed) => sealed,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Encryption error: {}", e)),
+    };
+
+    let imap = ImapAccount {
+        server: body.imap_server.clone(),
+        port: body.imap_port.unwrap_or(993),
+        password: imap_password,
+    };
+    let result = repos.users.upsert_imap(&body.id, &body.email, &imap).await;
 
     match result {
         Ok(_) => HttpResponse::Ok().json(UserResponse {
@@ -83,45 +125,70 @@ pub async fn create_user(
     }
 }
 
-/// Start Google OAuth flow
-pub async fn auth_google(query: web::Query<AuthQuery>) -> HttpResponse {
-    match oauth::google_auth_url(&format!("{}:google", query.user_id)) {
-        Ok(url) => HttpResponse::Found()
-            .append_header(("Location", url))
-            .finish(),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Error: {}", e)),
+/// Start an OAuth flow for the caller's own account: the tokens it yields are bound to them.
+/// Returns the consent URL; a browser navigation can't carry the Bearer token, so the frontend goes there itself.
+async fn start_oauth(auth: &AuthenticatedUser, pool: &PgPool, user_id: Option<&str>, provider: &str, redirect: Option<&str>) -> HttpResponse {
+    if let Err(e) = user_id.map_or(Ok(()), |id| auth.ensure_owns(id)).and_then(|_| auth.ensure_session()) {
+        return e.error_response();
     }
-}
 
-/// Start Microsoft OAuth flow
-pub async fn auth_microsoft(query: web::Query<AuthQuery>) -> HttpResponse {
-    match oauth::microsoft_auth_url(&format!("{}:microsoft", query.user_id)) {
-        Ok(url) => HttpResponse::Found()
-            .append_header(("Location", url))
-            .finish(),
+    let (state, pkce_challenge) = match oauth_state::issue(pool, &auth.user_id, provider, redirect).await {
+        Ok(issued) => issued,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Error: {}", e)),
+    };
+
+    let url = match provider {
+        "microsoft" => oauth::microsoft_auth_url(&state, pkce_challenge),
+        _ => oauth::google_auth_url(&state, pkce_challenge),
+    };
+    match url {
+        Ok(url) => HttpResponse::Ok().json(serde_json::json!({ "url": url })),
         Err(e) => HttpResponse::InternalServerError().json(format!("Error: {}", e)),
     }
 }
 
+/// Start Google OAuth flow (requires a login session)
+pub async fn auth_google(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    query: web::Query<AuthQuery>,
+) -> HttpResponse {
+    start_oauth(&auth, pool.get_ref(), query.user_id.as_deref(), "google", None).await
+}
+
+/// Start Microsoft OAuth flow (requires a login session)
+pub async fn auth_microsoft(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    query: web::Query<AuthQuery>,
+) -> HttpResponse {
+    start_oauth(&auth, pool.get_ref(), query.user_id.as_deref(), "microsoft", None).await
+}
+
 /// Handle OAuth callback from Google/Microsoft
 pub async fn auth_callback(
     pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    keys: web::Data<KeyRing>,
     query: web::Query<CallbackQuery>,
 ) -> HttpResponse {
-    // Parse state to get user_id, provider, and optional redirect
-    // Format: user_id:provider[:redirect_url]
-    let parts: Vec<&str> = query.state.splitn(3, ':').collect();
-    if parts.len() < 2 {
-        return HttpResponse::BadRequest().json("Invalid state");
-    }
-    let user_id = parts[0];
-    let provider = parts[1];
-    let redirect_url = if parts.len() == 3 { Some(parts[2]) } else { None };
+    // Verify and consume the signed state: who started the flow, where to go next, PKCE verifier
+    let state = match oauth_state::consume(pool.get_ref(), &query.state).await {
+        Ok(state) => state,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let user_id = state.user_id.as_str();
+    let provider = state.provider.as_str();
+    // Checked when the flow started; checked again in case the allowlist changed since
+    let redirect_url = match state.redirect.as_deref().map(|url| redirects::validate(Some(url))).transpose() {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
 
     // Exchange code for tokens based on provider
     let tokens = match provider {
-        "google" | "gmail_connect" => oauth::google_exchange_code(&query.code).await,
-        "microsoft" => oauth::microsoft_exchange_code(&query.code).await,
+        "google" | "gmail_connect" => oauth::google_exchange_code(&query.code, state.pkce_verifier).await,
+        "microsoft" => oauth::microsoft_exchange_code(&query.code, state.pkce_verifier).await,
         _ => return HttpResponse::BadRequest().json("Unknown provider"),
     };
 
@@ -192,42 +259,31 @@ pub async fn auth_callback(
     // Normalized provider name for DB
     let db_provider = if provider == "gmail_connect" { "google" } else { provider };
 
+    let (access_token, refresh_token) = match seal_tokens(&keys, user_id, &tokens) {
+        Ok(sealed) => sealed,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Encryption error: {}", e)),
+    };
+
     // Save tokens to database
-    let result = sqlx::query(
-        r#"
-        INSERT INTO users (id, email, auth_provider, access_token, refresh_token, token_expires_at, imap_server, imap_port)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, 993)
-        ON CONFLICT (id) DO UPDATE SET
-            auth_provider = EXCLUDED.auth_provider,
-            access_token = EXCLUDED.access_token,
-            refresh_token = EXCLUDED.refresh_token,
-            token_expires_at = EXCLUDED.token_expires_at,
-            imap_server = EXCLUDED.imap_server
-        "#
-    )
-    .bind(user_id)
-    .bind(&email)
-    .bind(db_provider)
-    .bind(&tokens.access_token)
-    .bind(&tokens.refresh_token)
-    .bind(expires_at)
-    .bind(imap_server)
-    .execute(pool.get_ref())
-    .await;
+    let sealed = SealedTokens {
+        access_token,
+        refresh_token,
+        expires_at,
+    };
+    let result = repos.tokens.save_login(user_id, &email, db_provider, imap_server, &sealed).await;
 
     match result {
         Ok(_) => {
             if let Some(url) = redirect_url {
                  HttpResponse::Found().append_header(("Location", url)).finish()
             } else {
-                // Generate JWT token for the user
-                let token = jwt::generate_token(user_id).unwrap_or_default();
+                // Only a logged-in user can start the flow, so they already have a session:
+                // handing out another here would give one to whoever holds this callback URL
                 HttpResponse::Ok().json(serde_json::json!({
                     "success": true,
                     "user_id": user_id,
                     "provider": provider,
-                    "token": token,
-                    "message": "OAuth authentication successful!"
+                    "message": "Mailbox connected"
                 }))
             }
         }
@@ -235,225 +291,152 @@ pub async fn auth_callback(
     }
 }
 
-/// Sync latest email from user's IMAP server (requires Bearer token)
+/// Sync new emails from the user's connected mailbox (requires Bearer token)
 pub async fn sync_emails(
-    req: HttpRequest,
+    auth: AuthenticatedUser,
     pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    keys: web::Data<KeyRing>,
     path_user_id: web::Path<String>,
 ) -> HttpResponse {
-    // Validate JWT token
-    let auth_header = req.headers().get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("");
-    
-    let token = match jwt::extract_bearer_token(auth_header) {
-        Some(t) => t,
-        None => return HttpResponse::Unauthorized().json("Missing Authorization: Bearer <token>"),
-    };
-    
-    let token_user_id = match jwt::validate_token(token) {
-        Ok(uid) => uid,
-        Err(e) => return HttpResponse::Unauthorized().json(format!("Invalid token: {}", e)),
-    };
-    
     let user_id = path_user_id.into_inner();
     
     // Ensure user can only access their own data
-    if token_user_id != user_id {
-        return HttpResponse::Forbidden().json("Token does not match user_id");
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
     }
     
-    // Get user's credentials (OAuth or IMAP)
-    let user_result = sqlx::query(
-        r#"
-        SELECT email, imap_server, imap_port, imap_password, 
-               auth_provider, access_token, refresh_token, token_expires_at
-        FROM users WHERE id = $1
-        "#
-    )
-    .bind(&user_id)
-    .fetch_optional(pool.get_ref())
-    .await;
-
-    let user = match user_result {
-        Ok(Some(row)) => row,
-        Ok(None) => return HttpResponse::NotFound().json("User not found"),
+    // Take the lease like the scheduler does, so the two never sync one mailbox at once
+    let owner = format!("manual-{}", uuid::Uuid::new_v4());
+    match sync::claim_account(pool.get_ref(), &user_id, &owner, sync::DEFAULT_LEASE_SECS).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return match sync::status(pool.get_ref(), &user_id).await {
+                Ok(Some(status)) if status.syncing => HttpResponse::Conflict().json("A sync of this mailbox is already running"),
+                Ok(Some(_)) => HttpResponse::BadRequest().json("No mailbox connected. Use OAuth or set IMAP password."),
+                Ok(None) => HttpResponse::NotFound().json("User not found"),
+                Err(e) => HttpResponse::InternalServerError().json(format!("DB Error: {}", e)),
+            };
+        }
         Err(e) => return HttpResponse::InternalServerError().json(format!("DB Error: {}", e)),
-    };
+    }
 
-    let email: String = user.get("email");
-    let imap_server: Option<String> = user.get("imap_server");
-    let port: i32 = user.get("imap_port");
-    let imap_password: Option<String> = user.get("imap_password");
-    let auth_provider: Option<String> = user.get("auth_provider");
-    let access_token: Option<String> = user.get("access_token");
-
-    // Use Gmail API for Google OAuth users, IMAP for others (also allow WorkOS users who connected Gmail)
-    if (auth_provider.as_deref() == Some("google") || 
-        auth_provider.as_deref() == Some("gmail_connect") || 
-        auth_provider.as_deref() == Some("workos")) && access_token.is_some() {
-        // Use Gmail API (more reliable than IMAP XOAUTH2)
-        let token = access_token.unwrap();
-        match crate::core::gmail_api::fetch_gmail_emails(&token, 2).await {
-            Ok(emails) if !emails.is_empty() => {
-                // Save all emails to database
-                let mut saved_count = 0;
-                for fetched in &emails {
-                        let insert_result = sqlx::query(
-                            r#"
-                            INSERT INTO emails (user_id, message_id, sender, subject, body_preview, received_at)
-                            VALUES ($1, $2, $3, $4, $5, TO_TIMESTAMP($6))
-                            ON CONFLICT (user_id, message_id) DO UPDATE SET
-                                received_at = EXCLUDED.received_at
-                            "#
-                        )
-                        .bind(&user_id)
-                        .bind(&fetched.message_id)
-                        .bind(&fetched.sender)
-                        .bind(&fetched.subject)
-                        .bind(&fetched.body_preview)
-                        .bind(fetched.received_at as f64)
-                        .execute(pool.get_ref())
-                        .await;
-                    
-                    if insert_result.is_ok() {
-                        saved_count += 1;
-                    }
-                }
-                
-                let latest = emails.first().unwrap();
-                HttpResponse::Ok().json(serde_json::json!({
-                    "synced": true,
-                    "count": saved_count,
-                    "email": {
-                        "sender": latest.sender,
-                        "subject": latest.subject,
-                        "preview": latest.body_preview,
-                        "received_at": chrono::Utc::now().to_string()
-                    },
-                    "message": format!("Synced {} emails successfully", saved_count)
-                }))
-            }
-            Ok(_) => HttpResponse::Ok().json(SyncResponse {
-                synced: false,
-                email: None,
-                message: "No emails found in inbox".to_string(),
-            }),
-            Err(e) => HttpResponse::InternalServerError().json(SyncResponse {
-                synced: false,
-                email: None,
-                message: format!("Gmail API error: {}", e),
-            }),
-        }
-    } else if imap_password.is_some() && imap_server.is_some() {
-        // Use IMAP for non-Google providers
-        let creds = ImapCredentials {
-            email: email.clone(),
-            password: imap_password,
-            access_token: None,
-            server: imap_server.unwrap(),
-            port: port as u16,
-        };
-        
-        match fetch_latest_email(&creds).await {
-            Ok(Some(fetched)) => {
-                let insert_result = sqlx::query(
-                    r#"
-                    INSERT INTO emails (user_id, message_id, sender, subject, body_preview, received_at)
-                    VALUES ($1, $2, $3, $4, $5, TO_TIMESTAMP($6))
-                    ON CONFLICT (user_id, message_id) DO UPDATE SET
-                        received_at = EXCLUDED.received_at
-                    "#
-                )
-                .bind(&user_id)
-                .bind(&fetched.message_id)
-                .bind(&fetched.sender)
-                .bind(&fetched.subject)
-                .bind(&fetched.body_preview)
-                .bind(fetched.received_at as f64)
-                .execute(pool.get_ref())
-                .await;
+    let result = sync::sync_user(&repos, &keys.into_inner(), &user_id).await;
+    if let Err(e) = sync::record_result(pool.get_ref(), &user_id, Some(&owner), &result, sync::DEFAULT_JITTER_PERCENT).await {
+        eprintln!("⚠️ Failed to record sync result for {}: {}", user_id, e);
+    }
 
-                match insert_result {
-                    Ok(_) => HttpResponse::Ok().json(SyncResponse {
-                        synced: true,
-                        email: Some(EmailResponse {
-                            sender: fetched.sender,
-                            subject: fetched.subject,
-                            preview: fetched.body_preview,
-                            otp: None,
-                            received_at: chrono::Utc::now().to_string(),
-                        }),
-                        message: "Email synced successfully".to_string(),
-                    }),
-                    Err(e) => HttpResponse::InternalServerError().json(format!("Failed to save: {}", e)),
-                }
-            }
-            Ok(None) => HttpResponse::Ok().json(SyncResponse {
-                synced: false,
-                email: None,
-                message: "No emails found in inbox".to_string(),
-            }),
-            Err(e) => HttpResponse::InternalServerError().json(SyncResponse {
+    match result {
+        Ok(report) => match report.latest {
+            Some(latest) => HttpResponse::Ok().json(serde_json::json!({
+                "synced": true,
+                "provider": report.provider,
+                "count": report.saved,
+                "failed": report.failures.len(),
+                "failures": report.failures,
+                "email": {
+                    "sender": latest.sender,
+                    "subject": latest.subject,
+                    "preview": latest.body_preview,
+                    "otp": latest.otp,
+                    "received_at": chrono::Utc::now().to_string()
+                },
+                "message": format!("Synced {} emails successfully", report.saved)
+            })),
+            None if !report.failures.is_empty() => HttpResponse::BadGateway().json(serde_json::json!({
+                "synced": false,
+                "provider": report.provider,
+                "count": 0,
+                "failed": report.failures.len(),
+                "failures": report.failures,
+                "message": "Failed to fetch any emails"
+            })),
+            None => HttpResponse::Ok().json(SyncResponse {
                 synced: false,
                 email: None,
-                message: format!("IMAP error: {}", e),
+                message: "No new emails in inbox".to_string(),
             }),
-        }
-    } else {
-        HttpResponse::BadRequest().json("No credentials configured. Use OAuth or set IMAP password.")
+        },
+        Err(sync::SyncError::UserNotFound) => HttpResponse::NotFound().json("User not found"),
+        Err(e @ sync::SyncError::NoCredentials(_)) => HttpResponse::BadRequest().json(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().json(SyncResponse {
+            synced: false,
+            email: None,
+            message: e.to_string(),
+        }),
     }
 }
 
-/// Get latest email from database (requires Bearer token)
-pub async fn get_latest(
-    req: HttpRequest,
+#[derive(Deserialize)]
+pub struct SyncSettingsRequest {
+    interval_secs: i32,
+}
+
+/// Get background sync status for a user's mailbox (requires Bearer token)
+pub async fn get_sync_status(
+    auth: AuthenticatedUser,
     pool: web::Data<PgPool>,
-    path_user_id: web::Path<String> 
+    path_user_id: web::Path<String>,
 ) -> HttpResponse {
-    // Validate JWT token
-    let auth_header = req.headers().get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("");
-    
-    let token = match jwt::extract_bearer_token(auth_header) {
-        Some(t) => t,
-        None => return HttpResponse::Unauthorized().json("Missing Authorization: Bearer <token>"),
-    };
+    let user_id = path_user_id.into_inner();
     
-    let token_user_id = match jwt::validate_token(token) {
-        Ok(uid) => uid,
-        Err(e) => return HttpResponse::Unauthorized().json(format!("Invalid token: {}", e)),
-    };
+    // Ensure user can only access their own data
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    match sync::status(pool.get_ref(), &user_id).await {
+        Ok(Some(status)) => HttpResponse::Ok().json(status),
+        Ok(None) => HttpResponse::NotFound().json("User not found"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB Error: {}", e)),
+    }
+}
+
+/// Change how often the background scheduler syncs a user's mailbox (requires Bearer token)
+pub async fn update_sync_settings(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path_user_id: web::Path<String>,
+    body: web::Json<SyncSettingsRequest>,
+) -> HttpResponse {
+    let user_id = path_user_id.into_inner();
     
+    // Ensure user can only access their own data
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ManageMail)) {
+        return e.error_response();
+    }
+
+    if body.interval_secs < sync::MIN_INTERVAL_SECS {
+        return HttpResponse::BadRequest().json(format!("interval_secs must be at least {}", sync::MIN_INTERVAL_SECS));
+    }
+
+    match sync::set_interval(pool.get_ref(), &user_id, body.interval_secs).await {
+        Ok(_) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": user_id,
+            "interval_secs": body.interval_secs
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB Error: {}", e)),
+    }
+}
+
+/// Get latest email from database (requires Bearer token)
+pub async fn get_latest(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path_user_id: web::Path<String>,
+) -> HttpResponse {
     let user_id_str = path_user_id.into_inner();
     
     // Ensure user can only access their own data
-    if token_user_id != user_id_str {
-        return HttpResponse::Forbidden().json("Token does not match user_id");
-    }
-    let result = sqlx::query(
-        r#"
-        SELECT sender, subject, body_preview, otp, received_at::text
-        FROM emails
-        WHERE user_id = $1
-        ORDER BY received_at DESC
-        LIMIT 1
-        "#
-    )
-    .bind(&user_id_str)
-    .fetch_optional(pool.get_ref())
-    .await;
-
-    match result {
-        Ok(Some(row)) => HttpResponse::Ok().json(EmailResponse {
-            sender: row.get("sender"),
-            subject: row.get::<Option<String>, _>("subject").unwrap_or_default(),
-            preview: row.get::<Option<String>, _>("body_preview").unwrap_or_default(),
-            otp: row.get::<Option<String>, _>("otp"),
-            received_at: row.get::<Option<String>, _>("received_at").unwrap_or_default(),
-        }),
-        Ok(None) => HttpResponse::NotFound().json("Inbox Empty"),
+    if let Err(e) = auth.ensure_owns(&user_id_str).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+    let newest = EmailQuery { limit: 1, ..Default::default() };
+    match repos.emails.list(Inbox::Personal(&user_id_str), &newest).await {
+        Ok(rows) => match rows.into_iter().next() {
+            Some(row) => HttpResponse::Ok().json(EmailResponse::from(row)),
+            None => HttpResponse::NotFound().json("Inbox Empty"),
+        },
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -473,7 +456,12 @@ pub struct EmailWebhookPayload {
     pub subject: String,
     pub body: String,
     pub message_id: Option<String>,
+    pub in_reply_to: Option<String>, // Raw header values, as <id> lists
+    pub references: Option<String>,
     pub otp: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>, // [name, value] pairs, when the worker forwards them
+    pub raw: Option<String>,            // The whole message, base64: parsed and DKIM-checked like SMTP mail
 }
 
 #[derive(Deserialize)]
@@ -482,64 +470,116 @@ pub struct WorkOSCallbackQuery {
     state: Option<String>,
 }
 
-/// Start WorkOS AuthKit login (supports Google, email, etc.)
-    pub async fn auth_workos_sso(query: web::Query<SSOQuery>) -> HttpResponse {
+/// Pick the login target of an SSO request: an explicit connection or organization first,
+/// then the WorkOS organization that verified the email's domain, else AuthKit
+async fn sso_target(config: &workos_auth::WorkOSConfig, query: &SSOQuery) -> workos_auth::LoginTarget {
+    use workos_auth::LoginTarget;
+
+    if let Some(id) = query.connection_id.as_deref().filter(|id| !id.is_empty()) {
+        return LoginTarget::Connection(id.to_string());
+    }
+    if let Some(id) = query.organization_id.as_deref().filter(|id| !id.is_empty()) {
+        return LoginTarget::Organization(id.to_string());
+    }
+
+    let domain = query.email.as_deref()
+        .and_then(|email| email.rsplit_once('@'))
+        .map(|(_, domain)| domain.trim().to_lowercase())
+        .filter(|domain| !domain.is_empty());
+    if let Some(domain) = domain {
+        match workos_auth::find_organization_by_domain(config, &domain).await {
+            Ok(Some(org)) => return LoginTarget::Organization(org.id),
+            Ok(None) => {}
+            // Discovery is a shortcut: AuthKit still lets the user sign in
+            Err(e) => eprintln!("⚠️ WorkOS organization lookup for {} failed: {}", domain, e),
+        }
+    }
+
+    LoginTarget::AuthKit
+}
+
+/// Start WorkOS login: AuthKit (Google, email, etc.) or enterprise SSO
+/// through `connection_id`, `organization_id` or the domain of `email`
+pub async fn auth_workos_sso(req: HttpRequest, query: web::Query<SSOQuery>) -> HttpResponse {
     let config = match workos_auth::WorkOSConfig::from_env() {
         Ok(c) => c,
         Err(e) => return HttpResponse::InternalServerError().json(format!("Config error: {}", e)),
     };
     
-    let redirect_base = query.redirect_to.as_deref().unwrap_or("http://localhost:5176");
-    let state = format!("authkit_login|{}", redirect_base);
-    let url = workos_auth::get_auth_url(&config, &state);
+    let redirect_base = match redirects::validate(query.redirect_to.as_deref()) {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    // Bound to this browser through a cookie: the callback only finishes logins it started
+    let (state, nonce) = match oauth_state::issue_login(&redirect_base) {
+        Ok(issued) => issued,
+        Err(e) => return HttpResponse::InternalServerError().json(e),
+    };
+    let target = sso_target(&config, &query).await;
+    let url = workos_auth::get_auth_url(&config, &state, &target, query.email.as_deref());
     
     HttpResponse::Found()
+        .cookie(login_cookie(&req, nonce, actix_web::cookie::time::Duration::minutes(10)))
         .append_header(("Location", url))
         .finish()
 }
 
+/// The cookie a WorkOS login state is bound to, sent back only to the callback
+fn login_cookie(req: &HttpRequest, nonce: String, max_age: actix_web::cookie::time::Duration) -> actix_web::cookie::Cookie<'static> {
+    actix_web::cookie::Cookie::build(oauth_state::LOGIN_COOKIE, nonce)
+        .path("/auth/workos/callback")
+        .http_only(true)
+        .secure(req.connection_info().scheme() == "https")
+        // Lax still sends it on the top-level redirect back from WorkOS
+        .same_site(actix_web::cookie::SameSite::Lax)
+        .max_age(max_age)
+        .finish()
+}
+
 /// Handle WorkOS AuthKit callback
 pub async fn auth_workos_callback(
+    req: HttpRequest,
     pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    keys: web::Data<KeyRing>,
     query: web::Query<WorkOSCallbackQuery>,
 ) -> HttpResponse {
     let config = match workos_auth::WorkOSConfig::from_env() {
         Ok(c) => c,
         Err(e) => return HttpResponse::InternalServerError().json(format!("Config error: {}", e)),
     };
+
+    // Only the browser that started this login may finish it, and the redirect target
+    // must still be on the allowlist
+    let nonce = req.cookie(oauth_state::LOGIN_COOKIE);
+    let base_url = match oauth_state::verify_login(query.state.as_deref().unwrap_or(""), nonce.as_ref().map(|c| c.value()))
+        .and_then(|redirect| redirects::validate(Some(&redirect)))
+    {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
     
     // Exchange code for user and tokens
-    let (user, _access_token, _refresh_token) = match workos_auth::authenticate_with_code(&config, &query.code).await {
+    let auth = match workos_auth::authenticate_with_code(&config, &query.code).await {
         Ok(result) => result,
         Err(e) => return HttpResponse::InternalServerError().json(format!("Auth failed: {}", e)),
     };
+    let user = &auth.user;
     
     // Create or update user in database
-    let row = sqlx::query(
-        r#"
-        INSERT INTO users (id, email, auth_provider)
-        VALUES ($1, $2, 'workos')
-        ON CONFLICT (id) DO UPDATE SET
-            email = EXCLUDED.email,
-            auth_provider = EXCLUDED.auth_provider
-        "#
-    )
-    .bind(&user.id)
-    .bind(&user.email)
-    .execute(pool.get_ref())
-    .await;
-    
-    match row {
+    match repos.users.upsert_workos(&user.id, &user.email).await {
         Ok(_) => {
-            // Generate JWT
-            let token = jwt::generate_token(&user.id).unwrap_or_default();
-            
             // Fetch Alias
-            let alias_row = sqlx::query("SELECT alias FROM temp_aliases WHERE user_id = $1 ORDER BY created_at DESC LIMIT 1")
-                .bind(&user.id)
-                .fetch_optional(pool.get_ref())
-                .await.unwrap_or(None);
-            let temp_alias: Option<String> = alias_row.map(|r| r.get("alias"));
+            let temp_alias = repos.aliases.personal(&user.id).await.unwrap_or(None);
+
+            // SSO logins join the organization linked to their WorkOS organization
+            let organization_id = match &auth.organization_id {
+                Some(workos_org_id) => match orgs::provision(pool.get_ref(), workos_org_id, &user.id).await {
+                    Ok(org_id) => org_id,
+                    Err(e) => return HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+                },
+                None => None,
+            };
             
             let user_json = serde_json::json!({
                 "id": user.id,
@@ -547,87 +587,234 @@ pub async fn auth_workos_callback(
                 "first_name": user.first_name,
                 "last_name": user.last_name,
                 "email_verified": user.email_verified,
-                "temp_alias": temp_alias
-            }).to_string();
-
-            // Extract redirect base URL from state
-            let state_parts: Vec<&str> = query.state.as_deref().unwrap_or("").split('|').collect();
-            let base_url = if state_parts.len() > 1 { state_parts[1] } else { "http://localhost:5176" };
-            let base_url = base_url.trim_end_matches('/');
-            let encoded_user = url::form_urlencoded::byte_serialize(user_json.as_bytes()).collect::<String>();
-            
-            let redirect_url = format!("{}/?token={}&user={}", base_url, token, encoded_user);
+                "temp_alias": temp_alias,
+                "organization_id": organization_id
+            });
+
+            // Keep the WorkOS session: our session is refreshed through it and ends with it
+            let workos_session_id = match workos_sessions::store(pool.get_ref(), &keys, &user.id, &auth.access_token, &auth.refresh_token, auth.organization_id.as_deref()).await {
+                Ok(id) => id,
+                Err(e) => return HttpResponse::InternalServerError().json(e),
+            };
+
+            // The frontend trades this one-time code for the token at POST /auth/exchange
+            let code = match exchange_code::issue(pool.get_ref(), &user.id, &user_json, Some(&workos_session_id)).await {
+                Ok(code) => code,
+                Err(e) => return HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+            };
+            // The redirect target may already carry a query of its own
+            let mut redirect_url = match url::Url::parse(&base_url) {
+                Ok(url) => url,
+                Err(e) => return HttpResponse::BadRequest().json(format!("Invalid redirect URL: {}", e)),
+            };
+            redirect_url.query_pairs_mut().append_pair("code", &code);
             
             HttpResponse::Found()
-                .append_header(("Location", redirect_url))
+                .cookie(login_cookie(&req, String::new(), actix_web::cookie::time::Duration::ZERO))
+                .append_header(("Location", redirect_url.as_str()))
                 .finish()
         }
         Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
     }
 }
 
+#[derive(Deserialize)]
+pub struct ExchangeRequest {
+    code: String,
+}
+
+/// Trade a one-time code from a login redirect for a JWT and the user profile
+pub async fn exchange_login_code(
+    pool: web::Data<PgPool>,
+    body: web::Json<ExchangeRequest>,
+) -> HttpResponse {
+    let redeemed = match exchange_code::redeem(pool.get_ref(), &body.code).await {
+        Ok(Some(redeemed)) => redeemed,
+        Ok(None) => return HttpResponse::BadRequest().json("Invalid or expired code"),
+        Err(e) => return HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    };
+
+    match sessions::start(pool.get_ref(), &redeemed.user_id, redeemed.workos_session_id.as_deref()).await {
+        Ok(session) => HttpResponse::Ok().json(serde_json::json!({
+            "token": session.token,
+            "refresh_token": session.refresh_token,
+            "expires_in": session.expires_in,
+            "user": redeemed.profile
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Rotate a refresh token: returns a new access token and a new refresh token
+pub async fn refresh_session(
+    pool: web::Data<PgPool>,
+    keys: web::Data<KeyRing>,
+    body: web::Json<RefreshRequest>,
+) -> HttpResponse {
+    match sessions::refresh(pool.get_ref(), &keys, &body.refresh_token).await {
+        Ok(session) => HttpResponse::Ok().json(session),
+        Err(e @ sessions::RefreshError::Internal(_)) => HttpResponse::InternalServerError().json(e.to_string()),
+        Err(e) => HttpResponse::Unauthorized().json(e.to_string()),
+    }
+}
+
+/// End the caller's login session; its access and refresh tokens stop working.
+/// For WorkOS logins the WorkOS session is revoked too, and `logout_url` clears the AuthKit cookie.
+pub async fn logout(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    let Some(session_id) = &auth.session_id else {
+        return HttpResponse::BadRequest().json("Token is not bound to a session");
+    };
+
+    let workos_session_id = match sessions::revoke(pool.get_ref(), session_id).await {
+        Ok(id) => id,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    };
+
+    // Logged out here either way; a WorkOS failure only leaves its own session to expire
+    let logout_url = match workos_session_id {
+        Some(workos_session_id) => {
+            if let Err(e) = workos_sessions::end(pool.get_ref(), &workos_session_id).await {
+                eprintln!("⚠️ Failed to revoke WorkOS session {}: {}", workos_session_id, e);
+            }
+            workos_auth::WorkOSConfig::from_env()
+                .ok()
+                .map(|config| workos_auth::logout_url(&config, &workos_session_id, &redirects::default_redirect()))
+        }
+        None => None,
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "message": "Logged out",
+        "logout_url": logout_url
+    }))
+}
+
+/// WorkOS events: a revoked session or a removed user ends their sessions here right away
+pub async fn handle_workos_webhook(
+    pool: web::Data<PgPool>,
+    req: HttpRequest,
+    body: web::Bytes,
+) -> HttpResponse {
+    let secret = std::env::var("WORKOS_WEBHOOK_SECRET").unwrap_or_default();
+    let signature = req.headers().get("WorkOS-Signature")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("");
+
+    if secret.is_empty() || !workos_auth::verify_webhook(signature, &body, &secret, chrono::Utc::now().timestamp_millis()) {
+        return HttpResponse::Unauthorized().json("Invalid webhook signature");
+    }
+
+    let event: workos_auth::WebhookEvent = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(e) => return HttpResponse::BadRequest().json(format!("Invalid event: {}", e)),
+    };
+    let Some(id) = event.data.get("id").and_then(|v| v.as_str()) else {
+        return HttpResponse::Ok().json("Ignored");
+    };
+
+    let result = match event.event.as_str() {
+        "session.revoked" => {
+            println!("🔒 WorkOS revoked session {}", id);
+            match workos_sessions::mark_ended(pool.get_ref(), id).await {
+                Ok(()) => sessions::revoke_linked(pool.get_ref(), id).await,
+                Err(e) => Err(e),
+            }
+        }
+        "user.deleted" => {
+            println!("🔒 WorkOS removed user {}", id);
+            match workos_sessions::mark_user_ended(pool.get_ref(), id).await {
+                Ok(()) => sessions::revoke_all(pool.get_ref(), id).await,
+                Err(e) => Err(e),
+            }
+        }
+        _ => return HttpResponse::Ok().json("Ignored"),
+    };
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json("Event processed"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Public keys that verify our access tokens, for services that want to check them without asking us
+pub async fn jwks() -> HttpResponse {
+    match jwt::KeySet::from_env() {
+        Ok(keys) => HttpResponse::Ok()
+            .insert_header(("Cache-Control", "public, max-age=300"))
+            .json(keys.jwks()),
+        Err(e) => HttpResponse::InternalServerError().json(e),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct ConnectGmailQuery {
-    user_id: String,
+    user_id: Option<String>, // Optional; must name the caller if given
     redirect_to: Option<String>,
 }
 
-/// Start Gmail OAuth to connect email access (after WorkOS login)
-pub async fn connect_gmail(query: web::Query<ConnectGmailQuery>) -> HttpResponse {
-    let redirect_base = query.redirect_to.as_deref().unwrap_or("http://localhost:5176");
-    // State format: user_id:gmail_connect:redirect_base
-    let state = format!("{}:gmail_connect:{}", query.user_id, redirect_base);
-    
-    // Redirect to Google OAuth with gmail scope
-    match oauth::google_auth_url(&state) {
-        Ok(url) => HttpResponse::Found()
-            .append_header(("Location", url))
-            .finish(),
-        Err(e) => HttpResponse::InternalServerError().json(format!("Error: {}", e)),
-    }
+/// Start Gmail OAuth to connect email access to the caller's account (requires a login session)
+pub async fn connect_gmail(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    query: web::Query<ConnectGmailQuery>,
+) -> HttpResponse {
+    let redirect_base = match redirects::validate(query.redirect_to.as_deref()) {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    start_oauth(&auth, pool.get_ref(), query.user_id.as_deref(), "gmail_connect", Some(&redirect_base)).await
 }
 
 /// Handle Gmail OAuth callback - stores access token for user
 pub async fn connect_gmail_callback(
     pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    keys: web::Data<KeyRing>,
     query: web::Query<CallbackQuery>,
 ) -> HttpResponse {
-    // Parse state to get user_id and redirect
-    let parts: Vec<&str> = query.state.split(':').collect();
-    if parts.len() < 2 {
-        return HttpResponse::BadRequest().json("Invalid state");
-    }
-    let user_id = parts[0];
-    let redirect_base = if parts.len() > 2 { parts[2] } else { "http://localhost:5176" };
-    // Ensure no trailing slash
-    let redirect_base = redirect_base.trim_end_matches('/');
+    // Verify and consume the signed state to get user_id and redirect
+    let state = match oauth_state::consume(pool.get_ref(), &query.state).await {
+        Ok(state) if state.provider == "gmail_connect" => state,
+        Ok(_) => return HttpResponse::BadRequest().json("Invalid state"),
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let user_id = state.user_id.as_str();
+    let redirect_base = match redirects::validate(state.redirect.as_deref()) {
+        Ok(url) => url,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
     
     // Exchange code for tokens
-    let tokens = match oauth::google_exchange_code(&query.code).await {
+    let tokens = match oauth::google_exchange_code(&query.code, state.pkce_verifier).await {
         Ok(t) => t,
         Err(e) => return HttpResponse::InternalServerError().json(format!("Token exchange failed: {}", e)),
     };
     
+    let (access_token, refresh_token) = match seal_tokens(&keys, user_id, &tokens) {
+        Ok(sealed) => sealed,
+        Err(e) => return HttpResponse::InternalServerError().json(format!("Encryption error: {}", e)),
+    };
+
     // Store Gmail tokens for this user
-    let result = sqlx::query(
-        r#"
-        UPDATE users 
-        SET access_token = $1, 
-            refresh_token = $2,
-            auth_provider = COALESCE(auth_provider, 'google')
-        WHERE id = $3
-        "#
-    )
-    .bind(&tokens.access_token)
-    .bind(&tokens.refresh_token)
-    .bind(user_id)
-    .execute(pool.get_ref())
-    .await;
+    let sealed = SealedTokens {
+        access_token,
+        refresh_token,
+        expires_at: tokens.expires_in.map(|secs| chrono::Utc::now() + chrono::Duration::seconds(secs as i64)),
+    };
+    let result = repos.tokens.connect(user_id, "google", &sealed).await;
 
     match result {
         Ok(_) => {
             HttpResponse::Found()
-                .append_header(("Location", redirect_base))
+                .append_header(("Location", redirect_base.as_str()))
                 .finish()
         }
         Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
@@ -638,45 +825,25 @@ pub async fn connect_gmail_callback(
 
 /// Create a temporary email alias for the logged-in user
 pub async fn create_temp_mail(
-    pool: web::Data<PgPool>,
-    req: HttpRequest,
+    repos: web::Data<Repos>,
+    auth: AuthenticatedUser,
 ) -> HttpResponse {
-    // Validate Token
-    let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok()).unwrap_or("");
-    let token = match jwt::extract_bearer_token(auth_header) {
-        Some(t) => t,
-        None => return HttpResponse::Unauthorized().json("Missing token"),
-    };
-    let user_id = match jwt::validate_token(token) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
-    };
+    if let Err(e) = auth.ensure_scope(Scope::ManageAliases) {
+        return e.error_response();
+    }
+    let user_id = auth.user_id;
 
     let timestamp = chrono::Utc::now().timestamp_micros();
     let alias = format!("temp_{}", timestamp);
     // let email = format!("{}@localhost", alias); 
     
-    // Clear old aliases (keep 1 for now)
-    let _ = sqlx::query("DELETE FROM temp_aliases WHERE user_id = $1")
-        .bind(&user_id)
-        .execute(pool.get_ref())
-        .await;
-
-    // Insert new alias
-    let result = sqlx::query("INSERT INTO temp_aliases (alias, user_id) VALUES ($1, $2)")
-        .bind(&alias)
-        .bind(&user_id)
-        .execute(pool.get_ref())
-        .await;
-    
-    match result {
+    // Replace the old alias (keep 1 for now), shared ones belong to their organization
+    match repos.aliases.replace_personal(&user_id, &alias).await {
         Ok(_) => {
-            // Get mail domain from env, default to localhost for dev
-            let mail_domain = std::env::var("MAIL_DOMAIN").unwrap_or_else(|_| "localhost".to_string());
             HttpResponse::Ok().json(serde_json::json!({
                 "id": user_id, // Return real user ID
+                "email": alias_address(&alias),
                 "alias": alias,
-                "email": format!("{}@{}", alias, mail_domain)
             }))
         },
         Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
@@ -685,37 +852,38 @@ pub async fn create_temp_mail(
 
 /// Delete a temporary email alias
 pub async fn delete_temp_mail(
-    pool: web::Data<PgPool>,
-    req: HttpRequest,
+    repos: web::Data<Repos>,
+    auth: AuthenticatedUser,
 ) -> HttpResponse {
-     // Validate Token
-    let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok()).unwrap_or("");
-    let token = match jwt::extract_bearer_token(auth_header) {
-        Some(t) => t,
-        None => return HttpResponse::Unauthorized().json("Missing token"),
-    };
-    let user_id = match jwt::validate_token(token) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::Unauthorized().json("Invalid token"),
-    };
+    if let Err(e) = auth.ensure_scope(Scope::ManageAliases) {
+        return e.error_response();
+    }
+    let user_id = auth.user_id;
 
-    let result = sqlx::query("DELETE FROM temp_aliases WHERE user_id = $1")
-        .bind(&user_id)
-        .execute(pool.get_ref())
-        .await;
-        
-    match result {
+    match repos.aliases.delete_personal(&user_id).await {
         Ok(_) => HttpResponse::Ok().json("Deleted alias"),
         Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
     }
 }
 
+/// Full address of an alias (MAIL_DOMAIN, default to localhost for dev)
+fn alias_address(alias: &str) -> String {
+    let mail_domain = std::env::var("MAIL_DOMAIN").unwrap_or_else(|_| "localhost".to_string());
+    format!("{}@{}", alias, mail_domain)
+}
+
 pub struct SyncedEmail {
+    pub id: i64,
+    pub alias: Option<String>,
     pub sender: String,
     pub subject: String,
     pub preview: String,
     pub otp: Option<String>,
+    pub read: bool,
     pub received_at: String,
+    pub thread_id: i64,
+    pub starred: bool,
+    pub labels: Vec<String>,
 }
 
 impl serde::Serialize for SyncedEmail {
@@ -724,108 +892,783 @@ impl serde::Serialize for SyncedEmail {
         S: serde::Serializer,
     {
         use serde::ser::SerializeStruct;
-        let mut state = serializer.serialize_struct("SyncedEmail", 5)?;
+        let mut state = serializer.serialize_struct("SyncedEmail", 11)?;
+        state.serialize_field("id", &self.id)?;
+        state.serialize_field("alias", &self.alias)?;
         state.serialize_field("sender", &self.sender)?;
         state.serialize_field("subject", &self.subject)?;
         state.serialize_field("preview", &self.preview)?;
         state.serialize_field("otp", &self.otp)?;
+        state.serialize_field("read", &self.read)?;
         state.serialize_field("received_at", &self.received_at)?;
+        state.serialize_field("thread_id", &self.thread_id)?;
+        state.serialize_field("starred", &self.starred)?;
+        state.serialize_field("labels", &self.labels)?;
         state.end()
     }
 }
 
-/// Get all emails for a user
-pub async fn get_all_emails(
-    pool: web::Data<PgPool>,
-    path: web::Path<String>,
-) -> HttpResponse {
-    let user_id = path.into_inner();
-    
-    let result = sqlx::query(
-        r#"
-        SELECT sender, subject, body_preview, otp, received_at::text
-        FROM emails
-        WHERE user_id = $1
-        ORDER BY received_at DESC
-        LIMIT 50
-        "#
-    )
-    .bind(user_id)
-    .fetch_all(pool.get_ref())
-    .await;
-    
-    match result {
-        Ok(rows) => {
-            let emails: Vec<SyncedEmail> = rows.into_iter().map(|row| SyncedEmail {
-                sender: row.get::<String, _>("sender"),
-                subject: row.get::<Option<String>, _>("subject").unwrap_or_default(),
-                preview: row.get::<Option<String>, _>("body_preview").unwrap_or_default(),
-                otp: row.get::<Option<String>, _>("otp"),
-                received_at: row.get::<Option<String>, _>("received_at").unwrap_or_default(),
-            }).collect();
-            HttpResponse::Ok().json(emails)
-        },
-        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+impl From<EmailRow> for SyncedEmail {
+    fn from(row: EmailRow) -> Self {
+        SyncedEmail {
+            id: row.id,
+            alias: row.alias,
+            sender: row.sender,
+            subject: row.subject.unwrap_or_default(),
+            preview: row.body_preview.unwrap_or_default(),
+            otp: row.otp,
+            read: row.read_at.is_some(),
+            received_at: row.received_at.to_string(),
+            thread_id: row.thread_id,
+            starred: row.starred,
+            labels: row.labels,
+        }
     }
 }
 
-/// Handle incoming email webhook from Cloudflare
-pub async fn handle_email_webhook(
-    pool: web::Data<PgPool>,
-    payload: web::Json<EmailWebhookPayload>,
-    req: HttpRequest,
-) -> HttpResponse {
-    // 1. Verify Secret
-    let secret = std::env::var("WEBHOOK_SECRET").unwrap_or_default();
-    let header_secret = req.headers().get("X-Webhook-Secret")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("");
-        
-    if secret.is_empty() || secret != header_secret {
-        return HttpResponse::Unauthorized().json("Invalid webhook secret");
-    }
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
 
-    // 2. Extract Alias (local part of email)
-    // "temp_123@domain.com" -> "temp_123"
-    let to_address = payload.to.clone();
-    let local_part = to_address.split('@').next().unwrap_or("");
+/// Filters and page of `GET /emails/{user}` and `GET /orgs/{org}/emails`
+#[derive(Deserialize)]
+pub struct EmailListQuery {
+    alias: Option<String>,
+    sender: Option<String>,   // Substring of the sender, any case
+    subject: Option<String>,  // Substring of the subject, any case
+    after: Option<String>,    // RFC 3339, inclusive
+    before: Option<String>,   // RFC 3339, exclusive
+    has_otp: Option<bool>,
+    read: Option<bool>,       // false: unread only
+    starred: Option<bool>,
+    label: Option<String>,
+    cursor: Option<String>,   // next_cursor of the previous page
+    limit: Option<i64>,
+}
 
-    println!("📩 Webhook received email for: {}", local_part);
+impl EmailListQuery {
+    fn filters_anything(&self) -> bool {
+        self.alias.is_some()
+            || self.sender.is_some()
+            || self.subject.is_some()
+            || self.after.is_some()
+            || self.before.is_some()
+            || self.has_otp.is_some()
+            || self.read.is_some()
+            || self.starred.is_some()
+            || self.label.is_some()
+    }
+
+    fn to_query(&self) -> Result<EmailQuery, String> {
+        let time = |name: &str, value: &Option<String>| {
+            value.as_deref().map(|v| {
+                chrono::DateTime::parse_from_rfc3339(v)
+                    .map(|t| t.naive_utc())
+                    .map_err(|_| format!("{} must be an RFC 3339 time", name))
+            }).transpose()
+        };
+        let cursor = match self.cursor.as_deref() {
+            Some(token) => Some(EmailCursor::decode(token).ok_or("Invalid cursor")?),
+            None => None,
+        };
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+        }
+
+        Ok(EmailQuery {
+            alias: self.alias.clone(),
+            sender: self.sender.clone(),
+            subject: self.subject.clone(),
+            after: time("after", &self.after)?,
+            before: time("before", &self.before)?,
+            has_otp: self.has_otp,
+            read: self.read,
+            starred: self.starred,
+            label: self.label.clone(),
+            cursor,
+            limit,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct EmailPage {
+    emails: Vec<SyncedEmail>,
+    next_cursor: Option<String>, // None on the last page
+}
+
+/// List one page of an inbox
+async fn list_emails(repos: &Repos, inbox: Inbox<'_>, params: &EmailListQuery) -> HttpResponse {
+    let mut query = match params.to_query() {
+        Ok(query) => query,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    // One more than asked tells whether another page follows
+    let limit = query.limit;
+    query.limit += 1;
+
+    match repos.emails.list(inbox, &query).await {
+        Ok(mut rows) => {
+            let next_cursor = if rows.len() as i64 > limit {
+                rows.truncate(limit as usize);
+                rows.last().map(|row| EmailCursor::of(row).encode())
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(EmailPage {
+                emails: rows.into_iter().map(SyncedEmail::from).collect(),
+                next_cursor,
+            })
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Get all emails for a user (requires Bearer token)
+pub async fn get_all_emails(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+    query: web::Query<EmailListQuery>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+
+    // Ensure user can only access their own data
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    list_emails(&repos, Inbox::Personal(&user_id), &query).await
+}
+
+/// Page of `GET /threads/{user}` and `GET /orgs/{org}/threads`
+#[derive(Deserialize)]
+pub struct ThreadListQuery {
+    cursor: Option<String>, // next_cursor of the previous page
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ThreadSummary {
+    thread_id: i64,
+    subject: String,
+    participants: Vec<String>,
+    message_count: i64,
+    unread_count: i64,
+    latest_preview: String,
+    last_received_at: String,
+}
+
+impl From<ThreadRow> for ThreadSummary {
+    fn from(row: ThreadRow) -> Self {
+        ThreadSummary {
+            thread_id: row.thread_id,
+            subject: row.subject.unwrap_or_default(),
+            participants: row.participants,
+            message_count: row.message_count,
+            unread_count: row.unread_count,
+            latest_preview: row.latest_preview.unwrap_or_default(),
+            last_received_at: row.last_received_at.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ThreadPage {
+    threads: Vec<ThreadSummary>,
+    next_cursor: Option<String>, // None on the last page
+}
+
+#[derive(Serialize)]
+pub struct ThreadResponse {
+    thread_id: i64,
+    subject: String,
+    messages: Vec<SyncedEmail>, // Oldest first
+}
+
+/// List one page of an inbox's threads, most recently active first
+async fn list_threads(repos: &Repos, inbox: Inbox<'_>, params: &ThreadListQuery) -> HttpResponse {
+    let cursor = match params.cursor.as_deref().map(EmailCursor::decode) {
+        Some(None) => return HttpResponse::BadRequest().json("Invalid cursor"),
+        Some(cursor) => cursor,
+        None => None,
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().json(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+    }
+
+    // One more than asked tells whether another page follows
+    match repos.emails.threads(inbox, &ThreadQuery { cursor, limit: limit + 1 }).await {
+        Ok(mut rows) => {
+            let next_cursor = if rows.len() as i64 > limit {
+                rows.truncate(limit as usize);
+                rows.last().map(|row| EmailCursor { received_at: row.last_received_at, id: row.thread_id }.encode())
+            } else {
+                None
+            };
+            HttpResponse::Ok().json(ThreadPage {
+                threads: rows.into_iter().map(ThreadSummary::from).collect(),
+                next_cursor,
+            })
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Every message of one thread
+async fn show_thread(repos: &Repos, inbox: Inbox<'_>, thread_id: i64) -> HttpResponse {
+    match repos.emails.thread(inbox, thread_id).await {
+        Ok(rows) if rows.is_empty() => HttpResponse::NotFound().json("Thread not found"),
+        Ok(rows) => HttpResponse::Ok().json(ThreadResponse {
+            thread_id,
+            subject: rows[0].subject.clone().unwrap_or_default(),
+            messages: rows.into_iter().map(SyncedEmail::from).collect(),
+        }),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// List a user's conversations (requires Bearer token)
+pub async fn get_threads(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+    query: web::Query<ThreadListQuery>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    list_threads(&repos, Inbox::Personal(&user_id), &query).await
+}
+
+/// Get one conversation (requires Bearer token)
+pub async fn get_thread(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (user_id, thread_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    show_thread(&repos, Inbox::Personal(&user_id), thread_id).await
+}
+
+const DEFAULT_SEARCH_RESULTS: i64 = 20;
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,          // See `core::search` for the syntax
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    email: SyncedEmail,
+    rank: f32,
+    snippet: String, // HTML-escaped, matches wrapped in <mark></mark>
+}
+
+/// Search a user's mail, best match first (requires Bearer token)
+pub async fn search_emails(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+    params: web::Query<SearchParams>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    let query = match search::parse(&params.q) {
+        Ok(query) if query.is_empty() => return HttpResponse::BadRequest().json("Empty search"),
+        Ok(query) => query,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_RESULTS);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return HttpResponse::BadRequest().json(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+    }
+
+    match repos.emails.search(Inbox::Personal(&user_id), &query, limit).await {
+        Ok(hits) => {
+            let results: Vec<SearchResult> = hits.into_iter().map(|hit| SearchResult {
+                email: SyncedEmail::from(hit.email),
+                rank: hit.rank,
+                snippet: hit.snippet,
+            }).collect();
+            HttpResponse::Ok().json(serde_json::json!({ "results": results }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+#[derive(Serialize)]
+pub struct AttachmentResponse {
+    id: i64,
+    filename: Option<String>,
+    content_type: Option<String>,
+    size_bytes: i32,
+    content: Option<String>, // Base64
+}
+
+/// One email with everything it was received with
+#[derive(Serialize)]
+pub struct EmailDetailResponse {
+    #[serde(flatten)]
+    summary: SyncedEmail,
+    message_id: Option<String>,
+    headers: Vec<headers::Field>, // Empty for mail that arrived already parsed (webhooks) without them
+    dkim: Option<Vec<DkimSignature>>, // One result per signature; null when not verified on arrival
+    body_text: Option<String>,
+    body_html: Option<String>,
+    attachments: Vec<AttachmentResponse>,
+}
+
+impl From<EmailDetail> for EmailDetailResponse {
+    fn from(detail: EmailDetail) -> Self {
+        use base64::Engine;
+        let headers = header_fields(&detail);
+        EmailDetailResponse {
+            message_id: detail.email.message_id.clone(),
+            summary: SyncedEmail::from(detail.email),
+            headers: headers.iter().map(|(name, value)| headers::Field::new(name, value)).collect(),
+            dkim: detail.dkim,
+            body_text: detail.body_text,
+            body_html: detail.body_html,
+            attachments: detail.attachments.into_iter().map(|a| AttachmentResponse {
+                id: a.id,
+                filename: a.filename,
+                content_type: a.content_type,
+                size_bytes: a.size_bytes,
+                content: a.content.map(|c| base64::engine::general_purpose::STANDARD.encode(c)),
+            }).collect(),
+        }
+    }
+}
+
+/// Headers as stored, or parsed from the raw message for mail stored before they were
+fn header_fields(detail: &EmailDetail) -> Vec<(String, String)> {
+    match (&detail.headers, &detail.raw_message) {
+        (Some(headers), _) => headers.clone(),
+        (None, Some(raw)) => ingest::header_fields(raw),
+        (None, None) => Vec::new(),
+    }
+}
+
+/// Headers of one email of `inbox`, 404 if it has no such email
+async fn email_headers(repos: &Repos, inbox: Inbox<'_>, email_id: i64) -> Result<Vec<(String, String)>, HttpResponse> {
+    match repos.emails.get(inbox, email_id).await {
+        Ok(Some(detail)) => Ok(header_fields(&detail)),
+        Ok(None) => Err(HttpResponse::NotFound().json("Email not found")),
+        Err(e) => Err(HttpResponse::InternalServerError().json(format!("DB error: {}", e))),
+    }
+}
+
+/// Headers of one email, structured: Received chain, Authentication-Results,
+/// List-Unsubscribe and X- headers (requires Bearer token)
+pub async fn get_email_headers(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (user_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    match email_headers(&repos, Inbox::Personal(&user_id), email_id).await {
+        Ok(fields) => HttpResponse::Ok().json(headers::analyze(&fields)),
+        Err(response) => response,
+    }
+}
+
+/// What about one email could hurt its delivery (requires Bearer token)
+pub async fn get_deliverability_report(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (user_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    match email_headers(&repos, Inbox::Personal(&user_id), email_id).await {
+        Ok(fields) => HttpResponse::Ok().json(headers::report(&fields)),
+        Err(response) => response,
+    }
+}
+
+/// Get one email with headers, bodies and attachments (requires Bearer token)
+pub async fn get_email(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (user_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    show_email(&repos, Inbox::Personal(&user_id), email_id).await
+}
+
+/// One email of `inbox` with headers, bodies and attachments
+async fn show_email(repos: &Repos, inbox: Inbox<'_>, email_id: i64) -> HttpResponse {
+    match repos.emails.get(inbox, email_id).await {
+        Ok(Some(detail)) => HttpResponse::Ok().json(EmailDetailResponse::from(detail)),
+        Ok(None) => HttpResponse::NotFound().json("Email not found"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Download an email as it was received (requires Bearer token)
+pub async fn get_raw_email(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (user_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    download_email(&repos, Inbox::Personal(&user_id), email_id).await
+}
+
+/// One email of `inbox` as it was received, as an .eml attachment
+async fn download_email(repos: &Repos, inbox: Inbox<'_>, email_id: i64) -> HttpResponse {
+    match repos.emails.raw(inbox, email_id).await {
+        Ok(Some(raw)) => HttpResponse::Ok()
+            .content_type("message/rfc822")
+            .append_header(("Content-Disposition", format!("attachment; filename=\"{}.eml\"", email_id)))
+            .body(raw),
+        Ok(None) => HttpResponse::NotFound().json("Raw message not available"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Delete one email (requires Bearer token)
+pub async fn delete_email(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (user_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ManageMail)) {
+        return e.error_response();
+    }
+
+    remove_email(&repos, Inbox::Personal(&user_id), email_id).await
+}
+
+/// Delete one email of `inbox`
+async fn remove_email(repos: &Repos, inbox: Inbox<'_>, email_id: i64) -> HttpResponse {
+    match repos.emails.delete(inbox, email_id).await {
+        Ok(true) => HttpResponse::Ok().json("Deleted email"),
+        Ok(false) => HttpResponse::NotFound().json("Email not found"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+const MAX_LABEL_CHARS: usize = 64;
+
+/// Body of `PATCH /emails/{user}/{id}`; absent fields are left as they are
+#[derive(Deserialize)]
+pub struct StateChangeRequest {
+    read: Option<bool>,
+    starred: Option<bool>,
+    #[serde(default)]
+    add_labels: Vec<String>,
+    #[serde(default)]
+    remove_labels: Vec<String>,
+}
+
+impl StateChangeRequest {
+    fn to_change(&self) -> Result<StateChange, String> {
+        if self.read.is_none() && self.starred.is_none() && self.add_labels.is_empty() && self.remove_labels.is_empty() {
+            return Err("Nothing to change: pass read, starred, add_labels or remove_labels".to_string());
+        }
+        let labels = |labels: &[String]| {
+            labels.iter().map(|label| {
+                let label = label.trim();
+                if label.is_empty() || label.chars().count() > MAX_LABEL_CHARS || label.chars().any(char::is_control) {
+                    return Err(format!("Labels must be 1 to {} printable characters", MAX_LABEL_CHARS));
+                }
+                Ok(label.to_string())
+            }).collect::<Result<Vec<_>, String>>()
+        };
+        Ok(StateChange {
+            read: self.read,
+            starred: self.starred,
+            add_labels: labels(&self.add_labels)?,
+            remove_labels: labels(&self.remove_labels)?,
+        })
+    }
+}
+
+/// Body of `PATCH /emails/{user}`: the same change for several emails
+#[derive(Deserialize)]
+pub struct BulkStateChangeRequest {
+    ids: Vec<i64>,
+    #[serde(flatten)]
+    change: StateChangeRequest,
+}
+
+/// Mark one email read or unread, star it or label it (requires Bearer token)
+pub async fn update_email_state(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    hub: web::Data<EventHub>,
+    path: web::Path<(String, i64)>,
+    body: web::Json<StateChangeRequest>,
+) -> HttpResponse {
+    let (user_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::OrganizeMail)) {
+        return e.error_response();
+    }
+
+    change_state(&repos, &hub, Inbox::Personal(&user_id), email_id, &body).await
+}
+
+/// Apply a state change to one email of `inbox` and tell its event subscribers
+async fn change_state(
+    repos: &Repos,
+    hub: &EventHub,
+    inbox: Inbox<'_>,
+    email_id: i64,
+    body: &StateChangeRequest,
+) -> HttpResponse {
+    let change = match body.to_change() {
+        Ok(change) => change,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+
+    match repos.emails.update_state(inbox, &[email_id], &change).await {
+        Ok(rows) if rows.is_empty() => HttpResponse::NotFound().json("Email not found"),
+        Ok(mut rows) => {
+            hub.state_changed(inbox, &rows).await;
+            HttpResponse::Ok().json(SyncedEmail::from(rows.remove(0)))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Apply one state change to several emails; unknown ids are skipped (requires Bearer token)
+pub async fn update_emails_state(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    hub: web::Data<EventHub>,
+    path: web::Path<String>,
+    body: web::Json<BulkStateChangeRequest>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::OrganizeMail)) {
+        return e.error_response();
+    }
+
+    change_states(&repos, &hub, Inbox::Personal(&user_id), &body).await
+}
+
+/// Apply one state change to several emails of `inbox` and tell its event subscribers
+async fn change_states(repos: &Repos, hub: &EventHub, inbox: Inbox<'_>, body: &BulkStateChangeRequest) -> HttpResponse {
+    if body.ids.is_empty() || body.ids.len() as i64 > MAX_PAGE_SIZE {
+        return HttpResponse::BadRequest().json(format!("ids must list 1 to {} emails", MAX_PAGE_SIZE));
+    }
+    let change = match body.change.to_change() {
+        Ok(change) => change,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+
+    match repos.emails.update_state(inbox, &body.ids, &change).await {
+        Ok(rows) => {
+            hub.state_changed(inbox, &rows).await;
+            let emails: Vec<SyncedEmail> = rows.into_iter().map(SyncedEmail::from).collect();
+            HttpResponse::Ok().json(serde_json::json!({ "emails": emails }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Unread mail per alias (requires Bearer token)
+pub async fn get_unread_counts(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    match repos.emails.unread_counts(Inbox::Personal(&user_id)).await {
+        Ok(counts) => {
+            let total: i64 = counts.iter().map(|c| c.unread).sum();
+            let aliases: Vec<_> = counts.into_iter()
+                .map(|c| serde_json::json!({ "alias": c.alias, "unread": c.unread }))
+                .collect();
+            HttpResponse::Ok().json(serde_json::json!({ "total": total, "aliases": aliases }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Server-sent events of an inbox. The stream ends once the caller's session or key is
+/// revoked, or (for shared inboxes) once they leave the organization.
+fn event_stream(auth: AuthenticatedUser, pool: &PgPool, hub: &EventHub, inbox: Inbox<'_>) -> HttpResponse {
+    let pool = pool.clone();
+    let org_id = match inbox {
+        Inbox::Shared(org_id) => Some(org_id.to_string()),
+        Inbox::Personal(_) => None,
+    };
+    let still_allowed: events::AccessCheck = Box::new(move || {
+        let (auth, pool, org_id) = (auth.clone(), pool.clone(), org_id.clone());
+        Box::pin(async move {
+            if !auth.still_valid(&pool).await.unwrap_or(false) {
+                return false;
+            }
+            match org_id {
+                Some(org_id) => auth.ensure_org_role(&pool, &org_id, Role::ReadOnly).await.is_ok(),
+                None => true,
+            }
+        })
+    });
+
+    use futures::StreamExt;
+    let frames = events::inbox_stream(hub.subscribe(inbox), still_allowed)
+        .map(|frame| Ok::<_, actix_web::Error>(web::Bytes::from(frame)));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .append_header(("Cache-Control", "no-cache"))
+        .streaming(frames)
+}
+
+/// Stream changes to a user's mail as server-sent events (requires Bearer token)
+pub async fn stream_events(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    hub: web::Data<EventHub>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ReadMail)) {
+        return e.error_response();
+    }
+
+    event_stream(auth, pool.get_ref(), hub.get_ref(), Inbox::Personal(&user_id))
+}
+
+#[derive(Deserialize)]
+pub struct BulkDeleteQuery {
+    all: Option<bool>, // Required to delete without any filter
+}
+
+/// Delete every email matching the listing filters (requires Bearer token)
+pub async fn delete_emails(
+    auth: AuthenticatedUser,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+    filters: web::Query<EmailListQuery>,
+    bulk: web::Query<BulkDeleteQuery>,
+) -> HttpResponse {
+    let user_id = path.into_inner();
+    if let Err(e) = auth.ensure_owns(&user_id).and_then(|_| auth.ensure_scope(Scope::ManageMail)) {
+        return e.error_response();
+    }
+
+    let query = match filters.to_query() {
+        Ok(query) => query,
+        Err(e) => return HttpResponse::BadRequest().json(e),
+    };
+    if !filters.filters_anything() && bulk.all != Some(true) {
+        return HttpResponse::BadRequest().json("Pass a filter, or all=true to empty the inbox");
+    }
+
+    match repos.emails.delete_matching(Inbox::Personal(&user_id), &query).await {
+        Ok(deleted) => HttpResponse::Ok().json(serde_json::json!({ "deleted": deleted })),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Handle incoming email webhook from Cloudflare
+pub async fn handle_email_webhook(
+    repos: web::Data<Repos>,
+    resolver: web::Data<dyn Resolver>,
+    payload: web::Json<EmailWebhookPayload>,
+    req: HttpRequest,
+) -> HttpResponse {
+    // 1. Verify Secret
+    let secret = std::env::var("WEBHOOK_SECRET").unwrap_or_default();
+    let header_secret = req.headers().get("X-Webhook-Secret")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("");
+        
+    if secret.is_empty() || secret != header_secret {
+        return HttpResponse::Unauthorized().json("Invalid webhook secret");
+    }
+
+    use base64::Engine;
+    let raw = match payload.raw.as_deref().map(|raw| base64::engine::general_purpose::STANDARD.decode(raw)).transpose() {
+        Ok(raw) => raw,
+        Err(_) => return HttpResponse::BadRequest().json("raw must be base64"),
+    };
+
+    // 2. Extract Alias (local part of email)
+    // "temp_123@domain.com" -> "temp_123"
+    let to_address = payload.to.clone();
+    let local_part = to_address.split('@').next().unwrap_or("");
+
+    println!("📩 Webhook received email for: {}", local_part);
 
     // 3. Lookup User ID
-    let user_row = sqlx::query(
-        r#"SELECT user_id AS id FROM temp_aliases WHERE alias=$1"#
-    )
-    .bind(local_part)
-    .fetch_optional(pool.get_ref())
-    .await;
-
-    match user_row {
-        Ok(Some(row)) => {
-            use sqlx::Row;
-            let user_id: String = row.get("id");
-            let message_id = payload.message_id.clone().unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
-            
-            // 4. Save to Database
-            let insert_res = sqlx::query(
-                r#"
-                INSERT INTO emails (user_id, message_id, sender, subject, body_preview, otp, received_at)
-                VALUES ($1, $2, $3, $4, $5, $6, NOW())
-                ON CONFLICT (user_id, message_id) DO NOTHING
-                "#
-            )
-            .bind(&user_id)
-            .bind(&message_id)
-            .bind(&payload.from)
-            .bind(&payload.subject)
-            .bind(&payload.body) // For now, body is preview
-            .bind(&payload.otp)
-            .execute(pool.get_ref())
-            .await;
-
-            match insert_res {
+    match repos.aliases.resolve(local_part).await {
+        Ok(Some(recipient)) => {
+            let payload_message_id = payload.message_id.as_deref()
+                .map(|id| id.trim_matches(['<', '>', ' ']).to_string())
+                .filter(|id| !id.is_empty());
+            // With the raw message, parse and check it like SMTP mail; otherwise take the worker's fields
+            let parsed = match raw.as_deref() {
+                Some(raw) => ingest::parse_received(raw, resolver.get_ref(), None).await,
+                None => None,
+            };
+            let email = match parsed {
+                Some(mut email) => {
+                    email.otp = email.otp.or_else(|| payload.otp.clone());
+                    email
+                }
+                None => ParsedEmail {
+                    message_id: payload_message_id.clone(),
+                    in_reply_to: payload.in_reply_to.as_deref().and_then(|h| threads::parse_ids(h).into_iter().next()),
+                    references: payload.references.as_deref().map(threads::parse_ids).unwrap_or_default(),
+                    sender: payload.from.clone(),
+                    subject: payload.subject.clone(),
+                    to: vec![payload.to.clone()],
+                    headers: payload.headers.iter()
+                        .filter(|(name, value)| !ingest::claims_our_results(name, value))
+                        .cloned()
+                        .collect(),
+                    body_preview: payload.body.clone(), // For now, body is preview
+                    otp: payload.otp.clone(),
+                    ..Default::default()
+                },
+            };
+
+            // 4. Save to Database, once per message: retries of the worker carry the same id, bare
+            // like SMTP's. Only mail that has none at all can't be told apart.
+            let message_id = payload_message_id
+                .or_else(|| email.message_id.clone())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+            let received_at = chrono::Utc::now().timestamp();
+            match repos.emails.store_if_new(&recipient, &message_id, &email, received_at).await {
                 Ok(_) => {
-                    println!("✅ Saved email via webhook for user {}", user_id);
+                    println!("✅ Saved email via webhook for user {}", recipient.user_id);
                     HttpResponse::Ok().json("Email processed")
                 },
                 Err(e) => {
@@ -842,6 +1685,605 @@ pub async fn handle_email_webhook(
     }
 }
 
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<i64>,
+}
+
+impl CreateApiKeyRequest {
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.name.trim().is_empty() || self.scopes.is_empty() {
+            return Err("name and at least one scope are required");
+        }
+        if self.expires_in_days.is_some_and(|days| days <= 0) {
+            return Err("expires_in_days must be positive");
+        }
+        Ok(())
+    }
+}
+
+/// Create an API key for CI and scripts (requires a login session).
+/// The key is only shown in this response.
+pub async fn create_api_key(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    body: web::Json<CreateApiKeyRequest>,
+) -> HttpResponse {
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(e);
+    }
+
+    match api_keys::create(pool.get_ref(), &auth.user_id, None, body.name.trim(), &body.scopes, body.expires_in_days).await {
+        Ok((info, key)) => HttpResponse::Created().json(serde_json::json!({
+            "key": key,
+            "api_key": info,
+            "message": "Store this key now, it will not be shown again"
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(e),
+    }
+}
+
+/// List the caller's API keys (requires a login session)
+pub async fn list_api_keys(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+
+    match api_keys::list(pool.get_ref(), &auth.user_id).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Revoke one of the caller's API keys (requires a login session)
+pub async fn revoke_api_key(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+
+    match api_keys::revoke(pool.get_ref(), &auth.user_id, &path.into_inner()).await {
+        Ok(true) => HttpResponse::Ok().json("API key revoked"),
+        Ok(false) => HttpResponse::NotFound().json("API key not found"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateOrgRequest {
+    name: String,
+}
+
+/// Create an organization owned by the caller (requires a login session)
+pub async fn create_org(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    body: web::Json<CreateOrgRequest>,
+) -> HttpResponse {
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().json("name is required");
+    }
+
+    match orgs::create(pool.get_ref(), &auth.user_id, body.name.trim()).await {
+        Ok(org) => HttpResponse::Created().json(org),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Organizations the caller belongs to (requires a login session)
+pub async fn list_orgs(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+) -> HttpResponse {
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+
+    match orgs::list_for_user(pool.get_ref(), &auth.user_id).await {
+        Ok(list) => HttpResponse::Ok().json(list),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Members of an organization and their roles
+pub async fn list_org_members(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    match orgs::members(pool.get_ref(), &org_id).await {
+        Ok(members) => HttpResponse::Ok().json(members),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SetMemberRequest {
+    role: Role,
+}
+
+/// Add a user to an organization or change their role (admins; only owners grant or take away owner)
+pub async fn set_org_member(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<(String, String)>,
+    body: web::Json<SetMemberRequest>,
+) -> HttpResponse {
+    let (org_id, user_id) = path.into_inner();
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Admin).await {
+        return e.error_response();
+    }
+
+    match orgs::change_member(pool.get_ref(), &org_id, &auth.user_id, &user_id, Some(body.role)).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "user_id": user_id, "role": body.role })),
+        Err(e) => member_error_response(e),
+    }
+}
+
+/// Remove a member from an organization (admins, or anyone leaving)
+pub async fn remove_org_member(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (org_id, user_id) = path.into_inner();
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    match orgs::change_member(pool.get_ref(), &org_id, &auth.user_id, &user_id, None).await {
+        Ok(()) => HttpResponse::Ok().json("Member removed"),
+        Err(e) => member_error_response(e),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LinkSsoRequest {
+    workos_organization_id: Option<String>,
+    #[serde(default = "default_sso_role")]
+    role: Role,
+}
+
+fn default_sso_role() -> Role {
+    Role::Member
+}
+
+/// Link the organization to a WorkOS organization so its SSO users join automatically.
+/// Owners only, from a login through that WorkOS organization: nobody else can claim it.
+pub async fn link_org_sso(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+    body: web::Json<LinkSsoRequest>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if body.role == Role::Owner {
+        return HttpResponse::BadRequest().json("SSO users can't join as owners");
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Owner).await {
+        return e.error_response();
+    }
+
+    let workos_organization_id = body.workos_organization_id.as_deref().filter(|id| !id.is_empty());
+    if let Some(workos_org_id) = workos_organization_id {
+        // Only someone who signed in through the WorkOS organization may claim it
+        let signed_in_through = match auth.session_id.as_deref() {
+            Some(session_id) => match workos_sessions::organization_of(pool.get_ref(), session_id).await {
+                Ok(org) => org,
+                Err(e) => return HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+            },
+            None => None,
+        };
+        if signed_in_through.as_deref() != Some(workos_org_id) {
+            return HttpResponse::Forbidden().json("Sign in through this WorkOS organization's SSO to link it");
+        }
+    }
+    match orgs::link_sso(pool.get_ref(), &org_id, workos_organization_id, body.role).await {
+        Ok(true) => HttpResponse::Ok().json(serde_json::json!({
+            "workos_organization_id": workos_organization_id,
+            "role": body.role
+        })),
+        Ok(false) => HttpResponse::Conflict().json("WorkOS organization is already linked to another organization"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+fn member_error_response(e: MemberError) -> HttpResponse {
+    match e {
+        MemberError::NotAllowed => HttpResponse::Forbidden().json(e.to_string()),
+        MemberError::LastOwner => HttpResponse::Conflict().json(e.to_string()),
+        MemberError::UnknownUser => HttpResponse::NotFound().json(e.to_string()),
+        MemberError::Internal(e) => HttpResponse::InternalServerError().json(e),
+    }
+}
+
+/// Create a shared alias; mail sent to it is readable by every member
+pub async fn create_org_alias(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ManageAliases) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Member).await {
+        return e.error_response();
+    }
+
+    let alias = format!("temp_{}", chrono::Utc::now().timestamp_micros());
+    match repos.aliases.create_shared(&org_id, &auth.user_id, &alias).await {
+        Ok(_) => HttpResponse::Created().json(serde_json::json!({
+            "org_id": org_id,
+            "email": alias_address(&alias),
+            "alias": alias,
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Shared aliases of an organization
+pub async fn list_org_aliases(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    match repos.aliases.list_shared(&org_id).await {
+        Ok(rows) => {
+            let aliases: Vec<serde_json::Value> = rows.into_iter().map(|row| {
+                serde_json::json!({
+                    "email": alias_address(&row.alias),
+                    "alias": row.alias,
+                    "created_by": row.user_id,
+                    "created_at": row.created_at.map(|at| at.to_string()),
+                })
+            }).collect();
+            HttpResponse::Ok().json(aliases)
+        }
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Delete a shared alias; mail already received stays in the shared inbox
+pub async fn delete_org_alias(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (org_id, alias) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ManageAliases) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Member).await {
+        return e.error_response();
+    }
+
+    match repos.aliases.delete_shared(&org_id, &alias).await {
+        Ok(true) => HttpResponse::Ok().json("Deleted alias"),
+        Ok(false) => HttpResponse::NotFound().json("Alias not found"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Mail received by an organization's shared aliases
+pub async fn get_org_emails(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+    query: web::Query<EmailListQuery>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    list_emails(&repos, Inbox::Shared(&org_id), &query).await
+}
+
+/// Conversations of an organization's shared inbox (any member)
+pub async fn get_org_threads(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<String>,
+    query: web::Query<ThreadListQuery>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    list_threads(&repos, Inbox::Shared(&org_id), &query).await
+}
+
+/// One conversation of an organization's shared inbox (any member)
+pub async fn get_org_thread(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (org_id, thread_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    show_thread(&repos, Inbox::Shared(&org_id), thread_id).await
+}
+
+/// One email of an organization's shared inbox with headers, bodies and attachments (any member)
+pub async fn get_org_email(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (org_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    show_email(&repos, Inbox::Shared(&org_id), email_id).await
+}
+
+/// Download an email of an organization's shared inbox as it was received (any member)
+pub async fn get_org_raw_email(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (org_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    download_email(&repos, Inbox::Shared(&org_id), email_id).await
+}
+
+/// Structured headers of an email of an organization's shared inbox (any member)
+pub async fn get_org_email_headers(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (org_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    match email_headers(&repos, Inbox::Shared(&org_id), email_id).await {
+        Ok(fields) => HttpResponse::Ok().json(headers::analyze(&fields)),
+        Err(response) => response,
+    }
+}
+
+/// What about an email of an organization's shared inbox could hurt its delivery (any member)
+pub async fn get_org_deliverability_report(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (org_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    match email_headers(&repos, Inbox::Shared(&org_id), email_id).await {
+        Ok(fields) => HttpResponse::Ok().json(headers::report(&fields)),
+        Err(response) => response,
+    }
+}
+
+/// Delete an email of an organization's shared inbox (members)
+pub async fn delete_org_email(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    path: web::Path<(String, i64)>,
+) -> HttpResponse {
+    let (org_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ManageMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Member).await {
+        return e.error_response();
+    }
+
+    remove_email(&repos, Inbox::Shared(&org_id), email_id).await
+}
+
+/// Mark an email of an organization's shared inbox read, starred or labelled (members)
+pub async fn update_org_email_state(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    hub: web::Data<EventHub>,
+    path: web::Path<(String, i64)>,
+    body: web::Json<StateChangeRequest>,
+) -> HttpResponse {
+    let (org_id, email_id) = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::OrganizeMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Member).await {
+        return e.error_response();
+    }
+
+    change_state(&repos, &hub, Inbox::Shared(&org_id), email_id, &body).await
+}
+
+/// Apply one state change to several emails of a shared inbox; unknown ids are skipped (members)
+pub async fn update_org_emails_state(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    repos: web::Data<Repos>,
+    hub: web::Data<EventHub>,
+    path: web::Path<String>,
+    body: web::Json<BulkStateChangeRequest>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::OrganizeMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Member).await {
+        return e.error_response();
+    }
+
+    change_states(&repos, &hub, Inbox::Shared(&org_id), &body).await
+}
+
+/// Stream changes to an organization's shared inbox as server-sent events (any member)
+pub async fn stream_org_events(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    hub: web::Data<EventHub>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_scope(Scope::ReadMail) {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::ReadOnly).await {
+        return e.error_response();
+    }
+
+    event_stream(auth, pool.get_ref(), hub.get_ref(), Inbox::Shared(&org_id))
+}
+
+/// Create an API key owned by an organization (admins, requires a login session).
+/// It acts with its creator's role in the organization and stops working if they leave.
+pub async fn create_org_api_key(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+    body: web::Json<CreateApiKeyRequest>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if let Err(e) = body.validate() {
+        return HttpResponse::BadRequest().json(e);
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Admin).await {
+        return e.error_response();
+    }
+
+    match api_keys::create(pool.get_ref(), &auth.user_id, Some(&org_id), body.name.trim(), &body.scopes, body.expires_in_days).await {
+        Ok((info, key)) => HttpResponse::Created().json(serde_json::json!({
+            "key": key,
+            "api_key": info,
+            "message": "Store this key now, it will not be shown again"
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(e),
+    }
+}
+
+/// List an organization's API keys (admins, requires a login session)
+pub async fn list_org_api_keys(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let org_id = path.into_inner();
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Admin).await {
+        return e.error_response();
+    }
+
+    match api_keys::list_for_org(pool.get_ref(), &org_id).await {
+        Ok(keys) => HttpResponse::Ok().json(keys),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
+/// Revoke an organization API key (admins, requires a login session)
+pub async fn revoke_org_api_key(
+    auth: AuthenticatedUser,
+    pool: web::Data<PgPool>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (org_id, key_id) = path.into_inner();
+    if let Err(e) = auth.ensure_session() {
+        return e.error_response();
+    }
+    if let Err(e) = auth.ensure_org_role(pool.get_ref(), &org_id, Role::Admin).await {
+        return e.error_response();
+    }
+
+    match api_keys::revoke_for_org(pool.get_ref(), &org_id, &key_id).await {
+        Ok(true) => HttpResponse::Ok().json("API key revoked"),
+        Ok(false) => HttpResponse::NotFound().json("API key not found"),
+        Err(e) => HttpResponse::InternalServerError().json(format!("DB error: {}", e)),
+    }
+}
+
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/users")
@@ -867,6 +2309,22 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         web::resource("/auth/workos/callback")
             .route(web::get().to(auth_workos_callback))
     )
+    .service(
+        web::resource("/auth/exchange")
+            .route(web::post().to(exchange_login_code))
+    )
+    .service(
+        web::resource("/auth/refresh")
+            .route(web::post().to(refresh_session))
+    )
+    .service(
+        web::resource("/auth/logout")
+            .route(web::post().to(logout))
+    )
+    .service(
+        web::resource("/.well-known/jwks.json")
+            .route(web::get().to(jwks))
+    )
     .service(
         web::resource("/connect/gmail")
             .route(web::get().to(connect_gmail))
@@ -879,6 +2337,14 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         web::resource("/sync/{user_id}")
             .route(web::get().to(sync_emails))
     )
+    .service(
+        web::resource("/sync/{user_id}/status")
+            .route(web::get().to(get_sync_status))
+    )
+    .service(
+        web::resource("/sync/{user_id}/settings")
+            .route(web::put().to(update_sync_settings))
+    )
     .service(
         web::resource("/latest/{user_id}")
             .route(web::get().to(get_latest))
@@ -891,10 +2357,134 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     .service(
         web::resource("/emails/{id}")
             .route(web::get().to(get_all_emails))
+            .route(web::patch().to(update_emails_state))
+            .route(web::delete().to(delete_emails))
+    )
+    .service(
+        web::resource("/emails/{user_id}/unread")
+            .route(web::get().to(get_unread_counts))
+    )
+    .service(
+        web::resource("/emails/{user_id}/search")
+            .route(web::get().to(search_emails))
+    )
+    .service(
+        web::resource("/emails/{user_id}/{email_id}")
+            .route(web::get().to(get_email))
+            .route(web::patch().to(update_email_state))
+            .route(web::delete().to(delete_email))
+    )
+    .service(
+        web::resource("/emails/{user_id}/{email_id}/raw")
+            .route(web::get().to(get_raw_email))
+    )
+    .service(
+        web::resource("/emails/{user_id}/{email_id}/headers")
+            .route(web::get().to(get_email_headers))
+    )
+    .service(
+        web::resource("/emails/{user_id}/{email_id}/deliverability")
+            .route(web::get().to(get_deliverability_report))
+    )
+    .service(
+        web::resource("/events/{user_id}")
+            .route(web::get().to(stream_events))
+    )
+    .service(
+        web::resource("/threads/{user_id}")
+            .route(web::get().to(get_threads))
+    )
+    .service(
+        web::resource("/threads/{user_id}/{thread_id}")
+            .route(web::get().to(get_thread))
+    )
+    .service(
+        web::resource("/api-keys")
+            .route(web::post().to(create_api_key))
+            .route(web::get().to(list_api_keys))
+    )
+    .service(
+        web::resource("/api-keys/{id}")
+            .route(web::delete().to(revoke_api_key))
+    )
+    .service(
+        web::resource("/orgs")
+            .route(web::post().to(create_org))
+            .route(web::get().to(list_orgs))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/members")
+            .route(web::get().to(list_org_members))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/members/{user_id}")
+            .route(web::put().to(set_org_member))
+            .route(web::delete().to(remove_org_member))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/sso")
+            .route(web::put().to(link_org_sso))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/aliases")
+            .route(web::post().to(create_org_alias))
+            .route(web::get().to(list_org_aliases))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/aliases/{alias}")
+            .route(web::delete().to(delete_org_alias))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/emails")
+            .route(web::get().to(get_org_emails))
+            .route(web::patch().to(update_org_emails_state))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/emails/{email_id}")
+            .route(web::get().to(get_org_email))
+            .route(web::patch().to(update_org_email_state))
+            .route(web::delete().to(delete_org_email))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/emails/{email_id}/raw")
+            .route(web::get().to(get_org_raw_email))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/emails/{email_id}/headers")
+            .route(web::get().to(get_org_email_headers))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/emails/{email_id}/deliverability")
+            .route(web::get().to(get_org_deliverability_report))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/events")
+            .route(web::get().to(stream_org_events))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/threads")
+            .route(web::get().to(get_org_threads))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/threads/{thread_id}")
+            .route(web::get().to(get_org_thread))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/api-keys")
+            .route(web::post().to(create_org_api_key))
+            .route(web::get().to(list_org_api_keys))
+    )
+    .service(
+        web::resource("/orgs/{org_id}/api-keys/{id}")
+            .route(web::delete().to(revoke_org_api_key))
     )
     .service(
         web::resource("/webhooks/email")
             .route(web::post().to(handle_email_webhook))
+    )
+    .service(
+        web::resource("/webhooks/workos")
+            .route(web::post().to(handle_workos_webhook))
     );
 }
 
@@ -0,0 +1,298 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+
+/// Every API key starts with this, so `AuthenticatedUser` can tell keys from JWTs
+pub const KEY_PREFIX: &str = "mk_";
+/// Characters of the public, indexed part of a key
+const LOOKUP_LEN: usize = 8;
+
+/// What an API key may be used for. JWT sessions may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "mail:read")]
+    ReadMail,
+    #[serde(rename = "mail:organize")]
+    OrganizeMail, // Mark read, star and label
+    #[serde(rename = "mail:manage")]
+    ManageMail, // Delete messages and change sync settings; implies mail:organize
+    #[serde(rename = "aliases:manage")]
+    ManageAliases,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ReadMail => "mail:read",
+            Scope::OrganizeMail => "mail:organize",
+            Scope::ManageMail => "mail:manage",
+            Scope::ManageAliases => "aliases:manage",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "mail:read" => Some(Scope::ReadMail),
+            "mail:organize" => Some(Scope::OrganizeMail),
+            "mail:manage" => Some(Scope::ManageMail),
+            "aliases:manage" => Some(Scope::ManageAliases),
+            _ => None,
+        }
+    }
+
+    /// Whether holding this scope is enough for `scope`
+    pub fn covers(&self, scope: Scope) -> bool {
+        *self == scope || (*self == Scope::ManageMail && scope == Scope::OrganizeMail)
+    }
+}
+
+/// An API key as shown to its owner; the secret is only returned once, on creation
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub org_id: Option<String>,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub created_at: Option<String>,
+}
+
+/// A verified key: who it belongs to and what it may do.
+/// Organization keys act as their creator, but only inside that organization.
+#[derive(Debug, Clone)]
+pub struct KeyOwner {
+    pub id: String,
+    pub user_id: String,
+    pub org_id: Option<String>,
+    pub scopes: Vec<Scope>,
+}
+
+/// New random key: `mk_<lookup>_<secret>`. Returns (full key, prefix stored in clear).
+fn generate() -> (String, String) {
+    let lookup = uuid::Uuid::new_v4().simple().to_string()[..LOOKUP_LEN].to_string();
+    let secret = uuid::Uuid::new_v4().simple().to_string();
+    let prefix = format!("{}{}", KEY_PREFIX, lookup);
+    (format!("{}_{}", prefix, secret), prefix)
+}
+
+/// SHA-256 of a key, as stored. Keys carry a random secret, so a fast hash is enough.
+fn digest_of(key: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The stored prefix of a presented key, if it looks like one of ours
+fn prefix_of(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(KEY_PREFIX)?;
+    let (lookup, secret) = rest.split_once('_')?;
+    if lookup.len() != LOOKUP_LEN || secret.is_empty() {
+        return None;
+    }
+    Some(&key[..KEY_PREFIX.len() + LOOKUP_LEN])
+}
+
+/// Create a key for a user, or for an organization when `org_id` is set.
+/// Returns the key info and the full key, which is not stored.
+pub async fn create(
+    pool: &PgPool,
+    user_id: &str,
+    org_id: Option<&str>,
+    name: &str,
+    scopes: &[Scope],
+    expires_in_days: Option<i64>,
+) -> Result<(ApiKeyInfo, String), String> {
+    let (key, prefix) = generate();
+    let scope_names: Vec<&str> = scopes.iter().map(Scope::as_str).collect();
+
+    let row = sqlx::query(
+        r#"
+        INSERT INTO api_keys (id, user_id, org_id, name, prefix, key_digest, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, NOW() + make_interval(days => $8))
+        RETURNING id, name, prefix, scopes, org_id, expires_at::text, last_used_at::text, created_at::text
+        "#
+    )
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(org_id)
+    .bind(name)
+    .bind(&prefix)
+    .bind(digest_of(&key))
+    .bind(&scope_names)
+    .bind(expires_in_days.map(|d| d as i32))
+    .fetch_one(pool)
+    .await
+    .map_err(|e| format!("DB error: {}", e))?;
+
+    Ok((info_from_row(&row), key))
+}
+
+/// Active (non-revoked) personal keys of a user, newest first
+pub async fn list(pool: &PgPool, user_id: &str) -> Result<Vec<ApiKeyInfo>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT id, name, prefix, scopes, org_id, expires_at::text, last_used_at::text, created_at::text
+        FROM api_keys
+        WHERE user_id = $1 AND org_id IS NULL AND revoked_at IS NULL
+        ORDER BY created_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(info_from_row).collect())
+}
+
+/// Active keys of an organization, whoever created them, newest first
+pub async fn list_for_org(pool: &PgPool, org_id: &str) -> Result<Vec<ApiKeyInfo>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT id, name, prefix, scopes, org_id, expires_at::text, last_used_at::text, created_at::text
+        FROM api_keys
+        WHERE org_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at DESC
+        "#
+    )
+    .bind(org_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().map(info_from_row).collect())
+}
+
+/// Revoke a personal key. Returns false if the user has no such active key.
+pub async fn revoke(pool: &PgPool, user_id: &str, key_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND org_id IS NULL AND revoked_at IS NULL")
+        .bind(key_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revoke an organization key. Returns false if the organization has no such active key.
+pub async fn revoke_for_org(pool: &PgPool, org_id: &str, key_id: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND org_id = $2 AND revoked_at IS NULL")
+        .bind(key_id)
+        .bind(org_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Check a presented key. Returns None for unknown, revoked, expired or wrong keys.
+pub async fn authenticate(pool: &PgPool, key: &str) -> Result<Option<KeyOwner>, String> {
+    let Some(prefix) = prefix_of(key) else {
+        return Ok(None);
+    };
+
+    let row = sqlx::query(
+        r#"
+        SELECT id, user_id, org_id, key_digest, key_hash, scopes FROM api_keys
+        WHERE prefix = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+        "#
+    )
+    .bind(prefix)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| format!("DB error: {}", e))?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let digest = digest_of(key);
+    let valid = match (row.get::<Option<String>, _>("key_digest"), row.get::<Option<String>, _>("key_hash")) {
+        (Some(stored), _) => stored == digest,
+        // Made before digests: bcrypt is deliberately slow, keep it off the async workers
+        (None, Some(key_hash)) => {
+            let presented = key.to_string();
+            tokio::task::spawn_blocking(move || bcrypt::verify(presented, &key_hash).unwrap_or(false))
+                .await
+                .map_err(|e| format!("Verification failed: {}", e))?
+        }
+        (None, None) => false,
+    };
+    if !valid {
+        return Ok(None);
+    }
+
+    // Old keys switch to the digest on first use
+    let key_id: String = row.get("id");
+    sqlx::query("UPDATE api_keys SET last_used_at = NOW(), key_digest = $2, key_hash = NULL WHERE id = $1")
+        .bind(&key_id)
+        .bind(&digest)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("DB error: {}", e))?;
+
+    Ok(Some(KeyOwner {
+        id: key_id,
+        user_id: row.get("user_id"),
+        org_id: row.get("org_id"),
+        scopes: parse_scopes(row.get("scopes")),
+    }))
+}
+
+/// Whether a key still works (not revoked or expired), for long requests that outlive the check
+pub async fn is_active(pool: &PgPool, key_id: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("SELECT 1 FROM api_keys WHERE id = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())")
+        .bind(key_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+fn parse_scopes(names: Vec<String>) -> Vec<Scope> {
+    names.iter().filter_map(|s| Scope::parse(s)).collect()
+}
+
+fn info_from_row(row: &sqlx::postgres::PgRow) -> ApiKeyInfo {
+    ApiKeyInfo {
+        id: row.get("id"),
+        name: row.get("name"),
+        prefix: row.get("prefix"),
+        scopes: parse_scopes(row.get("scopes")),
+        org_id: row.get("org_id"),
+        expires_at: row.get("expires_at"),
+        last_used_at: row.get("last_used_at"),
+        created_at: row.get("created_at"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_keys_carry_their_prefix() {
+        let (key, prefix) = generate();
+
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(prefix_of(&key), Some(prefix.as_str()));
+        assert_ne!(generate().0, key);
+    }
+
+    #[test]
+    fn malformed_keys_have_no_prefix() {
+        assert_eq!(prefix_of("eyJhbGciOiJIUzI1NiJ9.e30.sig"), None);
+        assert_eq!(prefix_of("mk_short_secret"), None);
+        assert_eq!(prefix_of("mk_abcdefgh_"), None);
+    }
+
+    #[test]
+    fn scopes_round_trip() {
+        for scope in [Scope::ReadMail, Scope::OrganizeMail, Scope::ManageMail, Scope::ManageAliases] {
+            assert_eq!(Scope::parse(scope.as_str()), Some(scope));
+        }
+        assert_eq!(Scope::parse("admin"), None);
+    }
+}
@@ -0,0 +1,311 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sqlx::{PgPool, Row};
+use std::env;
+use std::sync::Arc;
+
+/// Prefix of every sealed value; anything else is a legacy plaintext value
+const SEALED_PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+/// Master keys (key encryption keys) loaded from config.
+///
+/// `ENCRYPTION_KEYS` is a comma separated list of `key_id:base64(32 bytes)`,
+/// `ENCRYPTION_ACTIVE_KEY` names the key new values are sealed with.
+/// Old keys stay in the list until `mail-server reencrypt-secrets` has moved every row off them.
+/// Built once at startup and shared, like the other app data.
+pub struct KeyRing {
+    keys: Vec<(String, Key<Aes256Gcm>)>,
+    active: String,
+}
+
+impl KeyRing {
+    pub fn from_env() -> Result<Self, String> {
+        let raw = env::var("ENCRYPTION_KEYS").map_err(|_| "ENCRYPTION_KEYS not set")?;
+        Self::parse(&raw, env::var("ENCRYPTION_ACTIVE_KEY").ok())
+    }
+
+    /// Keys from the values of ENCRYPTION_KEYS and ENCRYPTION_ACTIVE_KEY
+    pub fn parse(raw: &str, active: Option<String>) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, encoded) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid ENCRYPTION_KEYS entry (expected id:base64key): {}", entry))?;
+            let bytes = STANDARD
+                .decode(encoded)
+                .map_err(|e| format!("Key '{}' is not valid base64: {}", id, e))?;
+            if bytes.len() != 32 {
+                return Err(format!("Key '{}' must be 32 bytes, got {}", id, bytes.len()));
+            }
+            keys.push((id.to_string(), *Key::<Aes256Gcm>::from_slice(&bytes)));
+        }
+
+        let active = match active {
+            Some(id) => id,
+            None => keys.last().map(|(id, _)| id.clone()).ok_or("ENCRYPTION_KEYS is empty")?,
+        };
+        if !keys.iter().any(|(id, _)| *id == active) {
+            return Err(format!("ENCRYPTION_ACTIVE_KEY '{}' is not in ENCRYPTION_KEYS", active));
+        }
+
+        Ok(Self { keys, active })
+    }
+
+    /// Id of the key new values are sealed with
+    pub fn active_key_id(&self) -> &str {
+        &self.active
+    }
+
+    fn key(&self, id: &str) -> Result<&Key<Aes256Gcm>, String> {
+        self.keys
+            .iter()
+            .find(|(key_id, _)| key_id == id)
+            .map(|(_, key)| key)
+            .ok_or_else(|| format!("Unknown encryption key '{}'", id))
+    }
+
+    /// Envelope-encrypt a value: a fresh data key encrypts the value, the master key wraps the data key.
+    /// `context` (e.g. "users.access_token:user_123") is authenticated so a value can't be moved to another row or column.
+    pub fn seal(&self, plaintext: &str, context: &str) -> Result<String, String> {
+        let master = Aes256Gcm::new(self.key(&self.active)?);
+        let data_key = Aes256Gcm::generate_key(OsRng);
+
+        let wrapped_key = encrypt(&master, data_key.as_slice(), context.as_bytes())?;
+        let ciphertext = encrypt(&Aes256Gcm::new(&data_key), plaintext.as_bytes(), context.as_bytes())?;
+
+        Ok(format!(
+            "{}{}:{}:{}",
+            SEALED_PREFIX,
+            self.active,
+            STANDARD.encode(wrapped_key),
+            STANDARD.encode(ciphertext)
+        ))
+    }
+
+    /// Decrypt a value produced by `seal`. Legacy plaintext values are returned as-is.
+    pub fn open(&self, stored: &str, context: &str) -> Result<String, String> {
+        let Some(sealed) = stored.strip_prefix(SEALED_PREFIX) else {
+            return Ok(stored.to_string());
+        };
+
+        let mut parts = sealed.splitn(3, ':');
+        let (Some(key_id), Some(wrapped_key), Some(ciphertext)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err("Malformed encrypted value".to_string());
+        };
+
+        let master = Aes256Gcm::new(self.key(key_id)?);
+        let wrapped_key = STANDARD.decode(wrapped_key).map_err(|_| "Malformed encrypted value")?;
+        let data_key = decrypt(&master, &wrapped_key, context.as_bytes())?;
+        if data_key.len() != 32 {
+            return Err("Malformed encrypted value".to_string());
+        }
+
+        let ciphertext = STANDARD.decode(ciphertext).map_err(|_| "Malformed encrypted value")?;
+        let plaintext = decrypt(&Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&data_key)), &ciphertext, context.as_bytes())?;
+
+        String::from_utf8(plaintext).map_err(|_| "Decrypted value is not UTF-8".to_string())
+    }
+
+    /// Seal an optional secret column for a user row
+    pub fn seal_column(&self, user_id: &str, column: &str, value: Option<&str>) -> Result<Option<String>, String> {
+        value.map(|v| self.seal(v, &context(user_id, column))).transpose()
+    }
+}
+
+/// Key id a stored value was sealed with (None for legacy plaintext)
+pub fn key_id_of(stored: &str) -> Option<&str> {
+    stored.strip_prefix(SEALED_PREFIX)?.split(':').next()
+}
+
+/// Authenticated-data context for a secret column of a user row
+pub fn context(user_id: &str, column: &str) -> String {
+    format!("users.{}:{}", column, user_id)
+}
+
+/// Columns of `users` holding secrets
+const SECRET_COLUMNS: [&str; 3] = ["imap_password", "access_token", "refresh_token"];
+
+/// Re-seal every stored secret that is plaintext or sealed with a non-active key.
+/// Returns the number of rows rewritten.
+pub async fn reencrypt_user_secrets(pool: &PgPool, keys: &KeyRing) -> Result<u64, String> {
+    // secrets_key_id is kept by the database: NULL for plaintext or mixed keys
+    let rows = sqlx::query(
+        r#"
+        SELECT id FROM users
+        WHERE (imap_password IS NOT NULL OR access_token IS NOT NULL OR refresh_token IS NOT NULL)
+          AND secrets_key_id IS DISTINCT FROM $1
+        "#
+    )
+    .bind(keys.active_key_id())
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("DB error: {}", e))?;
+
+    let mut rewritten = 0;
+    for row in rows {
+        if reseal_user(pool, keys, row.get("id")).await? {
+            rewritten += 1;
+        }
+    }
+
+    Ok(rewritten)
+}
+
+/// Re-seal one user's secrets with the active key. Each write only lands if the row still
+/// holds what was read, so a token refreshed meanwhile is never overwritten with an old one.
+async fn reseal_user(pool: &PgPool, keys: &KeyRing, user_id: String) -> Result<bool, String> {
+    loop {
+        let row = sqlx::query("SELECT imap_password, access_token, refresh_token FROM users WHERE id = $1")
+            .bind(&user_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("DB error: {}", e))?;
+        let Some(row) = row else {
+            return Ok(false);
+        };
+
+        let mut stored = Vec::new();
+        let mut values = Vec::new();
+        for column in SECRET_COLUMNS {
+            let value: Option<String> = row.get(column);
+            let resealed = match &value {
+                Some(value) if key_id_of(value) != Some(keys.active_key_id()) => {
+                    let ctx = context(&user_id, column);
+                    Some(keys.seal(&keys.open(value, &ctx)?, &ctx)?)
+                }
+                _ => value.clone(),
+            };
+            stored.push(value);
+            values.push(resealed);
+        }
+        if stored == values {
+            return Ok(false);
+        }
+
+        let result = sqlx::query(
+            r#"
+            UPDATE users
+            SET imap_password = $1, access_token = $2, refresh_token = $3
+            WHERE id = $4
+              AND imap_password IS NOT DISTINCT FROM $5
+              AND access_token IS NOT DISTINCT FROM $6
+              AND refresh_token IS NOT DISTINCT FROM $7
+            "#
+        )
+        .bind(&values[0])
+        .bind(&values[1])
+        .bind(&values[2])
+        .bind(&user_id)
+        .bind(&stored[0])
+        .bind(&stored[1])
+        .bind(&stored[2])
+        .execute(pool)
+        .await
+        .map_err(|e| format!("DB error: {}", e))?;
+
+        if result.rows_affected() > 0 {
+            return Ok(true);
+        }
+        // Changed under us (e.g. a token refresh): read it again
+    }
+}
+
+/// An encrypted credential as loaded from the database.
+/// It is only decrypted when a provider actually needs it.
+#[derive(Clone)]
+pub struct SealedSecret {
+    keys: Arc<KeyRing>,
+    stored: String,
+    context: String,
+}
+
+impl SealedSecret {
+    pub fn new(keys: Arc<KeyRing>, stored: String, context: String) -> Self {
+        Self { keys, stored, context }
+    }
+
+    pub fn open(&self) -> Result<String, String> {
+        self.keys.open(&self.stored, &self.context)
+    }
+}
+
+impl std::fmt::Debug for SealedSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SealedSecret({})", key_id_of(&self.stored).unwrap_or("plaintext"))
+    }
+}
+
+fn encrypt(cipher: &Aes256Gcm, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut out = nonce.to_vec();
+    out.extend(
+        cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad })
+            .map_err(|_| "Encryption failed")?,
+    );
+    Ok(out)
+}
+
+fn decrypt(cipher: &Aes256Gcm, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < NONCE_LEN {
+        return Err("Malformed encrypted value".to_string());
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Decryption failed (wrong key or tampered value)".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(ids: &[&str], active: &str) -> KeyRing {
+        KeyRing {
+            keys: ids.iter().enumerate().map(|(i, id)| (id.to_string(), *Key::<Aes256Gcm>::from_slice(&[i as u8 + 1; 32]))).collect(),
+            active: active.to_string(),
+        }
+    }
+
+    #[test]
+    fn seal_and_open_round_trip() {
+        let keys = ring(&["k1"], "k1");
+        let sealed = keys.seal("app-password", "users.imap_password:u1").unwrap();
+
+        assert!(!sealed.contains("app-password"));
+        assert_eq!(key_id_of(&sealed), Some("k1"));
+        assert_eq!(keys.open(&sealed, "users.imap_password:u1").unwrap(), "app-password");
+    }
+
+    #[test]
+    fn open_rejects_other_context_and_tampering() {
+        let keys = ring(&["k1"], "k1");
+        let sealed = keys.seal("token", "users.access_token:u1").unwrap();
+
+        assert!(keys.open(&sealed, "users.access_token:u2").is_err());
+
+        let mut tampered = sealed.clone();
+        tampered.replace_range(tampered.len() - 4.., "AAA=");
+        assert!(keys.open(&tampered, "users.access_token:u1").is_err());
+    }
+
+    #[test]
+    fn rotated_keys_still_open_old_values() {
+        let old = ring(&["k1"], "k1");
+        let sealed = old.seal("token", "ctx").unwrap();
+
+        let rotated = ring(&["k1", "k2"], "k2");
+        assert_eq!(rotated.open(&sealed, "ctx").unwrap(), "token");
+        assert_eq!(key_id_of(&rotated.seal("token", "ctx").unwrap()), Some("k2"));
+    }
+
+    #[test]
+    fn legacy_plaintext_passes_through() {
+        let keys = ring(&["k1"], "k1");
+        assert_eq!(keys.open("plain-token", "ctx").unwrap(), "plain-token");
+        assert_eq!(key_id_of("plain-token"), None);
+    }
+}
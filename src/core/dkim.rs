@@ -0,0 +1,649 @@
+//! DKIM (RFC 6376) verification of received mail: every DKIM-Signature is checked against
+//! the key its signer publishes at `<selector>._domainkey.<domain>`, for rsa-sha256 and
+//! ed25519-sha256 (RFC 8463) with simple and relaxed canonicalization.
+
+use base64::Engine;
+use ring::{digest, signature};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use crate::core::dns::Resolver;
+use crate::core::headers;
+
+/// Signatures checked per message; any beyond are reported without a lookup
+const MAX_SIGNATURES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimResult {
+    Pass,
+    Fail,      // The body or headers changed since signing, or the key doesn't match
+    Neutral,   // The signature can't be checked: malformed, unsupported or expired
+    Policy,    // Not checked by choice (over the per-message limit)
+    TempError, // The key couldn't be looked up right now
+    PermError, // The signer publishes no usable key
+}
+
+impl fmt::Display for DkimResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DkimResult::Pass => "pass",
+            DkimResult::Fail => "fail",
+            DkimResult::Neutral => "neutral",
+            DkimResult::Policy => "policy",
+            DkimResult::TempError => "temperror",
+            DkimResult::PermError => "permerror",
+        })
+    }
+}
+
+/// The outcome for one DKIM-Signature header, with what it claims to be
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DkimSignature {
+    pub result: DkimResult,
+    pub domain: Option<String>,           // d=, the signer
+    pub selector: Option<String>,         // s=
+    pub algorithm: Option<String>,        // a=
+    pub identity: Option<String>,         // i=, defaults to @domain
+    pub canonicalization: Option<String>, // c=, header/body
+    pub signed_headers: Vec<String>,      // h=
+    pub body_length: Option<usize>,       // l=: only this much of the body is covered
+    pub reason: Option<String>,
+}
+
+impl DkimSignature {
+    /// This signature's part of an Authentication-Results header (RFC 8601)
+    pub fn result_clause(&self) -> String {
+        let mut clause = format!("dkim={}", self.result);
+        if let Some(reason) = &self.reason {
+            clause.push_str(&format!(" {}", headers::comment(reason)));
+        }
+        let properties = [("header.d", &self.domain), ("header.i", &self.identity), ("header.s", &self.selector), ("header.a", &self.algorithm)];
+        for (name, value) in properties {
+            if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                clause.push_str(&format!(" {}={}", name, headers::property_value(value)));
+            }
+        }
+        clause
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Algorithm {
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// The tags of a DKIM-Signature that verification needs
+struct Signature {
+    algorithm: Algorithm,
+    header_canon: Canonicalization,
+    body_canon: Canonicalization,
+    domain: String,
+    selector: String,
+    headers: Vec<String>,
+    body_hash: Vec<u8>,
+    value: Vec<u8>,
+    length: Option<usize>,
+    identity_domain: String,
+}
+
+/// A published key (RFC 6376 3.6.1)
+struct Key {
+    algorithm: Algorithm,
+    public: Vec<u8>,
+    strict: bool, // t=s: i= must be in exactly d=, not a subdomain
+}
+
+type Failure = (DkimResult, String);
+
+/// "v=1; a=rsa-sha256; d=example.com" as tag/value pairs, whitespace around them removed
+fn parse_tags(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tags: Vec<(String, String)> = Vec::new();
+    for part in text.split(';') {
+        if part.trim().is_empty() {
+            continue; // A trailing ';' is allowed
+        }
+        let (name, value) = part.split_once('=').ok_or_else(|| format!("tag without a value: '{}'", part.trim()))?;
+        let name = name.trim().to_string();
+        if tags.iter().any(|(seen, _)| *seen == name) {
+            return Err(format!("duplicate {}= tag", name));
+        }
+        tags.push((name, value.trim().to_string()));
+    }
+    Ok(tags)
+}
+
+fn tag<'a>(tags: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str())
+}
+
+fn base64_tag(value: &str) -> Option<Vec<u8>> {
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    base64::engine::general_purpose::STANDARD.decode(value).ok()
+}
+
+/// The report for a signature before it's checked: what its tags claim
+fn describe(tags: &[(String, String)]) -> DkimSignature {
+    let text = |name| tag(tags, name).map(str::to_string);
+    DkimSignature {
+        result: DkimResult::Neutral,
+        domain: text("d"),
+        selector: text("s"),
+        algorithm: text("a"),
+        identity: text("i").or_else(|| text("d").map(|d| format!("@{}", d))),
+        canonicalization: text("c"),
+        signed_headers: tag(tags, "h").map(|h| h.split(':').map(|name| name.trim().to_string()).collect()).unwrap_or_default(),
+        body_length: tag(tags, "l").and_then(|l| l.parse().ok()),
+        reason: None,
+    }
+}
+
+fn parse_signature(tags: &[(String, String)], now: i64) -> Result<Signature, Failure> {
+    let neutral = |reason: &str| (DkimResult::Neutral, reason.to_string());
+    let required = |name: &str| tag(tags, name).filter(|v| !v.is_empty()).ok_or_else(|| neutral(&format!("missing {}= tag", name)));
+
+    if required("v")? != "1" {
+        return Err(neutral("unsupported version"));
+    }
+    let algorithm = match required("a")?.to_ascii_lowercase().as_str() {
+        "rsa-sha256" => Algorithm::RsaSha256,
+        "ed25519-sha256" => Algorithm::Ed25519Sha256,
+        "rsa-sha1" => return Err(neutral("rsa-sha1 is no longer accepted, RFC 8301")),
+        other => return Err(neutral(&format!("unsupported algorithm {}", other))),
+    };
+    let canon = |name: &str| match name.to_ascii_lowercase().as_str() {
+        "simple" => Ok(Canonicalization::Simple),
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        other => Err(neutral(&format!("unsupported canonicalization {}", other))),
+    };
+    let (header_canon, body_canon) = match tag(tags, "c").map(|c| c.split_once('/').unwrap_or((c, "simple"))) {
+        Some((header, body)) => (canon(header)?, canon(body)?),
+        None => (Canonicalization::Simple, Canonicalization::Simple),
+    };
+    let domain = required("d")?.to_ascii_lowercase();
+    let headers: Vec<String> = required("h")?.split(':').map(|name| name.trim().to_ascii_lowercase()).collect();
+    if !headers.iter().any(|name| name == "from") {
+        return Err(neutral("From is not signed"));
+    }
+    let identity_domain = match tag(tags, "i") {
+        Some(identity) => identity.rsplit_once('@').ok_or_else(|| neutral("bad i= tag"))?.1.to_ascii_lowercase(),
+        None => domain.clone(),
+    };
+    if identity_domain != domain && !identity_domain.ends_with(&format!(".{}", domain)) {
+        return Err(neutral("i= is not within d="));
+    }
+    if tag(tags, "q").is_some_and(|q| !q.split(':').any(|method| method.trim().eq_ignore_ascii_case("dns/txt"))) {
+        return Err(neutral("unsupported key query method"));
+    }
+    if let Some(expires) = tag(tags, "x") {
+        if expires.parse::<i64>().map_err(|_| neutral("bad x= tag"))? < now {
+            return Err(neutral("signature expired"));
+        }
+    }
+    let length = match tag(tags, "l") {
+        Some(l) => Some(l.parse().map_err(|_| neutral("bad l= tag"))?),
+        None => None,
+    };
+
+    Ok(Signature {
+        algorithm,
+        header_canon,
+        body_canon,
+        selector: required("s")?.to_string(),
+        body_hash: base64_tag(required("bh")?).ok_or_else(|| neutral("bad bh= tag"))?,
+        value: base64_tag(required("b")?).ok_or_else(|| neutral("bad b= tag"))?,
+        domain,
+        headers,
+        length,
+        identity_domain,
+    })
+}
+
+fn parse_key(text: &str) -> Result<Key, String> {
+    let tags = parse_tags(text)?;
+    if tag(&tags, "v").is_some_and(|v| v != "DKIM1") {
+        return Err("not a DKIM1 key".to_string());
+    }
+    let algorithm = match tag(&tags, "k").unwrap_or("rsa") {
+        "rsa" => Algorithm::RsaSha256,
+        "ed25519" => Algorithm::Ed25519Sha256,
+        other => return Err(format!("unsupported key type {}", other)),
+    };
+    if tag(&tags, "h").is_some_and(|h| !h.split(':').any(|hash| hash.trim() == "sha256")) {
+        return Err("key does not allow sha256".to_string());
+    }
+    if tag(&tags, "s").is_some_and(|s| !s.split(':').any(|service| matches!(service.trim(), "*" | "email"))) {
+        return Err("key is not for email".to_string());
+    }
+    let public = tag(&tags, "p").ok_or("key has no p= tag")?;
+    if public.is_empty() {
+        return Err("key revoked".to_string());
+    }
+    let public = base64_tag(public).ok_or("bad p= tag")?;
+    let strict = tag(&tags, "t").is_some_and(|t| t.split(':').any(|flag| flag.trim() == "s"));
+    Ok(Key { algorithm, public, strict })
+}
+
+/// One DER element: its tag, its contents and what follows it
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        (rest[..count].iter().fold(0, |len, &b| len << 8 | b as usize), &rest[count..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The RSAPublicKey in a key published as SubjectPublicKeyInfo (or bare, which some do)
+fn rsa_public_key(der: &[u8]) -> Option<&[u8]> {
+    let (tag, sequence, _) = der_element(der)?;
+    let (first, _, rest) = der_element(sequence)?;
+    match (tag, first) {
+        (0x30, 0x02) => Some(der), // Already RSAPublicKey: SEQUENCE { INTEGER, INTEGER }
+        (0x30, 0x30) => {
+            let (bit_string, key, _) = der_element(rest)?;
+            (bit_string == 0x03).then_some(key.split_first().filter(|(&unused, _)| unused == 0)?.1)
+        }
+        _ => None,
+    }
+}
+
+/// Lines end in CRLF, whatever the message arrived with
+fn crlf(raw: &[u8]) -> Cow<'_, [u8]> {
+    let bare_lf = raw.iter().enumerate().any(|(i, &b)| b == b'\n' && (i == 0 || raw[i - 1] != b'\r'));
+    if !bare_lf {
+        return Cow::Borrowed(raw);
+    }
+    let mut fixed = Vec::with_capacity(raw.len() + raw.len() / 32);
+    for (i, &b) in raw.iter().enumerate() {
+        if b == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            fixed.push(b'\r');
+        }
+        fixed.push(b);
+    }
+    Cow::Owned(fixed)
+}
+
+/// Header fields as received (folds and final CRLF kept) with their lowercase names, and the body
+fn split_message(raw: &[u8]) -> (Vec<(String, &[u8])>, &[u8]) {
+    let mut fields: Vec<(String, &[u8])> = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        if line == b"\r\n" {
+            if offset > start {
+                fields.push(field(&raw[start..offset]));
+            }
+            return (fields, &raw[offset + line.len()..]);
+        }
+        if !line.starts_with(b" ") && !line.starts_with(b"\t") && offset > start {
+            fields.push(field(&raw[start..offset]));
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if offset > start {
+        fields.push(field(&raw[start..offset]));
+    }
+    (fields, &[])
+}
+
+fn field(text: &[u8]) -> (String, &[u8]) {
+    let name = text.split(|&b| b == b':').next().unwrap_or_default();
+    (String::from_utf8_lossy(name).trim().to_ascii_lowercase(), text)
+}
+
+fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+/// Runs of spaces and tabs as one space
+fn collapse_wsp(text: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(text.len());
+    for &b in text {
+        if !is_wsp(b) {
+            collapsed.push(b);
+        } else if collapsed.last() != Some(&b' ') {
+            collapsed.push(b' ');
+        }
+    }
+    collapsed
+}
+
+fn trim_wsp(text: &[u8]) -> &[u8] {
+    let start = text.iter().position(|&b| !is_wsp(b)).unwrap_or(text.len());
+    let end = text.iter().rposition(|&b| !is_wsp(b)).map_or(start, |i| i + 1);
+    &text[start..end]
+}
+
+/// A header field as it's hashed (RFC 6376 3.4.1, 3.4.2)
+fn canonical_header(field: &[u8], canon: Canonicalization) -> Vec<u8> {
+    match canon {
+        Canonicalization::Simple => field.to_vec(),
+        Canonicalization::Relaxed => {
+            let colon = field.iter().position(|&b| b == b':').unwrap_or(field.len());
+            let name = String::from_utf8_lossy(trim_wsp(&field[..colon])).to_ascii_lowercase();
+            let value: Vec<u8> = field.get(colon + 1..).unwrap_or_default().iter().copied().filter(|&b| b != b'\r' && b != b'\n').collect();
+            let mut canonical = name.into_bytes();
+            canonical.push(b':');
+            canonical.extend_from_slice(trim_wsp(&collapse_wsp(&value)));
+            canonical.extend_from_slice(b"\r\n");
+            canonical
+        }
+    }
+}
+
+/// The body as it's hashed (RFC 6376 3.4.3, 3.4.4)
+fn canonical_body(body: &[u8], canon: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body.split_inclusive(|&b| b == b'\n').map(|line| line.strip_suffix(b"\r\n").unwrap_or(line).to_vec()).collect();
+    if canon == Canonicalization::Relaxed {
+        for line in lines.iter_mut() {
+            let collapsed = collapse_wsp(line);
+            *line = collapsed.strip_suffix(b" ").unwrap_or(&collapsed).to_vec();
+        }
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        // An empty body is one empty line when simple, nothing when relaxed
+        return if canon == Canonicalization::Simple { b"\r\n".to_vec() } else { Vec::new() };
+    }
+    lines.iter().flat_map(|line| line.iter().chain(b"\r\n")).copied().collect()
+}
+
+/// The signature field with the value of its b= tag removed, as it was when signed
+fn without_signature(field: &[u8]) -> Vec<u8> {
+    let colon = field.iter().position(|&b| b == b':').map_or(field.len(), |i| i + 1);
+    let end = if field.ends_with(b"\r\n") { field.len() - 2 } else { field.len() };
+    let mut stripped = field[..colon].to_vec();
+    let value = &field[colon..end];
+    for (i, part) in value.split(|&b| b == b';').enumerate() {
+        if i > 0 {
+            stripped.push(b';');
+        }
+        match part.iter().position(|&b| b == b'=') {
+            Some(eq) if part[..eq].trim_ascii() == b"b" => {
+                stripped.extend_from_slice(&part[..=eq]);
+            }
+            _ => stripped.extend_from_slice(part),
+        }
+    }
+    stripped.extend_from_slice(&field[end..]);
+    stripped
+}
+
+/// What the signer hashed: the signed headers, bottom-most instance first, then the signature itself
+fn signed_data(fields: &[(String, &[u8])], own: usize, signature: &Signature) -> Vec<u8> {
+    let mut used = vec![false; fields.len()];
+    let mut data = Vec::new();
+    for name in &signature.headers {
+        // A name listed more often than the header appears stands for nothing (over-signing)
+        if let Some(i) = (0..fields.len()).rev().find(|&i| !used[i] && fields[i].0 == *name) {
+            used[i] = true;
+            data.extend(canonical_header(fields[i].1, signature.header_canon));
+        }
+    }
+    let own = canonical_header(&without_signature(fields[own].1), signature.header_canon);
+    data.extend_from_slice(own.strip_suffix(b"\r\n").unwrap_or(&own));
+    data
+}
+
+async fn lookup_key(resolver: &dyn Resolver, signature: &Signature) -> Result<Key, Failure> {
+    let name = format!("{}._domainkey.{}", signature.selector, signature.domain);
+    let records = resolver.txt(&name).await.map_err(|e| (DkimResult::TempError, e))?;
+    let mut error = format!("no key at {}", name);
+    for record in records {
+        match parse_key(&record) {
+            Ok(key) => return Ok(key),
+            Err(e) => error = format!("{}: {}", name, e),
+        }
+    }
+    Err((DkimResult::PermError, error))
+}
+
+async fn check(resolver: &dyn Resolver, fields: &[(String, &[u8])], own: usize, body: &[u8], signature: &Signature) -> Result<(), Failure> {
+    let mut canonical = canonical_body(body, signature.body_canon);
+    if let Some(length) = signature.length {
+        if length > canonical.len() {
+            return Err((DkimResult::Fail, "body is shorter than l=".to_string()));
+        }
+        canonical.truncate(length);
+    }
+    if digest::digest(&digest::SHA256, &canonical).as_ref() != signature.body_hash.as_slice() {
+        return Err((DkimResult::Fail, "body hash did not verify".to_string()));
+    }
+
+    let key = lookup_key(resolver, signature).await?;
+    if key.algorithm != signature.algorithm {
+        return Err((DkimResult::PermError, "key type does not match a=".to_string()));
+    }
+    if key.strict && signature.identity_domain != signature.domain {
+        return Err((DkimResult::Fail, "key requires i= in exactly d=".to_string()));
+    }
+    let data = signed_data(fields, own, signature);
+    let verified = match signature.algorithm {
+        Algorithm::RsaSha256 => {
+            let public = rsa_public_key(&key.public).ok_or((DkimResult::PermError, "bad RSA key".to_string()))?;
+            // Keys under 1024 bits are refused (RFC 8301)
+            signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, public).verify(&data, &signature.value)
+        }
+        Algorithm::Ed25519Sha256 => {
+            // Ed25519 signs the SHA-256 of the headers, not the headers (RFC 8463 3)
+            let hash = digest::digest(&digest::SHA256, &data);
+            signature::UnparsedPublicKey::new(&signature::ED25519, &key.public).verify(hash.as_ref(), &signature.value)
+        }
+    };
+    verified.map_err(|_| (DkimResult::Fail, "signature did not verify".to_string()))
+}
+
+/// Check every DKIM-Signature of a message, top (added last) first
+pub async fn verify(resolver: &dyn Resolver, raw: &[u8]) -> Vec<DkimSignature> {
+    verify_at(resolver, raw, chrono::Utc::now().timestamp()).await
+}
+
+async fn verify_at(resolver: &dyn Resolver, raw: &[u8], now: i64) -> Vec<DkimSignature> {
+    let raw = crlf(raw);
+    let (fields, body) = split_message(&raw);
+    let mut results = Vec::new();
+    for (own, (name, text)) in fields.iter().enumerate() {
+        if name != "dkim-signature" {
+            continue;
+        }
+        let colon = text.iter().position(|&b| b == b':').map_or(text.len(), |i| i + 1);
+        let value = String::from_utf8_lossy(&text[colon..]).into_owned();
+        let tags = match parse_tags(&value) {
+            Ok(tags) => tags,
+            Err(e) => {
+                results.push(DkimSignature { reason: Some(e), ..describe(&[]) });
+                continue;
+            }
+        };
+        let mut report = describe(&tags);
+        let outcome = if results.len() >= MAX_SIGNATURES {
+            Err((DkimResult::Policy, format!("only {} signatures are checked per message", MAX_SIGNATURES)))
+        } else {
+            match parse_signature(&tags, now) {
+                Ok(signature) => check(resolver, &fields, own, body, &signature).await,
+                Err(failure) => Err(failure),
+            }
+        };
+        match outcome {
+            Ok(()) => report.result = DkimResult::Pass,
+            Err((result, reason)) => {
+                report.result = result;
+                report.reason = Some(reason);
+            }
+        }
+        results.push(report);
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::dns::Zone;
+
+    /// Public halves of tests/fixtures/dkim_rsa.pem and dkim_ed25519.pem, which signed dkim_signed.eml
+    const RSA_KEY: &str = "MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC43qlBKGK1sNh1pX1oSBloIIhwllt+uFXpXk+ey0HTCFjLaQkHop/d+7iHuoHq5utdfiP4fFHeBjs486jZjGaXplZylO4+lEX/MdlIgjOYKW8snfwiduAz65EBkv425OAt2cD2LFDR1IK72F2gK1evQ/q9OPoQQju160Go0pa+EwIDAQAB";
+    const ED25519_KEY: &str = "68k/CInIdklF1kNMoKm/4gtxwdnDwRCPMTS+ZVZZ2fA=";
+
+    fn fixture() -> String {
+        std::fs::read_to_string(format!("{}/tests/fixtures/dkim_signed.eml", env!("CARGO_MANIFEST_DIR"))).unwrap()
+    }
+
+    fn zone() -> Zone {
+        Zone::new()
+            .txt("2024a._domainkey.shop.example", &format!("v=DKIM1; k=rsa; p={}", RSA_KEY))
+            .txt("ed1._domainkey.shop.example", &format!("v=DKIM1; k=ed25519; p={}", ED25519_KEY))
+            .txt("k1._domainkey.esp.example", &format!("k=rsa; t=y; p={}", RSA_KEY))
+    }
+
+    async fn results(zone: &Zone, message: &str) -> Vec<(DkimResult, Option<String>)> {
+        verify(zone, message.as_bytes()).await.into_iter().map(|s| (s.result, s.reason)).collect()
+    }
+
+    #[tokio::test]
+    async fn signatures_from_another_implementation_verify() {
+        let signatures = verify(&zone(), fixture().as_bytes()).await;
+        assert_eq!(signatures.len(), 3);
+        assert!(signatures.iter().all(|s| s.result == DkimResult::Pass), "{:?}", signatures);
+
+        let ed25519 = &signatures[1];
+        assert_eq!(ed25519.domain.as_deref(), Some("shop.example"));
+        assert_eq!(ed25519.identity.as_deref(), Some("@mail.shop.example"));
+        assert_eq!(ed25519.canonicalization.as_deref(), Some("relaxed/relaxed"));
+        assert_eq!(ed25519.signed_headers, vec!["From", "Subject", "Date", "To"]);
+        assert_eq!(
+            signatures[0].result_clause(),
+            "dkim=pass header.d=esp.example header.i=@esp.example header.s=k1 header.a=rsa-sha256"
+        );
+    }
+
+    #[test]
+    fn reasons_and_values_stay_inside_the_clause() {
+        let signature = DkimSignature {
+            result: DkimResult::Fail,
+            domain: Some("shop.example; dmarc=pass".into()),
+            selector: None,
+            algorithm: None,
+            identity: None,
+            canonicalization: None,
+            signed_headers: Vec::new(),
+            body_length: None,
+            reason: Some("bad) ; dmarc=pass (\\".into()),
+        };
+        assert_eq!(
+            signature.result_clause(),
+            "dkim=fail (bad\\) ; dmarc=pass \\(\\\\) header.d=\"shop.example; dmarc=pass\""
+        );
+    }
+
+    #[tokio::test]
+    async fn line_endings_do_not_matter() {
+        let signatures = verify(&zone(), fixture().replace("\r\n", "\n").as_bytes()).await;
+        assert!(signatures.iter().all(|s| s.result == DkimResult::Pass));
+    }
+
+    #[tokio::test]
+    async fn changes_after_signing_are_caught() {
+        let zone = zone();
+        let body = fixture().replace("482913.", "000000.");
+        assert!(results(&zone, &body).await.iter().all(|r| *r == (DkimResult::Fail, Some("body hash did not verify".to_string()))));
+
+        let subject = fixture().replace("Your verification", "Your  verification");
+        let bad_signature = (DkimResult::Fail, Some("signature did not verify".to_string()));
+        // Relaxed canonicalization tolerates whitespace changes, simple doesn't
+        assert_eq!(results(&zone, &subject).await, vec![bad_signature, (DkimResult::Pass, None), (DkimResult::Pass, None)]);
+
+        // Whitespace at line ends of the body only matters to simple body canonicalization
+        let trailing = fixture().replace("Hi there,  ", "Hi there,");
+        let outcome = results(&zone, &trailing).await;
+        assert_eq!(outcome[1], (DkimResult::Pass, None));
+        assert_eq!(outcome[2].0, DkimResult::Fail);
+
+        // A second From added on top is covered by the over-signed From
+        let added = format!("From: attacker@evil.example\r\n{}", fixture());
+        assert_eq!(results(&zone, &added).await[2].0, DkimResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn key_problems_are_reported() {
+        let message = fixture();
+        let missing = results(&Zone::new(), &message).await;
+        assert_eq!(missing[0], (DkimResult::PermError, Some("no key at k1._domainkey.esp.example".to_string())));
+
+        let down = results(&Zone::new().failing("ed1._domainkey.shop.example"), &message).await;
+        assert_eq!(down[1].0, DkimResult::TempError);
+
+        let zone = Zone::new()
+            .txt("k1._domainkey.esp.example", "v=DKIM1; p=")
+            .txt("ed1._domainkey.shop.example", &format!("k=rsa; p={}", RSA_KEY))
+            .txt("2024a._domainkey.shop.example", &format!("t=s; p={}", RSA_KEY));
+        let broken = results(&zone, &message).await;
+        assert_eq!(broken[0], (DkimResult::PermError, Some("k1._domainkey.esp.example: key revoked".to_string())));
+        assert_eq!(broken[1], (DkimResult::PermError, Some("key type does not match a=".to_string())));
+        assert_eq!(broken[2], (DkimResult::Pass, None)); // No i=, so strict mode is satisfied
+    }
+
+    #[tokio::test]
+    async fn malformed_signatures_are_neutral() {
+        let zone = zone();
+        let signature = |tags: &str| format!("DKIM-Signature: {}\r\nFrom: a@shop.example\r\n\r\nHi\r\n", tags);
+        let reason = |tags: &str| {
+            let message = signature(tags);
+            let zone = &zone;
+            async move { verify_at(zone, message.as_bytes(), 1_718_000_000).await.remove(0).reason.unwrap() }
+        };
+        let base = "v=1; d=shop.example; s=2024a; bh=AAAA; b=AAAA";
+        assert_eq!(reason(&format!("{}; a=rsa-sha1; h=from", base)).await, "rsa-sha1 is no longer accepted, RFC 8301");
+        assert_eq!(reason(&format!("{}; a=rsa-sha256; h=subject", base)).await, "From is not signed");
+        assert_eq!(reason(&format!("{}; a=rsa-sha256; h=from; i=@evil.example", base)).await, "i= is not within d=");
+        assert_eq!(reason(&format!("{}; a=rsa-sha256; h=from; x=1", base)).await, "signature expired");
+        assert_eq!(reason(&format!("{}; a=rsa-sha256; h=from; h=to", base)).await, "duplicate h= tag");
+        assert_eq!(reason("v=1; a=rsa-sha256; h=from").await, "missing d= tag");
+        assert!(verify(&zone, b"From: a@shop.example\r\n\r\nunsigned\r\n").await.is_empty());
+    }
+
+    #[test]
+    fn canonicalization() {
+        let field = b"Subject : Hello \t there\r\n  again \r\n";
+        assert_eq!(canonical_header(field, Canonicalization::Relaxed), b"subject:Hello there again\r\n");
+        assert_eq!(canonical_header(field, Canonicalization::Simple), field);
+
+        let body = b"a  b \r\n\r\nc\r\n\r\n\r\n";
+        assert_eq!(canonical_body(body, Canonicalization::Relaxed), b"a b\r\n\r\nc\r\n");
+        assert_eq!(canonical_body(body, Canonicalization::Simple), b"a  b \r\n\r\nc\r\n");
+        assert_eq!(canonical_body(b"", Canonicalization::Simple), b"\r\n");
+        assert_eq!(canonical_body(b"\r\n\r\n", Canonicalization::Relaxed), b"");
+    }
+
+    #[test]
+    fn the_signature_value_is_blanked_but_bh_kept() {
+        let field = b"DKIM-Signature: v=1; bh=xyz;\r\n b=abc\r\n def; d=a.example\r\n";
+        assert_eq!(without_signature(field), b"DKIM-Signature: v=1; bh=xyz;\r\n b=; d=a.example\r\n");
+    }
+
+    #[test]
+    fn rsa_keys_are_unwrapped() {
+        let spki = base64_tag(RSA_KEY).unwrap();
+        let bare = rsa_public_key(&spki).unwrap();
+        assert_eq!(bare[0], 0x30);
+        assert_eq!(rsa_public_key(bare), Some(bare));
+        assert_eq!(rsa_public_key(b"\x30\x03\x01\x01\x00"), None);
+    }
+}
@@ -0,0 +1,289 @@
+//! DNS lookups for sender checks (SPF, DKIM). Checks take a `&dyn Resolver`: the server
+//! asks a DNS-over-HTTPS endpoint, tests hand in a `Zone` with the records they need.
+
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Used when DNS_RESOLVER_URL is not set
+const DEFAULT_DOH_URL: &str = "https://cloudflare-dns.com/dns-query";
+
+/// Per query; whole checks have their own deadline on top
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Record lookups. An empty list means the name has no such records (or doesn't exist);
+/// `Err` is a failure worth retrying later (timeout, SERVFAIL).
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// TXT records, the strings of each joined
+    async fn txt(&self, name: &str) -> Result<Vec<String>, String>;
+    async fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>, String>;
+    async fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, String>;
+    /// Mail exchangers, most preferred first
+    async fn mx(&self, name: &str) -> Result<Vec<String>, String>;
+    /// Names the address points back to
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, String>;
+}
+
+/// A name as compared and looked up: lowercase, without the root dot
+pub fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// "1.2.0.192.in-addr.arpa" for 192.0.2.1, nibbles under "ip6.arpa" for IPv6
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(ip) => {
+            let mut nibbles = nibbles(ip);
+            nibbles.reverse();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+/// The 32 hex digits of an IPv6 address, most significant first
+pub fn nibbles(ip: Ipv6Addr) -> Vec<String> {
+    ip.octets().iter().flat_map(|b| [b >> 4, b & 0xf]).map(|n| format!("{:x}", n)).collect()
+}
+
+/// Resolves over DNS-over-HTTPS with the JSON API (Cloudflare, Google and others serve it)
+pub struct DohResolver {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    kind: u16,
+    data: String,
+}
+
+impl DohResolver {
+    pub fn new(url: &str) -> Self {
+        let client = reqwest::Client::builder().timeout(QUERY_TIMEOUT).build().unwrap_or_default();
+        Self { client, url: url.to_string() }
+    }
+
+    /// The endpoint in DNS_RESOLVER_URL, else Cloudflare's
+    pub fn from_env() -> Self {
+        Self::new(&env::var("DNS_RESOLVER_URL").unwrap_or_else(|_| DEFAULT_DOH_URL.to_string()))
+    }
+
+    /// The data of every answer of `kind` (CNAMEs followed by the server are skipped)
+    async fn query(&self, name: &str, kind: u16) -> Result<Vec<String>, String> {
+        let response = self
+            .client
+            .get(&self.url)
+            .query(&[("name", name), ("type", &kind.to_string())])
+            .header(reqwest::header::ACCEPT, "application/dns-json")
+            .send()
+            .await
+            .map_err(|e| {
+                let cause = if e.is_timeout() {
+                    "timed out".to_string()
+                } else if e.is_connect() {
+                    "resolver unreachable".to_string()
+                } else {
+                    e.without_url().to_string()
+                };
+                format!("DNS query for {} failed: {}", name, cause)
+            })?;
+        if !response.status().is_success() {
+            return Err(format!("DNS query for {} failed: HTTP {}", name, response.status()));
+        }
+        let body: DohResponse = response.json().await.map_err(|e| format!("Bad DNS response for {}: {}", name, e))?;
+        match body.status {
+            0 => Ok(body.answer.into_iter().filter(|a| a.kind == kind).map(|a| a.data).collect()),
+            3 => Ok(Vec::new()), // NXDOMAIN
+            rcode => Err(format!("DNS query for {} failed with rcode {}", name, rcode)),
+        }
+    }
+}
+
+/// The text of a TXT answer: its quoted strings concatenated, escapes undone.
+/// Some servers send the text unquoted, which is taken as is.
+pub fn txt_data(data: &str) -> String {
+    if !data.starts_with('"') {
+        return data.to_string();
+    }
+    let mut text = String::new();
+    let mut chars = data.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => {
+                let digits: String = std::iter::from_fn(|| chars.next_if(char::is_ascii_digit)).take(3).collect();
+                match digits.parse::<u8>() {
+                    Ok(byte) if digits.len() == 3 => text.push(byte as char),
+                    _ => text.extend(digits.chars().chain(chars.next())),
+                }
+            }
+            c if quoted => text.push(c),
+            _ => {} // Space between strings
+        }
+    }
+    text
+}
+
+#[async_trait]
+impl Resolver for DohResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, String> {
+        Ok(self.query(name, 16).await?.iter().map(|data| txt_data(data)).collect())
+    }
+
+    async fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>, String> {
+        Ok(self.query(name, 1).await?.iter().filter_map(|data| data.parse().ok()).collect())
+    }
+
+    async fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, String> {
+        Ok(self.query(name, 28).await?.iter().filter_map(|data| data.parse().ok()).collect())
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, String> {
+        let mut exchanges: Vec<(u16, String)> = self
+            .query(name, 15)
+            .await?
+            .iter()
+            .filter_map(|data| {
+                let (preference, exchange) = data.split_once(' ')?;
+                Some((preference.parse().ok()?, normalize(exchange)))
+            })
+            .collect();
+        exchanges.sort();
+        Ok(exchanges.into_iter().map(|(_, exchange)| exchange).collect())
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, String> {
+        Ok(self.query(&reverse_name(ip), 12).await?.iter().map(|data| normalize(data)).collect())
+    }
+}
+
+/// Records held in memory, standing in for DNS in tests. Names not added have no records;
+/// names marked failing give a temporary error for every lookup.
+#[derive(Default)]
+pub struct Zone {
+    records: HashMap<(String, &'static str), Vec<String>>,
+    failing: Vec<String>,
+    queries: AtomicUsize,
+}
+
+impl Zone {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(mut self, name: &str, kind: &'static str, data: &str) -> Self {
+        self.records.entry((normalize(name), kind)).or_default().push(data.to_string());
+        self
+    }
+
+    pub fn txt(self, name: &str, text: &str) -> Self {
+        self.add(name, "TXT", text)
+    }
+
+    /// An A or AAAA record, depending on the address
+    pub fn ip(self, name: &str, ip: &str) -> Self {
+        let kind = if ip.contains(':') { "AAAA" } else { "A" };
+        self.add(name, kind, ip)
+    }
+
+    /// Exchangers are returned in the order added
+    pub fn mx(self, name: &str, exchange: &str) -> Self {
+        self.add(name, "MX", &normalize(exchange))
+    }
+
+    pub fn ptr(self, ip: &str, name: &str) -> Self {
+        let reverse = reverse_name(ip.parse().expect("ptr needs an IP address"));
+        self.add(&reverse, "PTR", &normalize(name))
+    }
+
+    pub fn failing(mut self, name: &str) -> Self {
+        self.failing.push(normalize(name));
+        self
+    }
+
+    /// Lookups answered so far
+    pub fn queries(&self) -> usize {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    fn lookup(&self, name: &str, kind: &'static str) -> Result<Vec<String>, String> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let name = normalize(name);
+        if self.failing.contains(&name) {
+            return Err(format!("DNS query for {} timed out", name));
+        }
+        Ok(self.records.get(&(name, kind)).cloned().unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl Resolver for Zone {
+    async fn txt(&self, name: &str) -> Result<Vec<String>, String> {
+        self.lookup(name, "TXT")
+    }
+
+    async fn a(&self, name: &str) -> Result<Vec<Ipv4Addr>, String> {
+        Ok(self.lookup(name, "A")?.iter().filter_map(|ip| ip.parse().ok()).collect())
+    }
+
+    async fn aaaa(&self, name: &str) -> Result<Vec<Ipv6Addr>, String> {
+        Ok(self.lookup(name, "AAAA")?.iter().filter_map(|ip| ip.parse().ok()).collect())
+    }
+
+    async fn mx(&self, name: &str) -> Result<Vec<String>, String> {
+        self.lookup(name, "MX")
+    }
+
+    async fn ptr(&self, ip: IpAddr) -> Result<Vec<String>, String> {
+        self.lookup(&reverse_name(ip), "PTR")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txt_strings_are_joined() {
+        assert_eq!(txt_data("\"v=spf1 include:a.example \" \"-all\""), "v=spf1 include:a.example -all");
+        assert_eq!(txt_data("\"say \\\"hi\\\" \\059\""), "say \"hi\" ;");
+        assert_eq!(txt_data("v=spf1 -all"), "v=spf1 -all");
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(reverse_name("192.0.2.1".parse().unwrap()), "1.2.0.192.in-addr.arpa");
+        let v6 = reverse_name("2001:db8::1".parse().unwrap());
+        assert!(v6.starts_with("1.0.0.0.0.0.0.0."));
+        assert!(v6.ends_with("8.b.d.0.1.0.0.2.ip6.arpa"));
+    }
+
+    #[tokio::test]
+    async fn zones_answer_what_was_added() {
+        let zone = Zone::new().txt("A.example.", "hello").ip("a.example", "2001:db8::1").failing("down.example");
+        assert_eq!(Resolver::txt(&zone, "a.example").await.unwrap(), vec!["hello"]);
+        assert_eq!(zone.aaaa("a.example").await.unwrap(), vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()]);
+        assert!(zone.a("a.example").await.unwrap().is_empty());
+        assert!(Resolver::mx(&zone, "down.example").await.is_err());
+        assert_eq!(zone.queries(), 4);
+    }
+}
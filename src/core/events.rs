@@ -0,0 +1,377 @@
+//! Live updates for connected clients. Handlers publish to the hub shared as
+//! `web::Data<EventHub>`; `GET /events/{user}` streams what concerns the caller.
+
+use futures::future::BoxFuture;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{Instant, MissedTickBehavior};
+use crate::db::{EmailRow, Inbox};
+
+/// Updates a slow subscriber may fall behind by before it misses some
+const CAPACITY: usize = 256;
+
+/// Idle streams send a comment this often so proxies don't close them; the caller's access
+/// is checked again at the same pace
+const KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// Postgres channel that carries changes between replicas
+const NOTIFY_CHANNEL: &str = "mail_events";
+/// NOTIFY payloads must be shorter than this many bytes
+const NOTIFY_LIMIT: usize = 8000;
+
+/// New state of an email after a change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailState {
+    pub id: i64,
+    pub alias: Option<String>,
+    pub read: bool,
+    pub starred: bool,
+    pub labels: Vec<String>,
+}
+
+impl From<&EmailRow> for EmailState {
+    fn from(row: &EmailRow) -> Self {
+        EmailState {
+            id: row.id,
+            alias: row.alias.clone(),
+            read: row.read_at.is_some(),
+            starred: row.starred,
+            labels: row.labels.clone(),
+        }
+    }
+}
+
+/// What subscribers of an inbox hear
+#[derive(Debug, Clone)]
+pub enum Update {
+    Changed(Vec<EmailState>),
+    Missed, // Changes may have been lost (e.g. the database connection dropped): refetch
+}
+
+/// A change as sent through Postgres
+#[derive(Debug, Serialize, Deserialize)]
+struct Notice {
+    inbox: String,
+    emails: Vec<EmailState>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    missed: bool, // An email too big for one notification changed: subscribers should refetch
+}
+
+impl Notice {
+    fn payload(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// The inbox and what its subscribers hear
+    fn into_update(self) -> (String, Update) {
+        let update = if self.missed { Update::Missed } else { Update::Changed(self.emails) };
+        (self.inbox, update)
+    }
+}
+
+/// Split a change into notices whose payloads each fit in one NOTIFY
+fn notices(inbox: &str, emails: Vec<EmailState>) -> Vec<Notice> {
+    let empty = |missed| Notice { inbox: inbox.to_string(), emails: Vec::new(), missed };
+    let base = empty(false).payload().len();
+    let mut notices = Vec::new();
+    let (mut current, mut size) = (empty(false), base);
+    for email in emails {
+        let len = serde_json::to_string(&email).map(|json| json.len()).unwrap_or_default();
+        if base + len >= NOTIFY_LIMIT {
+            // Labels make it too big even alone
+            notices.push(empty(true));
+            continue;
+        }
+        if !current.emails.is_empty() && size + 1 + len >= NOTIFY_LIMIT {
+            notices.push(std::mem::replace(&mut current, empty(false)));
+            size = base;
+        }
+        size += usize::from(!current.emails.is_empty()) + len;
+        current.emails.push(email);
+    }
+    if !current.emails.is_empty() {
+        notices.push(current);
+    }
+    notices
+}
+
+/// Channel name of an inbox
+fn key(inbox: Inbox<'_>) -> String {
+    match inbox {
+        Inbox::Personal(user_id) => format!("user:{}", user_id),
+        Inbox::Shared(org_id) => format!("org:{}", org_id),
+    }
+}
+
+/// One channel per inbox with subscribers, so a busy inbox never makes others lag.
+/// With Postgres, changes go through NOTIFY and reach the streams of every replica.
+#[derive(Clone, Default)]
+pub struct EventHub {
+    channels: Arc<Mutex<HashMap<String, broadcast::Sender<Update>>>>,
+    pool: Option<PgPool>,
+    database_url: Option<String>, // The listener keeps a connection of its own, outside the pool
+}
+
+impl EventHub {
+    /// A hub for this process only
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A hub shared by every replica on `pool`, the database at `database_url`;
+    /// `listen` must run for it to deliver anything
+    pub fn with_postgres(pool: PgPool, database_url: &str) -> Self {
+        EventHub { pool: Some(pool), database_url: Some(database_url.to_string()), ..Self::default() }
+    }
+
+    /// Tell the subscribers of an inbox that some of its emails changed (nobody listening is fine)
+    pub async fn state_changed(&self, inbox: Inbox<'_>, rows: &[EmailRow]) {
+        let emails: Vec<EmailState> = rows.iter().map(EmailState::from).collect();
+        let Some(pool) = &self.pool else {
+            if !emails.is_empty() {
+                self.deliver(&key(inbox), Update::Changed(emails));
+            }
+            return;
+        };
+
+        for notice in notices(&key(inbox), emails) {
+            let sent = sqlx::query("SELECT pg_notify($1, $2)")
+                .bind(NOTIFY_CHANNEL)
+                .bind(notice.payload())
+                .execute(pool)
+                .await;
+            if let Err(e) = sent {
+                // Streams on this replica still hear about it
+                eprintln!("⚠️ Failed to publish mail event: {}", e);
+                let (inbox, update) = notice.into_update();
+                self.deliver(&inbox, update);
+            }
+        }
+    }
+
+    /// Updates of one inbox, from now on
+    pub fn subscribe(&self, inbox: Inbox<'_>) -> broadcast::Receiver<Update> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .entry(key(inbox))
+            .or_insert_with(|| broadcast::channel(CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Relay notifications from Postgres to the local subscribers, for as long as the process runs.
+    /// When the connection drops every stream is told it missed updates.
+    pub async fn listen(&self) {
+        let Some(database_url) = &self.database_url else { return };
+        loop {
+            let mut listener = match PgListener::connect(database_url).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("⚠️ Can't listen for mail events: {}", e);
+                    tokio::time::sleep(KEEPALIVE).await;
+                    continue;
+                }
+            };
+            if let Err(e) = listener.listen(NOTIFY_CHANNEL).await {
+                eprintln!("⚠️ Can't listen for mail events: {}", e);
+                tokio::time::sleep(KEEPALIVE).await;
+                continue;
+            }
+
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match serde_json::from_str::<Notice>(notification.payload()) {
+                        Ok(notice) => {
+                            let (inbox, update) = notice.into_update();
+                            self.deliver(&inbox, update);
+                        }
+                        Err(e) => eprintln!("⚠️ Unreadable mail event: {}", e),
+                    },
+                    // Reconnected: whatever was sent in between is gone
+                    Ok(None) => self.missed_all(),
+                    Err(e) => {
+                        eprintln!("⚠️ Lost mail events connection: {}", e);
+                        self.missed_all();
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, inbox: &str, update: Update) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(inbox) {
+            if sender.send(update).is_err() {
+                channels.remove(inbox); // Every subscriber is gone
+            }
+        }
+    }
+
+    fn missed_all(&self) {
+        for sender in self.channels.lock().unwrap().values() {
+            let _ = sender.send(Update::Missed);
+        }
+    }
+}
+
+/// A server-sent event: `event: <name>` and one line of JSON data
+pub fn sse_frame(name: &str, data: &impl Serialize) -> String {
+    format!("event: {}\ndata: {}\n\n", name, serde_json::to_string(data).unwrap_or_default())
+}
+
+/// Checked every keepalive: whether the caller may still read the inbox
+pub type AccessCheck = Box<dyn Fn() -> BoxFuture<'static, bool>>;
+
+/// Server-sent events for one inbox, until the hub goes away or `still_allowed` says no:
+/// `state` with the emails that changed, `lagged` when some were missed and the client should refetch
+pub fn inbox_stream(receiver: broadcast::Receiver<Update>, still_allowed: AccessCheck) -> impl Stream<Item = String> {
+    stream_every(KEEPALIVE, receiver, still_allowed)
+}
+
+fn stream_every(period: std::time::Duration, receiver: broadcast::Receiver<Update>, still_allowed: AccessCheck) -> impl Stream<Item = String> {
+    let mut keepalive = tokio::time::interval_at(Instant::now() + period, period);
+    keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    futures::stream::unfold((receiver, keepalive, still_allowed), |(mut receiver, mut keepalive, still_allowed)| async move {
+        let frame = tokio::select! {
+            update = receiver.recv() => match update {
+                Ok(Update::Changed(emails)) => sse_frame("state", &emails),
+                Ok(Update::Missed) => sse_frame("lagged", &serde_json::json!({ "missed": null })),
+                Err(RecvError::Lagged(missed)) => sse_frame("lagged", &serde_json::json!({ "missed": missed })),
+                Err(RecvError::Closed) => return None,
+            },
+            _ = keepalive.tick() => {
+                if !still_allowed().await {
+                    return None;
+                }
+                ": keepalive\n\n".to_string()
+            }
+        };
+        Some((frame, (receiver, keepalive, still_allowed)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: i64) -> EmailRow {
+        EmailRow {
+            id,
+            user_id: "user_a".to_string(),
+            org_id: None,
+            message_id: None,
+            sender: "a@example.com".to_string(),
+            subject: None,
+            body_preview: None,
+            otp: None,
+            alias: None,
+            read_at: None,
+            received_at: chrono::NaiveDateTime::default(),
+            thread_id: id,
+            starred: true,
+            labels: vec!["work".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_only_get_changes_of_their_inbox() {
+        let hub = EventHub::new();
+        let mut mine = hub.subscribe(Inbox::Personal("user_a"));
+        let mut shared = hub.subscribe(Inbox::Shared("user_a"));
+        hub.state_changed(Inbox::Personal("user_a"), &[row(1)]).await;
+        hub.state_changed(Inbox::Personal("user_a"), &[]).await; // Nothing changed, nothing sent
+        hub.state_changed(Inbox::Personal("user_b"), &[row(2)]).await;
+
+        match mine.try_recv().unwrap() {
+            Update::Changed(emails) => assert_eq!(emails[0].id, 1),
+            update => panic!("{:?}", update),
+        }
+        assert!(mine.try_recv().is_err());
+        assert!(shared.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_end_once_access_is_gone() {
+        use futures::StreamExt;
+        let hub = EventHub::new();
+        let allowed = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let check = allowed.clone();
+        let stream = stream_every(
+            std::time::Duration::from_millis(10),
+            hub.subscribe(Inbox::Personal("user_a")),
+            Box::new(move || {
+                let allowed = check.load(std::sync::atomic::Ordering::SeqCst);
+                Box::pin(async move { allowed })
+            }),
+        );
+        futures::pin_mut!(stream);
+
+        assert_eq!(stream.next().await.as_deref(), Some(": keepalive\n\n"));
+        allowed.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(stream.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn changes_reach_other_replicas_through_postgres() {
+        let Ok(database_url) = std::env::var("DATABASE_URL") else { return };
+        // A pool of one connection: publishing must not wait on the listener's
+        let pool = sqlx::postgres::PgPoolOptions::new().max_connections(1).connect(&database_url).await.unwrap();
+        let (here, there) = (EventHub::with_postgres(pool.clone(), &database_url), EventHub::with_postgres(pool, &database_url));
+        let listening = there.clone();
+        tokio::spawn(async move { listening.listen().await });
+
+        let inbox = Inbox::Personal("user_replicated");
+        let mut receiver = there.subscribe(inbox);
+        // The listener may not be up yet: publish until it hears one
+        let update = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            loop {
+                here.state_changed(inbox, &[row(3)]).await;
+                if let Ok(Ok(update)) = tokio::time::timeout(std::time::Duration::from_millis(100), receiver.recv()).await {
+                    break update;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(update, Update::Changed(emails) if emails[0].id == 3));
+    }
+
+    #[test]
+    fn notices_fit_in_one_notify_each() {
+        let labelled = |id| EmailState {
+            labels: (0..40).map(|n| format!("label-{:02}-{}", n, "x".repeat(50))).collect(),
+            ..EmailState::from(&row(id))
+        };
+        let emails: Vec<EmailState> = (1..=25).map(labelled).collect();
+
+        let notices = notices("org:org_1", emails);
+        assert!(notices.len() > 1);
+        assert!(notices.iter().all(|notice| !notice.missed && notice.payload().len() < NOTIFY_LIMIT));
+        let ids: Vec<i64> = notices.iter().flat_map(|notice| notice.emails.iter().map(|email| email.id)).collect();
+        assert_eq!(ids, (1..=25).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn emails_too_big_to_notify_tell_subscribers_to_refetch() {
+        let huge = EmailState { labels: vec!["x".repeat(NOTIFY_LIMIT)], ..EmailState::from(&row(1)) };
+        let notices = notices("org:org_1", vec![huge, EmailState::from(&row(2))]);
+
+        assert_eq!(notices.len(), 2);
+        assert!(notices[0].missed && notices[0].payload().len() < NOTIFY_LIMIT);
+        assert!(matches!(serde_json::from_str::<Notice>(&notices[0].payload()).unwrap().into_update().1, Update::Missed));
+        assert_eq!(notices[1].emails[0].id, 2);
+    }
+
+    #[test]
+    fn frames_are_one_event_each() {
+        let frame = sse_frame("state", &EmailState::from(&row(7)));
+        assert_eq!(frame, "event: state\ndata: {\"id\":7,\"alias\":null,\"read\":false,\"starred\":true,\"labels\":[\"work\"]}\n\n");
+    }
+}
@@ -0,0 +1,57 @@
+use sqlx::{PgPool, Row};
+use chrono::{Utc, Duration};
+
+/// How long the frontend has to redeem a code after the redirect
+const CODE_TTL_SECS: i64 = 60;
+
+/// A redeemed code: who logged in and how
+pub struct Redeemed {
+    pub user_id: String,
+    pub profile: serde_json::Value,
+    pub workos_session_id: Option<String>, // The session to tie the login to, for WorkOS logins
+}
+
+/// Issue a one-time code the frontend trades for a session at `POST /auth/exchange`.
+/// Keeps JWTs out of redirect URLs, browser history and Referer headers.
+/// `profile` is returned alongside the token (name, email, alias, ...).
+pub async fn issue(
+    pool: &PgPool,
+    user_id: &str,
+    profile: &serde_json::Value,
+    workos_session_id: Option<&str>,
+) -> Result<String, sqlx::Error> {
+    let code = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let expires_at = Utc::now() + Duration::seconds(CODE_TTL_SECS);
+
+    sqlx::query("DELETE FROM auth_exchange_codes WHERE expires_at < NOW()")
+        .execute(pool)
+        .await?;
+
+    sqlx::query("INSERT INTO auth_exchange_codes (code, user_id, profile, workos_session_id, expires_at) VALUES ($1, $2, $3, $4, $5)")
+        .bind(&code)
+        .bind(user_id)
+        .bind(profile.to_string())
+        .bind(workos_session_id)
+        .bind(expires_at.naive_utc())
+        .execute(pool)
+        .await?;
+
+    Ok(code)
+}
+
+/// Redeem a code. Each code works once.
+pub async fn redeem(pool: &PgPool, code: &str) -> Result<Option<Redeemed>, sqlx::Error> {
+    let row = sqlx::query("DELETE FROM auth_exchange_codes WHERE code = $1 AND expires_at > NOW() RETURNING user_id, profile, workos_session_id")
+        .bind(code)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.map(|r| {
+        let profile: String = r.get("profile");
+        Redeemed {
+            user_id: r.get("user_id"),
+            profile: serde_json::from_str(&profile).unwrap_or_default(),
+            workos_session_id: r.get("workos_session_id"),
+        }
+    }))
+}
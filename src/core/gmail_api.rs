@@ -1,4 +1,26 @@
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::{general_purpose::GeneralPurpose, GeneralPurposeConfig, DecodePaddingMode};
+use crate::core::crypto::SealedSecret;
+use crate::core::oauth;
+use crate::core::provider::{MailProvider, MessageList, MessageRef, ProviderError, RawMessage};
+
+const GMAIL_BASE: &str = "https://gmail.googleapis.com/gmail/v1/users/me";
+/// How many message fetches run against the Gmail API at once
+const MAX_CONCURRENT_FETCHES: usize = 8;
+/// Retries per request on rate limiting or server errors
+const MAX_RETRIES: u32 = 4;
+const MAX_BACKOFF_SECS: u64 = 32;
+
+/// Gmail returns `raw` as base64url, sometimes with and sometimes without padding
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
 
 #[derive(Debug, Deserialize)]
 struct GmailMessageList {
@@ -12,118 +34,322 @@ struct GmailMessageRef {
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
-struct GmailMessage {
+struct GmailProfile {
+    history_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GmailHistoryList {
+    history: Option<Vec<GmailHistory>>,
+    next_page_token: Option<String>,
+    history_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GmailHistory {
     id: String,
-    payload: Option<GmailPayload>,
-    snippet: Option<String>,
-    internal_date: Option<String>, // Gmail returns this as stringified long
+    messages_added: Option<Vec<GmailMessageAdded>>,
 }
 
 #[derive(Debug, Deserialize)]
-struct GmailPayload {
-    headers: Option<Vec<GmailHeader>>,
+struct GmailMessageAdded {
+    message: GmailMessageRef,
 }
 
 #[derive(Debug, Deserialize)]
-struct GmailHeader {
-    name: String,
-    value: String,
-}
-
-#[derive(Debug, Clone, Serialize)]
-pub struct FetchedEmail {
-    pub message_id: String,
-    pub sender: String,
-    pub subject: String,
-    pub body_preview: String,
-    pub received_at: i64, // Timestamp in milliseconds
-}
-
-/// Fetch emails from Gmail API
-pub async fn fetch_gmail_emails(access_token: &str, max_results: u32) -> Result<Vec<FetchedEmail>, String> {
-    let client = reqwest::Client::new();
-    
-    // 1. List messages
-    let list_url = format!(
-        "https://gmail.googleapis.com/gmail/v1/users/me/messages?maxResults={}",
-        max_results
-    );
-    
-    let list_resp = client
-        .get(&list_url)
-        .bearer_auth(access_token)
-        .send()
-        .await
-        .map_err(|e| format!("Failed to list messages: {}", e))?;
-
-    if !list_resp.status().is_success() {
-        let error_text = list_resp.text().await.unwrap_or_default();
-        return Err(format!("Gmail API error: {}", error_text));
-    }
-
-    let message_list: GmailMessageList = list_resp
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse message list: {}", e))?;
-
-    let messages = match message_list.messages {
-        Some(m) if !m.is_empty() => m,
-        _ => return Ok(vec![]),
-    };
+#[serde(rename_all = "camelCase")]
+struct GmailMessage {
+    raw: Option<String>,           // Full RFC822 message, base64url encoded (format=raw)
+    internal_date: Option<String>, // Gmail returns this as stringified long
+}
 
-    // 2. Fetch each message details
-    let mut emails = Vec::new();
-    
-    for msg_ref in messages.iter().take(max_results as usize) {
-        let msg_url = format!(
-            "https://gmail.googleapis.com/gmail/v1/users/me/messages/{}?format=metadata&metadataHeaders=From&metadataHeaders=Subject",
-            msg_ref.id
-        );
-        
-        let msg_resp = client
-            .get(&msg_url)
-            .bearer_auth(access_token)
-            .send()
-            .await;
-
-        if let Ok(resp) = msg_resp {
-            if let Ok(msg) = resp.json::<GmailMessage>().await {
-                let mut sender = String::new();
-                let mut subject = String::new();
-                
-                if let Some(payload) = msg.payload {
-                    if let Some(headers) = payload.headers {
-                        for header in headers {
-                            match header.name.as_str() {
-                                "From" => sender = header.value,
-                                "Subject" => subject = header.value,
-                                _ => {}
-                            }
-                        }
-                    }
+/// Gmail API backend. The sync cursor is the mailbox historyId.
+pub struct GmailProvider {
+    client: reqwest::Client,
+    sealed_access_token: SealedSecret,
+    sealed_refresh_token: Option<SealedSecret>,
+    access_token: RwLock<Option<String>>, // Decrypted on connect
+}
+
+impl GmailProvider {
+    pub fn new(access_token: SealedSecret, refresh_token: Option<SealedSecret>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            sealed_access_token: access_token,
+            sealed_refresh_token: refresh_token,
+            access_token: RwLock::new(None),
+        }
+    }
+
+    fn token(&self) -> String {
+        self.access_token.read().unwrap().clone().unwrap_or_default()
+    }
+
+    /// Latest `limit` inbox messages, used on first sync or when history has expired
+    async fn list_latest(&self, limit: usize) -> Result<Vec<MessageRef>, ProviderError> {
+        let list_url = format!("{}/messages?labelIds=INBOX&maxResults={}", GMAIL_BASE, limit);
+
+        let message_list: GmailMessageList = get_with_retry(&self.client, &list_url, &self.token())
+            .await
+            .map_err(|e| e.listing("Failed to list messages"))?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse message list: {}", e))?;
+
+        Ok(message_list.messages.unwrap_or_default()
+            .into_iter()
+            .map(|m| MessageRef { id: m.id, received_at: None, cursor: None })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl MailProvider for GmailProvider {
+    fn name(&self) -> &'static str {
+        "google"
+    }
+
+    fn max_concurrency(&self) -> usize {
+        MAX_CONCURRENT_FETCHES
+    }
+
+    async fn connect(&self) -> Result<(), String> {
+        let mut token = self.access_token.write().unwrap();
+        if token.is_none() {
+            *token = Some(self.sealed_access_token.open()?);
+        }
+        Ok(())
+    }
+
+    async fn list_new_messages(&self, cursor: Option<&str>, limit: usize) -> Result<MessageList, ProviderError> {
+        let token = self.token();
+
+        let Some(start_history_id) = cursor else {
+            // First sync: remember where history starts, then take the latest messages
+            let profile: GmailProfile = get_with_retry(&self.client, &format!("{}/profile", GMAIL_BASE), &token)
+                .await
+                .map_err(|e| e.listing("Failed to load profile"))?
+                .json()
+                .await
+                .map_err(|e| format!("Failed to parse profile: {}", e))?;
+
+            return Ok(MessageList {
+                messages: self.list_latest(limit).await?,
+                cursor: Some(profile.history_id),
+            });
+        };
+
+        // Incremental sync: walk history pages for messages added to INBOX
+        let mut records: Vec<GmailHistory> = Vec::new();
+        let mut page_token: Option<String> = None;
+        let latest_history_id = loop {
+            let mut url = format!(
+                "{}/history?startHistoryId={}&historyTypes=messageAdded&labelId=INBOX",
+                GMAIL_BASE, start_history_id
+            );
+            if let Some(page) = &page_token {
+                url.push_str(&format!("&pageToken={}", page));
+            }
+
+            let history: GmailHistoryList = match get_with_retry(&self.client, &url, &token).await {
+                Ok(resp) => resp.json().await.map_err(|e| format!("Failed to parse history: {}", e))?,
+                // Gmail only keeps about a week of history; start over from the latest messages
+                Err(e) if e.status == Some(reqwest::StatusCode::NOT_FOUND) => {
+                    return self.list_new_messages(None, limit).await;
                 }
-                
-                let internal_date = msg.internal_date
-                    .and_then(|d| d.parse::<i64>().ok())
-                    .map(|ms| ms / 1000)
-                    .unwrap_or_else(|| chrono::Utc::now().timestamp());
-
-                emails.push(FetchedEmail {
-                    message_id: msg.id,
-                    sender,
-                    subject,
-                    body_preview: msg.snippet.unwrap_or_default(),
-                    received_at: internal_date,
-                });
+                Err(e) => return Err(e.listing("Failed to list history")),
+            };
+
+            records.extend(history.history.unwrap_or_default());
+
+            match history.next_page_token {
+                Some(next) => page_token = Some(next),
+                None => break history.history_id,
             }
+        };
+
+        Ok(added_messages(start_history_id, &latest_history_id, records, limit))
+    }
+
+    async fn fetch_raw(&self, message: &MessageRef) -> Result<RawMessage, ProviderError> {
+        let msg_url = format!("{}/messages/{}?format=raw", GMAIL_BASE, message.id);
+
+        let msg: GmailMessage = get_with_retry(&self.client, &msg_url, &self.token())
+            .await
+            .map_err(ApiError::fetching)?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse message: {}", e))?;
+
+        let data = msg.raw
+            .ok_or_else(|| "Message has no raw content".to_string())
+            .and_then(|raw| BASE64_URL.decode(raw.trim()).map_err(|e| format!("Invalid base64url: {}", e)))
+            .map_err(ProviderError::Unreadable)?;
+
+        Ok(RawMessage {
+            data,
+            received_at: msg.internal_date
+                .and_then(|d| d.parse::<i64>().ok())
+                .map(|ms| ms / 1000),
+        })
+    }
+
+    async fn refresh_credentials(&self) -> Result<Option<oauth::OAuthTokens>, String> {
+        let Some(sealed) = &self.sealed_refresh_token else {
+            return Ok(None);
+        };
+
+        let tokens = oauth::google_refresh_token(&sealed.open()?).await?;
+        *self.access_token.write().unwrap() = Some(tokens.access_token.clone());
+        Ok(Some(tokens))
+    }
+}
+
+/// Messages added in `records` (oldest first, as history lists them), up to `limit` of them.
+/// A record is never split: each message resumes after the record once its last message is
+/// stored. When every added message fits, the cursor is the mailbox's `latest_history_id`.
+fn added_messages(start_history_id: &str, latest_history_id: &str, records: Vec<GmailHistory>, limit: usize) -> MessageList {
+    let mut messages: Vec<MessageRef> = Vec::new();
+    let mut resume = start_history_id.to_string();
+    let mut records = records.into_iter().peekable();
+
+    while messages.len() < limit {
+        let Some(record) = records.next() else { break };
+        let mut added: Vec<String> = record.messages_added.unwrap_or_default().into_iter().map(|a| a.message.id).collect();
+        added.retain(|id| !messages.iter().any(|m| m.id == *id));
+
+        let last = added.len().saturating_sub(1);
+        for (i, id) in added.into_iter().enumerate() {
+            let cursor = if i == last { record.id.clone() } else { resume.clone() };
+            messages.push(MessageRef { id, received_at: None, cursor: Some(cursor) });
+        }
+        resume = record.id;
+    }
+
+    let cursor = match records.peek() {
+        Some(_) => resume,
+        None => latest_history_id.to_string(),
+    };
+    messages.reverse();
+    MessageList { messages, cursor: Some(cursor) }
+}
+
+/// A Gmail API request that failed; `status` is None when no response came back
+#[derive(Debug)]
+struct ApiError {
+    status: Option<reqwest::StatusCode>,
+    message: String,
+}
+
+impl ApiError {
+    /// As the error of a listing, `context` saying what failed
+    fn listing(self, context: &str) -> ProviderError {
+        let message = format!("{}: {}", context, self);
+        match self.status {
+            Some(reqwest::StatusCode::UNAUTHORIZED) => ProviderError::Unauthorized(message),
+            _ => ProviderError::Failed(message),
+        }
+    }
+
+    /// As the error of fetching one message: one that is gone stays gone
+    fn fetching(self) -> ProviderError {
+        let message = self.to_string();
+        match self.status {
+            Some(reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE) => ProviderError::Unreadable(message),
+            Some(reqwest::StatusCode::UNAUTHORIZED) => ProviderError::Unauthorized(message),
+            _ => ProviderError::Failed(message),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "Gmail API error ({}): {}", status, self.message),
+            None => write!(f, "Request failed: {}", self.message),
         }
     }
+}
+
+/// GET a Gmail API URL, backing off on 429 / rateLimitExceeded and transient 5xx errors
+async fn get_with_retry(client: &reqwest::Client, url: &str, access_token: &str) -> Result<reqwest::Response, ApiError> {
+    let mut attempt = 0;
+
+    loop {
+        let resp = client
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(|e| ApiError { status: None, message: e.to_string() })?;
+
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        let error_text = resp.text().await.unwrap_or_default();
+
+        // Gmail reports quota errors as 429, or as 403 with a rateLimitExceeded reason
+        let rate_limited = status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            || (status == reqwest::StatusCode::FORBIDDEN
+                && (error_text.contains("rateLimitExceeded") || error_text.contains("userRateLimitExceeded")));
+        let retryable = rate_limited || status.is_server_error();
+
+        if !retryable || attempt >= MAX_RETRIES {
+            return Err(ApiError { status: Some(status), message: error_text });
+        }
 
-    Ok(emails)
+        // Honor Retry-After when present, otherwise exponential backoff: 1s, 2s, 4s, ...
+        let delay = retry_after.unwrap_or(1 << attempt).min(MAX_BACKOFF_SECS);
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        attempt += 1;
+    }
 }
 
-/// Fetch latest email from Gmail API
-pub async fn fetch_gmail_latest(access_token: &str) -> Result<Option<FetchedEmail>, String> {
-    let emails = fetch_gmail_emails(access_token, 1).await?;
-    Ok(emails.into_iter().next())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(records: serde_json::Value) -> Vec<GmailHistory> {
+        serde_json::from_value(records).unwrap()
+    }
+
+    fn listed(list: &MessageList) -> Vec<(&str, Option<&str>)> {
+        list.messages.iter().map(|m| (m.id.as_str(), m.cursor.as_deref())).collect()
+    }
+
+    #[test]
+    fn history_is_taken_oldest_first_up_to_the_limit() {
+        let records = history(serde_json::json!([
+            { "id": "11", "messagesAdded": [{ "message": { "id": "a" } }] },
+            { "id": "12", "messagesAdded": [{ "message": { "id": "b" } }, { "message": { "id": "c" } }] },
+            { "id": "13" },
+            { "id": "14", "messagesAdded": [{ "message": { "id": "d" } }] },
+        ]));
+
+        // Record 12 is taken whole, and the cursor resumes after it rather than at the latest history
+        let list = added_messages("10", "20", records, 2);
+        assert_eq!(listed(&list), vec![("c", Some("12")), ("b", Some("11")), ("a", Some("11"))]);
+        assert_eq!(list.cursor.as_deref(), Some("12"));
+    }
+
+    #[test]
+    fn the_latest_history_id_is_kept_once_everything_fits() {
+        let records = history(serde_json::json!([
+            { "id": "11", "messagesAdded": [{ "message": { "id": "a" } }] },
+            { "id": "12", "messagesAdded": [{ "message": { "id": "a" } }] },
+        ]));
+
+        let list = added_messages("10", "20", records, 25);
+        assert_eq!(listed(&list), vec![("a", Some("11"))]);
+        assert_eq!(list.cursor.as_deref(), Some("20"));
+    }
 }
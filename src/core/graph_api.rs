@@ -0,0 +1,305 @@
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+use async_trait::async_trait;
+use crate::core::crypto::SealedSecret;
+use crate::core::oauth;
+use crate::core::provider::{MailProvider, MessageList, MessageRef, ProviderError, RawMessage};
+
+const GRAPH_BASE: &str = "https://graph.microsoft.com/v1.0";
+/// How many MIME downloads run against Graph at once
+const MAX_CONCURRENT_FETCHES: usize = 4;
+/// Retries per request on throttling (429/503)
+const MAX_RETRIES: u32 = 4;
+const MAX_BACKOFF_SECS: u64 = 32;
+/// How far back the first delta sync of an account looks
+const INITIAL_SYNC_DAYS: i64 = 7;
+
+#[derive(Debug, Deserialize)]
+struct DeltaPage {
+    value: Vec<GraphMessageRef>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+    #[serde(rename = "@odata.deltaLink")]
+    delta_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphMessageRef {
+    id: String,
+    received_date_time: Option<String>,
+    #[serde(rename = "@removed")]
+    removed: Option<serde_json::Value>,
+}
+
+/// Microsoft Graph backend. The sync cursor is the inbox deltaLink.
+pub struct GraphProvider {
+    client: reqwest::Client,
+    api_base: String, // GRAPH_API_URL, for pointing at a mock in tests
+    sealed_access_token: SealedSecret,
+    sealed_refresh_token: Option<SealedSecret>,
+    access_token: RwLock<Option<String>>, // Decrypted on connect
+}
+
+impl GraphProvider {
+    pub fn new(access_token: SealedSecret, refresh_token: Option<SealedSecret>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_base: env::var("GRAPH_API_URL")
+                .unwrap_or_else(|_| GRAPH_BASE.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            sealed_access_token: access_token,
+            sealed_refresh_token: refresh_token,
+            access_token: RwLock::new(None),
+        }
+    }
+
+    fn token(&self) -> String {
+        self.access_token.read().unwrap().clone().unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl MailProvider for GraphProvider {
+    fn name(&self) -> &'static str {
+        "microsoft"
+    }
+
+    fn max_concurrency(&self) -> usize {
+        MAX_CONCURRENT_FETCHES
+    }
+
+    async fn connect(&self) -> Result<(), String> {
+        let mut token = self.access_token.write().unwrap();
+        if token.is_none() {
+            *token = Some(self.sealed_access_token.open()?);
+        }
+        Ok(())
+    }
+
+    /// Walk the delta pages until Graph hands back a new deltaLink, or until `limit` messages
+    /// are in: then the nextLink of the last page read is the cursor. Pages are never split,
+    /// and a message resumes at its own page until the last message on it is stored.
+    async fn list_new_messages(&self, cursor: Option<&str>, limit: usize) -> Result<MessageList, ProviderError> {
+        let token = self.token();
+        let mut url = match cursor {
+            Some(link) => link.to_string(),
+            None => {
+                let since = (chrono::Utc::now() - chrono::Duration::days(INITIAL_SYNC_DAYS))
+                    .format("%Y-%m-%dT%H:%M:%SZ");
+                format!(
+                    "{}/me/mailFolders/inbox/messages/delta?$select=id,receivedDateTime&$filter=receivedDateTime+ge+{}",
+                    self.api_base, since
+                )
+            }
+        };
+
+        let mut refs: Vec<MessageRef> = Vec::new();
+        let next_cursor = loop {
+            let page: DeltaPage = match get_with_retry(&self.client, &url, &token).await {
+                Ok(resp) => resp.json().await.map_err(|e| format!("Failed to parse delta page: {}", e))?,
+                // The delta token expired (syncStateNotFound): start over with an initial delta query
+                Err(e) if e.status == Some(reqwest::StatusCode::GONE) && cursor.is_some() => {
+                    return self.list_new_messages(None, limit).await;
+                }
+                Err(e) => return Err(e.listing("Failed to list messages")),
+            };
+
+            let after_page = page.next_link.clone().or_else(|| page.delta_link.clone());
+            refs.extend(page_messages(&url, page.value, after_page));
+
+            match (page.next_link, page.delta_link) {
+                // Enough for this round: the rest of the backlog comes next time
+                (Some(next), _) if refs.len() >= limit => break Some(next),
+                (Some(next), _) => url = next,
+                (None, delta) => break delta,
+            }
+        };
+
+        // Delta order, latest last
+        refs.reverse();
+        Ok(MessageList { messages: refs, cursor: next_cursor })
+    }
+
+    /// Download a message as MIME ($value)
+    async fn fetch_raw(&self, message: &MessageRef) -> Result<RawMessage, ProviderError> {
+        let url = format!("{}/me/messages/{}/$value", self.api_base, message.id);
+
+        let data = get_with_retry(&self.client, &url, &self.token())
+            .await
+            .map_err(ApiError::fetching)?
+            .bytes()
+            .await
+            .map_err(|e| format!("Failed to read message: {}", e))?;
+
+        Ok(RawMessage { data: data.to_vec(), received_at: None })
+    }
+
+    async fn refresh_credentials(&self) -> Result<Option<oauth::OAuthTokens>, String> {
+        let Some(sealed) = &self.sealed_refresh_token else {
+            return Ok(None);
+        };
+
+        let tokens = oauth::microsoft_refresh_token(&sealed.open()?).await?;
+        *self.access_token.write().unwrap() = Some(tokens.access_token.clone());
+        Ok(Some(tokens))
+    }
+}
+
+/// Messages added on the delta page at `page_url`. Each resumes at that page, except the last,
+/// which resumes at `after_page`: the page is done once it is stored.
+fn page_messages(page_url: &str, value: Vec<GraphMessageRef>, after_page: Option<String>) -> Vec<MessageRef> {
+    let added: Vec<GraphMessageRef> = value.into_iter().filter(|m| m.removed.is_none()).collect();
+    let last = added.len().saturating_sub(1);
+
+    added
+        .into_iter()
+        .enumerate()
+        .map(|(i, m)| MessageRef {
+            received_at: m.received_date_time
+                .as_deref()
+                .and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok())
+                .map(|d| d.timestamp()),
+            id: m.id,
+            cursor: if i == last { after_page.clone() } else { Some(page_url.to_string()) },
+        })
+        .collect()
+}
+
+/// A Graph request that failed; `status` is None when no response came back
+#[derive(Debug)]
+struct ApiError {
+    status: Option<reqwest::StatusCode>,
+    message: String,
+}
+
+impl ApiError {
+    /// As the error of a listing, `context` saying what failed
+    fn listing(self, context: &str) -> ProviderError {
+        let message = format!("{}: {}", context, self);
+        match self.status {
+            Some(reqwest::StatusCode::UNAUTHORIZED) => ProviderError::Unauthorized(message),
+            _ => ProviderError::Failed(message),
+        }
+    }
+
+    /// As the error of fetching one message: one that is gone stays gone
+    fn fetching(self) -> ProviderError {
+        let message = self.to_string();
+        match self.status {
+            Some(reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE) => ProviderError::Unreadable(message),
+            Some(reqwest::StatusCode::UNAUTHORIZED) => ProviderError::Unauthorized(message),
+            _ => ProviderError::Failed(message),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "Graph API error ({}): {}", status, self.message),
+            None => write!(f, "Request failed: {}", self.message),
+        }
+    }
+}
+
+/// GET a Graph URL, honoring Retry-After on throttling responses
+async fn get_with_retry(client: &reqwest::Client, url: &str, access_token: &str) -> Result<reqwest::Response, ApiError> {
+    let mut attempt = 0;
+
+    loop {
+        let resp = client
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(|e| ApiError { status: None, message: e.to_string() })?;
+
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+
+        let retry_after = resp
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        let error_text = resp.text().await.unwrap_or_default();
+
+        let retryable = status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+        if !retryable || attempt >= MAX_RETRIES {
+            return Err(ApiError { status: Some(status), message: error_text });
+        }
+
+        let delay = retry_after.unwrap_or(1 << attempt).min(MAX_BACKOFF_SECS);
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    /// `GET /me/mailFolders/inbox/messages/delta`: one message, then a fresh deltaLink
+    async fn mock_initial_delta(req: HttpRequest) -> HttpResponse {
+        let base = format!("http://{}", req.connection_info().host());
+        HttpResponse::Ok().json(serde_json::json!({
+            "value": [{ "id": "a", "receivedDateTime": "2025-10-06T10:00:00Z" }],
+            "@odata.deltaLink": format!("{}/delta?$deltatoken=fresh", base),
+        }))
+    }
+
+    /// A deltaLink whose token Graph no longer knows
+    async fn mock_expired_delta() -> HttpResponse {
+        HttpResponse::Gone().json(serde_json::json!({ "error": { "code": "syncStateNotFound" } }))
+    }
+
+    #[test]
+    fn a_page_is_done_only_with_its_last_message() {
+        let value: Vec<GraphMessageRef> = serde_json::from_value(serde_json::json!([
+            { "id": "a", "receivedDateTime": "2025-10-06T10:00:00Z" },
+            { "id": "gone", "@removed": { "reason": "deleted" } },
+            { "id": "b" },
+        ]))
+        .unwrap();
+
+        let messages = page_messages("https://graph/page1", value, Some("https://graph/page2".to_string()));
+        let listed: Vec<_> = messages.iter().map(|m| (m.id.as_str(), m.cursor.as_deref())).collect();
+        assert_eq!(listed, vec![("a", Some("https://graph/page1")), ("b", Some("https://graph/page2"))]);
+        assert_eq!(messages[0].received_at, Some(1759744800));
+    }
+
+    #[actix_web::test]
+    async fn an_expired_delta_link_starts_over() {
+        let server = HttpServer::new(|| {
+            App::new()
+                .route("/me/mailFolders/inbox/messages/delta", web::get().to(mock_initial_delta))
+                .route("/expired", web::get().to(mock_expired_delta))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let base = format!("http://{}", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let keys = std::sync::Arc::new(crate::core::crypto::KeyRing::parse("k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=", None).unwrap());
+        let provider = GraphProvider {
+            client: reqwest::Client::new(),
+            api_base: base.clone(),
+            sealed_access_token: SealedSecret::new(keys, "token".to_string(), "ctx".to_string()),
+            sealed_refresh_token: None,
+            access_token: RwLock::new(Some("token".to_string())),
+        };
+
+        let list = provider.list_new_messages(Some(&format!("{}/expired", base)), 25).await.unwrap();
+        assert_eq!(list.messages.iter().map(|m| m.id.as_str()).collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(list.cursor, Some(format!("{}/delta?$deltatoken=fresh", base)));
+    }
+}
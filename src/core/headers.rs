@@ -0,0 +1,453 @@
+//! Structured views of message headers for deliverability checks: the Received chain
+//! with hop delays, Authentication-Results (RFC 8601), List-Unsubscribe (RFC 2369, 8058)
+//! and X- headers, plus a report of what looks wrong.
+
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use crate::core::ingest::AUTHSERV_ID;
+
+/// Hops slower than this are called out in the report
+const SLOW_HOP_SECS: i64 = 300;
+
+/// A header, or a property of an authentication result
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+}
+
+impl Field {
+    pub fn new(name: &str, value: &str) -> Self {
+        Field { name: name.to_string(), value: value.to_string() }
+    }
+}
+
+/// One Received header, oldest hop first in a chain
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Hop {
+    pub from: Option<String>,
+    pub by: Option<String>,
+    pub with: Option<String>,
+    pub received_at: Option<DateTime<FixedOffset>>,
+    pub delay_secs: Option<i64>, // Since the previous hop, or the Date header for the first
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuthResult {
+    pub method: String, // spf, dkim, dmarc, arc, ...
+    pub result: String, // pass, fail, softfail, neutral, none, temperror, permerror, ...
+    pub reason: Option<String>,
+    pub properties: Vec<Field>, // smtp.mailfrom, header.d, header.s, ...
+}
+
+/// One Authentication-Results header
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuthenticationResults {
+    pub authserv_id: String, // Who checked
+    pub results: Vec<AuthResult>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ListUnsubscribe {
+    pub mailto: Vec<String>,
+    pub http: Vec<String>,
+    pub one_click: bool, // List-Unsubscribe-Post: List-Unsubscribe=One-Click
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HeaderAnalysis {
+    pub received: Vec<Hop>,
+    pub total_delay_secs: Option<i64>, // From the Date header (or first hop) to the last hop
+    pub authentication_results: Vec<AuthenticationResults>, // Newest (added last) first
+    pub list_unsubscribe: Option<ListUnsubscribe>,
+    pub x_headers: Vec<Field>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliverabilityReport {
+    pub spf: String,   // Our own result on arrival, "none" if we didn't check
+    pub dkim: String,  // "pass" if any signature passed
+    pub dmarc: String,
+    pub hops: usize,
+    pub total_delay_secs: Option<i64>,
+    pub list_unsubscribe: bool,
+    pub one_click_unsubscribe: bool,
+    pub warnings: Vec<String>,
+}
+
+/// Values of every header called `name`, top to bottom
+fn values<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> {
+    headers.iter().filter(move |(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+/// `text` without (comments), which may nest; `\(` and `\)` inside them don't count
+fn strip_comments(text: &str) -> String {
+    let mut depth = 0;
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if depth > 0 => {
+                chars.next();
+            }
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            c if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+fn parse_date(text: &str) -> Option<DateTime<FixedOffset>> {
+    let text = strip_comments(text);
+    DateTime::parse_from_rfc2822(text.split_whitespace().collect::<Vec<_>>().join(" ").as_str()).ok()
+}
+
+/// "from a.example (...) by b.example with ESMTPS id 123; Tue, 2 Jan 2024 10:00:00 +0000"
+pub fn parse_received(value: &str) -> Hop {
+    let (clauses, date) = match value.rsplit_once(';') {
+        Some((clauses, date)) => (clauses, parse_date(date)),
+        None => (value, None),
+    };
+    let clauses = strip_comments(clauses);
+    let words: Vec<&str> = clauses.split_whitespace().collect();
+    let after = |keyword: &str| {
+        words.iter()
+            .position(|w| w.eq_ignore_ascii_case(keyword))
+            .and_then(|i| words.get(i + 1))
+            .map(|w| w.to_string())
+    };
+    Hop { from: after("from"), by: after("by"), with: after("with"), received_at: date, delay_secs: None }
+}
+
+/// Split on `separator` outside double quotes, keeping the quotes (and escapes within them)
+fn split_unquoted(text: &str, separator: impl Fn(char) -> bool) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if quoted && c == '\\' {
+            current.push(c);
+            current.extend(chars.next());
+            continue;
+        }
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if !quoted && separator(c) {
+            parts.push(std::mem::take(&mut current));
+        } else {
+            current.push(c);
+        }
+    }
+    parts.push(current);
+    parts.into_iter().map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect()
+}
+
+/// "mx.example; spf=pass smtp.mailfrom=a.example; dkim=fail reason=\"bad sig\" header.d=a.example"
+pub fn parse_authentication_results(value: &str) -> AuthenticationResults {
+    let mut parts = split_unquoted(&strip_comments(value), |c| c == ';').into_iter();
+    let authserv_id = parts.next().and_then(|p| p.split_whitespace().next().map(str::to_string)).unwrap_or_default();
+
+    let results = parts
+        .filter_map(|part| {
+            let mut tokens = split_unquoted(&part, char::is_whitespace).into_iter();
+            let (method, result) = tokens.next()?.split_once('=').map(|(m, r)| (m.to_string(), r.to_string()))?;
+            let mut auth = AuthResult {
+                method: method.split('/').next().unwrap_or_default().to_ascii_lowercase(),
+                result: result.to_ascii_lowercase(),
+                reason: None,
+                properties: Vec::new(),
+            };
+            for token in tokens {
+                let Some((key, value)) = token.split_once('=') else { continue };
+                let value = unquote(value);
+                if key.eq_ignore_ascii_case("reason") {
+                    auth.reason = Some(value);
+                } else {
+                    auth.properties.push(Field::new(&key.to_ascii_lowercase(), &value));
+                }
+            }
+            Some(auth)
+        })
+        .collect();
+    AuthenticationResults { authserv_id, results }
+}
+
+/// A quoted-string's content with its escapes undone; anything else as is
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        out.extend(if c == '\\' { chars.next() } else { Some(c) });
+    }
+    out
+}
+
+/// `text` as a header (comment): parentheses and backslashes escaped, line breaks and other controls dropped
+pub fn comment(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('(');
+    for c in text.chars().filter(|c| !c.is_control()) {
+        if matches!(c, '(' | ')' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push(')');
+    out
+}
+
+/// A property value of an authentication result: as is when it is a plain
+/// address or word, otherwise a quoted-string, so it can't end the result or add one
+pub fn property_value(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.@".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        return value.to_string();
+    }
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars().filter(|c| !c.is_control()) {
+        if matches!(c, '"' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+/// An Authentication-Results value for what `authserv_id` checked, one "method=result ..." clause per check
+pub fn authentication_results(authserv_id: &str, clauses: &[String]) -> String {
+    if clauses.is_empty() {
+        return format!("{}; none", authserv_id);
+    }
+    format!("{}; {}", authserv_id, clauses.join("; "))
+}
+
+/// "<mailto:unsub@a.example?subject=unsubscribe>, <https://a.example/u/123>"
+pub fn parse_list_unsubscribe(value: &str, post: Option<&str>) -> ListUnsubscribe {
+    let mut list = ListUnsubscribe {
+        one_click: post.is_some_and(|p| p.trim().eq_ignore_ascii_case("List-Unsubscribe=One-Click")),
+        ..Default::default()
+    };
+    for uri in value.split('<').skip(1).filter_map(|part| part.split_once('>')).map(|(uri, _)| uri.trim()) {
+        if uri.get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("mailto:")) {
+            list.mailto.push(uri.to_string());
+        } else if uri.starts_with("http://") || uri.starts_with("https://") {
+            list.http.push(uri.to_string());
+        }
+    }
+    list
+}
+
+pub fn analyze(headers: &[(String, String)]) -> HeaderAnalysis {
+    // Each relay adds its Received header on top: the bottom one is the first hop
+    let mut received: Vec<Hop> = values(headers, "Received").map(parse_received).collect();
+    received.reverse();
+
+    let sent_at = values(headers, "Date").next().and_then(parse_date);
+    let mut previous = sent_at;
+    for hop in &mut received {
+        if let (Some(before), Some(at)) = (previous, hop.received_at) {
+            hop.delay_secs = Some((at - before).num_seconds());
+        }
+        previous = hop.received_at.or(previous);
+    }
+    let first = sent_at.or_else(|| received.iter().find_map(|h| h.received_at));
+    let last = received.iter().rev().find_map(|h| h.received_at);
+    let total_delay_secs = first.zip(last).map(|(first, last)| (last - first).num_seconds());
+
+    let list_unsubscribe = values(headers, "List-Unsubscribe")
+        .next()
+        .map(|value| parse_list_unsubscribe(value, values(headers, "List-Unsubscribe-Post").next()));
+
+    HeaderAnalysis {
+        received,
+        total_delay_secs,
+        authentication_results: values(headers, "Authentication-Results").map(parse_authentication_results).collect(),
+        list_unsubscribe,
+        x_headers: headers.iter()
+            .filter(|(name, _)| name.get(..2).is_some_and(|p| p.eq_ignore_ascii_case("x-")))
+            .map(|(name, value)| Field::new(name, value))
+            .collect(),
+    }
+}
+
+/// The verdict of the newest of our own Authentication-Results that checked `method`, with its reason.
+/// Results under any other authserv-id came with the message and prove nothing.
+fn verdict<'a>(analysis: &'a HeaderAnalysis, method: &str) -> Option<&'a AuthResult> {
+    let results = analysis.authentication_results.iter()
+        .filter(|ar| ar.authserv_id.eq_ignore_ascii_case(AUTHSERV_ID))
+        .map(|ar| ar.results.iter().filter(|r| r.method == method).collect::<Vec<_>>())
+        .find(|results| !results.is_empty())?;
+    // Several DKIM signatures: one that passes is enough
+    results.iter().find(|r| r.result == "pass").or(results.first()).copied()
+}
+
+pub fn report(headers: &[(String, String)]) -> DeliverabilityReport {
+    let analysis = analyze(headers);
+    let mut warnings = Vec::new();
+
+    if !analysis.authentication_results.iter().any(|ar| ar.authserv_id.eq_ignore_ascii_case(AUTHSERV_ID)) {
+        warnings.push("Not checked on arrival: SPF, DKIM and DMARC results are unknown".to_string());
+    }
+    let mut result_of = |method: &str, name: &str| {
+        let Some(result) = verdict(&analysis, method) else { return "none".to_string() };
+        if result.result != "pass" {
+            let reason = result.reason.as_ref().map(|r| format!(" ({})", r)).unwrap_or_default();
+            warnings.push(format!("{} {}{}", name, result.result, reason));
+        }
+        result.result.clone()
+    };
+    let (spf, dkim, dmarc) = (result_of("spf", "SPF"), result_of("dkim", "DKIM"), result_of("dmarc", "DMARC"));
+
+    for (i, hop) in analysis.received.iter().enumerate() {
+        let by = hop.by.as_deref().unwrap_or("unknown host");
+        match hop.delay_secs {
+            Some(delay) if delay > SLOW_HOP_SECS => warnings.push(format!("Hop {} ({}) took {}s", i + 1, by, delay)),
+            Some(delay) if delay < 0 => {
+                warnings.push(format!("Hop {} ({}) is timestamped {}s before the previous one: clock skew", i + 1, by, -delay))
+            }
+            _ => {}
+        }
+    }
+
+    if values(headers, "Message-ID").next().is_none() {
+        warnings.push("No Message-ID header".to_string());
+    }
+    if values(headers, "Date").next().is_none() {
+        warnings.push("No Date header".to_string());
+    }
+    let bulk = values(headers, "List-Id").next().is_some()
+        || values(headers, "Precedence").any(|p| matches!(p.trim().to_ascii_lowercase().as_str(), "bulk" | "list"));
+    match &analysis.list_unsubscribe {
+        None if bulk => warnings.push("Bulk mail without a List-Unsubscribe header".to_string()),
+        Some(list) if !list.one_click => {
+            warnings.push("List-Unsubscribe without one-click unsubscribe (List-Unsubscribe-Post, RFC 8058)".to_string())
+        }
+        _ => {}
+    }
+
+    DeliverabilityReport {
+        spf,
+        dkim,
+        dmarc,
+        hops: analysis.received.len(),
+        total_delay_secs: analysis.total_delay_secs,
+        list_unsubscribe: analysis.list_unsubscribe.is_some(),
+        one_click_unsubscribe: analysis.list_unsubscribe.as_ref().is_some_and(|l| l.one_click),
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn received_chain_runs_oldest_first_with_delays() {
+        let headers = headers(&[
+            ("Received", "from relay.example (relay.example [192.0.2.2]) by mx.mailpulse.net with ESMTPS id 9; Tue, 2 Jan 2024 10:07:00 +0000"),
+            ("Received", "from laptop (unknown [198.51.100.7]) by relay.example (Postfix) with ESMTPSA id 1; Tue, 2 Jan 2024 11:00:30 +0100 (CET)"),
+            ("Date", "Tue, 2 Jan 2024 10:00:00 +0000"),
+        ]);
+        let analysis = analyze(&headers);
+
+        assert_eq!(analysis.received.len(), 2);
+        assert_eq!(analysis.received[0].from.as_deref(), Some("laptop"));
+        assert_eq!(analysis.received[0].by.as_deref(), Some("relay.example"));
+        assert_eq!(analysis.received[0].with.as_deref(), Some("ESMTPSA"));
+        assert_eq!(analysis.received[0].delay_secs, Some(30));
+        assert_eq!(analysis.received[1].delay_secs, Some(390));
+        assert_eq!(analysis.total_delay_secs, Some(420));
+    }
+
+    #[test]
+    fn authentication_results_are_split_into_methods() {
+        let results = parse_authentication_results(
+            "mx.google.com; dkim=fail reason=\"signature; verification failed\" header.i=@a.example header.s=s1; \
+             spf=pass (google.com: domain of b@a.example designates 192.0.2.1 as permitted sender) smtp.mailfrom=b@a.example; \
+             dmarc=FAIL (p=REJECT) header.from=a.example",
+        );
+        assert_eq!(results.authserv_id, "mx.google.com");
+        assert_eq!(results.results.len(), 3);
+        assert_eq!(results.results[0].method, "dkim");
+        assert_eq!(results.results[0].reason.as_deref(), Some("signature; verification failed"));
+        assert_eq!(results.results[0].properties[1], Field::new("header.s", "s1"));
+        assert_eq!(results.results[1].properties, vec![Field::new("smtp.mailfrom", "b@a.example")]);
+        assert_eq!(results.results[2].result, "fail");
+
+        assert!(parse_authentication_results("mx.example; none").results.is_empty());
+
+        let ours = authentication_results("mx.example", &["spf=pass (ok) smtp.mailfrom=b@a.example".to_string(), "dkim=fail".to_string()]);
+        let parsed = parse_authentication_results(&ours);
+        assert_eq!(parsed.authserv_id, "mx.example");
+        assert_eq!(parsed.results.iter().map(|r| r.method.as_str()).collect::<Vec<_>>(), vec!["spf", "dkim"]);
+        assert_eq!(authentication_results("mx.example", &[]), "mx.example; none");
+    }
+
+    #[test]
+    fn values_we_write_cannot_forge_results() {
+        let clause = format!(
+            "spf=fail {} smtp.mailfrom={}",
+            comment("bad (nested) \\ reason)\r\nX-Evil: 1"),
+            property_value("x@y; dmarc=pass"),
+        );
+        assert_eq!(clause, "spf=fail (bad \\(nested\\) \\\\ reason\\)X-Evil: 1) smtp.mailfrom=\"x@y; dmarc=pass\"");
+        assert_eq!(property_value("b@a.example"), "b@a.example");
+        assert_eq!(property_value("say \"hi\""), "\"say \\\"hi\\\"\"");
+
+        let parsed = parse_authentication_results(&authentication_results("mx.example", &[clause]));
+        assert_eq!(parsed.results.len(), 1);
+        assert_eq!(parsed.results[0].method, "spf");
+        assert_eq!(parsed.results[0].properties, vec![Field::new("smtp.mailfrom", "x@y; dmarc=pass")]);
+    }
+
+    #[test]
+    fn list_unsubscribe_targets_are_sorted_out() {
+        let list = parse_list_unsubscribe(
+            "<mailto:u@a.example?subject=unsubscribe>, <https://a.example/u/1>",
+            Some("List-Unsubscribe=One-Click"),
+        );
+        assert_eq!(list.mailto, vec!["mailto:u@a.example?subject=unsubscribe"]);
+        assert_eq!(list.http, vec!["https://a.example/u/1"]);
+        assert!(list.one_click);
+    }
+
+    #[test]
+    fn report_flags_what_would_hurt_delivery() {
+        let report = report(&headers(&[
+            ("Authentication-Results", "mailpulse.net; spf=softfail smtp.mailfrom=a.example; dkim=fail; dkim=pass header.d=a.example"),
+            ("Authentication-Results", "relay.example; spf=pass; dmarc=pass"),
+            ("List-Id", "News <news.a.example>"),
+            ("X-Mailer", "Campaigns 2.0"),
+        ]));
+
+        assert_eq!(report.spf, "softfail");
+        assert_eq!(report.dkim, "pass");
+        assert_eq!(report.dmarc, "none"); // relay.example's word for it counts for nothing
+        assert!(!report.list_unsubscribe);
+        assert_eq!(report.warnings, vec![
+            "SPF softfail",
+            "No Message-ID header",
+            "No Date header",
+            "Bulk mail without a List-Unsubscribe header",
+        ]);
+        assert_eq!(analyze(&headers(&[("x-mailer", "a")])).x_headers.len(), 1);
+
+        // Results that came with the message, however good they look, mean it wasn't checked here
+        let forwarded = super::report(&headers(&[("Authentication-Results", "mx.example; spf=pass; dkim=pass; dmarc=pass")]));
+        assert_eq!((forwarded.spf.as_str(), forwarded.dkim.as_str(), forwarded.dmarc.as_str()), ("none", "none", "none"));
+        assert!(forwarded.warnings[0].starts_with("Not checked on arrival"));
+    }
+}
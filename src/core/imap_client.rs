@@ -1,131 +1,167 @@
 use async_std::net::TcpStream;
 use async_native_tls::TlsStream;
-use mail_parser::Message;
 use futures::StreamExt;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use crate::core::crypto::SealedSecret;
+use crate::core::provider::{MailProvider, MessageList, MessageRef, ProviderError, RawMessage};
 
 pub struct ImapCredentials {
     pub email: String,
-    pub password: Option<String>,      // Regular password or app password
-    pub access_token: Option<String>,  // OAuth access token
+    pub password: Option<SealedSecret>,      // Regular password or app password
+    pub access_token: Option<SealedSecret>,  // OAuth access token
     pub server: String,
     pub port: u16,
 }
 
-#[derive(Debug, Clone)]
-pub struct FetchedEmail {
-    pub message_id: Option<String>,
-    pub sender: String,
-    pub subject: String,
-    pub body_preview: String,
-    pub received_at: i64,
+type ImapSession = async_imap::Session<TlsStream<TcpStream>>;
+
+/// IMAP backend. The sync cursor is `<UIDVALIDITY>:<highest UID seen>`.
+pub struct ImapProvider {
+    creds: ImapCredentials,
+    session: Mutex<Option<(ImapSession, u32)>>, // Session and the INBOX UIDVALIDITY
 }
 
-/// Fetch the latest email from an IMAP server (supports both password and OAuth)
-pub async fn fetch_latest_email(creds: &ImapCredentials) -> Result<Option<FetchedEmail>, String> {
-    // Connect to IMAP server using async-std
-    let addr = format!("{}:{}", creds.server, creds.port);
-    let tcp_stream = TcpStream::connect(&addr)
-        .await
-        .map_err(|e| format!("Failed to connect: {}", e))?;
-
-    // Establish TLS connection
-    let tls = async_native_tls::TlsConnector::new();
-    let tls_stream: TlsStream<TcpStream> = tls
-        .connect(&creds.server, tcp_stream)
-        .await
-        .map_err(|e| format!("TLS error: {}", e))?;
-
-    // Create IMAP client
-    let client = async_imap::Client::new(tls_stream);
-    
-    // Login - use OAuth or password
-    let mut session = if let Some(ref access_token) = creds.access_token {
-        // XOAUTH2 authentication
-        let xoauth2 = xoauth2_string(&creds.email, access_token);
-        client
-            .authenticate("XOAUTH2", XOAuth2Authenticator { token: xoauth2 })
+impl ImapProvider {
+    pub fn new(creds: ImapCredentials) -> Self {
+        Self { creds, session: Mutex::new(None) }
+    }
+}
+
+#[async_trait]
+impl MailProvider for ImapProvider {
+    fn name(&self) -> &'static str {
+        "imap"
+    }
+
+    /// Connect, log in (password or OAuth) and select INBOX
+    async fn connect(&self) -> Result<(), String> {
+        let mut guard = self.session.lock().await;
+        if guard.is_some() {
+            return Ok(());
+        }
+
+        let creds = &self.creds;
+
+        // Connect to IMAP server using async-std
+        let addr = format!("{}:{}", creds.server, creds.port);
+        let tcp_stream = TcpStream::connect(&addr)
             .await
-            .map_err(|(e, _)| format!("OAuth login failed: {}", e))?
-    } else if let Some(ref password) = creds.password {
-        // Regular password authentication
-        client
-            .login(&creds.email, password)
+            .map_err(|e| format!("Failed to connect: {}", e))?;
+
+        // Establish TLS connection
+        let tls = async_native_tls::TlsConnector::new();
+        let tls_stream: TlsStream<TcpStream> = tls
+            .connect(&creds.server, tcp_stream)
             .await
-            .map_err(|(e, _)| format!("Login failed: {}", e))?
-    } else {
-        return Err("No credentials provided".to_string());
-    };
-
-    // Select INBOX
-    session
-        .select("INBOX")
-        .await
-        .map_err(|e| format!("Failed to select INBOX: {}", e))?;
-
-    // Search for all messages and get the latest one
-    let search_result = session
-        .search("ALL")
-        .await
-        .map_err(|e| format!("Search failed: {}", e))?;
-
-    // Get the highest UID (latest email)
-    let latest_uid = search_result.iter().max().copied();
-    
-    let mut result_email: Option<FetchedEmail> = None;
-    
-    if let Some(uid) = latest_uid {
-        // Fetch the email
+            .map_err(|e| format!("TLS error: {}", e))?;
+
+        // Create IMAP client
+        let client = async_imap::Client::new(tls_stream);
+        
+        // Login - use OAuth or password
+        // Credentials are only decrypted here, right before login
+        let mut session = if let Some(ref access_token) = creds.access_token {
+            // XOAUTH2 authentication
+            let xoauth2 = xoauth2_string(&creds.email, &access_token.open()?);
+            client
+                .authenticate("XOAUTH2", XOAuth2Authenticator { token: xoauth2 })
+                .await
+                .map_err(|(e, _)| format!("OAuth login failed: {}", e))?
+        } else if let Some(ref password) = creds.password {
+            // Regular password authentication
+            client
+                .login(&creds.email, password.open()?)
+                .await
+                .map_err(|(e, _)| format!("Login failed: {}", e))?
+        } else {
+            return Err("No credentials provided".to_string());
+        };
+
+        // Select INBOX
+        let mailbox = session
+            .select("INBOX")
+            .await
+            .map_err(|e| format!("Failed to select INBOX: {}", e))?;
+
+        *guard = Some((session, mailbox.uid_validity.unwrap_or(0)));
+        Ok(())
+    }
+
+    async fn list_new_messages(&self, cursor: Option<&str>, limit: usize) -> Result<MessageList, ProviderError> {
+        let mut guard = self.session.lock().await;
+        let (session, uid_validity) = guard.as_mut().ok_or("Not connected")?;
+
+        // A changed UIDVALIDITY invalidates every UID we remembered
+        let last_uid = cursor
+            .and_then(|c| c.split_once(':'))
+            .filter(|(validity, _)| validity.parse::<u32>().ok() == Some(*uid_validity))
+            .and_then(|(_, uid)| uid.parse::<u32>().ok());
+
+        let query = match last_uid {
+            Some(uid) => format!("UID {}:*", uid + 1),
+            None => "ALL".to_string(),
+        };
+
+        let search_result = session
+            .uid_search(&query)
+            .await
+            .map_err(|e| format!("Search failed: {}", e))?;
+
+        // "n:*" always matches the highest UID, even when it is below n
+        let mut uids: Vec<u32> = search_result
+            .into_iter()
+            .filter(|uid| last_uid.is_none_or(|last| *uid > last))
+            .collect();
+        uids.sort_unstable();
+
+        // The first sync takes the latest messages; after that the backlog goes oldest first
+        if last_uid.is_none() {
+            uids = uids.split_off(uids.len().saturating_sub(limit));
+        } else {
+            uids.truncate(limit);
+        }
+
+        let cursor_at = |uid: u32| format!("{}:{}", uid_validity, uid);
+        let resume = uids.last().copied().or(last_uid).unwrap_or(0);
+        Ok(MessageList {
+            messages: uids
+                .into_iter()
+                .rev()
+                .map(|uid| MessageRef { id: uid.to_string(), received_at: None, cursor: Some(cursor_at(uid)) })
+                .collect(),
+            cursor: Some(cursor_at(resume)),
+        })
+    }
+
+    async fn fetch_raw(&self, message: &MessageRef) -> Result<RawMessage, ProviderError> {
+        let mut guard = self.session.lock().await;
+        let (session, _) = guard.as_mut().ok_or("Not connected")?;
+
         let mut messages_stream = session
-            .fetch(uid.to_string(), "RFC822")
+            .uid_fetch(&message.id, "(RFC822 INTERNALDATE)")
             .await
             .map_err(|e| format!("Fetch failed: {}", e))?;
 
+        let mut raw = None;
         while let Some(message_result) = messages_stream.next().await {
-            if let Ok(message) = message_result {
-                if let Some(body) = message.body() {
-                    if let Some(parsed) = Message::parse(body) {
-                        result_email = Some(FetchedEmail {
-                            message_id: parsed.message_id().map(|s| s.to_string()),
-                            sender: extract_sender(&parsed),
-                            subject: parsed.subject().unwrap_or("").to_string(),
-                            body_preview: parsed
-                                .body_text(0)
-                                .map(|b| b.chars().take(500).collect::<String>())
-                                .unwrap_or_default(),
-                            received_at: parsed.date()
-                                .map(|d| d.to_timestamp())
-                                .unwrap_or_else(|| chrono::Utc::now().timestamp()),
-                        });
-                        break;
-                    }
-                }
+            let fetched = message_result.map_err(|e| format!("Fetch failed: {}", e))?;
+            if let Some(body) = fetched.body() {
+                raw = Some(RawMessage {
+                    data: body.to_vec(),
+                    received_at: fetched.internal_date().map(|d| d.timestamp()),
+                });
             }
         }
-    }
 
-    // Logout
-    let _ = session.logout().await;
-    Ok(result_email)
-}
+        // Expunged since it was listed
+        raw.ok_or_else(|| ProviderError::Unreadable(format!("Message {} not found", message.id)))
+    }
 
-fn extract_sender(message: &Message) -> String {
-    use mail_parser::{HeaderValue, Addr};
-    
-    if let Some(header_value) = message.header("From") {
-        match header_value {
-            HeaderValue::Address(addr) => {
-                addr.address.as_ref().map(|s| s.to_string()).unwrap_or_default()
-            }
-            HeaderValue::AddressList(list) => {
-                list.first()
-                    .and_then(|a: &Addr| a.address.as_ref())
-                    .map(|s| s.to_string())
-                    .unwrap_or_default()
-            }
-            _ => String::new(),
+    async fn disconnect(&self) {
+        if let Some((mut session, _)) = self.session.lock().await.take() {
+            let _ = session.logout().await;
         }
-    } else {
-        String::new()
     }
 }
 
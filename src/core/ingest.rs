@@ -0,0 +1,231 @@
+use mail_parser::{Addr, HeaderValue, Message, MimeHeaders};
+use serde::Serialize;
+use crate::core::dkim::{self, DkimSignature};
+use crate::core::dns::Resolver;
+use crate::core::headers;
+use crate::core::spf::SpfCheck;
+
+const PREVIEW_CHARS: usize = 500;
+
+/// Names this server as the checker in the Authentication-Results it adds
+pub const AUTHSERV_ID: &str = "mailpulse.net";
+
+/// Words that usually sit next to a one-time code in verification emails
+const OTP_KEYWORDS: [&str; 7] = ["otp", "code", "passcode", "verification", "one-time", "pin", "token"];
+
+/// An email parsed by mail-parser, independent of where it came from (SMTP, Gmail, ...)
+#[derive(Debug, Clone, Default)]
+pub struct ParsedEmail {
+    pub message_id: Option<String>,  // Message-ID header, without the angle brackets
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,     // References header, oldest first
+    pub sender: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body_preview: String,
+    pub body_text: Option<String>,
+    pub body_html: Option<String>,
+    pub otp: Option<String>,
+    pub attachments: Vec<ParsedAttachment>,
+    pub received_at: Option<i64>, // Date header as unix seconds
+    pub headers: Vec<(String, String)>, // Every header in order, values unfolded
+    pub dkim: Option<Vec<DkimSignature>>, // Set when signatures were verified on arrival
+    pub raw: Vec<u8>,             // The message as received, empty if it arrived already parsed
+}
+
+#[derive(Debug, Clone)]
+pub struct ParsedAttachment {
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub content: Vec<u8>,
+}
+
+/// A message a provider listed but could not fetch or store
+#[derive(Debug, Clone, Serialize)]
+pub struct FetchFailure {
+    pub message_id: String,
+    pub error: String,
+    pub permanent: bool, // Retrying won't help: the sync moves past it
+}
+
+/// Parse a raw RFC822 message into text, HTML, attachments and OTP.
+/// Authentication-Results claiming to be ours are dropped, see `claims_our_results`.
+pub fn parse_email(raw: &[u8]) -> Option<ParsedEmail> {
+    parse_message(&without_forged_results(raw))
+}
+
+fn parse_message(raw: &[u8]) -> Option<ParsedEmail> {
+    let message = Message::parse(raw)?;
+
+    let subject = message.subject().unwrap_or("").to_string();
+    let body_text = message.body_text(0).map(|b| b.into_owned());
+    let body_html = message.body_html(0).map(|b| b.into_owned());
+    let body_preview = body_text
+        .as_deref()
+        .map(|b| b.chars().take(PREVIEW_CHARS).collect::<String>())
+        .unwrap_or_default();
+
+    let otp = extract_otp(&subject)
+        .or_else(|| body_text.as_deref().and_then(extract_otp));
+
+    let attachments = message
+        .attachments()
+        .map(|part| ParsedAttachment {
+            filename: part.attachment_name().map(|s| s.to_string()),
+            content_type: part.content_type().map(|ct| match &ct.c_subtype {
+                Some(sub) => format!("{}/{}", ct.c_type, sub),
+                None => ct.c_type.to_string(),
+            }),
+            content: part.contents().to_vec(),
+        })
+        .collect();
+
+    Some(ParsedEmail {
+        message_id: message.message_id().map(|s| s.to_string()),
+        in_reply_to: message_ids(message.in_reply_to()).into_iter().next(),
+        references: message_ids(message.references()),
+        sender: extract_sender(&message),
+        to: extract_recipients(&message),
+        subject,
+        body_preview,
+        body_text,
+        body_html,
+        otp,
+        attachments,
+        received_at: message.date().map(|d| d.to_timestamp()),
+        headers: headers_of(&message),
+        dkim: None,
+        raw: raw.to_vec(),
+    })
+}
+
+/// Parse mail received directly (SMTP, webhooks with the raw message) after verifying its
+/// DKIM signatures. The results, and SPF when it was checked, are added on top as Authentication-Results.
+pub async fn parse_received(raw: &[u8], resolver: &dyn Resolver, spf: Option<&SpfCheck>) -> Option<ParsedEmail> {
+    let signatures = dkim::verify(resolver, raw).await;
+    let raw = without_forged_results(raw);
+    let clauses: Vec<String> = spf
+        .map(SpfCheck::result_clause)
+        .into_iter()
+        .chain(signatures.iter().map(DkimSignature::result_clause))
+        .collect();
+    let mut email = if clauses.is_empty() {
+        parse_message(&raw)?
+    } else {
+        parse_message(&prepend_header(&raw, "Authentication-Results", &headers::authentication_results(AUTHSERV_ID, &clauses)))?
+    };
+    email.dkim = Some(signatures);
+    Some(email)
+}
+
+/// The message with a header added on top, where receivers add trace and result headers.
+/// Line breaks in `value` become spaces: it can't start a header of its own.
+pub fn prepend_header(raw: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let mut message = format!("{}: {}\r\n", name, value.replace(['\r', '\n'], " ")).into_bytes();
+    message.extend_from_slice(raw);
+    message
+}
+
+/// Whether a header is an Authentication-Results naming us as the checker. Only we may add
+/// those: copies that arrive with a message are forged and get removed (RFC 8601 5).
+pub fn claims_our_results(name: &str, value: &str) -> bool {
+    name.trim().eq_ignore_ascii_case("Authentication-Results")
+        && headers::parse_authentication_results(value).authserv_id.eq_ignore_ascii_case(AUTHSERV_ID)
+}
+
+/// The message without the header fields `claims_our_results` refuses; the rest byte for byte
+fn without_forged_results(raw: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(raw.len());
+    let mut lines = raw.split_inclusive(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        if line == b"\r\n" || line == b"\n" {
+            message.extend_from_slice(line);
+            break; // End of the header section
+        }
+        let mut field = line.to_vec();
+        while let Some(folded) = lines.next_if(|next| next.first().is_some_and(|&b| b == b' ' || b == b'\t')) {
+            field.extend_from_slice(folded);
+        }
+        let text = String::from_utf8_lossy(&field);
+        let forged = text.split_once(':').is_some_and(|(name, value)| {
+            claims_our_results(name, &value.split_whitespace().collect::<Vec<_>>().join(" "))
+        });
+        if !forged {
+            message.extend_from_slice(&field);
+        }
+    }
+    message.extend(lines.flatten());
+    message
+}
+
+/// Header fields of a raw message in order, folded values joined on one line
+pub fn header_fields(raw: &[u8]) -> Vec<(String, String)> {
+    Message::parse(raw).map(|message| headers_of(&message)).unwrap_or_default()
+}
+
+fn headers_of(message: &Message) -> Vec<(String, String)> {
+    message
+        .headers_raw()
+        .map(|(name, value)| {
+            let value = value.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>();
+            (name.to_string(), value.join(" "))
+        })
+        .collect()
+}
+
+/// Get the first address from the From header
+pub fn extract_sender(message: &Message) -> String {
+    message.header("From").map(addresses).unwrap_or_default().into_iter().next().unwrap_or_default()
+}
+
+/// Every address of the To header
+pub fn extract_recipients(message: &Message) -> Vec<String> {
+    message.header("To").map(addresses).unwrap_or_default()
+}
+
+/// The message ids of In-Reply-To or References
+fn message_ids(value: &HeaderValue) -> Vec<String> {
+    value.as_text_list().unwrap_or_default().into_iter().map(str::to_string).collect()
+}
+
+/// The addresses of an address header, groups flattened
+fn addresses(value: &HeaderValue) -> Vec<String> {
+    let of = |list: &[Addr]| -> Vec<String> {
+        list.iter().filter_map(|a| a.address.as_ref().map(|s| s.to_string())).collect()
+    };
+    match value {
+        HeaderValue::Address(addr) => of(std::slice::from_ref(addr)),
+        HeaderValue::AddressList(list) => of(list),
+        HeaderValue::Group(group) => of(&group.addresses),
+        HeaderValue::GroupList(groups) => groups.iter().flat_map(|g| of(&g.addresses)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Find a 4-8 digit one-time code, preferring the first one after an OTP keyword
+pub fn extract_otp(text: &str) -> Option<String> {
+    let lower = text.to_ascii_lowercase();
+    let keyword_pos = OTP_KEYWORDS.iter().filter_map(|k| lower.find(k)).min()?;
+
+    let mut candidates = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (c.is_ascii_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                let token = &text[s..i];
+                if (4..=8).contains(&token.len()) && token.bytes().all(|b| b.is_ascii_digit()) {
+                    candidates.push((s, token));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    candidates
+        .iter()
+        .find(|(pos, _)| *pos > keyword_pos)
+        .or(candidates.first())
+        .map(|(_, token)| token.to_string())
+}
@@ -1,55 +1,438 @@
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
-use serde::{Deserialize, Serialize};
+use jsonwebtoken::{encode, decode, decode_header, Header, Algorithm, Validation, EncodingKey, DecodingKey};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use base64::Engine;
+use ring::signature::{Ed25519KeyPair, KeyPair, RsaKeyPair, RsaPublicKeyComponents};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::env;
+use std::sync::{Arc, Mutex};
 use chrono::{Utc, Duration};
 
+/// Kid given to a key configured through the single JWT_SECRET variable
+const DEFAULT_KID: &str = "default";
+/// The placeholder the server used to fall back to; never accept it as a key
+const PLACEHOLDER_SECRET: &str = "default-secret-change-in-production";
+const MIN_SECRET_LEN: usize = 32;
+/// Access tokens are short-lived; clients renew them with a refresh token
+const DEFAULT_ACCESS_TTL_SECS: i64 = 900;
+/// `aud` of access tokens unless JWT_AUDIENCE says otherwise
+const DEFAULT_AUDIENCE: &str = "mail-server";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,      // user_id
     pub exp: usize,       // expiration time
     pub iat: usize,       // issued at
+    pub iss: String,      // who issued the token (JWT_ISSUER)
+    pub aud: String,      // who the token is meant for (JWT_AUDIENCE)
+    #[serde(default)]
+    pub jti: String,      // unique token id
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>, // login session (auth_sessions.id) the token belongs to
+}
+
+/// One signing key and the algorithm it is used with
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    public: Option<Jwk>, // None for HMAC secrets, which must never be published
+}
+
+impl SigningKey {
+    fn hmac(kid: &str, secret: &str) -> Result<Self, String> {
+        if secret == PLACEHOLDER_SECRET || secret.len() < MIN_SECRET_LEN {
+            return Err(format!("JWT key '{}' must be a random secret of at least {} bytes", kid, MIN_SECRET_LEN));
+        }
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding: EncodingKey::from_secret(secret.as_bytes()),
+            decoding: DecodingKey::from_secret(secret.as_bytes()),
+            public: None,
+        })
+    }
+
+    /// RSA private key in PEM, PKCS#8 (`PRIVATE KEY`) or PKCS#1 (`RSA PRIVATE KEY`)
+    fn rsa(kid: &str, private_pem: &[u8]) -> Result<Self, String> {
+        let invalid = |e: String| format!("JWT key '{}' is not a valid RSA private key: {}", kid, e);
+
+        let parsed = pem::parse(private_pem).map_err(|e| invalid(e.to_string()))?;
+        let pair = match parsed.tag() {
+            "PRIVATE KEY" => RsaKeyPair::from_pkcs8(parsed.contents()),
+            "RSA PRIVATE KEY" => RsaKeyPair::from_der(parsed.contents()),
+            tag => return Err(invalid(format!("unexpected PEM block '{}'", tag))),
+        }
+        .map_err(|e| invalid(e.to_string()))?;
+
+        let public = RsaPublicKeyComponents::<Vec<u8>>::from(pair.public());
+        let (n, e) = (base64url(&public.n), base64url(&public.e));
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::RS256,
+            encoding: EncodingKey::from_rsa_pem(private_pem).map_err(|e| invalid(e.to_string()))?,
+            decoding: DecodingKey::from_rsa_components(&n, &e).map_err(|e| invalid(e.to_string()))?,
+            public: Some(Jwk {
+                common: jwk_common(kid, KeyAlgorithm::RS256),
+                algorithm: AlgorithmParameters::RSA(RSAKeyParameters { key_type: RSAKeyType::RSA, n, e }),
+            }),
+        })
+    }
+
+    /// Ed25519 private key in PKCS#8 PEM (`openssl genpkey -algorithm ed25519`)
+    fn ed25519(kid: &str, private_pem: &[u8]) -> Result<Self, String> {
+        let invalid = |e: String| format!("JWT key '{}' is not a valid Ed25519 private key: {}", kid, e);
+
+        let parsed = pem::parse(private_pem).map_err(|e| invalid(e.to_string()))?;
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(parsed.contents()).map_err(|e| invalid(e.to_string()))?;
+        let x = base64url(pair.public_key().as_ref());
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::EdDSA,
+            encoding: EncodingKey::from_ed_pem(private_pem).map_err(|e| invalid(e.to_string()))?,
+            decoding: DecodingKey::from_ed_components(&x).map_err(|e| invalid(e.to_string()))?,
+            public: Some(Jwk {
+                common: jwk_common(kid, KeyAlgorithm::EdDSA),
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x,
+                }),
+            }),
+        })
+    }
+}
+
+fn jwk_common(kid: &str, algorithm: KeyAlgorithm) -> CommonParameters {
+    CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(algorithm),
+        key_id: Some(kid.to_string()),
+        ..Default::default()
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The environment a `KeySet` was parsed from
+type KeyEnv = (Option<String>, Option<String>, Option<String>);
+
+/// Parsed keys, reused until the variables they came from change (PEM files are read once)
+static CACHED: Mutex<Option<(KeyEnv, Arc<KeySet>)>> = Mutex::new(None);
+
+/// Signing keys, identified by `kid`.
+///
+/// `JWT_KEYS` is a comma separated list of entries, either `kid:secret` (HS256) or
+/// `kid:RS256:/path/to/private.pem` / `kid:EdDSA:/path/to/private.pem`.
+/// `JWT_ACTIVE_KID` picks the one new tokens are signed with (defaults to the last).
+/// Tokens signed with any listed key stay valid, so a new key can be added and activated
+/// without logging everyone out. A single `JWT_SECRET` is still accepted and gets kid "default".
+/// Public halves of the asymmetric keys are served as a JWKS so others can verify our tokens.
+pub struct KeySet {
+    keys: Vec<SigningKey>,
+    active: String,
+}
+
+impl KeySet {
+    pub fn from_env() -> Result<Arc<Self>, String> {
+        let key_env: KeyEnv = (
+            env::var("JWT_KEYS").ok(),
+            env::var("JWT_SECRET").ok(),
+            env::var("JWT_ACTIVE_KID").ok(),
+        );
+
+        let mut cached = CACHED.lock().map_err(|_| "JWT key cache poisoned".to_string())?;
+        if let Some((from, keys)) = cached.as_ref() {
+            if *from == key_env {
+                return Ok(keys.clone());
+            }
+        }
+
+        let keys = Arc::new(Self::parse(key_env.0.clone(), key_env.1.clone(), key_env.2.clone())?);
+        *cached = Some((key_env, keys.clone()));
+        Ok(keys)
+    }
+
+    /// Keys from the values of JWT_KEYS, JWT_SECRET and JWT_ACTIVE_KID
+    pub(crate) fn parse(jwt_keys: Option<String>, jwt_secret: Option<String>, active_kid: Option<String>) -> Result<Self, String> {
+        let mut keys = Vec::new();
+        if let Some(list) = jwt_keys {
+            for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (kid, rest) = entry
+                    .split_once(':')
+                    .ok_or_else(|| format!("Invalid JWT_KEYS entry (expected kid:secret or kid:ALG:path): {}", entry))?;
+                let key = match rest.split_once(':') {
+                    Some(("RS256", path)) => SigningKey::rsa(kid, &read_key_file(kid, path)?)?,
+                    Some(("EdDSA", path)) => SigningKey::ed25519(kid, &read_key_file(kid, path)?)?,
+                    _ => SigningKey::hmac(kid, rest)?,
+                };
+                keys.push(key);
+            }
+        } else if let Some(secret) = jwt_secret {
+            keys.push(SigningKey::hmac(DEFAULT_KID, &secret)?);
+        }
+
+        if keys.is_empty() {
+            return Err("JWT_KEYS or JWT_SECRET must be set".to_string());
+        }
+
+        let active = match active_kid {
+            Some(kid) => kid,
+            None => keys.last().map(|key| key.kid.clone()).unwrap_or_default(),
+        };
+        if !keys.iter().any(|key| key.kid == active) {
+            return Err(format!("JWT_ACTIVE_KID '{}' is not in JWT_KEYS", active));
+        }
+
+        Ok(Self { keys, active })
+    }
+
+    pub fn active_kid(&self) -> &str {
+        &self.active
+    }
+
+    /// Sign claims with the active key, recording its kid in the header
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, String> {
+        let key = self.key(&self.active)?;
+        let header = Header { kid: Some(key.kid.clone()), ..Header::new(key.algorithm) };
+
+        encode(&header, claims, &key.encoding)
+            .map_err(|e| format!("Failed to sign token: {}", e))
+    }
+
+    /// Verify a token with the key named by its `kid` (tokens from before kids existed use "default").
+    /// Only that key's algorithm is accepted, whatever `validation` lists.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str, validation: &Validation) -> Result<T, String> {
+        let header = decode_header(token).map_err(|e| format!("Invalid token: {}", e))?;
+        let key = self.key(header.kid.as_deref().unwrap_or(DEFAULT_KID))?;
+
+        let mut validation = validation.clone();
+        validation.algorithms = vec![key.algorithm];
+
+        decode::<T>(token, &key.decoding, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| format!("Invalid token: {}", e))
+    }
+
+    /// Public keys for `/.well-known/jwks.json`: every asymmetric key that still verifies tokens
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet { keys: self.keys.iter().filter_map(|key| key.public.clone()).collect() }
+    }
+
+    fn key(&self, kid: &str) -> Result<&SigningKey, String> {
+        self.keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .ok_or_else(|| format!("Invalid token: unknown key id '{}'", kid))
+    }
+}
+
+fn read_key_file(kid: &str, path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("Cannot read JWT key '{}' from {}: {}", kid, path, e))
+}
+
+/// Lifetime of access tokens in seconds (JWT_ACCESS_TTL_SECS, default 15 minutes)
+pub fn access_ttl_secs() -> i64 {
+    env::var("JWT_ACCESS_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(DEFAULT_ACCESS_TTL_SECS)
 }
 
-/// Generate a JWT token for a user
-pub fn generate_token(user_id: &str) -> Result<String, String> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "default-secret-change-in-production".to_string());
-    
+/// `iss` of our tokens (JWT_ISSUER, defaults to SERVER_URL)
+pub fn issuer() -> String {
+    env::var("JWT_ISSUER")
+        .or_else(|_| env::var("SERVER_URL"))
+        .unwrap_or_else(|_| "http://localhost:8080".to_string())
+}
+
+/// `aud` of access tokens (JWT_AUDIENCE, default "mail-server")
+pub fn audience() -> String {
+    env::var("JWT_AUDIENCE").unwrap_or_else(|_| DEFAULT_AUDIENCE.to_string())
+}
+
+/// Generate an access token for a user.
+/// Pass the login session so the token dies with it; tokens without one can't be revoked early.
+pub fn generate_token(user_id: &str, session_id: Option<&str>) -> Result<String, String> {
+    KeySet::from_env()?.sign(&login_claims(user_id, session_id))
+}
+
+/// Claims of a new access token
+pub(crate) fn login_claims(user_id: &str, session_id: Option<&str>) -> Claims {
     let now = Utc::now();
-    let expires_at = now + Duration::hours(24); // Token valid for 24 hours
-    
-    let claims = Claims {
+    let expires_at = now + Duration::seconds(access_ttl_secs());
+
+    Claims {
         sub: user_id.to_string(),
         exp: expires_at.timestamp() as usize,
         iat: now.timestamp() as usize,
-    };
-    
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(secret.as_bytes()),
-    )
-    .map_err(|e| format!("Failed to generate token: {}", e))
-}
-
-/// Validate a JWT token and return the user_id
-pub fn validate_token(token: &str) -> Result<String, String> {
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "default-secret-change-in-production".to_string());
-    
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_bytes()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|e| format!("Invalid token: {}", e))?;
-    
-    Ok(token_data.claims.sub)
+        iss: issuer(),
+        aud: audience(),
+        jti: uuid::Uuid::new_v4().to_string(),
+        sid: session_id.map(str::to_string),
+    }
+}
+
+/// Validate a JWT token and return its claims
+pub fn validate_token(token: &str) -> Result<Claims, String> {
+    validate_with(&*KeySet::from_env()?, token)
+}
+
+/// Validate an access token against `keys`
+pub(crate) fn validate_with(keys: &KeySet, token: &str) -> Result<Claims, String> {
+    keys.verify::<Claims>(token, &access_validation(&issuer(), &audience()))
+}
+
+/// Rules for access tokens: unexpired, issued by `iss`, meant for `aud`
+fn access_validation(iss: &str, aud: &str) -> Validation {
+    let mut validation = Validation::new(Algorithm::HS256); // Algorithm is replaced by the key's own in verify
+    validation.set_issuer(&[iss]);
+    validation.set_audience(&[aud]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+    validation
 }
 
 /// Extract Bearer token from Authorization header
 pub fn extract_bearer_token(auth_header: &str) -> Option<&str> {
-    if auth_header.starts_with("Bearer ") {
-        Some(&auth_header[7..])
-    } else {
-        None
+    auth_header.strip_prefix("Bearer ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(entries: &[(&str, &str)], active: &str) -> KeySet {
+        KeySet {
+            keys: entries.iter().map(|(k, s)| SigningKey::hmac(k, s).unwrap()).collect(),
+            active: active.to_string(),
+        }
+    }
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            sub: "user_1".to_string(),
+            exp: (Utc::now().timestamp() + 60) as usize,
+            iat: Utc::now().timestamp() as usize,
+            iss: "https://mail.example.com".to_string(),
+            aud: "mail-server".to_string(),
+            jti: "t1".to_string(),
+            sid: Some("s1".to_string()),
+        }
+    }
+
+    fn validation() -> Validation {
+        access_validation("https://mail.example.com", "mail-server")
+    }
+
+    const OLD: &str = "old-secret-old-secret-old-secret-0";
+    const NEW: &str = "new-secret-new-secret-new-secret-1";
+
+    #[test]
+    fn weak_or_missing_secrets_are_refused() {
+        assert!(KeySet::parse(None, None, None).is_err());
+        assert!(KeySet::parse(None, Some(PLACEHOLDER_SECRET.to_string()), None).is_err());
+        assert!(KeySet::parse(None, Some("short".to_string()), None).is_err());
+        assert!(KeySet::parse(Some(format!("k1:{}", OLD)), None, Some("k2".to_string())).is_err());
+
+        let keys = KeySet::parse(Some(format!("k1:{},k2:{}", OLD, NEW)), None, None).unwrap();
+        assert_eq!(keys.active_kid(), "k2");
+        assert_eq!(KeySet::parse(None, Some(OLD.to_string()), None).unwrap().active_kid(), DEFAULT_KID);
+    }
+
+    #[test]
+    fn tokens_from_rotated_out_keys_still_verify() {
+        let token = keys(&[("k1", OLD)], "k1").sign(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("k1"));
+
+        let rotated = keys(&[("k1", OLD), ("k2", NEW)], "k2");
+        let verified: Claims = rotated.verify(&token, &validation()).unwrap();
+        assert_eq!(verified.sid.as_deref(), Some("s1"));
+
+        let new_token = rotated.sign(&claims()).unwrap();
+        assert_eq!(decode_header(&new_token).unwrap().kid.as_deref(), Some("k2"));
+    }
+
+    #[test]
+    fn removed_keys_no_longer_verify() {
+        let token = keys(&[("k1", OLD)], "k1").sign(&claims()).unwrap();
+        let result: Result<Claims, _> = keys(&[("k2", NEW)], "k2").verify(&token, &validation());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn kid_cannot_point_at_another_key() {
+        // Signed with k1's secret but claiming to be k2
+        let header = Header { kid: Some("k2".to_string()), ..Header::new(Algorithm::HS256) };
+        let forged = encode(&header, &claims(), &EncodingKey::from_secret(OLD.as_bytes())).unwrap();
+
+        let result: Result<Claims, _> = keys(&[("k1", OLD), ("k2", NEW)], "k2").verify(&forged, &validation());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn asymmetric_keys_sign_and_publish_their_public_half() {
+        let entries = format!("h1:{},r1:RS256:{},e1:EdDSA:{}", OLD, fixture("jwt_rs256.pem"), fixture("jwt_eddsa.pem"));
+
+        for (kid, algorithm) in [("r1", Algorithm::RS256), ("e1", Algorithm::EdDSA)] {
+            let keys = KeySet::parse(Some(entries.clone()), None, Some(kid.to_string())).unwrap();
+            let token = keys.sign(&claims()).unwrap();
+            assert_eq!(decode_header(&token).unwrap().alg, algorithm);
+
+            // Anyone holding only the JWKS can verify it
+            let jwks = keys.jwks();
+            let public = DecodingKey::from_jwk(jwks.find(kid).unwrap()).unwrap();
+            let mut validation = validation();
+            validation.algorithms = vec![algorithm];
+            assert_eq!(decode::<Claims>(&token, &public, &validation).unwrap().claims.sub, "user_1");
+            assert!(keys.verify::<Claims>(&token, &self::validation()).is_ok());
+        }
+
+        let jwks = KeySet::parse(Some(entries), None, None).unwrap().jwks();
+        assert_eq!(jwks.keys.len(), 2);
+        assert!(jwks.find("h1").is_none(), "HMAC secrets must never be published");
+    }
+
+    #[test]
+    fn algorithm_cannot_be_swapped_for_a_key() {
+        // HS256 token "signed" with the RSA key's public modulus, a classic confusion attack
+        let keys = KeySet::parse(Some(format!("r1:RS256:{}", fixture("jwt_rs256.pem"))), None, None).unwrap();
+        let AlgorithmParameters::RSA(rsa) = &keys.jwks().keys[0].algorithm else { panic!("expected an RSA key") };
+        let header = Header { kid: Some("r1".to_string()), ..Header::new(Algorithm::HS256) };
+        let forged = encode(&header, &claims(), &EncodingKey::from_secret(rsa.n.as_bytes())).unwrap();
+
+        assert!(keys.verify::<Claims>(&forged, &validation()).is_err());
+    }
+
+    #[test]
+    fn issuer_and_audience_must_match() {
+        let keys = keys(&[("k1", OLD)], "k1");
+
+        let foreign = keys.sign(&Claims { iss: "https://evil.example.com".to_string(), ..claims() }).unwrap();
+        assert!(keys.verify::<Claims>(&foreign, &validation()).is_err());
+
+        let other_service = keys.sign(&Claims { aud: "billing".to_string(), ..claims() }).unwrap();
+        assert!(keys.verify::<Claims>(&other_service, &validation()).is_err());
+
+        let valid = keys.sign(&claims()).unwrap();
+        assert!(keys.verify::<Claims>(&valid, &validation()).is_ok());
+    }
+
+    #[test]
+    fn unreadable_or_wrong_key_files_are_refused() {
+        assert!(KeySet::parse(Some("r1:RS256:/nonexistent.pem".to_string()), None, None).is_err());
+        assert!(KeySet::parse(Some(format!("r1:RS256:{}", fixture("jwt_eddsa.pem"))), None, None).is_err());
+        assert!(KeySet::parse(Some(format!("e1:EdDSA:{}", fixture("jwt_rs256.pem"))), None, None).is_err());
     }
 }
@@ -0,0 +1,191 @@
+use sqlx::{PgPool, Row};
+
+/// A schema change, embedded in the binary from `migrations/`
+#[derive(Debug)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration, in the order they apply. Applied migrations must never be edited:
+/// change the schema by appending a new one.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "baseline", sql: include_str!("../../migrations/0001_baseline.sql") },
+    Migration { version: 2, name: "email_alias_and_read_state", sql: include_str!("../../migrations/0002_email_alias_and_read_state.sql") },
+    Migration { version: 3, name: "raw_messages", sql: include_str!("../../migrations/0003_raw_messages.sql") },
+    Migration { version: 4, name: "full_text_search", sql: include_str!("../../migrations/0004_full_text_search.sql") },
+    Migration { version: 5, name: "threads", sql: include_str!("../../migrations/0005_threads.sql") },
+    Migration { version: 6, name: "email_state", sql: include_str!("../../migrations/0006_email_state.sql") },
+    Migration { version: 7, name: "headers", sql: include_str!("../../migrations/0007_headers.sql") },
+    Migration { version: 8, name: "dkim", sql: include_str!("../../migrations/0008_dkim.sql") },
+    Migration { version: 9, name: "workos_session_organization", sql: include_str!("../../migrations/0009_workos_session_organization.sql") },
+    Migration { version: 10, name: "used_refresh_tokens", sql: include_str!("../../migrations/0010_used_refresh_tokens.sql") },
+    Migration { version: 11, name: "api_key_digests", sql: include_str!("../../migrations/0011_api_key_digests.sql") },
+    Migration { version: 12, name: "derived_secrets_key_id", sql: include_str!("../../migrations/0012_derived_secrets_key_id.sql") },
+    Migration { version: 13, name: "email_threads", sql: include_str!("../../migrations/0013_email_threads.sql") },
+];
+
+/// Session-level advisory lock held while migrating, so replicas booting together take turns
+const LOCK_KEY: i64 = 0x6d61_696c_5f6d_6967; // "mail_mig"
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Modified { version: i64, name: &'static str }, // Applied with different SQL than this build has
+    Unknown(i64),                                  // Applied by a newer build
+    Failed { version: i64, name: &'static str, error: sqlx::Error },
+    Db(sqlx::Error),
+}
+
+impl std::fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MigrationError::Modified { version, name } => {
+                write!(f, "Migration {:04} '{}' was changed after it was applied", version, name)
+            }
+            MigrationError::Unknown(version) => {
+                write!(f, "Database has migration {:04}, which this build doesn't know: deploy a newer build", version)
+            }
+            MigrationError::Failed { version, name, error } => {
+                write!(f, "Migration {:04} '{}' failed: {}", version, name, error)
+            }
+            MigrationError::Db(e) => write!(f, "DB error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for MigrationError {
+    fn from(e: sqlx::Error) -> Self {
+        MigrationError::Db(e)
+    }
+}
+
+/// SHA-256 of a migration's SQL, hex encoded
+pub fn checksum(sql: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, sql.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The migrations still to apply, given the (version, checksum) pairs of the applied ones
+pub fn pending<'a>(migrations: &'a [Migration], applied: &[(i64, String)]) -> Result<Vec<&'a Migration>, MigrationError> {
+    for (version, applied_checksum) in applied {
+        match migrations.iter().find(|m| m.version == *version) {
+            Some(m) if checksum(m.sql) != *applied_checksum => {
+                return Err(MigrationError::Modified { version: m.version, name: m.name });
+            }
+            Some(_) => {}
+            None => return Err(MigrationError::Unknown(*version)),
+        }
+    }
+
+    Ok(migrations.iter().filter(|m| !applied.iter().any(|(v, _)| *v == m.version)).collect())
+}
+
+/// Apply pending migrations, each in its own transaction, and return them.
+/// A dry run applies them all in one transaction and rolls it back: the SQL is checked
+/// against the real schema without changing anything.
+pub async fn run(pool: &PgPool, dry_run: bool) -> Result<Vec<&'static Migration>, MigrationError> {
+    let mut conn = pool.acquire().await?;
+    sqlx::query("SELECT pg_advisory_lock($1)").bind(LOCK_KEY).execute(&mut *conn).await?;
+
+    let result = run_locked(&mut conn, dry_run).await;
+
+    // The lock belongs to the connection, which goes back to the pool
+    sqlx::query("SELECT pg_advisory_unlock($1)").bind(LOCK_KEY).execute(&mut *conn).await?;
+    result
+}
+
+async fn run_locked(conn: &mut sqlx::PgConnection, dry_run: bool) -> Result<Vec<&'static Migration>, MigrationError> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at TIMESTAMP NOT NULL DEFAULT NOW()
+        )
+        "#
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let applied: Vec<(i64, String)> = sqlx::query("SELECT version, checksum FROM schema_migrations ORDER BY version")
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(|row| (row.get("version"), row.get("checksum")))
+        .collect();
+
+    let todo = pending(MIGRATIONS, &applied)?;
+    if dry_run {
+        let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+        for m in &todo {
+            apply(&mut tx, m).await?;
+        }
+        tx.rollback().await?;
+        return Ok(todo);
+    }
+
+    for m in &todo {
+        let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+        apply(&mut tx, m).await?;
+        tx.commit().await?;
+    }
+    Ok(todo)
+}
+
+async fn apply(conn: &mut sqlx::PgConnection, m: &'static Migration) -> Result<(), MigrationError> {
+    let failed = |error| MigrationError::Failed { version: m.version, name: m.name, error };
+
+    // Migrations hold several statements, which only the simple query protocol runs
+    sqlx::raw_sql(m.sql).execute(&mut *conn).await.map_err(failed)?;
+    sqlx::query("INSERT INTO schema_migrations (version, name, checksum) VALUES ($1, $2, $3)")
+        .bind(m.version)
+        .bind(m.name)
+        .bind(checksum(m.sql))
+        .execute(&mut *conn)
+        .await
+        .map_err(failed)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[Migration] = &[
+        Migration { version: 1, name: "one", sql: "CREATE TABLE a (id INT);" },
+        Migration { version: 2, name: "two", sql: "CREATE TABLE b (id INT);" },
+    ];
+
+    #[test]
+    fn embedded_migrations_are_in_order() {
+        assert!(!MIGRATIONS.is_empty());
+        for (i, m) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(m.version, i as i64 + 1, "versions are consecutive from 1");
+            assert!(!m.sql.trim().is_empty(), "{} is empty", m.name);
+        }
+    }
+
+    #[test]
+    fn only_unapplied_migrations_are_pending() {
+        let all = pending(FIXTURES, &[]).unwrap();
+        assert_eq!(all.iter().map(|m| m.version).collect::<Vec<_>>(), vec![1, 2]);
+
+        let rest = pending(FIXTURES, &[(1, checksum(FIXTURES[0].sql))]).unwrap();
+        assert_eq!(rest.iter().map(|m| m.version).collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn edited_or_unknown_migrations_are_refused() {
+        let edited = pending(FIXTURES, &[(1, checksum("CREATE TABLE a (id BIGINT);"))]);
+        assert!(matches!(edited, Err(MigrationError::Modified { version: 1, .. })));
+
+        let newer = pending(FIXTURES, &[(3, checksum("anything"))]);
+        assert!(matches!(newer, Err(MigrationError::Unknown(3))));
+    }
+}
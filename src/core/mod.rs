@@ -4,3 +4,23 @@ pub mod oauth;
 pub mod gmail_api;
 pub mod jwt;
 pub mod workos_auth;
+pub mod ingest;
+pub mod graph_api;
+pub mod provider;
+pub mod sync;
+pub mod crypto;
+pub mod oauth_state;
+pub mod redirects;
+pub mod exchange_code;
+pub mod api_keys;
+pub mod sessions;
+pub mod orgs;
+pub mod workos_sessions;
+pub mod migrations;
+pub mod search;
+pub mod threads;
+pub mod events;
+pub mod headers;
+pub mod dns;
+pub mod spf;
+pub mod dkim;
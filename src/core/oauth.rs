@@ -1,6 +1,6 @@
 use oauth2::{
     AuthorizationCode, AuthUrl, ClientId, ClientSecret, CsrfToken,
-    RedirectUrl, Scope, TokenResponse, TokenUrl,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
     basic::BasicClient, reqwest::async_http_client,
 };
 use std::env;
@@ -62,11 +62,13 @@ pub fn microsoft_client() -> Result<BasicClient, String> {
 }
 
 /// Generate Google authorization URL (used for WorkOS login flow)
-pub fn google_auth_url(user_id: &str) -> Result<String, String> {
+/// `state` is the signed value from `oauth_state::issue`
+pub fn google_auth_url(state: &str, pkce_challenge: PkceCodeChallenge) -> Result<String, String> {
     let client = google_client()?;
     
     let (auth_url, _csrf_token) = client
-        .authorize_url(|| CsrfToken::new(user_id.to_string()))
+        .authorize_url(|| CsrfToken::new(state.to_string()))
+        .set_pkce_challenge(pkce_challenge)
         .add_scope(Scope::new("https://mail.google.com/".to_string()))
         .add_scope(Scope::new("email".to_string()))
         .add_extra_param("access_type", "offline")
@@ -77,12 +79,15 @@ pub fn google_auth_url(user_id: &str) -> Result<String, String> {
 }
 
 /// Generate Microsoft authorization URL
-pub fn microsoft_auth_url(user_id: &str) -> Result<String, String> {
+/// `state` is the signed value from `oauth_state::issue`
+pub fn microsoft_auth_url(state: &str, pkce_challenge: PkceCodeChallenge) -> Result<String, String> {
     let client = microsoft_client()?;
     
     let (auth_url, _csrf_token) = client
-        .authorize_url(|| CsrfToken::new(user_id.to_string()))
-        .add_scope(Scope::new("https://outlook.office.com/IMAP.AccessAsUser.All".to_string()))
+        .authorize_url(|| CsrfToken::new(state.to_string()))
+        .set_pkce_challenge(pkce_challenge)
+        .add_scope(Scope::new("https://graph.microsoft.com/Mail.Read".to_string()))
+        .add_scope(Scope::new("https://graph.microsoft.com/User.Read".to_string()))
         .add_scope(Scope::new("offline_access".to_string()))
         .add_scope(Scope::new("email".to_string()))
         .url();
@@ -91,11 +96,12 @@ pub fn microsoft_auth_url(user_id: &str) -> Result<String, String> {
 }
 
 /// Exchange authorization code for tokens (Google)
-pub async fn google_exchange_code(code: &str) -> Result<OAuthTokens, String> {
+pub async fn google_exchange_code(code: &str, pkce_verifier: PkceCodeVerifier) -> Result<OAuthTokens, String> {
     let client = google_client()?;
     
     let token_result = client
         .exchange_code(AuthorizationCode::new(code.to_string()))
+        .set_pkce_verifier(pkce_verifier)
         .request_async(async_http_client)
         .await
         .map_err(|e| format!("Token exchange failed: {}", e))?;
@@ -108,11 +114,12 @@ pub async fn google_exchange_code(code: &str) -> Result<OAuthTokens, String> {
 }
 
 /// Exchange authorization code for tokens (Microsoft)
-pub async fn microsoft_exchange_code(code: &str) -> Result<OAuthTokens, String> {
+pub async fn microsoft_exchange_code(code: &str, pkce_verifier: PkceCodeVerifier) -> Result<OAuthTokens, String> {
     let client = microsoft_client()?;
     
     let token_result = client
         .exchange_code(AuthorizationCode::new(code.to_string()))
+        .set_pkce_verifier(pkce_verifier)
         .request_async(async_http_client)
         .await
         .map_err(|e| format!("Token exchange failed: {}", e))?;
@@ -124,6 +131,40 @@ pub async fn microsoft_exchange_code(code: &str) -> Result<OAuthTokens, String>
     })
 }
 
+/// Get a new access token from a refresh token (Google)
+pub async fn google_refresh_token(refresh_token: &str) -> Result<OAuthTokens, String> {
+    let client = google_client()?;
+
+    let token_result = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| format!("Token refresh failed: {}", e))?;
+
+    Ok(OAuthTokens {
+        access_token: token_result.access_token().secret().clone(),
+        refresh_token: token_result.refresh_token().map(|t| t.secret().clone()),
+        expires_in: token_result.expires_in().map(|d| d.as_secs()),
+    })
+}
+
+/// Get a new access token from a refresh token (Microsoft)
+pub async fn microsoft_refresh_token(refresh_token: &str) -> Result<OAuthTokens, String> {
+    let client = microsoft_client()?;
+
+    let token_result = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
+        .request_async(async_http_client)
+        .await
+        .map_err(|e| format!("Token refresh failed: {}", e))?;
+
+    Ok(OAuthTokens {
+        access_token: token_result.access_token().secret().clone(),
+        refresh_token: token_result.refresh_token().map(|t| t.secret().clone()),
+        expires_in: token_result.expires_in().map(|d| d.as_secs()),
+    })
+}
+
 /// Generate XOAUTH2 string for IMAP authentication
 pub fn xoauth2_string(email: &str, access_token: &str) -> String {
     let auth_string = format!("user={}\x01auth=Bearer {}\x01\x01", email, access_token);
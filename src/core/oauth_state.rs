@@ -0,0 +1,247 @@
+use jsonwebtoken::{Algorithm, Validation};
+use oauth2::{PkceCodeChallenge, PkceCodeVerifier};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use chrono::{Utc, Duration};
+use crate::core::jwt;
+
+/// How long a user has to finish the provider consent screen
+const STATE_TTL_SECS: i64 = 600;
+/// Audience of state tokens, so they are never accepted as login tokens (and vice versa)
+const STATE_AUDIENCE: &str = "oauth_state";
+/// Audience of WorkOS login states: nobody is logged in yet, so they bind to the browser instead
+const LOGIN_AUDIENCE: &str = "workos_login";
+/// Cookie holding the nonce a WorkOS login state is bound to
+pub const LOGIN_COOKIE: &str = "workos_login";
+
+/// Signed contents of the OAuth `state` parameter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateClaims {
+    pub jti: String,              // Key of the server-side row holding the PKCE verifier
+    pub sub: String,              // user_id the tokens will be bound to
+    pub provider: String,         // google, microsoft or gmail_connect
+    pub redirect: Option<String>, // Where to send the browser afterwards
+    pub aud: String,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+/// A verified and consumed state: who started the flow, and the PKCE verifier for the code exchange
+#[derive(Debug)]
+pub struct OAuthState {
+    pub user_id: String,
+    pub provider: String,
+    pub redirect: Option<String>,
+    pub pkce_verifier: PkceCodeVerifier,
+}
+
+/// Start an OAuth flow: store a PKCE verifier and return the signed state plus the PKCE challenge
+pub async fn issue(
+    pool: &PgPool,
+    user_id: &str,
+    provider: &str,
+    redirect: Option<&str>,
+) -> Result<(String, PkceCodeChallenge), String> {
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let jti = uuid::Uuid::new_v4().to_string();
+    let now = Utc::now();
+    let expires_at = now + Duration::seconds(STATE_TTL_SECS);
+
+    // Abandoned flows never reach the callback, clear them out as we go
+    sqlx::query("DELETE FROM oauth_states WHERE expires_at < NOW()")
+        .execute(pool)
+        .await
+        .map_err(|e| format!("DB error: {}", e))?;
+
+    sqlx::query("INSERT INTO oauth_states (id, pkce_verifier, expires_at) VALUES ($1, $2, $3)")
+        .bind(&jti)
+        .bind(verifier.secret())
+        .bind(expires_at.naive_utc())
+        .execute(pool)
+        .await
+        .map_err(|e| format!("DB error: {}", e))?;
+
+    let state = sign(&*jwt::KeySet::from_env()?, &StateClaims {
+        jti,
+        sub: user_id.to_string(),
+        provider: provider.to_string(),
+        redirect: redirect.map(str::to_string),
+        aud: STATE_AUDIENCE.to_string(),
+        exp: expires_at.timestamp() as usize,
+        iat: now.timestamp() as usize,
+    })?;
+
+    Ok((state, challenge))
+}
+
+/// Verify a state returned by the provider and consume it.
+/// Fails if the signature is wrong, the state expired, or it was already used.
+pub async fn consume(pool: &PgPool, state: &str) -> Result<OAuthState, String> {
+    let claims = verify(&*jwt::KeySet::from_env()?, state)?;
+
+    // DELETE ... RETURNING makes the state single-use even with concurrent callbacks
+    let row = sqlx::query("DELETE FROM oauth_states WHERE id = $1 AND expires_at > NOW() RETURNING pkce_verifier")
+        .bind(&claims.jti)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| format!("DB error: {}", e))?
+        .ok_or("State already used or expired")?;
+
+    Ok(OAuthState {
+        user_id: claims.sub,
+        provider: claims.provider,
+        redirect: claims.redirect,
+        pkce_verifier: PkceCodeVerifier::new(row.get("pkce_verifier")),
+    })
+}
+
+/// Signed contents of the WorkOS login `state` parameter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LoginClaims {
+    browser: String,  // SHA-256 of the nonce in the LOGIN_COOKIE of the browser that started the login
+    redirect: String, // Where to send the browser afterwards
+    aud: String,
+    exp: usize,
+    iat: usize,
+}
+
+/// Start a WorkOS login: the signed state, and the nonce to set as LOGIN_COOKIE.
+/// Only the browser holding the cookie can finish the login, so nobody can slip a victim
+/// a callback link that signs them into someone else's account.
+pub fn issue_login(redirect: &str) -> Result<(String, String), String> {
+    let nonce = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let now = Utc::now();
+    let state = jwt::KeySet::from_env()?.sign(&LoginClaims {
+        browser: nonce_digest(&nonce),
+        redirect: redirect.to_string(),
+        aud: LOGIN_AUDIENCE.to_string(),
+        exp: (now + Duration::seconds(STATE_TTL_SECS)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+    })?;
+    Ok((state, nonce))
+}
+
+/// Check a WorkOS login state against the LOGIN_COOKIE nonce of the browser it came back to.
+/// Returns where to send the browser.
+pub fn verify_login(state: &str, nonce: Option<&str>) -> Result<String, String> {
+    verify_login_with(&*jwt::KeySet::from_env()?, state, nonce)
+}
+
+fn verify_login_with(keys: &jwt::KeySet, state: &str, nonce: Option<&str>) -> Result<String, String> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[LOGIN_AUDIENCE]);
+    validation.leeway = 0;
+
+    let claims = keys.verify::<LoginClaims>(state, &validation).map_err(|e| format!("Invalid state: {}", e))?;
+    match nonce {
+        Some(nonce) if nonce_digest(nonce) == claims.browser => Ok(claims.redirect),
+        _ => Err("Login was started in another browser".to_string()),
+    }
+}
+
+/// SHA-256 of a login nonce. Nonces are random, so a fast hash is enough.
+fn nonce_digest(nonce: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, nonce.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn sign(keys: &jwt::KeySet, claims: &StateClaims) -> Result<String, String> {
+    keys.sign(claims)
+}
+
+/// Check signature, audience and expiry of a state token
+fn verify(keys: &jwt::KeySet, state: &str) -> Result<StateClaims, String> {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[STATE_AUDIENCE]);
+    validation.leeway = 0;
+
+    keys.verify::<StateClaims>(state, &validation)
+        .map_err(|e| format!("Invalid state: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(exp_offset: i64) -> StateClaims {
+        let now = Utc::now().timestamp();
+        StateClaims {
+            jti: "state-1".to_string(),
+            sub: "user_1".to_string(),
+            provider: "google".to_string(),
+            redirect: Some("http://localhost:5173".to_string()),
+            aud: STATE_AUDIENCE.to_string(),
+            exp: (now + exp_offset) as usize,
+            iat: now as usize,
+        }
+    }
+
+    fn keys() -> jwt::KeySet {
+        jwt::KeySet::parse(None, Some("test-secret-test-secret-test-secret".to_string()), None).unwrap()
+    }
+
+    #[test]
+    fn valid_state_round_trips() {
+        let state = sign(&keys(), &claims(60)).unwrap();
+        let verified = verify(&keys(), &state).unwrap();
+
+        assert_eq!(verified.sub, "user_1");
+        assert_eq!(verified.provider, "google");
+        assert_eq!(verified.redirect.as_deref(), Some("http://localhost:5173"));
+    }
+
+    #[test]
+    fn tampered_state_is_rejected() {
+        let state = sign(&keys(), &claims(60)).unwrap();
+        let other = sign(&keys(), &StateClaims { sub: "victim".to_string(), ..claims(60) }).unwrap();
+
+        // Payload of one state with the signature of another
+        let parts: Vec<&str> = state.split('.').collect();
+        let spliced = format!("{}.{}.{}", parts[0], other.split('.').nth(1).unwrap(), parts[2]);
+        assert!(verify(&keys(), &spliced).is_err());
+
+        // The old unsigned format
+        assert!(verify(&keys(), "user_1:google").is_err());
+    }
+
+    #[test]
+    fn expired_state_is_rejected() {
+        let state = sign(&keys(), &claims(-1)).unwrap();
+        assert!(verify(&keys(), &state).is_err());
+    }
+
+    #[test]
+    fn login_states_only_verify_in_the_browser_that_started_them() {
+        let now = Utc::now().timestamp();
+        let login = |nonce: &str| LoginClaims {
+            browser: nonce_digest(nonce),
+            redirect: "http://localhost:5173".to_string(),
+            aud: LOGIN_AUDIENCE.to_string(),
+            exp: (now + 60) as usize,
+            iat: now as usize,
+        };
+        let state = keys().sign(&login("nonce-1")).unwrap();
+
+        assert_eq!(verify_login_with(&keys(), &state, Some("nonce-1")).unwrap(), "http://localhost:5173");
+        assert!(verify_login_with(&keys(), &state, Some("nonce-2")).is_err());
+        assert!(verify_login_with(&keys(), &state, None).is_err());
+        assert!(verify_login_with(&keys(), "authkit_login|http://localhost:5173", Some("nonce-1")).is_err());
+
+        // Neither kind of state passes for the other
+        assert!(verify(&keys(), &state).is_err());
+        let oauth = sign(&keys(), &claims(60)).unwrap();
+        assert!(verify_login_with(&keys(), &oauth, Some("nonce-1")).is_err());
+    }
+
+    #[test]
+    fn login_tokens_are_not_states() {
+        let token = keys().sign(&jwt::login_claims("user_1", None)).unwrap();
+        assert!(verify(&keys(), &token).is_err());
+
+        let state = sign(&keys(), &claims(60)).unwrap();
+        assert!(jwt::validate_with(&keys(), &state).is_err());
+    }
+}
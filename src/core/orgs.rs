@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+
+/// What a member may do in an organization. Each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Role {
+    #[serde(rename = "read-only")]
+    ReadOnly, // Read shared inboxes
+    #[serde(rename = "member")]
+    Member,   // ...and manage shared aliases and the state of shared mail
+    #[serde(rename = "admin")]
+    Admin,    // ...and manage members and organization API keys
+    #[serde(rename = "owner")]
+    Owner,    // ...and manage owners
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::ReadOnly => "read-only",
+            Role::Member => "member",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "read-only" => Some(Role::ReadOnly),
+            "member" => Some(Role::Member),
+            "admin" => Some(Role::Admin),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+}
+
+/// An organization as seen by one of its members
+#[derive(Debug, Serialize)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Member {
+    pub user_id: String,
+    pub email: Option<String>,
+    pub role: Role,
+    pub created_at: Option<String>,
+}
+
+/// Why a membership change was refused
+#[derive(Debug)]
+pub enum MemberError {
+    NotAllowed,     // The acting member's role can't make this change
+    LastOwner,      // Every organization keeps at least one owner
+    UnknownUser,
+    Internal(String),
+}
+
+impl std::fmt::Display for MemberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemberError::NotAllowed => write!(f, "Your role can't make this membership change"),
+            MemberError::LastOwner => write!(f, "An organization must keep at least one owner"),
+            MemberError::UnknownUser => write!(f, "User not found"),
+            MemberError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for MemberError {
+    fn from(e: sqlx::Error) -> Self {
+        MemberError::Internal(format!("DB error: {}", e))
+    }
+}
+
+/// Whether `actor` may move a member from `current` to `new` (None: not a member / removed).
+/// Admins manage everyone below owner; only owners touch the owner role.
+pub fn can_change(actor: Role, current: Option<Role>, new: Option<Role>) -> bool {
+    let limit = match actor {
+        Role::Owner => Role::Owner,
+        Role::Admin => Role::Admin,
+        _ => return false,
+    };
+    current.is_none_or(|r| r <= limit) && new.is_none_or(|r| r <= limit)
+}
+
+/// Create an organization, with its creator as owner
+pub async fn create(pool: &PgPool, owner_id: &str, name: &str) -> Result<Organization, sqlx::Error> {
+    let org_id = uuid::Uuid::new_v4().to_string();
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query("INSERT INTO organizations (id, name) VALUES ($1, $2) RETURNING created_at::text")
+        .bind(&org_id)
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query("INSERT INTO organization_members (org_id, user_id, role) VALUES ($1, $2, $3)")
+        .bind(&org_id)
+        .bind(owner_id)
+        .bind(Role::Owner.as_str())
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(Organization { id: org_id, name: name.to_string(), role: Role::Owner, created_at: row.get("created_at") })
+}
+
+/// Organizations a user belongs to, with their role in each
+pub async fn list_for_user(pool: &PgPool, user_id: &str) -> Result<Vec<Organization>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT o.id, o.name, m.role, o.created_at::text
+        FROM organizations o
+        JOIN organization_members m ON m.org_id = o.id
+        WHERE m.user_id = $1
+        ORDER BY o.created_at
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().filter_map(|row| Some(Organization {
+        id: row.get("id"),
+        name: row.get("name"),
+        role: Role::parse(row.get("role"))?,
+        created_at: row.get("created_at"),
+    })).collect())
+}
+
+/// A user's role in an organization, None if they are not a member
+pub async fn role_of(pool: &PgPool, org_id: &str, user_id: &str) -> Result<Option<Role>, sqlx::Error> {
+    let row = sqlx::query("SELECT role FROM organization_members WHERE org_id = $1 AND user_id = $2")
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.and_then(|r| Role::parse(r.get("role"))))
+}
+
+pub async fn members(pool: &PgPool, org_id: &str) -> Result<Vec<Member>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT m.user_id, u.email, m.role, m.created_at::text
+        FROM organization_members m
+        LEFT JOIN users u ON u.id = m.user_id
+        WHERE m.org_id = $1
+        ORDER BY m.created_at
+        "#
+    )
+    .bind(org_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.iter().filter_map(|row| Some(Member {
+        user_id: row.get("user_id"),
+        email: row.get("email"),
+        role: Role::parse(row.get("role"))?,
+        created_at: row.get("created_at"),
+    })).collect())
+}
+
+/// Add a member or change their role (`new` = None removes them), on behalf of `actor`.
+/// Members may always remove themselves, as long as someone else still owns the organization.
+pub async fn change_member(
+    pool: &PgPool,
+    org_id: &str,
+    actor_id: &str,
+    user_id: &str,
+    new: Option<Role>,
+) -> Result<(), MemberError> {
+    let mut tx = pool.begin().await?;
+
+    // Serialize membership changes per organization so two owners can't demote each other at once
+    sqlx::query("SELECT id FROM organizations WHERE id = $1 FOR UPDATE")
+        .bind(org_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let actor = locked_role(&mut tx, org_id, actor_id).await?.ok_or(MemberError::NotAllowed)?;
+    let current = locked_role(&mut tx, org_id, user_id).await?;
+
+    let leaving = actor_id == user_id && new.is_none();
+    if !leaving && !can_change(actor, current, new) {
+        return Err(MemberError::NotAllowed);
+    }
+
+    if current == Some(Role::Owner) && new != Some(Role::Owner) {
+        let owners: i64 = sqlx::query("SELECT COUNT(*) AS n FROM organization_members WHERE org_id = $1 AND role = 'owner'")
+            .bind(org_id)
+            .fetch_one(&mut *tx)
+            .await?
+            .get("n");
+        if owners <= 1 {
+            return Err(MemberError::LastOwner);
+        }
+    }
+
+    match new {
+        Some(role) => {
+            let known = sqlx::query("SELECT 1 FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(&mut *tx)
+                .await?;
+            if known.is_none() {
+                return Err(MemberError::UnknownUser);
+            }
+
+            sqlx::query(
+                r#"
+                INSERT INTO organization_members (org_id, user_id, role) VALUES ($1, $2, $3)
+                ON CONFLICT (org_id, user_id) DO UPDATE SET role = EXCLUDED.role
+                "#
+            )
+            .bind(org_id)
+            .bind(user_id)
+            .bind(role.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM organization_members WHERE org_id = $1 AND user_id = $2")
+                .bind(org_id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Link an organization to a WorkOS organization (None unlinks it).
+/// Users who sign in through that WorkOS organization's SSO then join with `sso_role`.
+/// Returns false if the WorkOS organization is already linked elsewhere.
+pub async fn link_sso(pool: &PgPool, org_id: &str, workos_organization_id: Option<&str>, sso_role: Role) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE organizations SET workos_organization_id = $1, sso_role = $2 WHERE id = $3")
+        .bind(workos_organization_id)
+        .bind(sso_role.as_str())
+        .bind(org_id)
+        .execute(pool)
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Add an SSO user to the organization linked to their WorkOS organization.
+/// Existing members keep their role. Returns the organization id, None if nothing is linked.
+pub async fn provision(pool: &PgPool, workos_organization_id: &str, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query("SELECT id, sso_role FROM organizations WHERE workos_organization_id = $1")
+        .bind(workos_organization_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some(row) = row else { return Ok(None) };
+
+    let org_id: String = row.get("id");
+    let role = Role::parse(row.get("sso_role")).unwrap_or(Role::Member);
+    sqlx::query(
+        r#"
+        INSERT INTO organization_members (org_id, user_id, role) VALUES ($1, $2, $3)
+        ON CONFLICT (org_id, user_id) DO NOTHING
+        "#
+    )
+    .bind(&org_id)
+    .bind(user_id)
+    .bind(role.as_str())
+    .execute(pool)
+    .await?;
+
+    Ok(Some(org_id))
+}
+
+async fn locked_role(tx: &mut sqlx::PgConnection, org_id: &str, user_id: &str) -> Result<Option<Role>, sqlx::Error> {
+    let row = sqlx::query("SELECT role FROM organization_members WHERE org_id = $1 AND user_id = $2")
+        .bind(org_id)
+        .bind(user_id)
+        .fetch_optional(tx)
+        .await?;
+
+    Ok(row.and_then(|r| Role::parse(r.get("role"))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_ordered_by_power() {
+        assert!(Role::ReadOnly < Role::Member);
+        assert!(Role::Member < Role::Admin);
+        assert!(Role::Admin < Role::Owner);
+        for role in [Role::ReadOnly, Role::Member, Role::Admin, Role::Owner] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+    }
+
+    #[test]
+    fn only_owners_touch_owners() {
+        assert!(can_change(Role::Owner, None, Some(Role::Owner)));
+        assert!(can_change(Role::Owner, Some(Role::Owner), Some(Role::Member)));
+        assert!(can_change(Role::Admin, None, Some(Role::Admin)));
+        assert!(can_change(Role::Admin, Some(Role::Member), None));
+
+        assert!(!can_change(Role::Admin, None, Some(Role::Owner)));
+        assert!(!can_change(Role::Admin, Some(Role::Owner), Some(Role::ReadOnly)));
+        assert!(!can_change(Role::Admin, Some(Role::Owner), None));
+    }
+
+    #[test]
+    fn members_manage_nobody() {
+        assert!(!can_change(Role::Member, None, Some(Role::ReadOnly)));
+        assert!(!can_change(Role::ReadOnly, Some(Role::ReadOnly), None));
+    }
+}
@@ -0,0 +1,442 @@
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::fmt;
+use std::sync::Mutex;
+use crate::core::ingest::{self, FetchFailure, ParsedEmail};
+use crate::core::crypto::SealedSecret;
+use crate::core::{gmail_api, graph_api, imap_client, oauth};
+
+/// Why a provider couldn't list a mailbox or fetch a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProviderError {
+    /// The provider refused the access token: refreshing it may help
+    Unauthorized(String),
+    /// The message is gone or can't be read (deleted since it was listed, malformed): retrying won't help
+    Unreadable(String),
+    Failed(String),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProviderError::Unauthorized(e) | ProviderError::Unreadable(e) | ProviderError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for ProviderError {
+    fn from(e: String) -> Self {
+        ProviderError::Failed(e)
+    }
+}
+
+impl From<&str> for ProviderError {
+    fn from(e: &str) -> Self {
+        ProviderError::Failed(e.to_string())
+    }
+}
+
+/// A message a provider reports as new since the last cursor
+#[derive(Debug, Clone)]
+pub struct MessageRef {
+    pub id: String,
+    pub received_at: Option<i64>, // Unix seconds, when the listing knows it
+    pub cursor: Option<String>,   // Resume point once this and every older listed message are stored
+}
+
+/// New messages plus the cursor to resume from on the next sync, once all of them are stored
+#[derive(Debug, Clone, Default)]
+pub struct MessageList {
+    pub messages: Vec<MessageRef>,
+    pub cursor: Option<String>,
+}
+
+/// A full RFC822 message as downloaded from the provider
+#[derive(Debug, Clone)]
+pub struct RawMessage {
+    pub data: Vec<u8>,
+    pub received_at: Option<i64>,
+}
+
+/// A fetched and parsed message, whatever backend it came from
+#[derive(Debug, Clone)]
+pub struct FetchedEmail {
+    pub message_id: String, // Provider message id (Gmail id, Graph id, IMAP UID)
+    pub received_at: i64,   // Timestamp in seconds
+    pub email: ParsedEmail,
+}
+
+/// Result of one sync round against a provider
+#[derive(Debug, Clone, Default)]
+pub struct SyncBatch {
+    pub emails: Vec<FetchedEmail>,
+    pub failures: Vec<FetchFailure>,
+    pub listed: Vec<MessageRef>, // Everything listed, newest first, whether fetched or not
+    pub cursor: Option<String>,
+}
+
+impl SyncBatch {
+    /// Where the next sync should resume, given which messages ended up stored: just after the
+    /// newest one with nothing older missing, so a message that failed is listed again.
+    /// Messages that failed for good are passed over, or they would hold back everything newer.
+    /// None keeps the current cursor.
+    pub fn resume_cursor(&self, stored: impl Fn(&str) -> bool) -> Option<String> {
+        let unreadable = |id: &str| self.failures.iter().any(|f| f.permanent && f.message_id == id);
+        let mut resume = None;
+        for message in self.listed.iter().rev() {
+            if !stored(&message.id) && !unreadable(&message.id) {
+                return resume;
+            }
+            resume = message.cursor.clone();
+        }
+        self.cursor.clone()
+    }
+}
+
+/// Everything stored on a `users` row that a provider may need.
+/// Secrets stay sealed until the provider opens them.
+#[derive(Debug, Clone, Default)]
+pub struct MailAccount {
+    pub email: String,
+    pub auth_provider: Option<String>,
+    pub imap_server: Option<String>,
+    pub imap_port: i32,
+    pub imap_password: Option<SealedSecret>,
+    pub access_token: Option<SealedSecret>,
+    pub refresh_token: Option<SealedSecret>,
+}
+
+/// A mail backend (IMAP, Gmail API, Microsoft Graph, ...)
+#[async_trait]
+pub trait MailProvider: Send + Sync {
+    /// Provider name, as stored in `users.auth_provider` terms
+    fn name(&self) -> &'static str;
+
+    /// How many `fetch_raw` calls may run at once
+    fn max_concurrency(&self) -> usize {
+        1
+    }
+
+    /// Open a session (no-op for stateless HTTP APIs)
+    async fn connect(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// List messages that arrived after `cursor` (None = first sync), newest first.
+    /// At most `limit` of them: with a larger backlog, the oldest, and a cursor that resumes
+    /// after them. Each carries the cursor to resume from once it and the older ones are stored.
+    async fn list_new_messages(&self, cursor: Option<&str>, limit: usize) -> Result<MessageList, ProviderError>;
+
+    /// Download one message as raw RFC822 bytes
+    async fn fetch_raw(&self, message: &MessageRef) -> Result<RawMessage, ProviderError>;
+
+    /// Refresh expiring credentials, returning new tokens to persist
+    async fn refresh_credentials(&self) -> Result<Option<oauth::OAuthTokens>, String> {
+        Ok(None)
+    }
+
+    /// Close the session, if any
+    async fn disconnect(&self) {}
+}
+
+/// Pick the backend for an account. This is the only place that branches on `auth_provider`.
+pub fn for_account(account: &MailAccount) -> Result<Box<dyn MailProvider>, String> {
+    let provider = account.auth_provider.as_deref();
+    let token = account.access_token.clone();
+
+    match (provider, token) {
+        // Also allow WorkOS users who connected Gmail
+        (Some("google") | Some("gmail_connect") | Some("workos"), Some(token)) => Ok(Box::new(
+            gmail_api::GmailProvider::new(token, account.refresh_token.clone()),
+        )),
+        (Some("microsoft"), Some(token)) => Ok(Box::new(
+            graph_api::GraphProvider::new(token, account.refresh_token.clone()),
+        )),
+        _ => match (&account.imap_password, &account.imap_server) {
+            (Some(password), Some(server)) => Ok(Box::new(imap_client::ImapProvider::new(
+                imap_client::ImapCredentials {
+                    email: account.email.clone(),
+                    password: Some(password.clone()),
+                    access_token: None,
+                    server: server.clone(),
+                    port: account.imap_port as u16,
+                },
+            ))),
+            _ => Err("No credentials configured. Use OAuth or set IMAP password.".to_string()),
+        },
+    }
+}
+
+/// List, download and parse new messages from any provider
+pub async fn sync_mailbox(
+    provider: &dyn MailProvider,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<SyncBatch, ProviderError> {
+    provider.connect().await?;
+
+    let listed = match provider.list_new_messages(cursor, limit).await {
+        Ok(listed) => listed,
+        Err(e) => {
+            provider.disconnect().await;
+            return Err(e);
+        }
+    };
+
+    let results: Vec<Result<FetchedEmail, FetchFailure>> = stream::iter(listed.messages.iter().cloned())
+        .map(|msg_ref: MessageRef| async move {
+            let raw = provider
+                .fetch_raw(&msg_ref)
+                .await
+                .map_err(|error| FetchFailure {
+                    message_id: msg_ref.id.clone(),
+                    permanent: matches!(error, ProviderError::Unreadable(_)),
+                    error: error.to_string(),
+                })?;
+
+            // The same bytes will never parse on a later attempt either
+            let email = ingest::parse_email(&raw.data).ok_or_else(|| FetchFailure {
+                message_id: msg_ref.id.clone(),
+                error: "Failed to parse message".to_string(),
+                permanent: true,
+            })?;
+
+            let received_at = msg_ref.received_at
+                .or(raw.received_at)
+                .or(email.received_at)
+                .unwrap_or_else(|| chrono::Utc::now().timestamp());
+
+            Ok(FetchedEmail {
+                message_id: msg_ref.id.clone(),
+                received_at,
+                email,
+            })
+        })
+        .buffered(provider.max_concurrency().max(1))
+        .collect()
+        .await;
+
+    provider.disconnect().await;
+
+    let mut batch = SyncBatch {
+        listed: listed.messages,
+        cursor: listed.cursor,
+        ..Default::default()
+    };
+    for result in results {
+        match result {
+            Ok(email) => batch.emails.push(email),
+            Err(failure) => batch.failures.push(failure),
+        }
+    }
+
+    Ok(batch)
+}
+
+/// Provider backed by a list of raw messages, for tests and local development.
+/// The cursor is the number of messages already seen.
+#[derive(Default)]
+pub struct InMemoryProvider {
+    messages: Mutex<Vec<(String, Vec<u8>)>>,
+    failing: Mutex<Vec<String>>,
+    lost: Mutex<Vec<String>>,
+    token_expired: Mutex<bool>,
+}
+
+impl InMemoryProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver a raw RFC822 message to the mailbox
+    pub fn push(&self, id: &str, raw: &[u8]) {
+        self.messages.lock().unwrap().push((id.to_string(), raw.to_vec()));
+    }
+
+    /// Make `fetch_raw` fail for a message id
+    pub fn fail_on(&self, id: &str) {
+        self.failing.lock().unwrap().push(id.to_string());
+    }
+
+    /// Keep listing a message but answer that it is gone when fetched, as when it was deleted in between
+    pub fn lose(&self, id: &str) {
+        self.lost.lock().unwrap().push(id.to_string());
+    }
+
+    /// Refuse the access token until the credentials are refreshed
+    pub fn expire_token(&self) {
+        *self.token_expired.lock().unwrap() = true;
+    }
+}
+
+#[async_trait]
+impl MailProvider for InMemoryProvider {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn list_new_messages(&self, cursor: Option<&str>, limit: usize) -> Result<MessageList, ProviderError> {
+        if *self.token_expired.lock().unwrap() {
+            return Err(ProviderError::Unauthorized("Access token expired".to_string()));
+        }
+        let messages = self.messages.lock().unwrap();
+        let seen = match cursor {
+            Some(c) => c.parse::<usize>().map_err(|_| format!("Invalid cursor: {}", c))?,
+            None => 0,
+        };
+
+        let new: Vec<MessageRef> = messages
+            .iter()
+            .enumerate()
+            .skip(seen)
+            .take(limit)
+            .map(|(i, (id, _))| MessageRef { id: id.clone(), received_at: None, cursor: Some((i + 1).to_string()) })
+            .collect();
+
+        Ok(MessageList {
+            cursor: Some((seen + new.len()).to_string()),
+            messages: new.into_iter().rev().collect(),
+        })
+    }
+
+    async fn fetch_raw(&self, message: &MessageRef) -> Result<RawMessage, ProviderError> {
+        if self.failing.lock().unwrap().contains(&message.id) {
+            return Err(format!("Simulated failure for {}", message.id).into());
+        }
+        if self.lost.lock().unwrap().contains(&message.id) {
+            return Err(ProviderError::Unreadable(format!("Message {} not found", message.id)));
+        }
+
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(id, _)| *id == message.id)
+            .map(|(_, data)| RawMessage { data: data.clone(), received_at: None })
+            .ok_or_else(|| ProviderError::Unreadable(format!("Message {} not found", message.id)))
+    }
+
+    async fn refresh_credentials(&self) -> Result<Option<oauth::OAuthTokens>, String> {
+        *self.token_expired.lock().unwrap() = false;
+        Ok(Some(oauth::OAuthTokens {
+            access_token: "refreshed-access-token".to_string(),
+            refresh_token: None,
+            expires_in: Some(3600),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::crypto::KeyRing;
+
+    fn raw(subject: &str, body: &str) -> Vec<u8> {
+        format!(
+            "From: Sender <sender@example.com>\r\nSubject: {}\r\nDate: Mon, 6 Oct 2025 10:00:00 +0000\r\n\r\n{}\r\n",
+            subject, body
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn first_sync_fetches_newest_first() {
+        let provider = InMemoryProvider::new();
+        provider.push("1", &raw("Welcome", "Hello"));
+        provider.push("2", &raw("Your code", "Your verification code is 482913"));
+
+        let batch = sync_mailbox(&provider, None, 10).await.unwrap();
+
+        assert_eq!(batch.cursor.as_deref(), Some("2"));
+        assert_eq!(batch.emails.len(), 2);
+        assert_eq!(batch.emails[0].message_id, "2");
+        assert_eq!(batch.emails[0].email.sender, "sender@example.com");
+        assert_eq!(batch.emails[0].email.otp.as_deref(), Some("482913"));
+        assert_eq!(batch.emails[1].received_at, 1759744800);
+    }
+
+    #[tokio::test]
+    async fn cursor_only_returns_new_messages() {
+        let provider = InMemoryProvider::new();
+        provider.push("1", &raw("Old", "old"));
+
+        let first = sync_mailbox(&provider, None, 10).await.unwrap();
+        provider.push("2", &raw("New", "new"));
+        let second = sync_mailbox(&provider, first.cursor.as_deref(), 10).await.unwrap();
+
+        assert_eq!(second.emails.len(), 1);
+        assert_eq!(second.emails[0].email.subject, "New");
+        assert_eq!(second.cursor.as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn fetch_failures_are_reported() {
+        let provider = InMemoryProvider::new();
+        provider.push("1", &raw("Ok", "ok"));
+        provider.push("2", &raw("Broken", "broken"));
+        provider.push("3", &raw("Ok too", "ok"));
+        provider.fail_on("2");
+
+        let batch = sync_mailbox(&provider, None, 10).await.unwrap();
+
+        assert_eq!(batch.emails.len(), 2);
+        assert_eq!(batch.failures.len(), 1);
+        assert_eq!(batch.failures[0].message_id, "2");
+
+        // The next sync starts over at the failed message
+        let fetched = |id: &str| batch.emails.iter().any(|e| e.message_id == id);
+        assert_eq!(batch.resume_cursor(fetched).as_deref(), Some("1"));
+        assert_eq!(batch.resume_cursor(|id| id != "1"), None);
+        assert_eq!(batch.resume_cursor(|_| true).as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn messages_that_fail_for_good_are_passed_over() {
+        let provider = InMemoryProvider::new();
+        provider.push("1", &raw("Ok", "ok"));
+        provider.push("2", &raw("Deleted", "deleted"));
+        provider.push("3", b"");
+        provider.push("4", &raw("Ok too", "ok"));
+        provider.lose("2");
+
+        let batch = sync_mailbox(&provider, None, 10).await.unwrap();
+
+        let failed: Vec<_> = batch.failures.iter().map(|f| (f.message_id.as_str(), f.permanent)).collect();
+        assert_eq!(failed, vec![("3", true), ("2", true)]);
+        let fetched = |id: &str| batch.emails.iter().any(|e| e.message_id == id);
+        assert_eq!(batch.resume_cursor(fetched).as_deref(), Some("4"));
+        // A message newer than them that failed for now still holds the cursor back
+        assert_eq!(batch.resume_cursor(|id| id == "1").as_deref(), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn backlogs_are_worked_off_oldest_first() {
+        let provider = InMemoryProvider::new();
+        for i in 1..=5 {
+            provider.push(&i.to_string(), &raw("Mail", "mail"));
+        }
+
+        let first = sync_mailbox(&provider, None, 2).await.unwrap();
+        let ids: Vec<_> = first.emails.iter().map(|e| e.message_id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "1"]);
+        assert_eq!(first.cursor.as_deref(), Some("2"));
+
+        let second = sync_mailbox(&provider, first.cursor.as_deref(), 2).await.unwrap();
+        let ids: Vec<_> = second.emails.iter().map(|e| e.message_id.as_str()).collect();
+        assert_eq!(ids, vec!["4", "3"]);
+    }
+
+    #[test]
+    fn accounts_without_credentials_are_rejected() {
+        let account = MailAccount { email: "a@example.com".to_string(), ..Default::default() };
+        assert!(for_account(&account).is_err());
+
+        let keys = std::sync::Arc::new(KeyRing::parse("k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=", None).unwrap());
+        let account = MailAccount {
+            auth_provider: Some("microsoft".to_string()),
+            access_token: Some(SealedSecret::new(keys, "token".to_string(), "ctx".to_string())),
+            ..Default::default()
+        };
+        assert_eq!(for_account(&account).unwrap().name(), "microsoft");
+    }
+}
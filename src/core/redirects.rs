@@ -0,0 +1,86 @@
+use std::env;
+
+/// Frontend origins used when ALLOWED_REDIRECT_ORIGINS is not set
+const DEFAULT_ORIGINS: [&str; 4] = [
+    "http://localhost:5173",
+    "http://127.0.0.1:5173",
+    "https://mail.rapidxoxo.dpdns.org",
+    "https://rapidxoxo.dpdns.org",
+];
+
+/// Origins the auth and connect flows may send the browser back to.
+/// `ALLOWED_REDIRECT_ORIGINS` is a comma separated list like `https://app.example.com,http://localhost:5173`.
+pub fn allowed_origins() -> Vec<String> {
+    match env::var("ALLOWED_REDIRECT_ORIGINS") {
+        Ok(list) => list
+            .split(',')
+            .map(|o| o.trim().trim_end_matches('/').to_string())
+            .filter(|o| !o.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_ORIGINS.iter().map(|o| o.to_string()).collect(),
+    }
+}
+
+/// Where to go when the client did not ask for a redirect
+pub fn default_redirect() -> String {
+    allowed_origins()
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| DEFAULT_ORIGINS[0].to_string())
+}
+
+/// Validate a client supplied redirect target against the allowlist.
+/// Returns the URL without a trailing slash, ready to append a path to.
+pub fn validate(redirect: Option<&str>) -> Result<String, String> {
+    match redirect {
+        Some(target) => check(target, &allowed_origins()),
+        None => Ok(default_redirect()),
+    }
+}
+
+fn check(target: &str, allowed: &[String]) -> Result<String, String> {
+    let parsed = url::Url::parse(target).map_err(|_| format!("Invalid redirect URL: {}", target))?;
+
+    // `https://allowed.example@evil.example` parses with allowed.example as the username
+    if !parsed.username().is_empty() || parsed.password().is_some() {
+        return Err("Redirect URL must not contain credentials".to_string());
+    }
+
+    let origin = parsed.origin().ascii_serialization();
+    if !matches!(parsed.scheme(), "http" | "https") || !allowed.contains(&origin) {
+        return Err(format!("Redirect origin not allowed: {}", origin));
+    }
+
+    Ok(target.trim_end_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        vec!["https://app.example.com".to_string(), "http://localhost:5173".to_string()]
+    }
+
+    #[test]
+    fn allowed_origins_pass() {
+        assert_eq!(check("https://app.example.com/", &allowed()).unwrap(), "https://app.example.com");
+        assert_eq!(check("http://localhost:5173/inbox", &allowed()).unwrap(), "http://localhost:5173/inbox");
+    }
+
+    #[test]
+    fn other_origins_are_rejected() {
+        for target in [
+            "https://evil.example",
+            "https://app.example.com.evil.example",
+            "https://app.example.com@evil.example",
+            "http://app.example.com",          // Scheme is part of the origin
+            "https://app.example.com:8443",    // So is the port
+            "javascript:alert(1)",
+            "//evil.example",
+            "/relative",
+        ] {
+            assert!(check(target, &allowed()).is_err(), "{} should be rejected", target);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Search query syntax: free text plus `from:`, `to:`, `subject:`, `has:otp`, `after:` and `before:`.
+//! Free text keeps Postgres web search syntax: `"exact phrase"`, `-excluded`, `or`.
+
+use chrono::{NaiveDate, NaiveDateTime};
+
+/// A parsed search; every part must match
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub text: String,         // Matched against the full-text index, ranked
+    pub from: Vec<String>,    // Substrings of the sender
+    pub to: Vec<String>,      // Substrings of a recipient or the alias
+    pub subject: Vec<String>, // Substrings of the subject
+    pub has_otp: bool,
+    pub after: Option<NaiveDateTime>,  // Inclusive
+    pub before: Option<NaiveDateTime>, // Exclusive
+}
+
+impl SearchQuery {
+    pub fn is_empty(&self) -> bool {
+        *self == SearchQuery::default()
+    }
+}
+
+/// Split on whitespace outside double quotes; quotes are kept
+fn tokens(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// A date (midnight UTC) or an RFC 3339 time
+fn parse_time(operator: &str, value: &str) -> Result<NaiveDateTime, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap_or_default());
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|t| t.naive_utc())
+        .map_err(|_| format!("{}: takes a date (YYYY-MM-DD) or an RFC 3339 time", operator))
+}
+
+pub fn parse(input: &str) -> Result<SearchQuery, String> {
+    let mut query = SearchQuery::default();
+    let mut text = Vec::new();
+
+    for token in tokens(input) {
+        let Some((operator, value)) = token.split_once(':').filter(|(op, _)| !op.contains('"')) else {
+            text.push(token);
+            continue;
+        };
+        let value = value.trim_matches('"');
+        let op = operator.to_ascii_lowercase();
+        if matches!(op.as_str(), "from" | "to" | "subject" | "has" | "after" | "before") && value.is_empty() {
+            return Err(format!("{}: needs a value", operator));
+        }
+
+        match op.as_str() {
+            "from" => query.from.push(value.to_string()),
+            "to" => query.to.push(value.to_string()),
+            "subject" => query.subject.push(value.to_string()),
+            "has" if value.eq_ignore_ascii_case("otp") => query.has_otp = true,
+            "has" => return Err(format!("Unknown has:{}, only has:otp is supported", value)),
+            "after" => query.after = Some(parse_time(operator, value)?),
+            "before" => query.before = Some(parse_time(operator, value)?),
+            _ => text.push(token), // "re:", "https://..." are plain text
+        }
+    }
+
+    query.text = text.join(" ");
+    Ok(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operators_are_split_from_free_text() {
+        let query = parse(r#"invoice from:stripe to:temp_shop subject:"payment received" has:otp after:2024-05-01"#).unwrap();
+
+        assert_eq!(query.text, "invoice");
+        assert_eq!(query.from, vec!["stripe"]);
+        assert_eq!(query.to, vec!["temp_shop"]);
+        assert_eq!(query.subject, vec!["payment received"]);
+        assert!(query.has_otp);
+        assert_eq!(query.after.unwrap().to_string(), "2024-05-01 00:00:00");
+        assert_eq!(query.before, None);
+    }
+
+    #[test]
+    fn web_search_syntax_is_kept_in_free_text() {
+        let query = parse(r#""exact phrase" -newsletter receipt or invoice"#).unwrap();
+        assert_eq!(query.text, r#""exact phrase" -newsletter receipt or invoice"#);
+
+        // Not an operator we know, nor one inside quotes
+        let query = parse(r#"https://stripe.com "note: read""#).unwrap();
+        assert_eq!(query.text, r#"https://stripe.com "note: read""#);
+    }
+
+    #[test]
+    fn operators_repeat_and_ignore_case() {
+        let query = parse("FROM:alice From:bob before:2024-06-01T12:00:00Z").unwrap();
+        assert_eq!(query.from, vec!["alice", "bob"]);
+        assert_eq!(query.before.unwrap().to_string(), "2024-06-01 12:00:00");
+        assert!(query.text.is_empty());
+    }
+
+    #[test]
+    fn bad_operator_values_are_rejected() {
+        assert!(parse("has:attachment").is_err());
+        assert!(parse("after:last-week").is_err());
+        assert!(parse("from:").is_err());
+        assert!(parse("   ").unwrap().is_empty());
+    }
+}
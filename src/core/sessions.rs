@@ -0,0 +1,226 @@
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use crate::core::crypto::KeyRing;
+use crate::core::jwt;
+use crate::core::workos_auth;
+use crate::core::workos_sessions;
+
+/// How long a session lasts without being refreshed
+const REFRESH_TTL_DAYS: i32 = 30;
+
+/// Tokens handed to a client when a session starts or is refreshed
+#[derive(Debug, Serialize)]
+pub struct SessionTokens {
+    pub token: String,          // Short-lived access JWT
+    pub refresh_token: String,  // `<session id>.<secret>`, single use
+    pub expires_in: i64,
+}
+
+/// Why a refresh was refused
+#[derive(Debug)]
+pub enum RefreshError {
+    Invalid,
+    Reused, // A rotated-out token came back: assume it leaked and end the session
+    Ended,  // The WorkOS session behind it was revoked
+    Internal(String),
+}
+
+impl std::fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshError::Invalid => write!(f, "Invalid or expired refresh token"),
+            RefreshError::Reused => write!(f, "Refresh token reuse detected, session revoked"),
+            RefreshError::Ended => write!(f, "Session was ended by the identity provider"),
+            RefreshError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(e: sqlx::Error) -> Self {
+        RefreshError::Internal(format!("DB error: {}", e))
+    }
+}
+
+/// Start a login session for a user.
+/// A session started through WorkOS is tied to the WorkOS session and ends with it.
+pub async fn start(pool: &PgPool, user_id: &str, workos_session_id: Option<&str>) -> Result<SessionTokens, String> {
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let secret = new_secret();
+    let refresh_hash = hash(secret.clone()).await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO auth_sessions (id, user_id, refresh_hash, workos_session_id, expires_at)
+        VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+        "#
+    )
+    .bind(&session_id)
+    .bind(user_id)
+    .bind(&refresh_hash)
+    .bind(workos_session_id)
+    .bind(REFRESH_TTL_DAYS)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("DB error: {}", e))?;
+
+    tokens(user_id, &session_id, &secret)
+}
+
+/// Trade a refresh token for a new access token and a new refresh token.
+/// The presented token stops working; presenting it again revokes the whole session.
+/// Any other wrong token is refused without touching the session.
+pub async fn refresh(pool: &PgPool, keys: &KeyRing, refresh_token: &str) -> Result<SessionTokens, RefreshError> {
+    let (session_id, secret) = refresh_token.split_once('.').ok_or(RefreshError::Invalid)?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT user_id, refresh_hash, workos_session_id FROM auth_sessions
+        WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        "#
+    )
+    .bind(session_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(RefreshError::Invalid)?;
+
+    let user_id: String = row.get("user_id");
+    let current_hash: String = row.get("refresh_hash");
+    let workos_session_id: Option<String> = row.get("workos_session_id");
+
+    if !verify(secret.to_string(), current_hash.clone()).await.map_err(RefreshError::Internal)? {
+        let used = sqlx::query("SELECT 1 FROM auth_session_used_tokens WHERE session_id = $1 AND token_hash = $2")
+            .bind(session_id)
+            .bind(used_hash(secret))
+            .fetch_optional(pool)
+            .await?;
+        if used.is_none() {
+            return Err(RefreshError::Invalid);
+        }
+        revoke(pool, session_id).await?;
+        return Err(RefreshError::Reused);
+    }
+
+    // Ask WorkOS first, so a user disabled there can't keep renewing access here
+    if let Some(workos_session_id) = &workos_session_id {
+        match workos_sessions::refresh(pool, keys, workos_session_id).await {
+            Ok(()) => {}
+            Err(workos_auth::RefreshError::Ended) => {
+                revoke(pool, session_id).await?;
+                return Err(RefreshError::Ended);
+            }
+            Err(workos_auth::RefreshError::Failed(e)) => return Err(RefreshError::Internal(e)),
+        }
+    }
+
+    let next_secret = new_secret();
+    let next_hash = hash(next_secret.clone()).await.map_err(RefreshError::Internal)?;
+
+    // Compare-and-swap on the old hash, so two concurrent refreshes can't both win
+    let mut tx = pool.begin().await?;
+    let rotated = sqlx::query(
+        r#"
+        UPDATE auth_sessions
+        SET refresh_hash = $1,
+            last_refreshed_at = NOW(),
+            expires_at = NOW() + make_interval(days => $2)
+        WHERE id = $3 AND refresh_hash = $4 AND revoked_at IS NULL
+        "#
+    )
+    .bind(&next_hash)
+    .bind(REFRESH_TTL_DAYS)
+    .bind(session_id)
+    .bind(&current_hash)
+    .execute(&mut *tx)
+    .await?;
+
+    if rotated.rows_affected() == 0 {
+        return Err(RefreshError::Invalid);
+    }
+
+    // Remember the rotated-out token, so its reuse is recognized
+    sqlx::query("INSERT INTO auth_session_used_tokens (session_id, token_hash) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+        .bind(session_id)
+        .bind(used_hash(secret))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    tokens(&user_id, session_id, &next_secret).map_err(RefreshError::Internal)
+}
+
+/// End a session; its access tokens are rejected from now on.
+/// Returns the WorkOS session it was tied to, which the caller should end as well.
+pub async fn revoke(pool: &PgPool, session_id: &str) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query("UPDATE auth_sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL RETURNING workos_session_id")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.and_then(|r| r.get("workos_session_id")))
+}
+
+/// End every session tied to a WorkOS session
+pub async fn revoke_linked(pool: &PgPool, workos_session_id: &str) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("UPDATE auth_sessions SET revoked_at = NOW() WHERE workos_session_id = $1 AND revoked_at IS NULL")
+        .bind(workos_session_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// End every session of a user
+pub async fn revoke_all(pool: &PgPool, user_id: &str) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("UPDATE auth_sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Whether access tokens of this session are still accepted
+pub async fn is_active(pool: &PgPool, session_id: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query("SELECT 1 FROM auth_sessions WHERE id = $1 AND revoked_at IS NULL AND expires_at > NOW()")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+fn tokens(user_id: &str, session_id: &str, secret: &str) -> Result<SessionTokens, String> {
+    Ok(SessionTokens {
+        token: jwt::generate_token(user_id, Some(session_id))?,
+        refresh_token: format!("{}.{}", session_id, secret),
+        expires_in: jwt::access_ttl_secs(),
+    })
+}
+
+fn new_secret() -> String {
+    format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+/// SHA-256 of a rotated-out secret. Secrets are random, so a fast hash is enough to look them up.
+fn used_hash(secret: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, secret.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// bcrypt is deliberately slow, keep it off the async workers
+async fn hash(secret: String) -> Result<String, String> {
+    tokio::task::spawn_blocking(move || bcrypt::hash(secret, bcrypt::DEFAULT_COST))
+        .await
+        .map_err(|e| format!("Hash error: {}", e))?
+        .map_err(|e| format!("Hash error: {}", e))
+}
+
+async fn verify(secret: String, hash: String) -> Result<bool, String> {
+    tokio::task::spawn_blocking(move || bcrypt::verify(secret, &hash).unwrap_or(false))
+        .await
+        .map_err(|e| format!("Verification failed: {}", e))
+}
@@ -0,0 +1,710 @@
+//! Sender Policy Framework (RFC 7208): whether the connecting host may send mail for the
+//! domain of the envelope sender. `check` evaluates the domain's policy with macros,
+//! include and redirect, within the RFC's limits on DNS lookups.
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use crate::core::dns::{self, Resolver};
+use crate::core::headers;
+
+/// Mechanisms and modifiers that query DNS, over the whole evaluation (4.6.4)
+const MAX_LOOKUPS: usize = 10;
+
+/// Lookups that found nothing, before the policy is taken as broken
+const MAX_VOID_LOOKUPS: usize = 2;
+
+/// MX hosts or PTR names looked at for one term
+const MAX_NAMES: usize = 10;
+
+/// Longest name a macro may expand to; longer ones lose labels from the left
+const MAX_DOMAIN_LEN: usize = 253;
+
+/// A whole check gives up after this, as a temporary error (4.6.4)
+const DEADLINE: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpfResult {
+    None,
+    Neutral,
+    Pass,
+    Fail,
+    SoftFail,
+    TempError,
+    PermError,
+}
+
+impl fmt::Display for SpfResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SpfResult::None => "none",
+            SpfResult::Neutral => "neutral",
+            SpfResult::Pass => "pass",
+            SpfResult::Fail => "fail",
+            SpfResult::SoftFail => "softfail",
+            SpfResult::TempError => "temperror",
+            SpfResult::PermError => "permerror",
+        })
+    }
+}
+
+/// The result for one envelope sender and client, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct SpfCheck {
+    pub result: SpfResult,
+    pub sender: String, // MAIL FROM, or postmaster@<HELO name> for bounces
+    pub ip: IpAddr,
+    pub reason: String,
+}
+
+impl SpfCheck {
+    /// This check's part of an Authentication-Results header (RFC 8601)
+    pub fn result_clause(&self) -> String {
+        format!("spf={} {} smtp.mailfrom={}", self.result, headers::comment(&self.reason), headers::property_value(&self.sender))
+    }
+}
+
+/// Why an evaluation stopped early
+#[derive(Debug, Clone, PartialEq)]
+enum Abort {
+    Temp(String),
+    Perm(String),
+}
+
+/// A piece of a macro string
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    Macro { letter: char, escape: bool, keep: Option<usize>, reverse: bool, delimiters: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mechanism {
+    All,
+    Include(Vec<Token>),
+    A(Option<Vec<Token>>, u8, u8), // Domain, IPv4 and IPv6 prefix lengths
+    Mx(Option<Vec<Token>>, u8, u8),
+    Ptr(Option<Vec<Token>>),
+    Ip4(Ipv4Addr, u8),
+    Ip6(Ipv6Addr, u8),
+    Exists(Vec<Token>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    directives: Vec<(SpfResult, Mechanism)>,
+    redirect: Option<Vec<Token>>,
+}
+
+/// Whether a TXT record is an SPF policy
+fn is_spf(text: &str) -> bool {
+    let version = text.get(..6).unwrap_or("");
+    version.eq_ignore_ascii_case("v=spf1") && text[6..].chars().next().is_none_or(|c| c == ' ')
+}
+
+/// Split "%{d2r}.example" into literals and macros. c, r and t only make sense in
+/// explanations, which are never shown here, so they are rejected everywhere.
+fn parse_macros(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => literal.push('%'),
+            Some('_') => literal.push(' '),
+            Some('-') => literal.push_str("%20"),
+            Some('{') => {
+                let mut body = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => body.push(c),
+                        None => return Err(format!("Unclosed macro in '{}'", text)),
+                    }
+                }
+                let mut body = body.chars();
+                let letter = body.next().filter(|c| "slodiphvSLODIPHV".contains(*c)).ok_or_else(|| format!("Bad macro in '{}'", text))?;
+                let rest: String = body.collect();
+                let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+                let rest = &rest[digits.len()..];
+                let keep = match digits.parse::<usize>() {
+                    Ok(0) => return Err(format!("Bad macro in '{}'", text)),
+                    Ok(n) => Some(n),
+                    Err(_) => None,
+                };
+                let reverse = rest.starts_with(['r', 'R']);
+                let delimiters = if reverse { &rest[1..] } else { rest };
+                if !delimiters.chars().all(|c| ".-+,/_=".contains(c)) {
+                    return Err(format!("Bad macro in '{}'", text));
+                }
+                if !literal.is_empty() {
+                    tokens.push(Token::Literal(std::mem::take(&mut literal)));
+                }
+                tokens.push(Token::Macro {
+                    letter: letter.to_ascii_lowercase(),
+                    escape: letter.is_ascii_uppercase(),
+                    keep,
+                    reverse,
+                    delimiters: delimiters.to_string(),
+                });
+            }
+            _ => return Err(format!("Bad '%' in '{}'", text)),
+        }
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    Ok(tokens)
+}
+
+/// "/24//64" at the end of an a or mx term: the domain before it and both prefix lengths
+fn split_cidr(spec: &str) -> Result<(&str, u8, u8), String> {
+    let trailing = |text: &str, slashes: &str, max: u8| -> Result<Option<(usize, u8)>, String> {
+        let digits = text.len() - text.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let start = text.len() - digits;
+        if digits == 0 || !text[..start].ends_with(slashes) || (slashes == "/" && text[..start].ends_with("//")) {
+            return Ok(None);
+        }
+        match text[start..].parse::<u8>() {
+            Ok(len) if len <= max => Ok(Some((start - slashes.len(), len))),
+            _ => Err(format!("Bad prefix length in '{}'", spec)),
+        }
+    };
+    let mut rest = spec;
+    let mut v6 = 128;
+    if let Some((end, len)) = trailing(rest, "//", 128)? {
+        rest = &rest[..end];
+        v6 = len;
+    }
+    let mut v4 = 32;
+    if let Some((end, len)) = trailing(rest, "/", 32)? {
+        rest = &rest[..end];
+        v4 = len;
+    }
+    Ok((rest, v4, v6))
+}
+
+fn parse_term(term: &str) -> Result<(SpfResult, Mechanism), String> {
+    let (qualifier, body) = match term.chars().next() {
+        Some('+') => (SpfResult::Pass, &term[1..]),
+        Some('-') => (SpfResult::Fail, &term[1..]),
+        Some('~') => (SpfResult::SoftFail, &term[1..]),
+        Some('?') => (SpfResult::Neutral, &term[1..]),
+        _ => (SpfResult::Pass, term),
+    };
+    let end = body.find([':', '/']).unwrap_or(body.len());
+    let (name, rest) = (body[..end].to_ascii_lowercase(), &body[end..]);
+    let domain = |rest: &str| -> Result<Option<Vec<Token>>, String> {
+        match rest.strip_prefix(':') {
+            Some(spec) if !spec.is_empty() => parse_macros(spec).map(Some),
+            None if rest.is_empty() => Ok(None),
+            _ => Err(format!("Bad term '{}'", term)),
+        }
+    };
+    let required = |rest: &str| domain(rest)?.ok_or_else(|| format!("'{}' needs a domain", term));
+    let network = |rest: &str, max: u8| -> Result<(String, u8), String> {
+        let spec = rest.strip_prefix(':').ok_or_else(|| format!("'{}' needs an address", term))?;
+        match spec.split_once('/') {
+            Some((address, len)) => match len.parse::<u8>() {
+                Ok(len) if len <= max => Ok((address.to_string(), len)),
+                _ => Err(format!("Bad prefix length in '{}'", term)),
+            },
+            None => Ok((spec.to_string(), max)),
+        }
+    };
+
+    let mechanism = match name.as_str() {
+        "all" if rest.is_empty() => Mechanism::All,
+        "include" => Mechanism::Include(required(rest)?),
+        "exists" => Mechanism::Exists(required(rest)?),
+        "ptr" => Mechanism::Ptr(domain(rest)?),
+        "a" | "mx" => {
+            let (spec, v4, v6) = split_cidr(rest)?;
+            let spec = domain(spec)?;
+            if name == "a" { Mechanism::A(spec, v4, v6) } else { Mechanism::Mx(spec, v4, v6) }
+        }
+        "ip4" => {
+            let (address, len) = network(rest, 32)?;
+            Mechanism::Ip4(address.parse().map_err(|_| format!("Bad address in '{}'", term))?, len)
+        }
+        "ip6" => {
+            let (address, len) = network(rest, 128)?;
+            Mechanism::Ip6(address.parse().map_err(|_| format!("Bad address in '{}'", term))?, len)
+        }
+        _ => return Err(format!("Unknown mechanism '{}'", term)),
+    };
+    Ok((qualifier, mechanism))
+}
+
+/// Parse a whole policy; any syntax error makes it unusable (4.6)
+fn parse_record(text: &str) -> Result<Record, String> {
+    let mut record = Record { directives: Vec::new(), redirect: None };
+    let mut exp = false;
+    for term in text[6..].split_whitespace() {
+        let modifier = term.split_once('=').filter(|(name, _)| {
+            name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        });
+        match modifier {
+            Some((name, value)) if name.eq_ignore_ascii_case("redirect") => {
+                if record.redirect.replace(parse_macros(value)?).is_some() {
+                    return Err("More than one redirect modifier".to_string());
+                }
+            }
+            Some((name, _)) if name.eq_ignore_ascii_case("exp") => {
+                // Explanations are for rejection messages, which are never sent here
+                if std::mem::replace(&mut exp, true) {
+                    return Err("More than one exp modifier".to_string());
+                }
+            }
+            Some(_) => {} // Unknown modifiers are ignored
+            None => record.directives.push(parse_term(term)?),
+        }
+    }
+    Ok(record)
+}
+
+/// Whether `ip` is in the network `net`/`len`
+fn in_network(ip: IpAddr, net: IpAddr, len: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Drop labels from the left until the name is short enough for DNS
+fn fit(name: &str) -> String {
+    let mut name = name.trim_end_matches('.');
+    while name.len() > MAX_DOMAIN_LEN {
+        name = name.split_once('.').map_or("", |(_, rest)| rest);
+    }
+    name.to_string()
+}
+
+/// Whether a name can be looked up: labels of 1 to 63 characters, at least two of them
+fn is_domain(name: &str) -> bool {
+    let labels: Vec<&str> = name.trim_end_matches('.').split('.').collect();
+    labels.len() > 1 && labels.iter().all(|l| !l.is_empty() && l.len() <= 63)
+}
+
+/// Percent-encode everything but unreserved characters (RFC 3986), for uppercase macros
+fn url_escape(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// State of one check_host() run, shared with the includes and redirects it follows
+struct Evaluation<'a> {
+    resolver: &'a dyn Resolver,
+    ip: IpAddr,
+    sender: String,
+    helo: String,
+    lookups: usize,
+    void_lookups: usize,
+}
+
+impl<'a> Evaluation<'a> {
+    fn new(resolver: &'a dyn Resolver, ip: IpAddr, sender: &str, helo: &str) -> Self {
+        // An IPv4 client reached over IPv6 is checked as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        Evaluation { resolver, ip, sender: sender.to_string(), helo: helo.to_string(), lookups: 0, void_lookups: 0 }
+    }
+
+    /// Count a term that queries DNS
+    fn lookup(&mut self) -> Result<(), Abort> {
+        self.lookups += 1;
+        if self.lookups > MAX_LOOKUPS {
+            return Err(Abort::Perm(format!("more than {} DNS lookups", MAX_LOOKUPS)));
+        }
+        Ok(())
+    }
+
+    /// Count a lookup that found nothing
+    fn found<T>(&mut self, records: Vec<T>) -> Result<Vec<T>, Abort> {
+        if records.is_empty() {
+            self.void_lookups += 1;
+            if self.void_lookups > MAX_VOID_LOOKUPS {
+                return Err(Abort::Perm(format!("more than {} DNS lookups found nothing", MAX_VOID_LOOKUPS)));
+            }
+        }
+        Ok(records)
+    }
+
+    /// Addresses of `name` in the client's family
+    async fn addresses(&self, name: &str) -> Result<Vec<IpAddr>, Abort> {
+        let found = match self.ip {
+            IpAddr::V4(_) => self.resolver.a(name).await.map(|ips| ips.into_iter().map(IpAddr::V4).collect()),
+            IpAddr::V6(_) => self.resolver.aaaa(name).await.map(|ips| ips.into_iter().map(IpAddr::V6).collect()),
+        };
+        found.map_err(Abort::Temp)
+    }
+
+    /// Names the client's address points to that point back to it (5.5)
+    async fn validated_names(&self) -> Vec<String> {
+        let names = self.resolver.ptr(self.ip).await.unwrap_or_default();
+        let mut validated = Vec::new();
+        for name in names.into_iter().take(MAX_NAMES) {
+            if self.addresses(&name).await.is_ok_and(|ips| ips.contains(&self.ip)) {
+                validated.push(dns::normalize(&name));
+            }
+        }
+        validated
+    }
+
+    async fn macro_value(&self, letter: char, domain: &str) -> String {
+        let (local, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("postmaster", &self.sender));
+        match letter {
+            's' => self.sender.clone(),
+            'l' => local.to_string(),
+            'o' => sender_domain.to_string(),
+            'd' => domain.to_string(),
+            'h' => self.helo.clone(),
+            'i' => match self.ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => dns::nibbles(ip).join("."),
+            },
+            'v' => if self.ip.is_ipv4() { "in-addr" } else { "ip6" }.to_string(),
+            _ => {
+                // 'p': a validated name in the domain, else any validated name
+                let names = self.validated_names().await;
+                let domain = dns::normalize(domain);
+                let within = |name: &&String| **name == domain || name.ends_with(&format!(".{}", domain));
+                names.iter().find(within).or(names.first()).cloned().unwrap_or_else(|| "unknown".to_string())
+            }
+        }
+    }
+
+    /// Expand a domain spec in the context of `domain`
+    async fn expand(&self, tokens: &[Token], domain: &str) -> Result<String, Abort> {
+        let mut expanded = String::new();
+        for token in tokens {
+            match token {
+                Token::Literal(text) => expanded.push_str(text),
+                Token::Macro { letter, escape, keep, reverse, delimiters } => {
+                    let value = self.macro_value(*letter, domain).await;
+                    let delimiters = if delimiters.is_empty() { "." } else { delimiters.as_str() };
+                    let mut parts: Vec<&str> = value.split(|c| delimiters.contains(c)).collect();
+                    if *reverse {
+                        parts.reverse();
+                    }
+                    let skip = parts.len().saturating_sub(keep.unwrap_or(parts.len()));
+                    let value = parts[skip..].join(".");
+                    expanded.push_str(&if *escape { url_escape(&value) } else { value });
+                }
+            }
+        }
+        let name = fit(&expanded);
+        if !is_domain(&name) {
+            return Err(Abort::Perm(format!("'{}' is not a domain name", expanded)));
+        }
+        Ok(name)
+    }
+
+    async fn target(&self, spec: &Option<Vec<Token>>, domain: &str) -> Result<String, Abort> {
+        match spec {
+            Some(tokens) => self.expand(tokens, domain).await,
+            None => Ok(domain.to_string()),
+        }
+    }
+
+    /// The prefix length of an a or mx term that applies to the client
+    fn cidr(&self, v4: u8, v6: u8) -> u8 {
+        if self.ip.is_ipv4() { v4 } else { v6 }
+    }
+
+    async fn matches(&mut self, mechanism: &Mechanism, domain: &str) -> Result<bool, Abort> {
+        match mechanism {
+            Mechanism::All => Ok(true),
+            Mechanism::Ip4(net, len) => Ok(in_network(self.ip, IpAddr::V4(*net), *len)),
+            Mechanism::Ip6(net, len) => Ok(in_network(self.ip, IpAddr::V6(*net), *len)),
+            Mechanism::Include(spec) => {
+                self.lookup()?;
+                let target = self.expand(spec, domain).await?;
+                match self.check_host(target.clone()).await? {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::None => Err(Abort::Perm(format!("included domain {} has no SPF record", target))),
+                    _ => Ok(false),
+                }
+            }
+            Mechanism::A(spec, v4, v6) => {
+                self.lookup()?;
+                let target = self.target(spec, domain).await?;
+                let found = self.addresses(&target).await?;
+                let len = self.cidr(*v4, *v6);
+                Ok(self.found(found)?.iter().any(|net| in_network(self.ip, *net, len)))
+            }
+            Mechanism::Mx(spec, v4, v6) => {
+                self.lookup()?;
+                let target = self.target(spec, domain).await?;
+                let found = self.resolver.mx(&target).await.map_err(Abort::Temp)?;
+                let exchanges = self.found(found)?;
+                if exchanges.len() > MAX_NAMES {
+                    return Err(Abort::Perm(format!("{} has more than {} MX records", target, MAX_NAMES)));
+                }
+                let len = self.cidr(*v4, *v6);
+                for exchange in exchanges {
+                    if self.addresses(&exchange).await?.iter().any(|net| in_network(self.ip, *net, len)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Mechanism::Ptr(spec) => {
+                self.lookup()?;
+                let target = dns::normalize(&self.target(spec, domain).await?);
+                let names = self.validated_names().await;
+                Ok(names.iter().any(|name| *name == target || name.ends_with(&format!(".{}", target))))
+            }
+            Mechanism::Exists(spec) => {
+                self.lookup()?;
+                let target = self.expand(spec, domain).await?;
+                // Always an A query, whatever the client's family (5.7)
+                let found = self.resolver.a(&target).await.map_err(Abort::Temp)?;
+                Ok(!self.found(found)?.is_empty())
+            }
+        }
+    }
+
+    /// check_host() of RFC 7208 section 4 for `domain`
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, Result<SpfResult, Abort>> {
+        Box::pin(async move {
+            let records = self.resolver.txt(&domain).await.map_err(Abort::Temp)?;
+            let mut policies = records.iter().filter(|text| is_spf(text));
+            let record = match (policies.next(), policies.next()) {
+                (None, _) => return Ok(SpfResult::None),
+                (Some(text), None) => parse_record(text).map_err(|e| Abort::Perm(format!("{}: {}", domain, e)))?,
+                (Some(_), Some(_)) => return Err(Abort::Perm(format!("{} has more than one SPF record", domain))),
+            };
+            for (qualifier, mechanism) in &record.directives {
+                if self.matches(mechanism, &domain).await? {
+                    return Ok(*qualifier);
+                }
+            }
+            // Only reached without "all", which always matches
+            if let Some(spec) = &record.redirect {
+                self.lookup()?;
+                let target = self.expand(spec, &domain).await?;
+                return match self.check_host(target.clone()).await? {
+                    SpfResult::None => Err(Abort::Perm(format!("redirect to {}, which has no SPF record", target))),
+                    result => Ok(result),
+                };
+            }
+            Ok(SpfResult::Neutral)
+        })
+    }
+}
+
+/// Evaluate SPF for mail from `mail_from` (empty for bounces) sent by `ip`, which said `helo`
+pub async fn check(resolver: &dyn Resolver, ip: IpAddr, mail_from: &str, helo: &str) -> SpfCheck {
+    let sender = match mail_from.rsplit_once('@') {
+        _ if mail_from.is_empty() => format!("postmaster@{}", helo),
+        Some(("", domain)) => format!("postmaster@{}", domain),
+        Some(_) => mail_from.to_string(),
+        None => format!("postmaster@{}", mail_from),
+    };
+    let domain = sender.rsplit_once('@').map(|(_, domain)| dns::normalize(domain)).unwrap_or_default();
+    let outcome = |result, reason: String| SpfCheck { result, sender: sender.clone(), ip, reason };
+
+    if !is_domain(&domain) {
+        return outcome(SpfResult::None, format!("{} is not a domain that can have an SPF record", domain));
+    }
+    let mut evaluation = Evaluation::new(resolver, ip, &sender, helo);
+    match tokio::time::timeout(DEADLINE, evaluation.check_host(domain.clone())).await {
+        Ok(Ok(SpfResult::Pass)) => outcome(SpfResult::Pass, format!("domain of {} designates {} as permitted sender", sender, ip)),
+        Ok(Ok(SpfResult::None)) => outcome(SpfResult::None, format!("domain of {} has no SPF record", sender)),
+        Ok(Ok(SpfResult::Neutral)) => outcome(SpfResult::Neutral, format!("domain of {} neither permits nor denies {}", sender, ip)),
+        Ok(Ok(result)) => outcome(result, format!("domain of {} does not designate {} as permitted sender", sender, ip)),
+        Ok(Err(Abort::Temp(reason))) => outcome(SpfResult::TempError, reason),
+        Ok(Err(Abort::Perm(reason))) => outcome(SpfResult::PermError, reason),
+        Err(_) => outcome(SpfResult::TempError, format!("SPF check of {} timed out", domain)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::dns::Zone;
+
+    async fn result(zone: &Zone, ip: &str, mail_from: &str) -> SpfResult {
+        check(zone, ip.parse().unwrap(), mail_from, "mail.a.example").await.result
+    }
+
+    async fn expand(spec: &str, ip: &str) -> String {
+        let zone = Zone::new();
+        let evaluation = Evaluation::new(&zone, ip.parse().unwrap(), "strong-bad@email.example.com", "mx.example.org");
+        evaluation.expand(&parse_macros(spec).unwrap(), "email.example.com").await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn mechanisms_match_the_client() {
+        let zone = Zone::new()
+            .txt("a.example", "v=spf1 ip4:192.0.2.0/24 ip6:2001:db8::/32 a:web.a.example mx include:esp.example ~all")
+            .txt("a.example", "google-site-verification=xyz") // Other TXT records don't count
+            .ip("web.a.example", "198.51.100.7")
+            .mx("a.example", "mx1.a.example")
+            .ip("mx1.a.example", "203.0.113.9")
+            .txt("esp.example", "v=spf1 ip4:198.18.0.0/15 -all");
+
+        assert_eq!(result(&zone, "192.0.2.44", "bob@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "2001:db8:1::5", "bob@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "::ffff:198.51.100.7", "bob@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "203.0.113.9", "bob@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "198.19.4.4", "bob@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "10.0.0.1", "bob@a.example").await, SpfResult::SoftFail);
+        assert_eq!(result(&zone, "10.0.0.1", "bob@nospf.example").await, SpfResult::None);
+    }
+
+    #[tokio::test]
+    async fn results_explain_themselves() {
+        // Bounces have no sender, the HELO name stands in
+        let zone = Zone::new().txt("a.example", "v=spf1 -all").txt("mail.a.example", "v=spf1 redirect=a.example");
+        let spf = check(&zone, "10.0.0.1".parse().unwrap(), "", "mail.a.example").await;
+        assert_eq!(
+            spf.result_clause(),
+            "spf=fail (domain of postmaster@mail.a.example does not designate 10.0.0.1 as permitted sender) \
+             smtp.mailfrom=postmaster@mail.a.example"
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect_applies_when_nothing_matched() {
+        let zone = Zone::new()
+            .txt("a.example", "v=spf1 ip4:192.0.2.1 redirect=_spf.b.example")
+            .txt("_spf.b.example", "v=spf1 ip4:192.0.2.2 -all")
+            .txt("c.example", "v=spf1 redirect=missing.example")
+            .txt("d.example", "v=spf1 ?all redirect=_spf.b.example");
+
+        assert_eq!(result(&zone, "192.0.2.1", "x@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "192.0.2.2", "x@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "192.0.2.3", "x@a.example").await, SpfResult::Fail);
+        assert_eq!(result(&zone, "192.0.2.3", "x@c.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.3", "x@d.example").await, SpfResult::Neutral);
+    }
+
+    #[tokio::test]
+    async fn includes_pass_only_on_pass() {
+        let zone = Zone::new()
+            .txt("a.example", "v=spf1 include:fails.example include:broken.example -all")
+            .txt("fails.example", "v=spf1 -all")
+            .txt("broken.example", "v=spf1 bogus:thing")
+            .txt("b.example", "v=spf1 include:none.example")
+            .txt("c.example", "v=spf1 include:down.example ~all")
+            .failing("down.example");
+
+        assert_eq!(result(&zone, "192.0.2.1", "x@a.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@b.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@c.example").await, SpfResult::TempError);
+    }
+
+    #[tokio::test]
+    async fn lookups_are_limited() {
+        // Ten includes deep is allowed, eleven is not
+        let mut zone = Zone::new().txt("l10.example", "v=spf1 ip4:192.0.2.1 -all");
+        for i in 0..10 {
+            zone = zone.txt(&format!("l{}.example", i), &format!("v=spf1 include:l{}.example -all", i + 1));
+        }
+        let zone = zone
+            .txt("deep.example", "v=spf1 include:l0.example -all")
+            .txt("loop.example", "v=spf1 include:loop.example -all")
+            .txt("void.example", "v=spf1 a:n1.example a:n2.example a:n3.example ~all")
+            .txt("mxs.example", "v=spf1 mx -all");
+        let zone = (0..11).fold(zone, |zone, i| zone.mx("mxs.example", &format!("mx{}.example", i)));
+
+        assert_eq!(result(&zone, "192.0.2.1", "x@l0.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "192.0.2.1", "x@deep.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@loop.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@void.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@mxs.example").await, SpfResult::PermError);
+    }
+
+    #[tokio::test]
+    async fn broken_policies_are_permanent_errors() {
+        let zone = Zone::new()
+            .txt("two.example", "v=spf1 -all")
+            .txt("two.example", "v=spf1 +all")
+            .txt("syntax.example", "v=spf1 ip4:192.0.2.1 ip4:300.1.1.1 -all")
+            .txt("macro.example", "v=spf1 exists:%{z}.example -all")
+            .txt("dup.example", "v=spf1 redirect=a.example redirect=b.example")
+            .txt("unknown-modifier.example", "v=spf1 foo=bar ip4:192.0.2.1 -all");
+
+        assert_eq!(result(&zone, "192.0.2.1", "x@two.example").await, SpfResult::PermError);
+        // Even when a mechanism before the error matched
+        assert_eq!(result(&zone, "192.0.2.1", "x@syntax.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@macro.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@dup.example").await, SpfResult::PermError);
+        assert_eq!(result(&zone, "192.0.2.1", "x@unknown-modifier.example").await, SpfResult::Pass);
+        assert!(!is_spf("v=spf10 -all"));
+    }
+
+    #[tokio::test]
+    async fn macros_expand_as_in_the_rfc() {
+        // RFC 7208 section 7.4
+        assert_eq!(expand("%{s}", "192.0.2.3").await, "strong-bad@email.example.com");
+        assert_eq!(expand("%{o}", "192.0.2.3").await, "email.example.com");
+        assert_eq!(expand("%{d4}", "192.0.2.3").await, "email.example.com");
+        assert_eq!(expand("%{d2}", "192.0.2.3").await, "example.com");
+        assert_eq!(expand("%{dr}", "192.0.2.3").await, "com.example.email");
+        assert_eq!(expand("%{d2r}", "192.0.2.3").await, "example.email");
+        assert_eq!(expand("%{l}.a.example", "192.0.2.3").await, "strong-bad.a.example");
+        assert_eq!(expand("%{l-}.a.example", "192.0.2.3").await, "strong.bad.a.example");
+        assert_eq!(expand("%{lr-}.a.example", "192.0.2.3").await, "bad.strong.a.example");
+        assert_eq!(expand("%{l1r-}.a.example", "192.0.2.3").await, "strong.a.example");
+        assert_eq!(expand("%{ir}.%{v}._spf.%{d2}", "192.0.2.3").await, "3.2.0.192.in-addr._spf.example.com");
+        assert_eq!(expand("%{lr-}.lp._spf.%{d2}", "192.0.2.3").await, "bad.strong.lp._spf.example.com");
+        assert_eq!(expand("%{ir}.%{v}.%{l1r-}.lp._spf.%{d2}", "192.0.2.3").await, "3.2.0.192.in-addr.strong.lp._spf.example.com");
+        assert_eq!(
+            expand("%{ir}.%{v}._spf.%{d2}", "2001:db8::cb01").await,
+            "1.0.b.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6._spf.example.com"
+        );
+        assert_eq!(expand("%{h}.%%20.%{p}", "192.0.2.3").await, "mx.example.org.%20.unknown");
+        assert_eq!(expand("%{S}.a.example", "192.0.2.3").await, "strong-bad%40email.example.com.a.example");
+    }
+
+    #[tokio::test]
+    async fn macros_drive_exists_and_ptr() {
+        let zone = Zone::new()
+            .txt("a.example", "v=spf1 exists:%{ir}.%{l1r+}._spf.%{d} ptr:mail.a.example -all")
+            .ip("1.2.0.192.bob._spf.a.example", "127.0.0.2")
+            .ptr("192.0.2.9", "out.mail.a.example")
+            .ip("out.mail.a.example", "192.0.2.9")
+            .ptr("192.0.2.10", "spoof.mail.a.example"); // Doesn't resolve back
+
+        assert_eq!(result(&zone, "192.0.2.1", "bob+tag@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "192.0.2.1", "alice@a.example").await, SpfResult::Fail);
+        assert_eq!(result(&zone, "192.0.2.9", "alice@a.example").await, SpfResult::Pass);
+        assert_eq!(result(&zone, "192.0.2.10", "alice@a.example").await, SpfResult::Fail);
+    }
+
+    #[test]
+    fn cidr_lengths_are_parsed() {
+        assert_eq!(split_cidr("").unwrap(), ("", 32, 128));
+        assert_eq!(split_cidr("/24").unwrap(), ("", 24, 128));
+        assert_eq!(split_cidr("//64").unwrap(), ("", 32, 64));
+        assert_eq!(split_cidr(":b.example/24//64").unwrap(), (":b.example", 24, 64));
+        assert!(split_cidr("/33").is_err());
+        assert!(in_network("192.0.2.200".parse().unwrap(), "192.0.2.0".parse().unwrap(), 24));
+        assert!(in_network("10.1.1.1".parse().unwrap(), "192.0.2.0".parse().unwrap(), 0));
+        assert!(!in_network("10.1.1.1".parse().unwrap(), "2001:db8::".parse().unwrap(), 0));
+    }
+}
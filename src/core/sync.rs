@@ -0,0 +1,340 @@
+use serde::Serialize;
+use sqlx::{PgPool, Row};
+use std::sync::Arc;
+use crate::core::crypto::{self, KeyRing, SealedSecret};
+use crate::core::ingest::{FetchFailure, ParsedEmail};
+use crate::core::provider::{self, MailAccount, MailProvider, ProviderError};
+use crate::db::{Recipient, Repos, SealedTokens, SyncAccount};
+
+/// Messages pulled per sync round
+const SYNC_BATCH_SIZE: usize = 25;
+/// Refresh OAuth tokens this long before they expire
+const TOKEN_REFRESH_MARGIN_SECS: i64 = 60;
+
+/// Default time between scheduled syncs of one account
+pub const DEFAULT_INTERVAL_SECS: i32 = 300;
+/// Shortest interval a user may configure
+pub const MIN_INTERVAL_SECS: i32 = 60;
+/// Random spread added to each interval so accounts connected together don't stay in lockstep
+pub const DEFAULT_JITTER_PERCENT: i32 = 10;
+/// How long a claimed account stays reserved for whoever syncs it
+pub const DEFAULT_LEASE_SECS: i64 = 300;
+
+/// SQL condition for users that have some mailbox connected
+const CONNECTED_ACCOUNTS: &str =
+    "access_token IS NOT NULL OR (imap_password IS NOT NULL AND imap_server IS NOT NULL)";
+
+#[derive(Debug)]
+pub enum SyncError {
+    UserNotFound,
+    NoCredentials(String),
+    Provider(String),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::UserNotFound => write!(f, "User not found"),
+            SyncError::NoCredentials(e) => write!(f, "{}", e),
+            SyncError::Provider(e) => write!(f, "Provider error: {}", e),
+            SyncError::Database(e) => write!(f, "DB Error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for SyncError {
+    fn from(e: sqlx::Error) -> Self {
+        SyncError::Database(e)
+    }
+}
+
+/// Outcome of syncing one account
+#[derive(Debug)]
+pub struct SyncReport {
+    pub provider: &'static str,
+    pub saved: usize,
+    pub failures: Vec<FetchFailure>,
+    pub latest: Option<ParsedEmail>,
+}
+
+/// Sync a user's connected mailbox, whatever the backend.
+/// Every provider goes through the same path: refresh, list since cursor, fetch, parse, store.
+pub async fn sync_user(repos: &Repos, keys: &Arc<KeyRing>, user_id: &str) -> Result<SyncReport, SyncError> {
+    let account = repos.users.sync_account(user_id).await?.ok_or(SyncError::UserNotFound)?;
+    let provider = provider::for_account(&mail_account(keys, user_id, &account)).map_err(SyncError::NoCredentials)?;
+    sync_account(repos, keys, user_id, &account, provider.as_ref()).await
+}
+
+/// The account as providers see it, secrets still sealed
+fn mail_account(keys: &Arc<KeyRing>, user_id: &str, account: &SyncAccount) -> MailAccount {
+    let sealed = |column: &str, stored: Option<&String>| {
+        stored.map(|stored| SealedSecret::new(keys.clone(), stored.clone(), crypto::context(user_id, column)))
+    };
+    let tokens = account.tokens.as_ref();
+    MailAccount {
+        email: account.email.clone(),
+        auth_provider: account.auth_provider.clone(),
+        imap_server: account.imap.server.clone(),
+        imap_port: account.imap.port,
+        imap_password: sealed("imap_password", account.imap.password.as_ref()),
+        access_token: sealed("access_token", tokens.map(|t| &t.access_token)),
+        refresh_token: sealed("refresh_token", tokens.and_then(|t| t.refresh_token.as_ref())),
+    }
+}
+
+/// Sync `account` of a user through `provider`, its backend
+pub async fn sync_account(
+    repos: &Repos,
+    keys: &KeyRing,
+    user_id: &str,
+    account: &SyncAccount,
+    provider: &dyn MailProvider,
+) -> Result<SyncReport, SyncError> {
+    let expiring = account.tokens.as_ref().and_then(|t| t.expires_at).is_some_and(|at| {
+        at.timestamp() <= chrono::Utc::now().timestamp() + TOKEN_REFRESH_MARGIN_SECS
+    });
+    let refreshed = expiring && refresh_tokens(repos, keys, user_id, provider).await?;
+
+    let cursor = account.cursor.as_deref();
+    let mut result = provider::sync_mailbox(provider, cursor, SYNC_BATCH_SIZE).await;
+    // Tokens of unknown expiry (or revoked early) only turn out stale when refused: refresh once and retry
+    if matches!(result, Err(ProviderError::Unauthorized(_))) && !refreshed && refresh_tokens(repos, keys, user_id, provider).await? {
+        result = provider::sync_mailbox(provider, cursor, SYNC_BATCH_SIZE).await;
+    }
+    let batch = result.map_err(|e| SyncError::Provider(e.to_string()))?;
+
+    // Save all emails, collecting per-message failures
+    let inbox = Recipient::mailbox(user_id);
+    let mut failures = batch.failures.clone();
+    let mut stored = Vec::new();
+    for item in &batch.emails {
+        match repos.emails.store(&inbox, Some(&item.message_id), &item.email, item.received_at).await {
+            Ok(_) => stored.push(item.message_id.as_str()),
+            Err(e) => failures.push(FetchFailure {
+                message_id: item.message_id.clone(),
+                error: format!("Failed to save: {}", e),
+                permanent: false,
+            }),
+        }
+    }
+
+    // Only move past what was stored or can never be: messages that failed for now are listed again next time
+    if let Some(next_cursor) = batch.resume_cursor(|id| stored.contains(&id)) {
+        repos.users.save_sync_cursor(user_id, &next_cursor).await?;
+    }
+
+    Ok(SyncReport {
+        provider: provider.name(),
+        saved: stored.len(),
+        failures,
+        latest: batch.emails.first().map(|e| e.email.clone()),
+    })
+}
+
+/// Ask the provider for fresh OAuth tokens and persist them. False if it has none to give.
+async fn refresh_tokens(repos: &Repos, keys: &KeyRing, user_id: &str, provider: &dyn MailProvider) -> Result<bool, SyncError> {
+    let Some(tokens) = provider.refresh_credentials().await.map_err(SyncError::Provider)? else {
+        return Ok(false);
+    };
+
+    let sealed = SealedTokens {
+        access_token: keys.seal(&tokens.access_token, &crypto::context(user_id, "access_token"))
+            .map_err(SyncError::Provider)?,
+        refresh_token: keys.seal_column(user_id, "refresh_token", tokens.refresh_token.as_deref())
+            .map_err(SyncError::Provider)?,
+        expires_at: tokens.expires_in.map(|secs| chrono::Utc::now() + chrono::Duration::seconds(secs as i64)),
+    };
+    repos.tokens.save_refreshed(user_id, &sealed).await?;
+    Ok(true)
+}
+
+/// Make sure every connected account has a sync schedule row
+pub async fn ensure_schedules(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let sql = format!(
+        r#"
+        INSERT INTO account_sync (user_id, interval_secs)
+        SELECT id, $1 FROM users WHERE {}
+        ON CONFLICT (user_id) DO NOTHING
+        "#,
+        CONNECTED_ACCOUNTS
+    );
+
+    let result = sqlx::query(&sql)
+    .bind(DEFAULT_INTERVAL_SECS)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Lease up to `limit` accounts that are due for a sync.
+/// SKIP LOCKED plus the lease columns keep other replicas from picking the same accounts.
+pub async fn claim_due_accounts(
+    pool: &PgPool,
+    owner: &str,
+    lease_secs: i64,
+    limit: i64,
+) -> Result<Vec<String>, sqlx::Error> {
+    // Accounts disconnected since their schedule was made are left alone
+    let sql = format!(
+        r#"
+        UPDATE account_sync
+        SET lease_owner = $1,
+            lease_expires_at = NOW() + make_interval(secs => $2)
+        WHERE user_id IN (
+            SELECT s.user_id FROM account_sync s
+            JOIN users ON users.id = s.user_id
+            WHERE s.next_sync_at <= NOW()
+              AND (s.lease_expires_at IS NULL OR s.lease_expires_at < NOW())
+              AND ({})
+            ORDER BY s.next_sync_at
+            LIMIT $3
+            FOR UPDATE OF s SKIP LOCKED
+        )
+        RETURNING user_id
+        "#,
+        CONNECTED_ACCOUNTS
+    );
+
+    let rows = sqlx::query(&sql)
+    .bind(owner)
+    .bind(lease_secs as f64)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|r| r.get("user_id")).collect())
+}
+
+/// Lease one account right away, due or not (manual syncs). False if someone else holds
+/// its lease, or if the user doesn't exist or has no mailbox connected.
+pub async fn claim_account(pool: &PgPool, user_id: &str, owner: &str, lease_secs: i64) -> Result<bool, sqlx::Error> {
+    let sql = format!(
+        r#"
+        INSERT INTO account_sync (user_id, interval_secs, lease_owner, lease_expires_at)
+        SELECT id, $3, $2, NOW() + make_interval(secs => $4) FROM users WHERE id = $1 AND ({})
+        ON CONFLICT (user_id) DO UPDATE SET
+            lease_owner = EXCLUDED.lease_owner,
+            lease_expires_at = EXCLUDED.lease_expires_at
+        WHERE account_sync.lease_expires_at IS NULL OR account_sync.lease_expires_at < NOW()
+        RETURNING user_id
+        "#,
+        CONNECTED_ACCOUNTS
+    );
+
+    let row = sqlx::query(&sql)
+    .bind(user_id)
+    .bind(owner)
+    .bind(DEFAULT_INTERVAL_SECS)
+    .bind(lease_secs as f64)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.is_some())
+}
+
+/// Store the outcome of a sync, release the lease (if `owner` holds it) and schedule the next run
+pub async fn record_result(
+    pool: &PgPool,
+    user_id: &str,
+    owner: Option<&str>,
+    result: &Result<SyncReport, SyncError>,
+    jitter_percent: i32,
+) -> Result<(), sqlx::Error> {
+    let (status, error, count) = match result {
+        Ok(report) if report.failures.is_empty() => ("ok", None, report.saved as i32),
+        Ok(report) => (
+            "partial",
+            report.failures.first().map(|f| format!("{}: {}", f.message_id, f.error)),
+            report.saved as i32,
+        ),
+        Err(e) => ("error", Some(e.to_string()), 0),
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO account_sync (user_id, interval_secs, last_sync_at, last_status, last_error, last_count, next_sync_at)
+        VALUES ($1, $2, NOW(), $3, $4, $5, NOW() + make_interval(secs => $2 * (1 + random() * $6 / 100.0)))
+        ON CONFLICT (user_id) DO UPDATE SET
+            last_sync_at = NOW(),
+            last_status = EXCLUDED.last_status,
+            last_error = EXCLUDED.last_error,
+            last_count = EXCLUDED.last_count,
+            next_sync_at = NOW() + make_interval(secs => account_sync.interval_secs * (1 + random() * $6 / 100.0)),
+            lease_owner = CASE WHEN account_sync.lease_owner = $7 THEN NULL ELSE account_sync.lease_owner END,
+            lease_expires_at = CASE WHEN account_sync.lease_owner = $7 THEN NULL ELSE account_sync.lease_expires_at END
+        "#
+    )
+    .bind(user_id)
+    .bind(DEFAULT_INTERVAL_SECS)
+    .bind(status)
+    .bind(error)
+    .bind(count)
+    .bind(jitter_percent)
+    .bind(owner)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Sync schedule and last result of an account, as shown to its owner
+#[derive(Debug, Serialize)]
+pub struct SyncStatus {
+    pub provider: Option<String>,
+    pub interval_secs: i32,
+    pub syncing: bool,
+    pub next_sync_at: Option<String>,
+    pub last_sync_at: Option<String>,
+    pub last_status: Option<String>,
+    pub last_error: Option<String>,
+    pub last_count: Option<i32>,
+}
+
+/// None if the user doesn't exist; users never synced get the defaults
+pub async fn status(pool: &PgPool, user_id: &str) -> Result<Option<SyncStatus>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT u.auth_provider, s.interval_secs, s.next_sync_at::text, s.last_sync_at::text,
+               s.last_status, s.last_error, s.last_count,
+               COALESCE(s.lease_expires_at > NOW(), false) AS syncing
+        FROM users u
+        LEFT JOIN account_sync s ON s.user_id = u.id
+        WHERE u.id = $1
+        "#
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| SyncStatus {
+        provider: row.get("auth_provider"),
+        interval_secs: row.get::<Option<i32>, _>("interval_secs").unwrap_or(DEFAULT_INTERVAL_SECS),
+        syncing: row.get("syncing"),
+        next_sync_at: row.get("next_sync_at"),
+        last_sync_at: row.get("last_sync_at"),
+        last_status: row.get("last_status"),
+        last_error: row.get("last_error"),
+        last_count: row.get("last_count"),
+    }))
+}
+
+/// Change how often the scheduler syncs an account
+pub async fn set_interval(pool: &PgPool, user_id: &str, interval_secs: i32) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO account_sync (user_id, interval_secs)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET
+            interval_secs = EXCLUDED.interval_secs,
+            next_sync_at = LEAST(account_sync.next_sync_at, NOW() + make_interval(secs => EXCLUDED.interval_secs))
+        "#
+    )
+    .bind(user_id)
+    .bind(interval_secs)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
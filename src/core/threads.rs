@@ -0,0 +1,223 @@
+//! Grouping mail into conversations. A message joins the thread of the messages its
+//! In-Reply-To and References headers name; failing that, of a message it names in turn
+//! (replies that arrived first); failing that, a reply ("Re: ...") joins the latest
+//! thread with the same subject, for clients that drop the headers.
+//!
+//! The decision is made here, by `place`; repositories only look up the `Related` mail
+//! and store the `Placement`.
+
+use chrono::NaiveDateTime;
+use crate::core::ingest::ParsedEmail;
+
+/// How far back a reply looks for a thread with the same subject
+pub const SUBJECT_WINDOW_SECS: i64 = 30 * 24 * 3600;
+
+/// Prefixes clients put before the subject of replies and forwards, in a few languages
+const REPLY_PREFIXES: [&str; 8] = ["re", "fwd", "fw", "aw", "wg", "sv", "vs", "tr"];
+
+/// The reply prefix at the start of `subject` ("Re:", "RE[2]:", "Fwd :"), and what follows it
+fn strip_prefix(subject: &str) -> Option<&str> {
+    let (prefix, rest) = subject.split_once(':')?;
+    let prefix = prefix.trim_end();
+    let word = prefix.split_once('[').map_or(prefix, |(word, count)| {
+        if count.strip_suffix(']').is_some_and(|n| n.chars().all(|c| c.is_ascii_digit())) { word } else { "" }
+    });
+    REPLY_PREFIXES.iter().any(|p| p.eq_ignore_ascii_case(word)).then_some(rest)
+}
+
+/// Whether the subject says the message answers or forwards another
+pub fn is_reply(subject: &str) -> bool {
+    strip_prefix(subject.trim_start()).is_some()
+}
+
+/// The subject messages of one conversation share: reply prefixes removed, whitespace collapsed, lowercased
+pub fn normalize_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    while let Some(rest) = strip_prefix(subject) {
+        subject = rest.trim_start();
+    }
+    subject.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Message ids an email refers to, nearest first: In-Reply-To, then References newest to oldest
+pub fn parent_ids(in_reply_to: Option<&str>, references: &[String]) -> Vec<String> {
+    let mut ids: Vec<String> = in_reply_to.into_iter().map(str::to_string).collect();
+    for id in references.iter().rev() {
+        if !ids.contains(id) {
+            ids.push(id.clone());
+        }
+    }
+    ids
+}
+
+/// The `<id>`s of an In-Reply-To or References header value, without the brackets
+pub fn parse_ids(header: &str) -> Vec<String> {
+    header
+        .split('<')
+        .skip(1)
+        .filter_map(|part| part.split_once('>'))
+        .map(|(id, _)| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// A stored email of the same inbox that may decide where a new one goes
+#[derive(Debug, Clone)]
+pub struct Related {
+    pub id: i64,
+    pub thread_id: i64,
+    pub message_id: Option<String>, // Message-ID header
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub subject: String, // As `normalize_subject` left it
+    pub received_at: NaiveDateTime,
+}
+
+impl Related {
+    fn replies_to(&self, message_id: &str) -> bool {
+        self.in_reply_to.as_deref() == Some(message_id) || self.references.iter().any(|r| r == message_id)
+    }
+}
+
+/// What a repository has to find for `place`: stored mail whose Message-ID is one of
+/// `parents`, that replies to `message_id`, or (if set) has `subject` and arrived after `since`.
+/// Passing more than that is fine.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub parents: Vec<String>,
+    pub message_id: Option<String>,
+    pub subject: Option<String>,
+    pub since: NaiveDateTime,
+}
+
+impl Lookup {
+    pub fn new(email: &ParsedEmail, received_at: NaiveDateTime) -> Self {
+        let subject = normalize_subject(&email.subject);
+        Self {
+            parents: parent_ids(email.in_reply_to.as_deref(), &email.references),
+            message_id: email.message_id.clone(),
+            subject: (is_reply(&email.subject) && !subject.is_empty()).then_some(subject),
+            since: received_at - chrono::Duration::seconds(SUBJECT_WINDOW_SECS),
+        }
+    }
+}
+
+/// Where a new email goes
+#[derive(Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub thread_id: i64,
+    pub merged: Vec<i64>, // Threads of replies stored before it, now part of `thread_id`
+}
+
+/// Thread a new email (`email_id`) joins, given the `related` mail of its inbox
+pub fn place(email_id: i64, lookup: &Lookup, related: &[Related]) -> Placement {
+    let related: Vec<&Related> = related.iter().filter(|r| r.id != email_id).collect();
+    let replies: Vec<&Related> = match &lookup.message_id {
+        Some(message_id) => related.iter().copied().filter(|r| r.replies_to(message_id)).collect(),
+        None => Vec::new(),
+    };
+
+    let by_parent = related.iter()
+        .filter_map(|r| {
+            let position = lookup.parents.iter().position(|p| Some(p) == r.message_id.as_ref())?;
+            Some((position, r.thread_id))
+        })
+        .min()
+        .map(|(_, thread_id)| thread_id);
+    let by_reply = || replies.iter().min_by_key(|r| (r.received_at, r.id)).map(|r| r.thread_id);
+    let by_subject = || {
+        let subject = lookup.subject.as_ref()?;
+        related.iter()
+            .filter(|r| r.subject == *subject && r.received_at > lookup.since)
+            .max_by_key(|r| (r.received_at, r.id))
+            .map(|r| r.thread_id)
+    };
+    let thread_id = by_parent.or_else(by_reply).or_else(by_subject).unwrap_or(email_id);
+
+    let mut merged: Vec<i64> = replies.iter().map(|r| r.thread_id).filter(|&t| t != thread_id).collect();
+    merged.sort();
+    merged.dedup();
+    Placement { thread_id, merged }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_prefixes_are_stripped() {
+        assert_eq!(normalize_subject("Re: RE[2]: Fwd:  Your   Order"), "your order");
+        assert_eq!(normalize_subject("AW: Rechnung"), "rechnung");
+        assert_eq!(normalize_subject("Your order"), "your order");
+        assert!(is_reply("re : hello"));
+    }
+
+    #[test]
+    fn other_colons_are_kept() {
+        assert_eq!(normalize_subject("Note: meeting moved"), "note: meeting moved");
+        assert_eq!(normalize_subject("Re[x]: hi"), "re[x]: hi");
+        assert!(!is_reply("Reminder: pay"));
+        assert!(!is_reply(""));
+    }
+
+    #[test]
+    fn parents_are_nearest_first() {
+        let references = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(parent_ids(Some("c"), &references), vec!["c", "b", "a"]);
+        assert_eq!(parent_ids(None, &[]), Vec::<String>::new());
+    }
+
+    fn related(id: i64, thread_id: i64, message_id: &str, in_reply_to: Option<&str>, subject: &str, minute: i64) -> Related {
+        Related {
+            id,
+            thread_id,
+            message_id: Some(message_id.to_string()),
+            in_reply_to: in_reply_to.map(str::to_string),
+            references: Vec::new(),
+            subject: subject.to_string(),
+            received_at: chrono::DateTime::from_timestamp(minute * 60, 0).unwrap().naive_utc(),
+        }
+    }
+
+    fn lookup(subject: &str, message_id: &str, in_reply_to: Option<&str>, minute: i64) -> Lookup {
+        let email = ParsedEmail {
+            subject: subject.to_string(),
+            message_id: Some(message_id.to_string()),
+            in_reply_to: in_reply_to.map(str::to_string),
+            ..Default::default()
+        };
+        Lookup::new(&email, chrono::DateTime::from_timestamp(minute * 60, 0).unwrap().naive_utc())
+    }
+
+    #[test]
+    fn replies_join_their_parent() {
+        let stored = [related(1, 1, "a", None, "order", 0), related(2, 2, "b", None, "order", 1)];
+        let placement = place(3, &lookup("Re: order", "c", Some("a"), 2), &stored);
+        assert_eq!(placement, Placement { thread_id: 1, merged: vec![] });
+    }
+
+    #[test]
+    fn early_replies_are_merged_into_their_parent() {
+        // Two replies to "a" arrived first, in threads of their own
+        let stored = [related(1, 1, "b", Some("a"), "order", 1), related(2, 2, "c", Some("a"), "order", 2)];
+        let placement = place(3, &lookup("Order", "a", None, 0), &stored);
+        assert_eq!(placement, Placement { thread_id: 1, merged: vec![2] });
+    }
+
+    #[test]
+    fn headerless_replies_fall_back_to_the_latest_subject_match() {
+        let stored = [related(1, 1, "a", None, "order", 0), related(2, 2, "b", None, "order", 5), related(4, 4, "d", None, "other", 6)];
+        assert_eq!(place(3, &lookup("Re: Order", "c", None, 10), &stored).thread_id, 2);
+
+        // Not a reply, or past the window: a thread of its own
+        assert_eq!(place(3, &lookup("Order", "c", None, 10), &stored).thread_id, 3);
+        let late = SUBJECT_WINDOW_SECS / 60 + 10;
+        assert_eq!(place(3, &lookup("Re: Order", "c", None, late), &stored).thread_id, 3);
+    }
+
+    #[test]
+    fn ids_are_read_from_headers() {
+        assert_eq!(parse_ids("<a@x> \r\n <b@y>"), vec!["a@x", "b@y"]);
+        assert!(parse_ids("no brackets").is_empty());
+    }
+}
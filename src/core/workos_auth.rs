@@ -1,11 +1,16 @@
 use std::env;
 use serde::{Deserialize, Serialize};
+use jsonwebtoken::{decode, DecodingKey, Validation};
+
+/// How old a webhook delivery may be before it is refused as a replay
+const WEBHOOK_TOLERANCE_SECS: i64 = 180;
 
 /// WorkOS User Management configuration
 pub struct WorkOSConfig {
     pub api_key: String,
     pub client_id: String,
     pub redirect_uri: String,
+    pub api_base: String, // WORKOS_API_URL, for pointing at a mock in tests
 }
 
 impl WorkOSConfig {
@@ -19,6 +24,10 @@ impl WorkOSConfig {
                 "{}/auth/workos/callback",
                 env::var("SERVER_URL").unwrap_or_else(|_| "http://localhost:8080".to_string())
             ),
+            api_base: env::var("WORKOS_API_URL")
+                .unwrap_or_else(|_| "https://api.workos.com".to_string())
+                .trim_end_matches('/')
+                .to_string(),
         })
     }
 }
@@ -37,6 +46,24 @@ struct AuthCodeResponse {
     user: WorkOSUserResponse,
     access_token: String,
     refresh_token: String,
+    organization_id: Option<String>,
+}
+
+/// A completed WorkOS login
+#[derive(Debug)]
+pub struct Authentication {
+    pub user: WorkOSUser,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub organization_id: Option<String>, // WorkOS organization the user signed in to, for SSO logins
+}
+
+/// Where the authorize redirect sends the user
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginTarget {
+    AuthKit,              // Hosted login page with every enabled method
+    Organization(String), // The SSO connection of a WorkOS organization
+    Connection(String),   // One specific SSO connection
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,28 +75,73 @@ struct WorkOSUserResponse {
     email_verified: bool,
 }
 
-/// Get authorization URL for AuthKit hosted UI - shows all enabled auth providers
-/// (Email, Google, Microsoft, GitHub, etc.)
-pub fn get_auth_url(config: &WorkOSConfig, state: &str) -> String {
-    // Using provider=authkit gives the full hosted login page with all auth methods
-    format!(
-        "https://api.workos.com/user_management/authorize?response_type=code&client_id={}&redirect_uri={}&provider=authkit&state={}",
-        config.client_id,
-        url::form_urlencoded::byte_serialize(config.redirect_uri.as_bytes()).collect::<String>(),
-        url::form_urlencoded::byte_serialize(state.as_bytes()).collect::<String>()
-    )
+/// Get the authorization URL for a login target.
+/// AuthKit shows all enabled auth providers (Email, Google, Microsoft, GitHub, etc.),
+/// an organization or connection goes straight to the enterprise identity provider.
+/// `login_hint` pre-fills the email where the provider supports it.
+pub fn get_auth_url(config: &WorkOSConfig, state: &str, target: &LoginTarget, login_hint: Option<&str>) -> String {
+    let mut params = url::form_urlencoded::Serializer::new(String::new());
+    params
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_uri);
+    match target {
+        LoginTarget::AuthKit => params.append_pair("provider", "authkit"),
+        LoginTarget::Organization(id) => params.append_pair("organization_id", id),
+        LoginTarget::Connection(id) => params.append_pair("connection_id", id),
+    };
+    if let Some(hint) = login_hint {
+        params.append_pair("login_hint", hint);
+    }
+    params.append_pair("state", state);
+
+    format!("{}/user_management/authorize?{}", config.api_base, params.finish())
+}
+
+#[derive(Debug, Deserialize)]
+struct OrganizationList {
+    data: Vec<OrganizationResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrganizationResponse {
+    pub id: String,
+    pub name: String,
+}
+
+/// The WorkOS organization that verified an email domain, if any
+pub async fn find_organization_by_domain(config: &WorkOSConfig, domain: &str) -> Result<Option<OrganizationResponse>, String> {
+    let response = reqwest::Client::new()
+        .get(format!("{}/organizations", config.api_base))
+        .bearer_auth(&config.api_key)
+        .query(&[("domains[]", domain)])
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!("WorkOS API error: {}", error_text));
+    }
+
+    let list: OrganizationList = response
+        .json()
+        .await
+        .map_err(|e| format!("Failed to parse response: {}", e))?;
+
+    Ok(list.data.into_iter().next())
 }
 
 /// Exchange authorization code for user and tokens
 pub async fn authenticate_with_code(
     config: &WorkOSConfig,
     code: &str,
-) -> Result<(WorkOSUser, String, String), String> {
+) -> Result<Authentication, String> {
     let client = reqwest::Client::new();
     
     // WorkOS expects client_secret in the request body, not bearer auth header
     let response = client
-        .post("https://api.workos.com/user_management/authenticate")
+        .post(format!("{}/user_management/authenticate", config.api_base))
         .header("Content-Type", "application/json")
         .json(&serde_json::json!({
             "client_id": config.client_id,
@@ -99,5 +171,194 @@ pub async fn authenticate_with_code(
         email_verified: auth_response.user.email_verified,
     };
 
-    Ok((user, auth_response.access_token, auth_response.refresh_token))
+    Ok(Authentication {
+        user,
+        access_token: auth_response.access_token,
+        refresh_token: auth_response.refresh_token,
+        organization_id: auth_response.organization_id,
+    })
+}
+
+/// Why WorkOS refused to refresh a session
+#[derive(Debug)]
+pub enum RefreshError {
+    Ended,            // Session revoked, user removed or refresh token no longer valid
+    Failed(String),   // WorkOS unreachable or our own configuration rejected; try again later
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshResponse {
+    access_token: String,
+    refresh_token: String,
+}
+
+/// Trade a WorkOS refresh token for a new access token and refresh token.
+/// WorkOS refuses this once the session was revoked or the user was removed.
+pub async fn refresh(config: &WorkOSConfig, refresh_token: &str) -> Result<(String, String), RefreshError> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/user_management/authenticate", config.api_base))
+        .json(&serde_json::json!({
+            "client_id": config.client_id,
+            "client_secret": config.api_key,
+            "refresh_token": refresh_token,
+            "grant_type": "refresh_token"
+        }))
+        .send()
+        .await
+        .map_err(|e| RefreshError::Failed(format!("Request failed: {}", e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        // 401 means our API key was refused and 429/5xx are transient: don't log users out for those
+        return Err(if status.is_client_error() && status.as_u16() != 401 && status.as_u16() != 429 {
+            RefreshError::Ended
+        } else {
+            RefreshError::Failed(format!("WorkOS API error: {}", error_text))
+        });
+    }
+
+    let refreshed: RefreshResponse = response
+        .json()
+        .await
+        .map_err(|e| RefreshError::Failed(format!("Failed to parse response: {}", e)))?;
+
+    Ok((refreshed.access_token, refreshed.refresh_token))
+}
+
+/// End a session at WorkOS, so its refresh token stops working there too
+pub async fn revoke_session(config: &WorkOSConfig, session_id: &str) -> Result<(), String> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/user_management/sessions/revoke", config.api_base))
+        .bearer_auth(&config.api_key)
+        .json(&serde_json::json!({ "session_id": session_id }))
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(format!("WorkOS API error: {}", error_text));
+    }
+    Ok(())
+}
+
+/// Where to send the browser so AuthKit forgets the session too (otherwise the next login is silent)
+pub fn logout_url(config: &WorkOSConfig, session_id: &str, return_to: &str) -> String {
+    format!(
+        "{}/user_management/sessions/logout?session_id={}&return_to={}",
+        config.api_base,
+        url::form_urlencoded::byte_serialize(session_id.as_bytes()).collect::<String>(),
+        url::form_urlencoded::byte_serialize(return_to.as_bytes()).collect::<String>()
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessTokenClaims {
+    sid: String,
+}
+
+/// The WorkOS session id (`sid`) of an access token.
+/// The token came straight from WorkOS over TLS, so its signature isn't checked here.
+pub fn session_id_of(access_token: &str) -> Option<String> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+
+    decode::<AccessTokenClaims>(access_token, &DecodingKey::from_secret(&[]), &validation)
+        .ok()
+        .map(|data| data.claims.sid)
+}
+
+/// A webhook delivery, see https://workos.com/docs/events
+#[derive(Debug, Deserialize)]
+pub struct WebhookEvent {
+    pub event: String,
+    pub data: serde_json::Value,
+}
+
+/// Check a `WorkOS-Signature: t=<ms>, v1=<hex hmac>` header against the raw body.
+/// The signature is HMAC-SHA256 over `<t>.<body>` with the endpoint's webhook secret.
+pub fn verify_webhook(signature: &str, body: &[u8], secret: &str, now_ms: i64) -> bool {
+    let mut timestamp = None;
+    let mut expected = None;
+    for part in signature.split(',').map(str::trim) {
+        match part.split_once('=') {
+            Some(("t", t)) => timestamp = Some(t),
+            Some(("v1", v)) => expected = Some(v),
+            _ => {}
+        }
+    }
+    let (Some(timestamp), Some(expected)) = (timestamp, expected) else {
+        return false;
+    };
+
+    let Ok(sent_ms) = timestamp.parse::<i64>() else {
+        return false;
+    };
+    if (now_ms - sent_ms).abs() > WEBHOOK_TOLERANCE_SECS * 1000 {
+        return false;
+    }
+    let Some(expected) = decode_hex(expected) else {
+        return false;
+    };
+
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let mut signed = Vec::with_capacity(timestamp.len() + 1 + body.len());
+    signed.extend_from_slice(timestamp.as_bytes());
+    signed.push(b'.');
+    signed.extend_from_slice(body);
+
+    ring::hmac::verify(&key, &signed, &expected).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+        let tag = ring::hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes());
+        let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        format!("t={}, v1={}", timestamp, hex)
+    }
+
+    #[test]
+    fn webhook_signatures_are_checked() {
+        let body = r#"{"event":"session.revoked","data":{"id":"session_1"}}"#;
+        let now = 1_700_000_000_000;
+        let header = sign("whsec", now, body);
+
+        assert!(verify_webhook(&header, body.as_bytes(), "whsec", now + 1000));
+        assert!(!verify_webhook(&header, body.as_bytes(), "other", now));
+        assert!(!verify_webhook(&header, br#"{"event":"user.deleted"}"#, "whsec", now));
+        assert!(!verify_webhook(&header, body.as_bytes(), "whsec", now + 600_000), "stale deliveries are replays");
+        assert!(!verify_webhook("v1=abcd", body.as_bytes(), "whsec", now));
+    }
+
+    #[test]
+    fn session_id_is_read_from_the_access_token() {
+        let claims = serde_json::json!({ "sid": "session_01H", "sub": "user_01H", "exp": 1 });
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"workos"),
+        )
+        .unwrap();
+
+        assert_eq!(session_id_of(&token).as_deref(), Some("session_01H"));
+        assert_eq!(session_id_of("not-a-jwt"), None);
+    }
 }
@@ -0,0 +1,190 @@
+use sqlx::{PgPool, Row};
+use crate::core::crypto::{self, KeyRing};
+use crate::core::workos_auth::{self, RefreshError, WorkOSConfig};
+
+/// Authenticated-data context of a stored WorkOS refresh token
+pub fn context(workos_session_id: &str) -> String {
+    format!("workos_sessions.refresh_token:{}", workos_session_id)
+}
+
+/// Remember the WorkOS session behind a login, with its refresh token sealed, and the
+/// WorkOS organization it signed in through. Returns the WorkOS session id.
+pub async fn store(
+    pool: &PgPool,
+    keys: &KeyRing,
+    user_id: &str,
+    access_token: &str,
+    refresh_token: &str,
+    organization_id: Option<&str>,
+) -> Result<String, String> {
+    let session_id = workos_auth::session_id_of(access_token)
+        .ok_or_else(|| "WorkOS access token has no session id".to_string())?;
+    let sealed = keys.seal(refresh_token, &context(&session_id))?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO workos_sessions (id, user_id, refresh_token, organization_id)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (id) DO UPDATE SET refresh_token = EXCLUDED.refresh_token, organization_id = EXCLUDED.organization_id
+        "#
+    )
+    .bind(&session_id)
+    .bind(user_id)
+    .bind(&sealed)
+    .bind(organization_id)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("DB error: {}", e))?;
+
+    Ok(session_id)
+}
+
+/// The WorkOS organization a login session signed in through, while its WorkOS session lasts.
+/// None for logins that didn't go through WorkOS SSO.
+pub async fn organization_of(pool: &PgPool, auth_session_id: &str) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        SELECT w.organization_id FROM auth_sessions s
+        JOIN workos_sessions w ON w.id = s.workos_session_id
+        WHERE s.id = $1 AND s.revoked_at IS NULL AND w.revoked_at IS NULL
+        "#
+    )
+    .bind(auth_session_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|r| r.get("organization_id")))
+}
+
+/// Refresh a WorkOS session through the WorkOS API, keeping the rotated refresh token.
+/// `Ended` means WorkOS no longer knows the session (revoked, user removed): it is marked as such.
+pub async fn refresh(pool: &PgPool, keys: &KeyRing, workos_session_id: &str) -> Result<(), RefreshError> {
+    let db_error = |e: sqlx::Error| RefreshError::Failed(format!("DB error: {}", e));
+    let config = WorkOSConfig::from_env().map_err(RefreshError::Failed)?;
+
+    // Held across the WorkOS call: two refreshes racing on one refresh token would make WorkOS refuse the second
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    let row = sqlx::query("SELECT refresh_token FROM workos_sessions WHERE id = $1 AND revoked_at IS NULL FOR UPDATE")
+        .bind(workos_session_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(RefreshError::Ended)?;
+
+    let sealed: String = row.get("refresh_token");
+    let refresh_token = match keys.open(&sealed, &context(workos_session_id)) {
+        Ok(token) => token,
+        Err(e) => {
+            // Sealed with a key that is gone: the session can't be continued
+            eprintln!("⚠️ Can't open WorkOS refresh token of {}: {}", workos_session_id, e);
+            drop(tx);
+            mark_ended(pool, workos_session_id).await.map_err(db_error)?;
+            return Err(RefreshError::Ended);
+        }
+    };
+
+    match workos_auth::refresh(&config, &refresh_token).await {
+        Ok((_, next_refresh_token)) => {
+            let sealed = keys.seal(&next_refresh_token, &context(workos_session_id)).map_err(RefreshError::Failed)?;
+            sqlx::query("UPDATE workos_sessions SET refresh_token = $1, last_refreshed_at = NOW() WHERE id = $2")
+                .bind(&sealed)
+                .bind(workos_session_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(db_error)?;
+            tx.commit().await.map_err(db_error)?;
+            Ok(())
+        }
+        Err(RefreshError::Ended) => {
+            drop(tx);
+            mark_ended(pool, workos_session_id).await.map_err(db_error)?;
+            Err(RefreshError::Ended)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// End a session on logout: here, and at WorkOS so its refresh token dies there too
+pub async fn end(pool: &PgPool, workos_session_id: &str) -> Result<(), String> {
+    mark_ended(pool, workos_session_id).await.map_err(|e| format!("DB error: {}", e))?;
+
+    let config = WorkOSConfig::from_env()?;
+    workos_auth::revoke_session(&config, workos_session_id).await
+}
+
+/// Record that WorkOS ended a session (logout, revocation webhook, refused refresh)
+pub async fn mark_ended(pool: &PgPool, workos_session_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE workos_sessions SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+        .bind(workos_session_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Record that every WorkOS session of a user ended (user removed in WorkOS)
+pub async fn mark_user_ended(pool: &PgPool, user_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE workos_sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Re-seal the refresh token of every live WorkOS session that is sealed with a non-active key.
+/// Returns the number of sessions rewritten.
+pub async fn reencrypt_refresh_tokens(pool: &PgPool, keys: &KeyRing) -> Result<u64, String> {
+    // Sealed values read enc:v1:<key id>:...
+    let rows = sqlx::query(
+        "SELECT id FROM workos_sessions WHERE revoked_at IS NULL AND split_part(refresh_token, ':', 3) <> $1"
+    )
+    .bind(keys.active_key_id())
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("DB error: {}", e))?;
+
+    let mut rewritten = 0;
+    for row in rows {
+        if reseal(pool, keys, row.get("id")).await? {
+            rewritten += 1;
+        }
+    }
+
+    Ok(rewritten)
+}
+
+/// Re-seal one session's refresh token with the active key, like `crypto` does for users:
+/// the write only lands if the token is still the one read, so a refresh meanwhile wins.
+async fn reseal(pool: &PgPool, keys: &KeyRing, workos_session_id: String) -> Result<bool, String> {
+    loop {
+        let row = sqlx::query("SELECT refresh_token FROM workos_sessions WHERE id = $1 AND revoked_at IS NULL")
+            .bind(&workos_session_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("DB error: {}", e))?;
+        let Some(row) = row else {
+            return Ok(false);
+        };
+
+        let stored: String = row.get("refresh_token");
+        if crypto::key_id_of(&stored) == Some(keys.active_key_id()) {
+            return Ok(false);
+        }
+        let ctx = context(&workos_session_id);
+        let resealed = keys.seal(&keys.open(&stored, &ctx)?, &ctx)?;
+
+        let result = sqlx::query("UPDATE workos_sessions SET refresh_token = $1 WHERE id = $2 AND refresh_token = $3")
+            .bind(&resealed)
+            .bind(&workos_session_id)
+            .bind(&stored)
+            .execute(pool)
+            .await
+            .map_err(|e| format!("DB error: {}", e))?;
+
+        if result.rows_affected() > 0 {
+            return Ok(true);
+        }
+        // Refreshed under us: read it again
+    }
+}
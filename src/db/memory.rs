@@ -0,0 +1,568 @@
+//! In-memory repositories for tests: same behaviour as Postgres, no database needed
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::core::dkim::DkimSignature;
+use crate::core::ingest::ParsedEmail;
+use crate::core::search::SearchQuery;
+use crate::core::threads;
+use super::{
+    AliasRepo, AliasRow, AttachmentRow, EmailDetail, EmailQuery, EmailRepo, EmailRow, ImapAccount, Inbox, Recipient,
+    SealedTokens, SearchHit, StateChange, SyncAccount, ThreadQuery, ThreadRow, TokenRepo, UnreadCount, UserRepo,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryUser {
+    pub email: String,
+    pub auth_provider: Option<String>,
+    pub imap: Option<ImapAccount>,
+    pub tokens: Option<SealedTokens>,
+    pub sync_cursor: Option<String>,
+}
+
+/// What an email has besides its row
+#[derive(Default)]
+struct Contents {
+    body_text: Option<String>,
+    body_html: Option<String>,
+    raw_message: Option<Vec<u8>>,
+    recipients: Option<String>,
+    header_message_id: Option<String>,
+    in_reply_to: Option<String>,
+    references: Vec<String>,
+    thread_subject: String,
+    headers: Vec<(String, String)>,
+    dkim: Option<Vec<DkimSignature>>,
+    attachments: Vec<AttachmentRow>,
+}
+
+#[derive(Default)]
+struct Tables {
+    users: HashMap<String, MemoryUser>,
+    aliases: Vec<AliasRow>,
+    emails: Vec<EmailRow>,
+    contents: HashMap<i64, Contents>,
+    last_id: i64, // Of emails and attachments, like a sequence
+}
+
+impl Tables {
+    fn next_id(&mut self) -> i64 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    /// Put a newly stored email in a thread, as `threads::place` decides
+    fn assign_thread(&mut self, email_id: i64, to: &Recipient, email: &ParsedEmail) {
+        let inbox = to.inbox();
+        let received_at = self.emails.iter().find(|e| e.id == email_id).map(|e| e.received_at).unwrap_or_default();
+        // Everything in the inbox: `place` picks what matters
+        let related: Vec<threads::Related> = self.emails.iter()
+            .filter(|e| in_inbox(e, inbox))
+            .filter_map(|e| {
+                let c = self.contents.get(&e.id)?;
+                Some(threads::Related {
+                    id: e.id,
+                    thread_id: e.thread_id,
+                    message_id: c.header_message_id.clone(),
+                    in_reply_to: c.in_reply_to.clone(),
+                    references: c.references.clone(),
+                    subject: c.thread_subject.clone(),
+                    received_at: e.received_at,
+                })
+            })
+            .collect();
+
+        let placement = threads::place(email_id, &threads::Lookup::new(email, received_at), &related);
+        for e in self.emails.iter_mut().filter(|e| in_inbox(e, inbox)) {
+            if e.id == email_id || placement.merged.contains(&e.thread_id) {
+                e.thread_id = placement.thread_id;
+            }
+        }
+    }
+}
+
+fn in_inbox(email: &EmailRow, inbox: Inbox<'_>) -> bool {
+    match inbox {
+        Inbox::Personal(user_id) => email.user_id == user_id && email.org_id.is_none(),
+        Inbox::Shared(org_id) => email.org_id.as_deref() == Some(org_id),
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+fn timestamp(secs: i64) -> NaiveDateTime {
+    DateTime::from_timestamp(secs, 0).unwrap_or_default().naive_utc()
+}
+
+fn contains(text: Option<&str>, part: &str) -> bool {
+    text.is_some_and(|text| text.to_lowercase().contains(&part.to_lowercase()))
+}
+
+/// The filters of `EmailQuery`, as the Postgres query applies them
+fn matches(email: &EmailRow, query: &EmailQuery) -> bool {
+    query.alias.as_ref().is_none_or(|alias| email.alias.as_ref() == Some(alias))
+        && query.sender.as_deref().is_none_or(|part| contains(Some(&email.sender), part))
+        && query.subject.as_deref().is_none_or(|part| contains(email.subject.as_deref(), part))
+        && query.after.is_none_or(|after| email.received_at >= after)
+        && query.before.is_none_or(|before| email.received_at < before)
+        && query.has_otp.is_none_or(|has_otp| email.otp.is_some() == has_otp)
+        && query.read.is_none_or(|read| email.read_at.is_some() == read)
+        && query.starred.is_none_or(|starred| email.starred == starred)
+        && query.label.as_ref().is_none_or(|label| email.labels.contains(label))
+        && query.cursor.is_none_or(|c| (email.received_at, email.id) < (c.received_at, c.id))
+}
+
+/// Words of a search's free text: a rough stand-in for `websearch_to_tsquery`.
+/// Every word must appear; `-word` must not; `or` and quotes are ignored.
+fn search_terms(text: &str) -> (Vec<String>, Vec<String>) {
+    let words = text.split_whitespace()
+        .map(|w| w.trim_matches('"').to_lowercase())
+        .filter(|w| !w.is_empty() && w != "or");
+    let (excluded, included): (Vec<String>, Vec<String>) = words.partition(|w| w.starts_with('-'));
+    (included, excluded.into_iter().map(|w| w[1..].to_string()).collect())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// The body, escaped, with words containing a term wrapped in `<mark>`
+fn highlight(body: &str, terms: &[String]) -> String {
+    body.split_whitespace()
+        .map(|word| {
+            let escaped = escape_html(word);
+            let lower = word.to_lowercase();
+            if terms.iter().any(|t| lower.contains(t.as_str())) {
+                format!("<mark>{}</mark>", escaped)
+            } else {
+                escaped
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user(&self, user_id: &str, email: &str) {
+        self.tables.lock().unwrap().users.insert(
+            user_id.to_string(),
+            MemoryUser { email: email.to_string(), ..Default::default() },
+        );
+    }
+
+    pub fn user(&self, user_id: &str) -> Option<MemoryUser> {
+        self.tables.lock().unwrap().users.get(user_id).cloned()
+    }
+
+    pub fn aliases(&self) -> Vec<AliasRow> {
+        self.tables.lock().unwrap().aliases.clone()
+    }
+
+    pub fn emails(&self) -> Vec<EmailRow> {
+        self.tables.lock().unwrap().emails.clone()
+    }
+
+    pub fn attachment_count(&self, email_id: i64) -> usize {
+        self.tables.lock().unwrap().contents.get(&email_id).map_or(0, |c| c.attachments.len())
+    }
+
+    fn insert_alias(&self, alias: &str, user_id: &str, org_id: Option<&str>) {
+        self.tables.lock().unwrap().aliases.push(AliasRow {
+            alias: alias.to_string(),
+            user_id: user_id.to_string(),
+            org_id: org_id.map(str::to_string),
+            created_at: Some(now()),
+        });
+    }
+
+}
+
+#[async_trait]
+impl UserRepo for MemoryStore {
+    async fn upsert_imap(&self, user_id: &str, email: &str, imap: &ImapAccount) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let user = tables.users.entry(user_id.to_string()).or_default();
+        user.email = email.to_string();
+        user.imap = Some(imap.clone());
+        Ok(())
+    }
+
+    async fn upsert_workos(&self, user_id: &str, email: &str) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let user = tables.users.entry(user_id.to_string()).or_default();
+        user.email = email.to_string();
+        user.auth_provider = Some("workos".to_string());
+        Ok(())
+    }
+
+    async fn sync_account(&self, user_id: &str) -> Result<Option<SyncAccount>, sqlx::Error> {
+        Ok(self.tables.lock().unwrap().users.get(user_id).map(|user| SyncAccount {
+            email: user.email.clone(),
+            auth_provider: user.auth_provider.clone(),
+            imap: user.imap.clone().unwrap_or(ImapAccount { server: None, port: 993, password: None }),
+            tokens: user.tokens.clone(),
+            cursor: user.sync_cursor.clone(),
+        }))
+    }
+
+    async fn save_sync_cursor(&self, user_id: &str, cursor: &str) -> Result<(), sqlx::Error> {
+        if let Some(user) = self.tables.lock().unwrap().users.get_mut(user_id) {
+            user.sync_cursor = Some(cursor.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TokenRepo for MemoryStore {
+    async fn save_login(
+        &self,
+        user_id: &str,
+        email: &str,
+        provider: &str,
+        imap_server: &str,
+        tokens: &SealedTokens,
+    ) -> Result<(), sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let user = tables.users.entry(user_id.to_string()).or_insert_with(|| MemoryUser {
+            email: email.to_string(),
+            ..Default::default()
+        });
+        user.auth_provider = Some(provider.to_string());
+        user.tokens = Some(tokens.clone());
+        let imap = user.imap.get_or_insert(ImapAccount { server: None, port: 993, password: None });
+        imap.server = Some(imap_server.to_string());
+        Ok(())
+    }
+
+    async fn connect(&self, user_id: &str, provider: &str, tokens: &SealedTokens) -> Result<(), sqlx::Error> {
+        if let Some(user) = self.tables.lock().unwrap().users.get_mut(user_id) {
+            user.auth_provider.get_or_insert_with(|| provider.to_string());
+            user.tokens = Some(tokens.clone());
+        }
+        Ok(())
+    }
+
+    async fn save_refreshed(&self, user_id: &str, tokens: &SealedTokens) -> Result<(), sqlx::Error> {
+        if let Some(user) = self.tables.lock().unwrap().users.get_mut(user_id) {
+            let refresh_token = tokens.refresh_token.clone().or_else(|| user.tokens.as_ref().and_then(|t| t.refresh_token.clone()));
+            user.tokens = Some(SealedTokens { refresh_token, ..tokens.clone() });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AliasRepo for MemoryStore {
+    async fn resolve(&self, alias: &str) -> Result<Option<Recipient>, sqlx::Error> {
+        Ok(self.tables.lock().unwrap().aliases.iter()
+            .find(|a| a.alias == alias)
+            .map(|a| Recipient { user_id: a.user_id.clone(), org_id: a.org_id.clone(), alias: Some(a.alias.clone()) }))
+    }
+
+    async fn personal(&self, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+        Ok(self.tables.lock().unwrap().aliases.iter()
+            .filter(|a| a.user_id == user_id && a.org_id.is_none())
+            .max_by_key(|a| a.created_at)
+            .map(|a| a.alias.clone()))
+    }
+
+    async fn replace_personal(&self, user_id: &str, alias: &str) -> Result<(), sqlx::Error> {
+        self.delete_personal(user_id).await?;
+        self.insert_alias(alias, user_id, None);
+        Ok(())
+    }
+
+    async fn delete_personal(&self, user_id: &str) -> Result<(), sqlx::Error> {
+        self.tables.lock().unwrap().aliases.retain(|a| a.user_id != user_id || a.org_id.is_some());
+        Ok(())
+    }
+
+    async fn create_shared(&self, org_id: &str, creator_id: &str, alias: &str) -> Result<(), sqlx::Error> {
+        self.insert_alias(alias, creator_id, Some(org_id));
+        Ok(())
+    }
+
+    async fn list_shared(&self, org_id: &str) -> Result<Vec<AliasRow>, sqlx::Error> {
+        let mut aliases: Vec<AliasRow> = self.tables.lock().unwrap().aliases.iter()
+            .filter(|a| a.org_id.as_deref() == Some(org_id))
+            .cloned()
+            .collect();
+        aliases.sort_by_key(|a| std::cmp::Reverse(a.created_at));
+        Ok(aliases)
+    }
+
+    async fn delete_shared(&self, org_id: &str, alias: &str) -> Result<bool, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let before = tables.aliases.len();
+        tables.aliases.retain(|a| a.alias != alias || a.org_id.as_deref() != Some(org_id));
+        Ok(tables.aliases.len() < before)
+    }
+}
+
+#[async_trait]
+impl EmailRepo for MemoryStore {
+    async fn store(
+        &self,
+        to: &Recipient,
+        message_id: Option<&str>,
+        email: &ParsedEmail,
+        received_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let existing = message_id.and_then(|mid| {
+            tables.emails.iter_mut().find(|e| e.user_id == to.user_id && e.message_id.as_deref() == Some(mid))
+        });
+
+        let (id, new) = match existing {
+            Some(row) => {
+                row.body_preview = Some(email.body_preview.clone());
+                row.otp = email.otp.clone();
+                row.received_at = timestamp(received_at);
+                (row.id, false)
+            }
+            None => {
+                let id = tables.next_id();
+                tables.emails.push(EmailRow {
+                    id,
+                    user_id: to.user_id.clone(),
+                    org_id: to.org_id.clone(),
+                    message_id: message_id.map(str::to_string),
+                    sender: email.sender.clone(),
+                    subject: Some(email.subject.clone()),
+                    body_preview: Some(email.body_preview.clone()),
+                    otp: email.otp.clone(),
+                    alias: to.alias.clone(),
+                    read_at: None,
+                    received_at: timestamp(received_at),
+                    thread_id: id,
+                    starred: false,
+                    labels: Vec::new(),
+                });
+                (id, true)
+            }
+        };
+        let attachments = email.attachments.iter()
+            .map(|a| AttachmentRow {
+                id: tables.next_id(),
+                filename: a.filename.clone(),
+                content_type: a.content_type.clone(),
+                size_bytes: a.content.len() as i32,
+                content: Some(a.content.clone()),
+            })
+            .collect();
+        tables.contents.insert(id, Contents {
+            body_text: email.body_text.clone(),
+            body_html: email.body_html.clone(),
+            raw_message: (!email.raw.is_empty()).then(|| email.raw.clone()),
+            recipients: (!email.to.is_empty()).then(|| email.to.join(", ")),
+            header_message_id: email.message_id.clone(),
+            in_reply_to: email.in_reply_to.clone(),
+            references: email.references.clone(),
+            thread_subject: threads::normalize_subject(&email.subject),
+            headers: email.headers.clone(),
+            dkim: email.dkim.clone(),
+            attachments,
+        });
+        if new {
+            tables.assign_thread(id, to, email);
+        }
+        Ok(id)
+    }
+
+    async fn store_if_new(
+        &self,
+        to: &Recipient,
+        message_id: &str,
+        email: &ParsedEmail,
+        received_at: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let known = self.tables.lock().unwrap().emails.iter()
+            .any(|e| e.user_id == to.user_id && e.message_id.as_deref() == Some(message_id));
+        if known {
+            return Ok(false);
+        }
+        self.store(to, Some(message_id), email, received_at).await?;
+        Ok(true)
+    }
+
+    async fn list(&self, inbox: Inbox<'_>, query: &EmailQuery) -> Result<Vec<EmailRow>, sqlx::Error> {
+        let mut emails: Vec<EmailRow> = self.emails().into_iter()
+            .filter(|e| in_inbox(e, inbox) && matches(e, query))
+            .collect();
+        emails.sort_by(|a, b| b.received_at.cmp(&a.received_at).then(b.id.cmp(&a.id)));
+        emails.truncate(query.limit.max(0) as usize);
+        Ok(emails)
+    }
+
+    async fn search(&self, inbox: Inbox<'_>, search: &SearchQuery, limit: i64) -> Result<Vec<SearchHit>, sqlx::Error> {
+        let (terms, excluded) = search_terms(&search.text);
+        let tables = self.tables.lock().unwrap();
+        let mut hits: Vec<SearchHit> = tables.emails.iter()
+            .filter(|e| in_inbox(e, inbox))
+            .filter_map(|e| {
+                let contents = tables.contents.get(&e.id);
+                let body = contents.and_then(|c| c.body_text.clone()).or_else(|| e.body_preview.clone()).unwrap_or_default();
+                let to = format!(
+                    "{} {}",
+                    contents.and_then(|c| c.recipients.as_deref()).unwrap_or(""),
+                    e.alias.as_deref().unwrap_or(""),
+                );
+                let document = format!("{} {} {}", e.subject.as_deref().unwrap_or(""), e.sender, body).to_lowercase();
+
+                let found = search.from.iter().all(|p| contains(Some(&e.sender), p))
+                    && search.to.iter().all(|p| contains(Some(&to), p))
+                    && search.subject.iter().all(|p| contains(e.subject.as_deref(), p))
+                    && (!search.has_otp || e.otp.is_some())
+                    && search.after.is_none_or(|after| e.received_at >= after)
+                    && search.before.is_none_or(|before| e.received_at < before)
+                    && terms.iter().all(|t| document.contains(t.as_str()))
+                    && !excluded.iter().any(|t| document.contains(t.as_str()));
+                found.then(|| SearchHit {
+                    email: e.clone(),
+                    rank: terms.iter().map(|t| document.matches(t.as_str()).count()).sum::<usize>() as f32,
+                    snippet: highlight(&body, &terms),
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| {
+            b.rank.total_cmp(&a.rank)
+                .then(b.email.received_at.cmp(&a.email.received_at))
+                .then(b.email.id.cmp(&a.email.id))
+        });
+        hits.truncate(limit.max(0) as usize);
+        Ok(hits)
+    }
+
+    async fn threads(&self, inbox: Inbox<'_>, query: &ThreadQuery) -> Result<Vec<ThreadRow>, sqlx::Error> {
+        let mut by_thread: HashMap<i64, Vec<EmailRow>> = HashMap::new();
+        for email in self.emails().into_iter().filter(|e| in_inbox(e, inbox)) {
+            by_thread.entry(email.thread_id).or_default().push(email);
+        }
+
+        let mut threads: Vec<ThreadRow> = by_thread.into_iter()
+            .map(|(thread_id, mut emails)| {
+                emails.sort_by_key(|e| (e.received_at, e.id));
+                let mut participants: Vec<String> = emails.iter().map(|e| e.sender.clone()).collect();
+                participants.sort();
+                participants.dedup();
+                let (first, last) = (&emails[0], &emails[emails.len() - 1]);
+                ThreadRow {
+                    thread_id,
+                    subject: first.subject.clone(),
+                    participants,
+                    message_count: emails.len() as i64,
+                    unread_count: emails.iter().filter(|e| e.read_at.is_none()).count() as i64,
+                    latest_preview: last.body_preview.clone(),
+                    last_received_at: last.received_at,
+                }
+            })
+            .filter(|t| query.cursor.is_none_or(|c| (t.last_received_at, t.thread_id) < (c.received_at, c.id)))
+            .collect();
+        threads.sort_by(|a, b| b.last_received_at.cmp(&a.last_received_at).then(b.thread_id.cmp(&a.thread_id)));
+        threads.truncate(query.limit.max(0) as usize);
+        Ok(threads)
+    }
+
+    async fn thread(&self, inbox: Inbox<'_>, thread_id: i64) -> Result<Vec<EmailRow>, sqlx::Error> {
+        let mut emails: Vec<EmailRow> = self.emails().into_iter()
+            .filter(|e| in_inbox(e, inbox) && e.thread_id == thread_id)
+            .collect();
+        emails.sort_by_key(|e| (e.received_at, e.id));
+        Ok(emails)
+    }
+
+    async fn get(&self, inbox: Inbox<'_>, id: i64) -> Result<Option<EmailDetail>, sqlx::Error> {
+        let tables = self.tables.lock().unwrap();
+        let Some(email) = tables.emails.iter().find(|e| e.id == id && in_inbox(e, inbox)) else { return Ok(None) };
+        let contents = tables.contents.get(&id);
+        Ok(Some(EmailDetail {
+            email: email.clone(),
+            body_text: contents.and_then(|c| c.body_text.clone()),
+            body_html: contents.and_then(|c| c.body_html.clone()),
+            raw_message: contents.and_then(|c| c.raw_message.clone()),
+            headers: contents.map(|c| c.headers.clone()).filter(|h| !h.is_empty()),
+            dkim: contents.and_then(|c| c.dkim.clone()),
+            attachments: contents.map(|c| c.attachments.clone()).unwrap_or_default(),
+        }))
+    }
+
+    async fn raw(&self, inbox: Inbox<'_>, id: i64) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        Ok(self.get(inbox, id).await?.and_then(|detail| detail.raw_message))
+    }
+
+    async fn update_state(&self, inbox: Inbox<'_>, ids: &[i64], change: &StateChange) -> Result<Vec<EmailRow>, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let mut changed = Vec::new();
+        for email in tables.emails.iter_mut().filter(|e| ids.contains(&e.id) && in_inbox(e, inbox)) {
+            match change.read {
+                Some(true) => email.read_at = email.read_at.or_else(|| Some(now())),
+                Some(false) => email.read_at = None,
+                None => {}
+            }
+            email.starred = change.starred.unwrap_or(email.starred);
+            email.labels.extend(change.add_labels.iter().cloned());
+            email.labels.retain(|label| !change.remove_labels.contains(label));
+            email.labels.sort();
+            email.labels.dedup();
+            changed.push(email.clone());
+        }
+        Ok(changed)
+    }
+
+    async fn unread_counts(&self, inbox: Inbox<'_>) -> Result<Vec<UnreadCount>, sqlx::Error> {
+        let mut counts: Vec<UnreadCount> = Vec::new();
+        for email in self.emails().iter().filter(|e| in_inbox(e, inbox) && e.read_at.is_none()) {
+            match counts.iter_mut().find(|c| c.alias == email.alias) {
+                Some(count) => count.unread += 1,
+                None => counts.push(UnreadCount { alias: email.alias.clone(), unread: 1 }),
+            }
+        }
+        counts.sort_by(|a, b| a.alias.cmp(&b.alias)); // None first, like NULLS FIRST
+        Ok(counts)
+    }
+
+    async fn delete(&self, inbox: Inbox<'_>, id: i64) -> Result<bool, sqlx::Error> {
+        let mut tables = self.tables.lock().unwrap();
+        let before = tables.emails.len();
+        tables.emails.retain(|e| e.id != id || !in_inbox(e, inbox));
+        let deleted = tables.emails.len() < before;
+        if deleted {
+            tables.contents.remove(&id);
+        }
+        Ok(deleted)
+    }
+
+    async fn delete_matching(&self, inbox: Inbox<'_>, query: &EmailQuery) -> Result<u64, sqlx::Error> {
+        let filters = EmailQuery { cursor: None, ..query.clone() };
+        let mut tables = self.tables.lock().unwrap();
+        let (deleted, kept): (Vec<EmailRow>, Vec<EmailRow>) = std::mem::take(&mut tables.emails)
+            .into_iter()
+            .partition(|e| in_inbox(e, inbox) && matches(e, &filters));
+        tables.emails = kept;
+        for email in &deleted {
+            tables.contents.remove(&email.id);
+        }
+        Ok(deleted.len() as u64)
+    }
+
+    async fn count_recent(&self, user_id: &str, window_secs: i64) -> Result<i64, sqlx::Error> {
+        let since = now() - chrono::Duration::seconds(window_secs);
+        Ok(self.tables.lock().unwrap().emails.iter()
+            .filter(|e| e.user_id == user_id && e.received_at > since)
+            .count() as i64)
+    }
+}
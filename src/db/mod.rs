@@ -0,0 +1,360 @@
+//! Typed access to users, aliases, emails and provider tokens.
+//! Handlers and workers talk to these traits; `postgres` backs them in production
+//! and `memory` in tests that run without a database.
+
+pub mod memory;
+pub mod postgres;
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::sync::Arc;
+use crate::core::dkim::DkimSignature;
+use crate::core::ingest::ParsedEmail;
+use crate::core::search::SearchQuery;
+
+/// A stored email, as listed in inboxes
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct EmailRow {
+    pub id: i64,
+    pub user_id: String,
+    pub org_id: Option<String>, // Set for mail received by a shared alias
+    pub message_id: Option<String>,
+    pub sender: String,
+    pub subject: Option<String>,
+    pub body_preview: Option<String>,
+    pub otp: Option<String>,
+    pub alias: Option<String>, // Alias it was addressed to, None for the mailbox itself
+    pub read_at: Option<NaiveDateTime>,
+    pub received_at: NaiveDateTime,
+    pub thread_id: i64,
+    pub starred: bool,
+    pub labels: Vec<String>,
+}
+
+/// A conversation, as listed in inboxes
+#[derive(Debug, Clone)]
+pub struct ThreadRow {
+    pub thread_id: i64,
+    pub subject: Option<String>,   // Of its first email
+    pub participants: Vec<String>, // Senders, each once
+    pub message_count: i64,
+    pub unread_count: i64,
+    pub latest_preview: Option<String>,
+    pub last_received_at: NaiveDateTime,
+}
+
+/// An email found by a search
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub email: EmailRow,
+    pub rank: f32,       // Higher is better; 0 when the search has no free text
+    pub snippet: String, // HTML-escaped body excerpt, matches wrapped in <mark></mark>
+}
+
+/// A stored email with everything it was received with
+#[derive(Debug, Clone)]
+pub struct EmailDetail {
+    pub email: EmailRow,
+    pub body_text: Option<String>,
+    pub body_html: Option<String>,
+    pub raw_message: Option<Vec<u8>>,
+    pub headers: Option<Vec<(String, String)>>, // None for mail stored before headers were kept
+    pub dkim: Option<Vec<DkimSignature>>,        // None unless verified on arrival
+    pub attachments: Vec<AttachmentRow>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AttachmentRow {
+    pub id: i64,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size_bytes: i32,
+    pub content: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct AliasRow {
+    pub alias: String,
+    pub user_id: String,        // Owner, or creator of a shared alias
+    pub org_id: Option<String>, // Organization of a shared alias
+    pub created_at: Option<NaiveDateTime>,
+}
+
+/// Whose inbox mail for an address lands in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipient {
+    pub user_id: String,
+    pub org_id: Option<String>,
+    pub alias: Option<String>,
+}
+
+impl Recipient {
+    /// Mail for the user's own mailbox rather than one of their aliases
+    pub fn mailbox(user_id: &str) -> Self {
+        Recipient { user_id: user_id.to_string(), org_id: None, alias: None }
+    }
+
+    /// The inbox mail for this recipient lands in
+    pub fn inbox(&self) -> Inbox<'_> {
+        match &self.org_id {
+            Some(org_id) => Inbox::Shared(org_id),
+            None => Inbox::Personal(&self.user_id),
+        }
+    }
+}
+
+/// A personal inbox, or the shared inbox of an organization
+#[derive(Debug, Clone, Copy)]
+pub enum Inbox<'a> {
+    Personal(&'a str),
+    Shared(&'a str),
+}
+
+/// Filters and page of an email listing; None fields don't filter
+#[derive(Debug, Clone, Default)]
+pub struct EmailQuery {
+    pub alias: Option<String>,
+    pub sender: Option<String>,  // Case-insensitive substring
+    pub subject: Option<String>, // Case-insensitive substring
+    pub after: Option<NaiveDateTime>,
+    pub before: Option<NaiveDateTime>,
+    pub has_otp: Option<bool>,
+    pub read: Option<bool>,
+    pub starred: Option<bool>,
+    pub label: Option<String>,
+    pub cursor: Option<EmailCursor>, // Only emails listed after this one
+    pub limit: i64,
+}
+
+/// Changes to the state of emails; None and empty fields leave it as is
+#[derive(Debug, Clone, Default)]
+pub struct StateChange {
+    pub read: Option<bool>,
+    pub starred: Option<bool>,
+    pub add_labels: Vec<String>,
+    pub remove_labels: Vec<String>, // Applied after add_labels
+}
+
+/// Unread mail of one alias of an inbox
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnreadCount {
+    pub alias: Option<String>, // None for mail to the mailbox itself
+    pub unread: i64,
+}
+
+/// A page of an inbox's threads
+#[derive(Debug, Clone, Default)]
+pub struct ThreadQuery {
+    pub cursor: Option<EmailCursor>, // Only threads last active before this one
+    pub limit: i64,
+}
+
+/// Position in a listing (newest first): the last email of the previous page,
+/// or for threads the last activity and id of the last thread
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmailCursor {
+    pub received_at: NaiveDateTime,
+    pub id: i64,
+}
+
+impl EmailCursor {
+    pub fn of(email: &EmailRow) -> Self {
+        EmailCursor { received_at: email.received_at, id: email.id }
+    }
+
+    /// Opaque token handed to clients as `next_cursor`
+    pub fn encode(&self) -> String {
+        use base64::Engine;
+        let raw = format!("{}:{}", self.received_at.and_utc().timestamp_micros(), self.id);
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw)
+    }
+
+    pub fn decode(token: &str) -> Option<Self> {
+        use base64::Engine;
+        let raw = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(token).ok()?;
+        let (micros, id) = std::str::from_utf8(&raw).ok()?.split_once(':')?;
+        Some(EmailCursor {
+            received_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?.naive_utc(),
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+/// IMAP credentials of a user, the password sealed with `crypto`
+#[derive(Debug, Clone)]
+pub struct ImapAccount {
+    pub server: Option<String>,
+    pub port: i32,
+    pub password: Option<String>,
+}
+
+/// OAuth tokens of a connected mailbox, sealed with `crypto`
+#[derive(Debug, Clone)]
+pub struct SealedTokens {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// What syncing a user's mailbox needs: their credentials as stored and where the last sync stopped
+#[derive(Debug, Clone)]
+pub struct SyncAccount {
+    pub email: String,
+    pub auth_provider: Option<String>,
+    pub imap: ImapAccount,
+    pub tokens: Option<SealedTokens>, // None without an access token
+    pub cursor: Option<String>,
+}
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    /// Create the user or replace their email and IMAP credentials
+    async fn upsert_imap(&self, user_id: &str, email: &str, imap: &ImapAccount) -> Result<(), sqlx::Error>;
+
+    /// Create or update a user who logged in through WorkOS
+    async fn upsert_workos(&self, user_id: &str, email: &str) -> Result<(), sqlx::Error>;
+
+    /// A user's mailbox as the sync sees it, None if there's no such user
+    async fn sync_account(&self, user_id: &str) -> Result<Option<SyncAccount>, sqlx::Error>;
+
+    /// Where the next sync of a user's mailbox resumes
+    async fn save_sync_cursor(&self, user_id: &str, cursor: &str) -> Result<(), sqlx::Error>;
+}
+
+#[async_trait]
+pub trait TokenRepo: Send + Sync {
+    /// Create the user or replace their provider and tokens after an OAuth login
+    async fn save_login(
+        &self,
+        user_id: &str,
+        email: &str,
+        provider: &str,
+        imap_server: &str,
+        tokens: &SealedTokens,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Attach tokens to an existing user (keeping their provider if they have one)
+    async fn connect(&self, user_id: &str, provider: &str, tokens: &SealedTokens) -> Result<(), sqlx::Error>;
+
+    /// Replace a user's tokens after a refresh, keeping their refresh token if `tokens` has none
+    async fn save_refreshed(&self, user_id: &str, tokens: &SealedTokens) -> Result<(), sqlx::Error>;
+}
+
+#[async_trait]
+pub trait AliasRepo: Send + Sync {
+    /// Where mail for `alias` goes, None if nobody owns it
+    async fn resolve(&self, alias: &str) -> Result<Option<Recipient>, sqlx::Error>;
+
+    /// A user's personal alias, if they have one
+    async fn personal(&self, user_id: &str) -> Result<Option<String>, sqlx::Error>;
+
+    /// Give a user a new personal alias, dropping the previous one
+    async fn replace_personal(&self, user_id: &str, alias: &str) -> Result<(), sqlx::Error>;
+
+    async fn delete_personal(&self, user_id: &str) -> Result<(), sqlx::Error>;
+
+    async fn create_shared(&self, org_id: &str, creator_id: &str, alias: &str) -> Result<(), sqlx::Error>;
+
+    /// Shared aliases of an organization, newest first
+    async fn list_shared(&self, org_id: &str) -> Result<Vec<AliasRow>, sqlx::Error>;
+
+    /// Returns false if the organization has no such alias
+    async fn delete_shared(&self, org_id: &str, alias: &str) -> Result<bool, sqlx::Error>;
+}
+
+#[async_trait]
+pub trait EmailRepo: Send + Sync {
+    /// Save a parsed email and its attachments, threaded with the mail it replies to;
+    /// storing the same `message_id` again updates it, so it must be a key the sender can't
+    /// choose (a provider's id), or None to always insert. Returns the email id.
+    async fn store(
+        &self,
+        to: &Recipient,
+        message_id: Option<&str>,
+        email: &ParsedEmail,
+        received_at: i64,
+    ) -> Result<i64, sqlx::Error>;
+
+    /// Save an email unless the recipient already has its message id. Returns whether it was new.
+    async fn store_if_new(
+        &self,
+        to: &Recipient,
+        message_id: &str,
+        email: &ParsedEmail,
+        received_at: i64,
+    ) -> Result<bool, sqlx::Error>;
+
+    /// Mail of an inbox matching `query`, newest first
+    async fn list(&self, inbox: Inbox<'_>, query: &EmailQuery) -> Result<Vec<EmailRow>, sqlx::Error>;
+
+    /// Mail of an inbox matching `search`, best match first
+    async fn search(&self, inbox: Inbox<'_>, search: &SearchQuery, limit: i64) -> Result<Vec<SearchHit>, sqlx::Error>;
+
+    /// Threads of an inbox, most recently active first
+    async fn threads(&self, inbox: Inbox<'_>, query: &ThreadQuery) -> Result<Vec<ThreadRow>, sqlx::Error>;
+
+    /// Mail of one thread, oldest first; empty if the inbox has no such thread
+    async fn thread(&self, inbox: Inbox<'_>, thread_id: i64) -> Result<Vec<EmailRow>, sqlx::Error>;
+
+    /// One email of an inbox with its bodies and attachments
+    async fn get(&self, inbox: Inbox<'_>, id: i64) -> Result<Option<EmailDetail>, sqlx::Error>;
+
+    /// The original message, None if the inbox has no such email or it arrived already parsed
+    async fn raw(&self, inbox: Inbox<'_>, id: i64) -> Result<Option<Vec<u8>>, sqlx::Error>;
+
+    /// Change the state of emails of an inbox; returns the ones that exist, as changed
+    async fn update_state(&self, inbox: Inbox<'_>, ids: &[i64], change: &StateChange) -> Result<Vec<EmailRow>, sqlx::Error>;
+
+    /// Unread mail of an inbox per alias, by alias name
+    async fn unread_counts(&self, inbox: Inbox<'_>) -> Result<Vec<UnreadCount>, sqlx::Error>;
+
+    /// Returns false if the inbox has no such email
+    async fn delete(&self, inbox: Inbox<'_>, id: i64) -> Result<bool, sqlx::Error>;
+
+    /// Delete all mail of an inbox matching the filters of `query` (its cursor and limit are ignored).
+    /// Returns how many were deleted.
+    async fn delete_matching(&self, inbox: Inbox<'_>, query: &EmailQuery) -> Result<u64, sqlx::Error>;
+
+    /// How much mail a user received in the last `window_secs` (personal and shared)
+    async fn count_recent(&self, user_id: &str, window_secs: i64) -> Result<i64, sqlx::Error>;
+}
+
+/// Every repository, shared by handlers as `web::Data<Repos>`
+#[derive(Clone)]
+pub struct Repos {
+    pub users: Arc<dyn UserRepo>,
+    pub tokens: Arc<dyn TokenRepo>,
+    pub aliases: Arc<dyn AliasRepo>,
+    pub emails: Arc<dyn EmailRepo>,
+}
+
+impl Repos {
+    pub fn postgres(pool: sqlx::PgPool) -> Self {
+        let repo = Arc::new(postgres::PgRepo::new(pool));
+        Repos { users: repo.clone(), tokens: repo.clone(), aliases: repo.clone(), emails: repo }
+    }
+
+    /// Repositories backed by `store`, which tests can inspect
+    pub fn in_memory(store: Arc<memory::MemoryStore>) -> Self {
+        Repos { users: store.clone(), tokens: store.clone(), aliases: store.clone(), emails: store }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let received_at = DateTime::from_timestamp_micros(1_700_000_000_123_456).unwrap().naive_utc();
+        let cursor = EmailCursor { received_at, id: 42 };
+        assert_eq!(EmailCursor::decode(&cursor.encode()), Some(cursor));
+    }
+
+    #[test]
+    fn malformed_cursors_are_rejected() {
+        assert_eq!(EmailCursor::decode("not base64!"), None);
+        assert_eq!(EmailCursor::decode("MTIzNDU"), None); // "12345", no id
+        assert_eq!(EmailCursor::decode("YWJjOjE"), None); // "abc:1"
+    }
+}
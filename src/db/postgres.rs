@@ -0,0 +1,810 @@
+//! Postgres repositories. Queries are checked against the schema at compile time:
+//! after changing one, run `cargo sqlx prepare` against a migrated database to refresh `.sqlx/`.
+
+use async_trait::async_trait;
+use sqlx::types::Json;
+use sqlx::PgPool;
+use crate::core::dkim::DkimSignature;
+use crate::core::ingest::ParsedEmail;
+use super::{
+    AliasRepo, AliasRow, AttachmentRow, EmailDetail, EmailQuery, EmailRepo, EmailRow, ImapAccount, Inbox, Recipient,
+    SealedTokens, SearchHit, StateChange, SyncAccount, ThreadQuery, ThreadRow, TokenRepo, UnreadCount, UserRepo,
+};
+use crate::core::search::SearchQuery;
+use crate::core::threads;
+
+pub struct PgRepo {
+    pool: PgPool,
+}
+
+impl PgRepo {
+    pub fn new(pool: PgPool) -> Self {
+        PgRepo { pool }
+    }
+}
+
+/// (user_id, org_id) an inbox's mail is stored under; personal mail has no org_id
+fn owner(inbox: Inbox<'_>) -> (Option<&str>, Option<&str>) {
+    match inbox {
+        Inbox::Personal(user_id) => (Some(user_id), None),
+        Inbox::Shared(org_id) => (None, Some(org_id)),
+    }
+}
+
+/// ILIKE pattern matching `text` anywhere, wildcards in it taken literally
+fn contains_pattern(text: &str) -> String {
+    let escaped = text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+#[async_trait]
+impl UserRepo for PgRepo {
+    async fn upsert_imap(&self, user_id: &str, email: &str, imap: &ImapAccount) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO users (id, email, imap_server, imap_port, imap_password)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (id) DO UPDATE SET
+                email = EXCLUDED.email,
+                imap_server = EXCLUDED.imap_server,
+                imap_port = EXCLUDED.imap_port,
+                imap_password = EXCLUDED.imap_password
+            "#,
+            user_id,
+            email,
+            imap.server,
+            imap.port,
+            imap.password,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn upsert_workos(&self, user_id: &str, email: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO users (id, email, auth_provider)
+            VALUES ($1, $2, 'workos')
+            ON CONFLICT (id) DO UPDATE SET
+                email = EXCLUDED.email,
+                auth_provider = EXCLUDED.auth_provider
+            "#,
+            user_id,
+            email,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn sync_account(&self, user_id: &str) -> Result<Option<SyncAccount>, sqlx::Error> {
+        let row = sqlx::query!(
+            r#"
+            SELECT email, imap_server, imap_port, imap_password,
+                   auth_provider, access_token, refresh_token,
+                   token_expires_at, sync_cursor
+            FROM users WHERE id = $1
+            "#,
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| SyncAccount {
+            email: row.email,
+            auth_provider: row.auth_provider,
+            imap: ImapAccount { server: row.imap_server, port: row.imap_port.unwrap_or(993), password: row.imap_password },
+            tokens: row.access_token.map(|access_token| SealedTokens {
+                access_token,
+                refresh_token: row.refresh_token,
+                expires_at: row.token_expires_at.map(|at| at.and_utc()),
+            }),
+            cursor: row.sync_cursor,
+        }))
+    }
+
+    async fn save_sync_cursor(&self, user_id: &str, cursor: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("UPDATE users SET sync_cursor = $1 WHERE id = $2", cursor, user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TokenRepo for PgRepo {
+    async fn save_login(
+        &self,
+        user_id: &str,
+        email: &str,
+        provider: &str,
+        imap_server: &str,
+        tokens: &SealedTokens,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO users (id, email, auth_provider, access_token, refresh_token, token_expires_at, imap_server, imap_port)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, 993)
+            ON CONFLICT (id) DO UPDATE SET
+                auth_provider = EXCLUDED.auth_provider,
+                access_token = EXCLUDED.access_token,
+                refresh_token = EXCLUDED.refresh_token,
+                token_expires_at = EXCLUDED.token_expires_at,
+                imap_server = EXCLUDED.imap_server
+            "#,
+            user_id,
+            email,
+            provider,
+            tokens.access_token,
+            tokens.refresh_token,
+            tokens.expires_at.map(|t| t.naive_utc()),
+            imap_server,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn connect(&self, user_id: &str, provider: &str, tokens: &SealedTokens) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET access_token = $1,
+                refresh_token = $2,
+                token_expires_at = $3,
+                auth_provider = COALESCE(auth_provider, $4)
+            WHERE id = $5
+            "#,
+            tokens.access_token,
+            tokens.refresh_token,
+            tokens.expires_at.map(|t| t.naive_utc()),
+            provider,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn save_refreshed(&self, user_id: &str, tokens: &SealedTokens) -> Result<(), sqlx::Error> {
+        // Providers only sometimes rotate the refresh token
+        sqlx::query!(
+            r#"
+            UPDATE users
+            SET access_token = $1,
+                refresh_token = COALESCE($2, refresh_token),
+                token_expires_at = $3
+            WHERE id = $4
+            "#,
+            tokens.access_token,
+            tokens.refresh_token,
+            tokens.expires_at.map(|t| t.naive_utc()),
+            user_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AliasRepo for PgRepo {
+    async fn resolve(&self, alias: &str) -> Result<Option<Recipient>, sqlx::Error> {
+        let row = sqlx::query!("SELECT user_id, org_id FROM temp_aliases WHERE alias = $1", alias)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|r| Recipient { user_id: r.user_id, org_id: r.org_id, alias: Some(alias.to_string()) }))
+    }
+
+    async fn personal(&self, user_id: &str) -> Result<Option<String>, sqlx::Error> {
+        let row = sqlx::query!(
+            "SELECT alias FROM temp_aliases WHERE user_id = $1 AND org_id IS NULL ORDER BY created_at DESC LIMIT 1",
+            user_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| r.alias))
+    }
+
+    async fn replace_personal(&self, user_id: &str, alias: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        // Shared aliases belong to their organization and stay
+        sqlx::query!("DELETE FROM temp_aliases WHERE user_id = $1 AND org_id IS NULL", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("INSERT INTO temp_aliases (alias, user_id) VALUES ($1, $2)", alias, user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    async fn delete_personal(&self, user_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!("DELETE FROM temp_aliases WHERE user_id = $1 AND org_id IS NULL", user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn create_shared(&self, org_id: &str, creator_id: &str, alias: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "INSERT INTO temp_aliases (alias, user_id, org_id) VALUES ($1, $2, $3)",
+            alias,
+            creator_id,
+            org_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list_shared(&self, org_id: &str) -> Result<Vec<AliasRow>, sqlx::Error> {
+        sqlx::query_as!(
+            AliasRow,
+            "SELECT alias, user_id, org_id, created_at FROM temp_aliases WHERE org_id = $1 ORDER BY created_at DESC",
+            org_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_shared(&self, org_id: &str, alias: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!("DELETE FROM temp_aliases WHERE alias = $1 AND org_id = $2", alias, org_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+impl PgRepo {
+    async fn store_attachments(
+        tx: &mut sqlx::PgConnection,
+        email_id: i64,
+        email: &ParsedEmail,
+    ) -> Result<(), sqlx::Error> {
+        // Storing the same message again replaces its attachments
+        sqlx::query!("DELETE FROM email_attachments WHERE email_id = $1", email_id)
+            .execute(&mut *tx)
+            .await?;
+
+        for attachment in &email.attachments {
+            sqlx::query!(
+                r#"
+                INSERT INTO email_attachments (email_id, filename, content_type, size_bytes, content)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+                email_id,
+                attachment.filename,
+                attachment.content_type,
+                attachment.content.len() as i32,
+                attachment.content,
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Put a newly stored email in a thread, as `threads::place` decides.
+    /// Emails already in a thread stay there.
+    async fn assign_thread(
+        tx: &mut sqlx::PgConnection,
+        email_id: i64,
+        to: &Recipient,
+        email: &ParsedEmail,
+    ) -> Result<(), sqlx::Error> {
+        let row = sqlx::query!(r#"SELECT thread_id, received_at AS "received_at!" FROM emails WHERE id = $1"#, email_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if row.thread_id.is_some() {
+            return Ok(());
+        }
+        let (user_id, org_id) = owner(to.inbox());
+
+        let lookup = threads::Lookup::new(email, row.received_at);
+        let related = sqlx::query_as!(
+            threads::Related,
+            r#"
+            SELECT id, thread_id AS "thread_id!", header_message_id AS message_id, in_reply_to,
+                   reference_ids AS "references!", COALESCE(thread_subject, '') AS "subject!", received_at AS "received_at!"
+            FROM emails
+            WHERE (user_id = $1 AND org_id IS NULL OR org_id = $2)
+              AND thread_id IS NOT NULL AND id <> $3
+              AND (header_message_id = ANY($4)
+                   OR in_reply_to = $5 OR $5 = ANY(reference_ids)
+                   OR (thread_subject = $6 AND received_at > $7))
+            "#,
+            user_id,
+            org_id,
+            email_id,
+            &lookup.parents,
+            lookup.message_id,
+            lookup.subject,
+            lookup.since,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        // Thread ids are email ids, so the merged ones can only be of this inbox
+        let placement = threads::place(email_id, &lookup, &related);
+        sqlx::query!(
+            "UPDATE emails SET thread_id = $1 WHERE id = $2 OR thread_id = ANY($3)",
+            placement.thread_id,
+            email_id,
+            &placement.merged,
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EmailRepo for PgRepo {
+    async fn store(
+        &self,
+        to: &Recipient,
+        message_id: Option<&str>,
+        email: &ParsedEmail,
+        received_at: i64,
+    ) -> Result<i64, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let email_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO emails (user_id, org_id, alias, message_id, sender, recipients, subject, body_preview, body_text, body_html, otp, raw_message, received_at,
+                                header_message_id, in_reply_to, reference_ids, thread_subject, headers, dkim)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, TO_TIMESTAMP($13), $14, $15, $16, $17, $18, $19)
+            ON CONFLICT (user_id, message_id) DO UPDATE SET
+                body_preview = EXCLUDED.body_preview,
+                body_text = EXCLUDED.body_text,
+                body_html = EXCLUDED.body_html,
+                otp = EXCLUDED.otp,
+                raw_message = EXCLUDED.raw_message,
+                received_at = EXCLUDED.received_at,
+                header_message_id = EXCLUDED.header_message_id,
+                in_reply_to = EXCLUDED.in_reply_to,
+                reference_ids = EXCLUDED.reference_ids,
+                thread_subject = EXCLUDED.thread_subject,
+                headers = EXCLUDED.headers,
+                dkim = EXCLUDED.dkim
+            RETURNING id
+            "#,
+            to.user_id,
+            to.org_id,
+            to.alias,
+            message_id,
+            email.sender,
+            (!email.to.is_empty()).then(|| email.to.join(", ")),
+            email.subject,
+            email.body_preview,
+            email.body_text,
+            email.body_html,
+            email.otp,
+            (!email.raw.is_empty()).then_some(&email.raw[..]),
+            received_at as f64,
+            email.message_id,
+            email.in_reply_to,
+            &email.references,
+            threads::normalize_subject(&email.subject),
+            (!email.headers.is_empty()).then(|| Json(&email.headers)) as _,
+            email.dkim.as_ref().map(Json) as _,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Self::store_attachments(&mut tx, email_id, email).await?;
+        Self::assign_thread(&mut tx, email_id, to, email).await?;
+        tx.commit().await?;
+        Ok(email_id)
+    }
+
+    async fn store_if_new(
+        &self,
+        to: &Recipient,
+        message_id: &str,
+        email: &ParsedEmail,
+        received_at: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let email_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO emails (user_id, org_id, alias, message_id, sender, recipients, subject, body_preview, body_text, body_html, otp, raw_message, received_at,
+                                header_message_id, in_reply_to, reference_ids, thread_subject, headers, dkim)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, TO_TIMESTAMP($13), $14, $15, $16, $17, $18, $19)
+            ON CONFLICT (user_id, message_id) DO NOTHING
+            RETURNING id
+            "#,
+            to.user_id,
+            to.org_id,
+            to.alias,
+            message_id,
+            email.sender,
+            (!email.to.is_empty()).then(|| email.to.join(", ")),
+            email.subject,
+            email.body_preview,
+            email.body_text,
+            email.body_html,
+            email.otp,
+            (!email.raw.is_empty()).then_some(&email.raw[..]),
+            received_at as f64,
+            email.message_id,
+            email.in_reply_to,
+            &email.references,
+            threads::normalize_subject(&email.subject),
+            (!email.headers.is_empty()).then(|| Json(&email.headers)) as _,
+            email.dkim.as_ref().map(Json) as _,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(email_id) = email_id else { return Ok(false) };
+        Self::store_attachments(&mut tx, email_id, email).await?;
+        Self::assign_thread(&mut tx, email_id, to, email).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn list(&self, inbox: Inbox<'_>, query: &EmailQuery) -> Result<Vec<EmailRow>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        let cursor = query.cursor.map(|c| (c.received_at, c.id)).unzip();
+
+        sqlx::query_as!(
+            EmailRow,
+            r#"
+            SELECT id, user_id, org_id, message_id, sender, subject, body_preview, otp, alias, read_at, received_at,
+                   COALESCE(thread_id, id) AS "thread_id!", starred, labels
+            FROM emails
+            WHERE (user_id = $1 AND org_id IS NULL OR org_id = $2)
+              AND ($3::text IS NULL OR alias = $3)
+              AND ($4::text IS NULL OR sender ILIKE $4)
+              AND ($5::text IS NULL OR subject ILIKE $5)
+              AND ($6::timestamp IS NULL OR received_at >= $6)
+              AND ($7::timestamp IS NULL OR received_at < $7)
+              AND ($8::bool IS NULL OR (otp IS NOT NULL) = $8)
+              AND ($9::bool IS NULL OR (read_at IS NOT NULL) = $9)
+              AND ($10::bool IS NULL OR starred = $10)
+              AND ($11::text IS NULL OR $11 = ANY(labels))
+              AND ($12::timestamp IS NULL OR (received_at, id) < ($12, $13))
+            ORDER BY received_at DESC, id DESC
+            LIMIT $14
+            "#,
+            user_id,
+            org_id,
+            query.alias,
+            query.sender.as_deref().map(contains_pattern),
+            query.subject.as_deref().map(contains_pattern),
+            query.after,
+            query.before,
+            query.has_otp,
+            query.read,
+            query.starred,
+            query.label,
+            cursor.0,
+            cursor.1,
+            query.limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn search(&self, inbox: Inbox<'_>, search: &SearchQuery, limit: i64) -> Result<Vec<SearchHit>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        let patterns = |parts: &[String]| parts.iter().map(|p| contains_pattern(p)).collect::<Vec<_>>();
+
+        // The body is escaped before ts_headline so the snippet is safe to render as HTML
+        let rows = sqlx::query!(
+            r#"
+            SELECT id, user_id, org_id, message_id, sender, subject, body_preview, otp, alias, read_at, received_at,
+                   COALESCE(thread_id, id) AS "thread_id!", starred, labels,
+                   CASE WHEN $3 = '' THEN 0 ELSE ts_rank(search, q) END AS "rank!",
+                   ts_headline(
+                       'english',
+                       replace(replace(replace(coalesce(body_text, body_preview, ''), '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+                       q,
+                       'StartSel=<mark>, StopSel=</mark>, MaxWords=30, MinWords=10, MaxFragments=2'
+                   ) AS "snippet!"
+            FROM emails, websearch_to_tsquery('english', $3) AS q
+            WHERE (user_id = $1 AND org_id IS NULL OR org_id = $2)
+              AND ($3 = '' OR search @@ q)
+              AND sender ILIKE ALL($4)
+              AND (coalesce(recipients, '') || ' ' || coalesce(alias, '')) ILIKE ALL($5)
+              AND coalesce(subject, '') ILIKE ALL($6)
+              AND (NOT $7 OR otp IS NOT NULL)
+              AND ($8::timestamp IS NULL OR received_at >= $8)
+              AND ($9::timestamp IS NULL OR received_at < $9)
+            ORDER BY 15 DESC, received_at DESC, id DESC
+            LIMIT $10
+            "#,
+            user_id,
+            org_id,
+            search.text,
+            &patterns(&search.from),
+            &patterns(&search.to),
+            &patterns(&search.subject),
+            search.has_otp,
+            search.after,
+            search.before,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| SearchHit {
+            email: EmailRow {
+                id: row.id,
+                user_id: row.user_id,
+                org_id: row.org_id,
+                message_id: row.message_id,
+                sender: row.sender,
+                subject: row.subject,
+                body_preview: row.body_preview,
+                otp: row.otp,
+                alias: row.alias,
+                read_at: row.read_at,
+                received_at: row.received_at,
+                thread_id: row.thread_id,
+                starred: row.starred,
+                labels: row.labels,
+            },
+            rank: row.rank,
+            snippet: row.snippet,
+        }).collect())
+    }
+
+    async fn threads(&self, inbox: Inbox<'_>, query: &ThreadQuery) -> Result<Vec<ThreadRow>, sqlx::Error> {
+        let cursor = query.cursor.map(|c| (c.received_at, c.id)).unzip();
+
+        // One page of threads off the email_threads index, then only those are aggregated
+        let page = match inbox {
+            Inbox::Personal(user_id) => sqlx::query_scalar!(
+                r#"
+                SELECT id FROM email_threads
+                WHERE user_id = $1 AND org_id IS NULL
+                  AND ($2::timestamp IS NULL OR (last_received_at, id) < ($2, $3))
+                ORDER BY last_received_at DESC, id DESC
+                LIMIT $4
+                "#,
+                user_id,
+                cursor.0,
+                cursor.1,
+                query.limit,
+            )
+            .fetch_all(&self.pool)
+            .await?,
+            Inbox::Shared(org_id) => sqlx::query_scalar!(
+                r#"
+                SELECT id FROM email_threads
+                WHERE org_id = $1
+                  AND ($2::timestamp IS NULL OR (last_received_at, id) < ($2, $3))
+                ORDER BY last_received_at DESC, id DESC
+                LIMIT $4
+                "#,
+                org_id,
+                cursor.0,
+                cursor.1,
+                query.limit,
+            )
+            .fetch_all(&self.pool)
+            .await?,
+        };
+
+        sqlx::query_as!(
+            ThreadRow,
+            r#"
+            SELECT thread_id AS "thread_id!",
+                   (array_agg(subject ORDER BY received_at, id))[1] AS subject,
+                   array_agg(DISTINCT sender) AS "participants!",
+                   COUNT(*) AS "message_count!",
+                   COUNT(*) FILTER (WHERE read_at IS NULL) AS "unread_count!",
+                   (array_agg(body_preview ORDER BY received_at DESC, id DESC))[1] AS latest_preview,
+                   MAX(received_at) AS "last_received_at!"
+            FROM emails
+            WHERE thread_id = ANY($1)
+            GROUP BY thread_id
+            ORDER BY MAX(received_at) DESC, thread_id DESC
+            "#,
+            &page,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn thread(&self, inbox: Inbox<'_>, thread_id: i64) -> Result<Vec<EmailRow>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        sqlx::query_as!(
+            EmailRow,
+            r#"
+            SELECT id, user_id, org_id, message_id, sender, subject, body_preview, otp, alias, read_at, received_at,
+                   COALESCE(thread_id, id) AS "thread_id!", starred, labels
+            FROM emails
+            WHERE (user_id = $1 AND org_id IS NULL OR org_id = $2)
+              AND COALESCE(thread_id, id) = $3
+            ORDER BY received_at, id
+            "#,
+            user_id,
+            org_id,
+            thread_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get(&self, inbox: Inbox<'_>, id: i64) -> Result<Option<EmailDetail>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        let row = sqlx::query!(
+            r#"
+            SELECT id, user_id, org_id, message_id, sender, subject, body_preview, otp, alias, read_at, received_at,
+                   COALESCE(thread_id, id) AS "thread_id!", starred, labels, body_text, body_html, raw_message,
+                   headers AS "headers: Json<Vec<(String, String)>>", dkim AS "dkim: Json<Vec<DkimSignature>>"
+            FROM emails
+            WHERE id = $1 AND (user_id = $2 AND org_id IS NULL OR org_id = $3)
+            "#,
+            id,
+            user_id,
+            org_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else { return Ok(None) };
+
+        let attachments = sqlx::query_as!(
+            AttachmentRow,
+            "SELECT id, filename, content_type, size_bytes, content FROM email_attachments WHERE email_id = $1 ORDER BY id",
+            id,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Some(EmailDetail {
+            email: EmailRow {
+                id: row.id,
+                user_id: row.user_id,
+                org_id: row.org_id,
+                message_id: row.message_id,
+                sender: row.sender,
+                subject: row.subject,
+                body_preview: row.body_preview,
+                otp: row.otp,
+                alias: row.alias,
+                read_at: row.read_at,
+                received_at: row.received_at,
+                thread_id: row.thread_id,
+                starred: row.starred,
+                labels: row.labels,
+            },
+            body_text: row.body_text,
+            body_html: row.body_html,
+            raw_message: row.raw_message,
+            headers: row.headers.map(|headers| headers.0),
+            dkim: row.dkim.map(|dkim| dkim.0),
+            attachments,
+        }))
+    }
+
+    async fn raw(&self, inbox: Inbox<'_>, id: i64) -> Result<Option<Vec<u8>>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        let raw = sqlx::query_scalar!(
+            "SELECT raw_message FROM emails WHERE id = $1 AND (user_id = $2 AND org_id IS NULL OR org_id = $3)",
+            id,
+            user_id,
+            org_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(raw.flatten())
+    }
+
+    async fn update_state(&self, inbox: Inbox<'_>, ids: &[i64], change: &StateChange) -> Result<Vec<EmailRow>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        // read_at keeps the time it was first read
+        sqlx::query_as!(
+            EmailRow,
+            r#"
+            UPDATE emails SET
+                read_at = CASE WHEN $4::bool IS NULL THEN read_at WHEN $4 THEN COALESCE(read_at, NOW()::timestamp) END,
+                starred = COALESCE($5, starred),
+                labels = ARRAY(
+                    SELECT DISTINCT label FROM unnest(labels || $6::text[]) AS label
+                    WHERE label <> ALL($7::text[])
+                    ORDER BY label
+                )
+            WHERE id = ANY($3) AND (user_id = $1 AND org_id IS NULL OR org_id = $2)
+            RETURNING id, user_id, org_id, message_id, sender, subject, body_preview, otp, alias, read_at, received_at,
+                      COALESCE(thread_id, id) AS "thread_id!", starred, labels
+            "#,
+            user_id,
+            org_id,
+            ids,
+            change.read,
+            change.starred,
+            &change.add_labels,
+            &change.remove_labels,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn unread_counts(&self, inbox: Inbox<'_>) -> Result<Vec<UnreadCount>, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        sqlx::query_as!(
+            UnreadCount,
+            r#"
+            SELECT alias, COUNT(*) AS "unread!"
+            FROM emails
+            WHERE (user_id = $1 AND org_id IS NULL OR org_id = $2) AND read_at IS NULL
+            GROUP BY alias
+            ORDER BY alias NULLS FIRST
+            "#,
+            user_id,
+            org_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete(&self, inbox: Inbox<'_>, id: i64) -> Result<bool, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        // Attachments go with it (ON DELETE CASCADE)
+        let result = sqlx::query!(
+            "DELETE FROM emails WHERE id = $1 AND (user_id = $2 AND org_id IS NULL OR org_id = $3)",
+            id,
+            user_id,
+            org_id,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_matching(&self, inbox: Inbox<'_>, query: &EmailQuery) -> Result<u64, sqlx::Error> {
+        let (user_id, org_id) = owner(inbox);
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM emails
+            WHERE (user_id = $1 AND org_id IS NULL OR org_id = $2)
+              AND ($3::text IS NULL OR alias = $3)
+              AND ($4::text IS NULL OR sender ILIKE $4)
+              AND ($5::text IS NULL OR subject ILIKE $5)
+              AND ($6::timestamp IS NULL OR received_at >= $6)
+              AND ($7::timestamp IS NULL OR received_at < $7)
+              AND ($8::bool IS NULL OR (otp IS NOT NULL) = $8)
+              AND ($9::bool IS NULL OR (read_at IS NOT NULL) = $9)
+              AND ($10::bool IS NULL OR starred = $10)
+              AND ($11::text IS NULL OR $11 = ANY(labels))
+            "#,
+            user_id,
+            org_id,
+            query.alias,
+            query.sender.as_deref().map(contains_pattern),
+            query.subject.as_deref().map(contains_pattern),
+            query.after,
+            query.before,
+            query.has_otp,
+            query.read,
+            query.starred,
+            query.label,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn count_recent(&self, user_id: &str, window_secs: i64) -> Result<i64, sqlx::Error> {
+        // Compared on the database clock, which stamped received_at
+        let count = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM emails
+            WHERE user_id = $1
+              AND received_at > NOW() - $2::bigint * INTERVAL '1 second'
+            "#,
+            user_id,
+            window_secs,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+}
@@ -1,3 +1,4 @@
 pub mod core;
 pub mod workers;
 pub mod api;
+pub mod db;
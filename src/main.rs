@@ -3,10 +3,12 @@ use actix_web::{http::header, App, HttpServer, web};
 use sqlx::postgres::PgPoolOptions;
 use dotenv::dotenv;
 use std::env;
+use std::sync::Arc;
+use mail_server::{api, db, workers};
+use mail_server::core::{crypto, dns, events, jwt, migrations, redirects, workos_sessions};
 
-mod core;
-mod workers;
-mod api;
+/// Pool connections kept for HTTP handlers and SMTP ingest on top of the scheduler's
+const HTTP_CONNECTIONS: u32 = 10;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -15,72 +17,125 @@ async fn main() -> std::io::Result<()> {
     let database_url = env::var("DATABASE_URL")
         .expect("DATABASE_URL must be set");
     
-    // Create database pool
+    // Create database pool: by default, room for every account the scheduler syncs at once
+    // plus HTTP handlers and SMTP ingest (DATABASE_MAX_CONNECTIONS overrides it)
+    let sync_connections = match workers::scheduler::enabled() {
+        true => workers::scheduler::SchedulerConfig::from_env().concurrency as u32,
+        false => 0,
+    };
+    let max_connections = env::var("DATABASE_MAX_CONNECTIONS")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(sync_connections + HTTP_CONNECTIONS)
+        .max(1);
     let pool = PgPoolOptions::new()
-        .max_connections(2)
+        .max_connections(max_connections)
         .connect(&database_url)
         .await
         .expect("Failed to create pool");
     
     println!("✅ Connected to Neon DB");
     
-    // Auto-migrate schema changes
-    println!("🔄 Running Database Migration...");
-    
-    // 1. Create temp_aliases table
-    let table_res = sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS temp_aliases (
-            alias TEXT PRIMARY KEY,
-            user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
-            created_at TIMESTAMP DEFAULT NOW()
-        );
-        "#
-    )
-    .execute(&pool)
-    .await;
-    
-    match table_res {
-        Ok(_) => println!("✅ Table 'temp_aliases' checked/created."),
-        Err(e) => eprintln!("❌ Failed to create table: {}", e),
+    // `mail-server migrate [--dry-run]` brings the schema up to date (or shows what would change), then exits
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        let dry_run = env::args().any(|arg| arg == "--dry-run");
+        match migrations::run(&pool, dry_run).await {
+            Ok(applied) if applied.is_empty() => println!("✅ Schema is up to date"),
+            Ok(applied) => {
+                for m in applied {
+                    let verb = if dry_run { "Would apply" } else { "Applied" };
+                    println!("✅ {} migration {:04} '{}'", verb, m.version, m.name);
+                }
+                if dry_run {
+                    println!("🧪 Dry run: every change was rolled back");
+                }
+            }
+            Err(e) => {
+                eprintln!("❌ {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
     }
 
-    // 2. Drop old column
-    let drop_res = sqlx::query("ALTER TABLE users DROP COLUMN IF EXISTS temp_alias")
-        .execute(&pool)
-        .await;
-        
-    match drop_res {
-        Ok(_) => println!("✅ Column 'temp_alias' cleanup done."),
-        Err(e) => eprintln!("⚠️ Failed to drop column (might not exist): {}", e),
+    // Never serve on a schema we couldn't bring up to date
+    println!("🔄 Running Database Migration...");
+    match migrations::run(&pool, false).await {
+        Ok(applied) => {
+            for m in applied {
+                println!("✅ Applied migration {:04} '{}'", m.version, m.name);
+            }
+        }
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(1);
+        }
     }
 
-    // 3. Add OTP column to emails table
-    let otp_col_res = sqlx::query("ALTER TABLE emails ADD COLUMN IF NOT EXISTS otp TEXT")
-        .execute(&pool)
-        .await;
-        
-    match otp_col_res {
-        Ok(_) => println!("✅ Column 'otp' checked/added to 'emails'."),
-        Err(e) => eprintln!("⚠️ Failed to add 'otp' column: {}", e),
+    // Refuse to start without real signing keys: tokens can't be issued or checked otherwise
+    let jwt_keys = jwt::KeySet::from_env().expect("JWT signing keys are not configured");
+    println!("🔑 Signing tokens with key '{}'", jwt_keys.active_kid());
+
+    // Stored credentials can't be read or written without the master keys
+    let keys = web::Data::new(crypto::KeyRing::from_env().expect("ENCRYPTION_KEYS must be set to valid keys"));
+    println!("🔐 Encrypting secrets with key '{}'", keys.active_key_id());
+
+    // `mail-server reencrypt-secrets` moves every stored secret onto the active key, then exits
+    if env::args().nth(1).as_deref() == Some("reencrypt-secrets") {
+        match crypto::reencrypt_user_secrets(&pool, &keys).await {
+            Ok(count) => println!("✅ Re-encrypted secrets for {} users", count),
+            Err(e) => {
+                eprintln!("❌ Re-encryption failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        match workos_sessions::reencrypt_refresh_tokens(&pool, &keys).await {
+            Ok(count) => println!("✅ Re-encrypted refresh tokens of {} WorkOS sessions", count),
+            Err(e) => {
+                eprintln!("❌ Re-encryption of WorkOS sessions failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
     }
     
+    let repos = db::Repos::postgres(pool.clone());
+    // One hub for every HTTP worker, fed through Postgres so changes reach streams on every replica
+    let events = web::Data::new(events::EventHub::with_postgres(pool.clone(), &database_url));
+    let listener = events.get_ref().clone();
+    tokio::spawn(async move {
+        listener.listen().await;
+    });
+
+    // SPF and DKIM keys are looked up over DNS-over-HTTPS (DNS_RESOLVER_URL)
+    let resolver: Arc<dyn dns::Resolver> = Arc::new(dns::DohResolver::from_env());
+
     // Spawn SMTP server in background
-    let smtp_pool = pool.clone();
+    let smtp_repos = repos.clone();
+    let smtp_resolver = resolver.clone();
     tokio::spawn(async move {
-        workers::smtp::start_server(smtp_pool).await;
+        workers::smtp::start_server(smtp_repos, smtp_resolver).await;
     });
+
+    // Spawn background mailbox sync (set SYNC_SCHEDULER=off to disable on a replica)
+    if workers::scheduler::enabled() {
+        let scheduler_pool = pool.clone();
+        let scheduler_repos = repos.clone();
+        let scheduler_keys = keys.clone().into_inner();
+        tokio::spawn(async move {
+            workers::scheduler::start_scheduler(scheduler_pool, scheduler_repos, scheduler_keys).await;
+        });
+    }
     
     println!("🚀 HTTP API running on http://0.0.0.0:8080");
     
     // Start HTTP server
     HttpServer::new(move || {
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:5173")
-            .allowed_origin("http://127.0.0.1:5173")
-            .allowed_origin("https://mail.rapidxoxo.dpdns.org")
-            .allowed_origin("https://rapidxoxo.dpdns.org")
-            .allowed_methods(vec!["GET", "POST", "DELETE", "OPTIONS"])
+        // The frontends allowed to call the API are the ones auth flows may redirect to
+        let cors = redirects::allowed_origins()
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
             .allowed_headers(vec![header::AUTHORIZATION, header::ACCEPT])
             .allowed_header(header::CONTENT_TYPE)
             .max_age(3600);
@@ -88,6 +143,10 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(repos.clone()))
+            .app_data(events.clone())
+            .app_data(keys.clone())
+            .app_data(web::Data::from(resolver.clone()))
             .configure(api::routes::config)
     })
     .bind("0.0.0.0:8080")?
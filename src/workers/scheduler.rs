@@ -0,0 +1,92 @@
+use futures::stream::{self, StreamExt};
+use sqlx::PgPool;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::core::crypto::KeyRing;
+use crate::core::sync;
+use crate::db::Repos;
+
+/// Background sync settings, read from the environment
+pub struct SchedulerConfig {
+    pub tick_secs: u64,        // How often to look for due accounts
+    pub concurrency: usize,    // Accounts synced at once by this replica
+    pub lease_secs: i64,       // How long a claimed account stays reserved for this replica
+    pub jitter_percent: i32,   // Random spread added to each account's interval
+    pub owner: String,         // Identifies this replica in lease columns
+}
+
+impl SchedulerConfig {
+    pub fn from_env() -> Self {
+        let parse = |key: &str, default: u64| {
+            env::var(key).ok().and_then(|v| v.parse::<u64>().ok()).unwrap_or(default)
+        };
+        let host = env::var("HOSTNAME").unwrap_or_else(|_| "mail-server".to_string());
+
+        Self {
+            tick_secs: parse("SYNC_TICK_SECS", 30).max(1),
+            concurrency: parse("SYNC_CONCURRENCY", 4).max(1) as usize,
+            lease_secs: parse("SYNC_LEASE_SECS", sync::DEFAULT_LEASE_SECS as u64) as i64,
+            jitter_percent: parse("SYNC_JITTER_PERCENT", sync::DEFAULT_JITTER_PERCENT as u64).min(100) as i32,
+            owner: format!("{}-{}", host, uuid::Uuid::new_v4()),
+        }
+    }
+}
+
+/// Whether the scheduler should run on this replica (SYNC_SCHEDULER=off disables it)
+pub fn enabled() -> bool {
+    !matches!(env::var("SYNC_SCHEDULER").as_deref(), Ok("off") | Ok("false") | Ok("0"))
+}
+
+/// Periodically sync every connected account
+pub async fn start_scheduler(pool: PgPool, repos: Repos, keys: Arc<KeyRing>) {
+    let config = SchedulerConfig::from_env();
+    println!(
+        "⏰ Sync scheduler running as {} (every {}s, {} at a time)",
+        config.owner, config.tick_secs, config.concurrency
+    );
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.tick_secs));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+
+        if let Err(e) = sync::ensure_schedules(&pool).await {
+            eprintln!("❌ Failed to create sync schedules: {}", e);
+            continue;
+        }
+
+        // Keep claiming until nothing is due, so a backlog doesn't wait one tick per batch
+        loop {
+            let due = match sync::claim_due_accounts(&pool, &config.owner, config.lease_secs, config.concurrency as i64).await {
+                Ok(ids) => ids,
+                Err(e) => {
+                    eprintln!("❌ Failed to claim accounts for sync: {}", e);
+                    break;
+                }
+            };
+            let drained = due.len() < config.concurrency;
+
+            stream::iter(due)
+                .for_each_concurrent(config.concurrency, |user_id| {
+                    let (pool, repos, keys, config) = (&pool, &repos, &keys, &config);
+                    async move {
+                        let result = sync::sync_user(repos, keys, &user_id).await;
+                        match &result {
+                            Ok(report) => println!("🔄 Synced {} emails for {} ({})", report.saved, user_id, report.provider),
+                            Err(e) => eprintln!("⚠️ Sync failed for {}: {}", user_id, e),
+                        }
+
+                        if let Err(e) = sync::record_result(pool, &user_id, Some(&config.owner), &result, config.jitter_percent).await {
+                            eprintln!("❌ Failed to record sync result for {}: {}", user_id, e);
+                        }
+                    }
+                })
+                .await;
+
+            if drained {
+                break;
+            }
+        }
+    }
+}
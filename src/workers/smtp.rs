@@ -1,180 +1,202 @@
-use tokio::net::TcpListener;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use sqlx::{PgPool, Row};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::core::dns::Resolver;
 use crate::core::limiter::check_rate_limit;
-use mail_parser::{Message, HeaderValue, Addr};
+use crate::core::{ingest, spf};
+use crate::db::{Recipient, Repos};
 
-fn extract_sender(message: &Message) -> String {
-    // from() returns Option<&HeaderValue>, but we need to handle it correctly
-    if let Some(header_value) = message.header("From") {
-        match header_value {
-            HeaderValue::Address(addr) => {
-                addr.address.as_ref().map(|s| s.to_string()).unwrap_or_default()
-            }
-            HeaderValue::AddressList(list) => {
-                list.first()
-                    .and_then(|a: &Addr| a.address.as_ref())
-                    .map(|s| s.to_string())
-                    .unwrap_or_default()
-            }
-            HeaderValue::Group(group) => {
-                group.addresses.first()
-                    .and_then(|a: &Addr| a.address.as_ref())
-                    .map(|s| s.to_string())
-                    .unwrap_or_default()
-            }
-            HeaderValue::GroupList(groups) => {
-                groups.first()
-                    .and_then(|g| g.addresses.first())
-                    .and_then(|a: &Addr| a.address.as_ref())
-                    .map(|s| s.to_string())
-                    .unwrap_or_default()
-            }
-            _ => String::new(),
-        }
-    } else {
-        String::new()
-    }
-}
-
-pub async fn start_server(pool: PgPool) {
+pub async fn start_server(repos: Repos, resolver: Arc<dyn Resolver>) {
     let listener = TcpListener::bind("0.0.0.0:2525").await.unwrap();
     println!("🛡️ SMTP Server running on :2525 with Rate Limits active");
 
-    let pool = Arc::new(pool);
-
     loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
-        let pool = pool.clone();
+        let (socket, peer) = listener.accept().await.unwrap();
+        let repos = repos.clone();
+        let resolver = resolver.clone();
 
         tokio::spawn(async move {
-            let mut buffer = [0; 2048]; // 2KB Buffer
-            
-            // 1. Handshake
-            if socket.write_all(b"220 mailpulse.net ESMTP\r\n").await.is_err() { return; }
+            handle_connection(socket, peer.ip(), &repos, resolver.as_ref()).await;
+        });
+    }
+}
 
-            // Simplistic State Tracking
-            let mut current_user_id = String::new();
-            
-            loop {
-                let n = match socket.read(&mut buffer).await {
-                    Ok(n) if n == 0 => return,
-                    Ok(n) => n,
-                    Err(_) => return,
-                };
-                let request = String::from_utf8_lossy(&buffer[0..n]);
+/// Speak SMTP with the client at `peer` until it quits or hangs up
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, peer: IpAddr, repos: &Repos, resolver: &dyn Resolver) {
+    let mut buffer = [0; 2048]; // 2KB Buffer
+    
+    // 1. Handshake
+    if socket.write_all(format!("220 {} ESMTP\r\n", ingest::AUTHSERV_ID).as_bytes()).await.is_err() { return; }
+
+    // Simplistic State Tracking
+    let mut recipient = Recipient::mailbox(""); // org_id is set when the alias is a shared inbox
+    let mut helo = String::new();
+    let mut sender_check = None; // SPF result for the current MAIL FROM, None until one is accepted
+    
+    loop {
+        let n = match socket.read(&mut buffer).await {
+            Ok(0) => return,
+            Ok(n) => n,
+            Err(_) => return,
+        };
+        let request = String::from_utf8_lossy(&buffer[0..n]);
 
-                // --- LOGIC FLOW ---
+        // --- LOGIC FLOW ---
 
-                if request.starts_with("HELO") || request.starts_with("EHLO") {
+        if request.starts_with("HELO") || request.starts_with("EHLO") {
+            // Both names end up in the Authentication-Results we add, so only well-formed ones are taken
+            match request.split_whitespace().nth(1).filter(|name| is_domain(name) || is_address_literal(name)) {
+                Some(name) => {
+                    helo = name.to_string();
                     let _ = socket.write_all(b"250 OK\r\n").await;
                 }
-                else if request.starts_with("MAIL FROM") {
+                None => {
+                    let _ = socket.write_all(b"501 Syntax: HELO hostname\r\n").await;
+                }
+            }
+        }
+        else if request.starts_with("MAIL FROM") {
+            // Checked but never refused on: the result is recorded on the message for the user to see
+            match reverse_path(request.lines().next().unwrap_or_default()) {
+                Some(path) => {
+                    sender_check = Some(spf::check(resolver, peer, path, &helo).await);
                     let _ = socket.write_all(b"250 OK\r\n").await;
                 }
-                else if request.starts_with("RCPT TO") {
-                    // Extract user from: RCPT TO:<user_123@mailpulse.net>
-                    // (Simplified parsing logic for demo)
-                    if let Some(start) = request.find('<') {
-                        if let Some(end) = request.find('@') {
-                            let extracted = request[start+1..end].to_string();
-                            
-                            // Resolve Alias or ID
-                            // Check users (id) or temp_aliases (alias)
-                            let row = sqlx::query(
-                                r#"
-                                SELECT id FROM users WHERE id=$1
-                                UNION
-                                SELECT user_id AS id FROM temp_aliases WHERE alias=$1
-                                "#
-                            )
-                                .bind(&extracted)
-                                .fetch_optional(pool.as_ref())
-                                .await
-                                .unwrap_or(None);
-                                
-                            if let Some(r) = row {
-                                current_user_id = r.get("id");
-                            } else {
-                                current_user_id = extracted;
-                            }
-                        }
-                    }
-
-                    // 🛑 STEP 1: CHECK RATE LIMIT
-                    // Before we say "OK", we check Neon DB
-                    if check_rate_limit(&pool, &current_user_id).await {
-                        let _ = socket.write_all(b"250 OK\r\n").await;
-                    } else {
-                        // Rate limit hit: Reject connection
-                        println!("🚫 Rate limit hit for {}", current_user_id);
-                        let _ = socket.write_all(b"450 Requested mail action not taken: limit exceeded\r\n").await;
-                        return; // Close connection
-                    }
+                None => {
+                    sender_check = None;
+                    let _ = socket.write_all(b"501 5.1.7 Bad sender address syntax\r\n").await;
                 }
-                else if request.starts_with("DATA") {
-                    let _ = socket.write_all(b"354 End data with <CRLF>.<CRLF>\r\n").await;
-                    
-                    // Read email data until we get <CRLF>.<CRLF>
-                    let mut email_data = Vec::new();
-                    loop {
-                        let n = match socket.read(&mut buffer).await {
-                            Ok(n) if n == 0 => break,
-                            Ok(n) => n,
-                            Err(_) => break,
-                        };
-                        email_data.extend_from_slice(&buffer[0..n]);
-                        
-                        // Check for end of data marker
-                        if email_data.ends_with(b"\r\n.\r\n") {
-                            break;
-                        }
-                    }
-                    
-                    // Parse email using mail-parser
-                    let (sender, subject, body_preview) = if let Some(message) = Message::parse(&email_data) {
-                        let sender_str = extract_sender(&message);
-                        let subject_str = message.subject().unwrap_or("").to_string();
-                        let body_str = message.body_text(0)
-                            .map(|b| b.chars().take(500).collect::<String>())
-                            .unwrap_or_default();
-                        
-                        (sender_str, subject_str, body_str)
-                    } else {
-                        (String::new(), String::new(), String::new())
-                    };
-                    
-                    // Insert into database
-                    let result = sqlx::query(
-                        r#"
-                        INSERT INTO emails (user_id, sender, subject, body_preview)
-                        VALUES ($1, $2, $3, $4)
-                        "#
-                    )
-                    .bind(&current_user_id)
-                    .bind(&sender)
-                    .bind(&subject)
-                    .bind(&body_preview)
-                    .execute(pool.as_ref())
-                    .await;
+            }
+        }
+        else if (request.starts_with("RCPT TO") || request.starts_with("DATA")) && sender_check.is_none() {
+            let _ = socket.write_all(b"503 5.5.1 Need MAIL FROM first\r\n").await;
+        }
+        else if request.starts_with("RCPT TO") {
+            // Extract user from: RCPT TO:<user_123@mailpulse.net>
+            // (Simplified parsing logic for demo)
+            if let Some(start) = request.find('<') {
+                if let Some(end) = request.find('@') {
+                    let extracted = request[start+1..end].to_string();
                     
-                    match result {
-                        Ok(_) => {
-                            println!("📧 Email saved for {}", current_user_id);
-                            let _ = socket.write_all(b"250 OK\r\n").await;
-                        }
-                        Err(e) => {
-                            eprintln!("❌ Failed to save email: {}", e);
-                            let _ = socket.write_all(b"451 Requested action aborted: local error\r\n").await;
-                        }
-                    }
+                    // Resolve Alias or ID
+                    recipient = resolve_recipient(repos, &extracted).await;
                 }
-                else if request.starts_with("QUIT") {
-                    let _ = socket.write_all(b"221 Bye\r\n").await;
-                    return;
+            }
+
+            // 🛑 STEP 1: CHECK RATE LIMIT
+            // Before we say "OK", we check Neon DB
+            if check_rate_limit(repos.emails.as_ref(), &recipient.user_id).await {
+                let _ = socket.write_all(b"250 OK\r\n").await;
+            } else {
+                // Rate limit hit: Reject connection
+                println!("🚫 Rate limit hit for {}", recipient.user_id);
+                let _ = socket.write_all(b"450 Requested mail action not taken: limit exceeded\r\n").await;
+                return; // Close connection
+            }
+        }
+        else if request.starts_with("DATA") {
+            let _ = socket.write_all(b"354 End data with <CRLF>.<CRLF>\r\n").await;
+            
+            // Read email data until we get <CRLF>.<CRLF>
+            let mut email_data = Vec::new();
+            loop {
+                let n = match socket.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(_) => break,
+                };
+                email_data.extend_from_slice(&buffer[0..n]);
+                
+                // Check for end of data marker
+                if email_data.ends_with(b"\r\n.\r\n") {
+                    break;
                 }
             }
-        });
+            
+            // Parse email using mail-parser (same pipeline as Gmail sync), with SPF and DKIM results on top
+            // Ends the transaction: the next message needs a MAIL FROM of its own
+            let email = ingest::parse_received(&unstuff(&email_data), resolver, sender_check.take().as_ref()).await.unwrap_or_default();
+            let received_at = chrono::Utc::now().timestamp();
+            
+            // Always a new row: the Message-ID header is the sender's choice, so it is kept for
+            // threading only and never used to find a stored email to update
+            let result = repos.emails
+                .store(&recipient, None, &email, received_at)
+                .await;
+            
+            match result {
+                Ok(_) => {
+                    println!("📧 Email saved for {}", recipient.user_id);
+                    let _ = socket.write_all(b"250 OK\r\n").await;
+                }
+                Err(e) => {
+                    eprintln!("❌ Failed to save email: {}", e);
+                    let _ = socket.write_all(b"451 Requested action aborted: local error\r\n").await;
+                }
+            }
+        }
+        else if request.starts_with("QUIT") {
+            let _ = socket.write_all(b"221 Bye\r\n").await;
+            return;
+        }
+    }
+}
+
+/// The message itself from DATA: without the final "." line, and leading dots
+/// the client doubled (RFC 5321 4.5.2) undone
+fn unstuff(data: &[u8]) -> Vec<u8> {
+    let data = data.strip_suffix(b".\r\n").unwrap_or(data);
+    let mut message = Vec::with_capacity(data.len());
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        message.extend_from_slice(line.strip_prefix(b".").unwrap_or(line));
+    }
+    message
+}
+
+/// The reverse-path of "MAIL FROM:<path> [parameters]", "" for the null sender (bounces).
+/// None unless it is a mailbox as RFC 5321 4.1.2 has it; quoted local parts aren't taken.
+fn reverse_path(command: &str) -> Option<&str> {
+    let path = command.get("MAIL FROM".len()..)?.trim_start().strip_prefix(':')?.trim_start().strip_prefix('<')?;
+    let (path, _) = path.split_once('>')?;
+    if path.is_empty() {
+        return Some(path);
+    }
+    // A source route (<@relay.example:user@a.example>) is ignored, as receivers should
+    let mailbox = match path.strip_prefix('@') {
+        Some(route) => route.split_once(':')?.1,
+        None => path,
+    };
+    let (local, domain) = mailbox.rsplit_once('@')?;
+    let atom = |atom: &str| !atom.is_empty() && atom.chars().all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c));
+    (local.split('.').all(atom) && (is_domain(domain) || is_address_literal(domain))).then_some(mailbox)
+}
+
+/// Dot-separated labels of letters, digits and inner hyphens
+fn is_domain(name: &str) -> bool {
+    let label = |label: &str| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+    name.len() <= 253 && name.split('.').all(label)
+}
+
+/// "[192.0.2.1]" or "[IPv6:2001:db8::1]"
+fn is_address_literal(name: &str) -> bool {
+    let Some(inner) = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) else { return false };
+    match inner.get(..5) {
+        Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => inner[5..].parse::<Ipv6Addr>().is_ok(),
+        _ => inner.parse::<Ipv4Addr>().is_ok(),
+    }
+}
+
+/// The owner of an alias (personal or shared), else the name itself as a user id
+async fn resolve_recipient(repos: &Repos, name: &str) -> Recipient {
+    match repos.aliases.resolve(name).await {
+        Ok(Some(recipient)) => recipient,
+        _ => Recipient::mailbox(name),
     }
 }
@@ -0,0 +1,190 @@
+//! Every protected route must refuse anonymous callers (401) and callers
+//! asking for someone else's data (403) before touching the database.
+
+mod common;
+
+use actix_web::{http::Method, http::StatusCode, test, web, App};
+use mail_server::api::routes;
+use mail_server::core::crypto::KeyRing;
+use mail_server::core::events::EventHub;
+use mail_server::core::jwt;
+use mail_server::db::{memory::MemoryStore, Repos};
+use std::sync::Arc;
+use sqlx::postgres::PgPoolOptions;
+
+use common::test_env;
+
+/// Routes that act on the user in the path (or body, or query)
+const USER_ROUTES: [(&str, &str); 22] = [
+    ("GET", "/sync/{user}"),
+    ("GET", "/sync/{user}/status"),
+    ("PUT", "/sync/{user}/settings"),
+    ("GET", "/latest/{user}"),
+    ("GET", "/emails/{user}"),
+    ("PATCH", "/emails/{user}"),
+    ("DELETE", "/emails/{user}"),
+    ("GET", "/emails/{user}/unread"),
+    ("GET", "/emails/{user}/1"),
+    ("PATCH", "/emails/{user}/1"),
+    ("DELETE", "/emails/{user}/1"),
+    ("GET", "/emails/{user}/1/raw"),
+    ("GET", "/emails/{user}/1/headers"),
+    ("GET", "/emails/{user}/1/deliverability"),
+    ("GET", "/emails/{user}/search?q=invoice"),
+    ("GET", "/threads/{user}"),
+    ("GET", "/events/{user}"),
+    ("GET", "/threads/{user}/1"),
+    ("POST", "/users"),
+    ("GET", "/auth/google?user_id={user}"),
+    ("GET", "/auth/microsoft?user_id={user}"),
+    ("GET", "/connect/gmail?user_id={user}"),
+];
+
+/// Routes that act on whoever the token belongs to
+const SELF_ROUTES: [(&str, &str); 6] = [
+    ("POST", "/temp-mail"),
+    ("DELETE", "/temp-mail"),
+    ("POST", "/api-keys"),
+    ("GET", "/api-keys"),
+    ("DELETE", "/api-keys/key_1"),
+    ("POST", "/auth/logout"),
+];
+
+/// Routes that act on an organization the caller must belong to
+const ORG_ROUTES: [(&str, &str); 23] = [
+    ("POST", "/orgs"),
+    ("GET", "/orgs"),
+    ("GET", "/orgs/org_1/members"),
+    ("PUT", "/orgs/org_1/members/user_c"),
+    ("DELETE", "/orgs/org_1/members/user_c"),
+    ("PUT", "/orgs/org_1/sso"),
+    ("POST", "/orgs/org_1/aliases"),
+    ("GET", "/orgs/org_1/aliases"),
+    ("DELETE", "/orgs/org_1/aliases/temp_1"),
+    ("GET", "/orgs/org_1/emails"),
+    ("PATCH", "/orgs/org_1/emails"),
+    ("GET", "/orgs/org_1/emails/1"),
+    ("PATCH", "/orgs/org_1/emails/1"),
+    ("DELETE", "/orgs/org_1/emails/1"),
+    ("GET", "/orgs/org_1/emails/1/raw"),
+    ("GET", "/orgs/org_1/emails/1/headers"),
+    ("GET", "/orgs/org_1/emails/1/deliverability"),
+    ("GET", "/orgs/org_1/events"),
+    ("GET", "/orgs/org_1/threads"),
+    ("GET", "/orgs/org_1/threads/1"),
+    ("POST", "/orgs/org_1/api-keys"),
+    ("GET", "/orgs/org_1/api-keys"),
+    ("DELETE", "/orgs/org_1/api-keys/key_1"),
+];
+
+macro_rules! app {
+    () => {{
+        test_env();
+        // Never connects: every request below must be rejected before a query runs
+        let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(EventHub::new()))
+                .app_data(web::Data::new(KeyRing::parse("k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=", None).unwrap()))
+                .app_data(web::Data::new(Repos::in_memory(Arc::new(MemoryStore::new()))))
+                .configure(routes::config),
+        )
+        .await
+    }};
+}
+
+fn request(method: &str, path: &str, owner: &str, token: Option<&str>) -> test::TestRequest {
+    let mut req = test::TestRequest::default()
+        .method(Method::from_bytes(method.as_bytes()).unwrap())
+        .uri(&path.replace("{user}", owner));
+
+    req = match path {
+        "/sync/{user}/settings" => req.set_json(serde_json::json!({ "interval_secs": 300 })),
+        "/emails/{user}" if method == "PATCH" => req.set_json(serde_json::json!({ "ids": [1], "read": true })),
+        "/emails/{user}/1" if method == "PATCH" => req.set_json(serde_json::json!({ "starred": true })),
+        "/orgs/org_1/emails" if method == "PATCH" => req.set_json(serde_json::json!({ "ids": [1], "read": true })),
+        "/orgs/org_1/emails/1" if method == "PATCH" => req.set_json(serde_json::json!({ "starred": true })),
+        "/users" => req.set_json(serde_json::json!({ "id": owner, "email": "owner@example.com" })),
+        "/api-keys" | "/orgs/org_1/api-keys" if method == "POST" => {
+            req.set_json(serde_json::json!({ "name": "ci", "scopes": ["mail:read"] }))
+        }
+        "/orgs" if method == "POST" => req.set_json(serde_json::json!({ "name": "QA" })),
+        "/orgs/org_1/members/user_c" if method == "PUT" => req.set_json(serde_json::json!({ "role": "member" })),
+        "/orgs/org_1/sso" => req.set_json(serde_json::json!({ "workos_organization_id": "org_workos" })),
+        _ => req,
+    };
+
+    match token {
+        Some(token) => req.insert_header(("Authorization", format!("Bearer {}", token))),
+        None => req,
+    }
+}
+
+#[actix_web::test]
+async fn protected_routes_require_a_token() {
+    let app = app!();
+
+    for (method, path) in USER_ROUTES.iter().chain(SELF_ROUTES.iter()).chain(ORG_ROUTES.iter()) {
+        let resp = test::call_service(&app, request(method, path, "user_a", None).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{} {} without token", method, path);
+        assert_eq!(resp.headers().get("WWW-Authenticate").unwrap(), "Bearer");
+    }
+}
+
+#[actix_web::test]
+async fn protected_routes_reject_invalid_tokens() {
+    let app = app!();
+
+    for (method, path) in USER_ROUTES.iter().chain(SELF_ROUTES.iter()).chain(ORG_ROUTES.iter()) {
+        let resp = test::call_service(&app, request(method, path, "user_a", Some("not-a-jwt")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{} {} with invalid token", method, path);
+    }
+}
+
+#[actix_web::test]
+async fn protected_routes_reject_other_users() {
+    let app = app!();
+    let token = jwt::generate_token("user_b", None).unwrap();
+
+    for (method, path) in USER_ROUTES {
+        let resp = test::call_service(&app, request(method, path, "user_a", Some(&token)).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{} {} for another user", method, path);
+    }
+}
+
+#[actix_web::test]
+async fn non_bearer_schemes_are_rejected() {
+    let app = app!();
+    let token = jwt::generate_token("user_a", None).unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/emails/user_a")
+        .insert_header(("Authorization", format!("Basic {}", token)))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn jwks_is_public_and_never_leaks_hmac_secrets() {
+    let app = app!();
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri("/.well-known/jwks.json").to_request()).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body: serde_json::Value = test::read_body_json(resp).await;
+    assert_eq!(body, serde_json::json!({ "keys": [] }));
+}
+
+#[actix_web::test]
+async fn workos_webhooks_must_be_signed() {
+    let app = app!();
+
+    let req = test::TestRequest::post()
+        .uri("/webhooks/workos")
+        .insert_header(("WorkOS-Signature", "t=0, v1=00"))
+        .set_payload(r#"{"event":"user.deleted","data":{"id":"user_a"}}"#)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
@@ -0,0 +1,48 @@
+//! API key checks against Postgres.
+
+mod common;
+
+use mail_server::core::api_keys::{self, Scope};
+use sqlx::Row;
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn keys_are_checked_against_their_digest() {
+    let (pool, user_id) = common::user().await;
+    let (info, key) = api_keys::create(&pool, &user_id, None, "ci", &[Scope::ReadMail], None).await.unwrap();
+
+    let owner = api_keys::authenticate(&pool, &key).await.unwrap().unwrap();
+    assert_eq!((owner.id.as_str(), owner.user_id.as_str()), (info.id.as_str(), user_id.as_str()));
+    assert_eq!(owner.scopes, vec![Scope::ReadMail]);
+
+    let wrong = format!("{}_{}", info.prefix, "0".repeat(32));
+    assert!(api_keys::authenticate(&pool, &wrong).await.unwrap().is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn bcrypt_keys_switch_to_a_digest_on_first_use() {
+    let (pool, user_id) = common::user().await;
+    let lookup = &uuid::Uuid::new_v4().simple().to_string()[..8];
+    let key = format!("mk_{}_{}", lookup, uuid::Uuid::new_v4().simple());
+    sqlx::query("INSERT INTO api_keys (id, user_id, name, prefix, key_hash, scopes) VALUES ($1, $2, 'old', $3, $4, '{mail:read}')")
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(&user_id)
+        .bind(&key[..11])
+        .bind(bcrypt::hash(&key, 4).unwrap())
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert!(api_keys::authenticate(&pool, &key).await.unwrap().is_some());
+    let row = sqlx::query("SELECT key_hash, key_digest FROM api_keys WHERE prefix = $1")
+        .bind(&key[..11])
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert!(row.get::<Option<String>, _>("key_hash").is_none());
+    assert!(row.get::<Option<String>, _>("key_digest").is_some());
+
+    // ...and keep working with it
+    assert!(api_keys::authenticate(&pool, &key).await.unwrap().is_some());
+}
@@ -0,0 +1,64 @@
+//! Fixtures shared by the integration tests.
+//!
+//! Tests that need Postgres are `#[ignore]`d, so a plain `cargo test` doesn't count them
+//! as passing. Run them with `DATABASE_URL=postgres://... cargo test -- --include-ignored`.
+
+// Each test crate uses only some of these
+#![allow(dead_code)]
+
+use std::sync::Once;
+
+use mail_server::core::{jwt, migrations};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+
+/// Configuration the handlers read from the environment. Set once, before any test reads it:
+/// writing the environment while other test threads read it is undefined behaviour.
+pub fn test_env() {
+    static ENV: Once = Once::new();
+    ENV.call_once(|| {
+        std::env::set_var("JWT_SECRET", "test-secret-test-secret-test-secret");
+        std::env::set_var("WEBHOOK_SECRET", "hook-secret");
+    });
+}
+
+pub fn bearer(user_id: &str) -> (&'static str, String) {
+    test_env();
+    ("Authorization", format!("Bearer {}", jwt::generate_token(user_id, None).unwrap()))
+}
+
+/// The next chunk of a streaming response body
+pub async fn next_chunk<B: actix_web::body::MessageBody + Unpin>(body: &mut B) -> String {
+    let chunk = std::future::poll_fn(|cx| std::pin::Pin::new(&mut *body).poll_next(cx)).await;
+    let bytes = chunk.unwrap().map_err(|_| "body error").unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+/// The migrated database at DATABASE_URL
+pub async fn database() -> PgPool {
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set for tests that need Postgres");
+    let pool = PgPoolOptions::new().connect(&database_url).await.unwrap();
+    migrations::run(&pool, false).await.unwrap();
+    pool
+}
+
+/// Fresh users of the database
+pub async fn users<const N: usize>(pool: &PgPool) -> [String; N] {
+    let ids = std::array::from_fn(|_| format!("user_{}", uuid::Uuid::new_v4().simple()));
+    for user_id in &ids {
+        sqlx::query("INSERT INTO users (id, email) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(format!("{}@example.com", user_id))
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+    ids
+}
+
+/// The migrated database with a fresh user
+pub async fn user() -> (PgPool, String) {
+    let pool = database().await;
+    let [user_id] = users(&pool).await;
+    (pool, user_id)
+}
@@ -0,0 +1,594 @@
+//! Alias and inbox handlers against in-memory repositories: no database needed.
+
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, test, web, App};
+use mail_server::api::routes;
+use mail_server::core::crypto::KeyRing;
+use mail_server::core::dns::{Resolver, Zone};
+use mail_server::core::events::EventHub;
+use mail_server::core::ingest::ParsedEmail;
+use mail_server::db::{memory::MemoryStore, AliasRepo, EmailRepo, Recipient, Repos};
+use sqlx::postgres::PgPoolOptions;
+
+use common::{bearer, next_chunk, test_env};
+
+macro_rules! app {
+    ($store:expr) => {
+        app!($store, Zone::new())
+    };
+    ($store:expr, $zone:expr) => {{
+        test_env();
+        // Never connects: these handlers only use the repositories
+        let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(EventHub::new()))
+                .app_data(web::Data::new(KeyRing::parse("k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=", None).unwrap()))
+                .app_data(web::Data::new(Repos::in_memory($store)))
+                .app_data(web::Data::from(Arc::new($zone) as Arc<dyn Resolver>))
+                .configure(routes::config),
+        )
+        .await
+    }};
+}
+
+#[actix_web::test]
+async fn mail_for_a_new_alias_shows_up_in_the_inbox() {
+    let store = Arc::new(MemoryStore::new());
+    let app = app!(store.clone());
+
+    let req = test::TestRequest::post().uri("/temp-mail").insert_header(bearer("user_a")).to_request();
+    let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let alias = created["alias"].as_str().unwrap().to_string();
+    assert_eq!(created["id"], "user_a");
+
+    let webhook = serde_json::json!({
+        "from": "noreply@shop.example",
+        "to": format!("{}@mailpulse.net", alias),
+        "subject": "Your code",
+        "body": "Your code is 123456",
+        "message_id": "m1",
+        "otp": "123456",
+    });
+    for _ in 0..2 {
+        // Delivered twice, stored once
+        let req = test::TestRequest::post()
+            .uri("/webhooks/email")
+            .insert_header(("X-Webhook-Secret", "hook-secret"))
+            .set_json(&webhook)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    let req = test::TestRequest::get().uri("/emails/user_a").insert_header(bearer("user_a")).to_request();
+    let page: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let emails = page["emails"].as_array().unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0]["sender"], "noreply@shop.example");
+    assert_eq!(emails[0]["otp"], "123456");
+    assert_eq!(emails[0]["alias"], alias.as_str());
+    assert!(page["next_cursor"].is_null());
+
+    let req = test::TestRequest::get().uri("/latest/user_a").insert_header(bearer("user_a")).to_request();
+    let latest: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(latest["subject"], "Your code");
+}
+
+#[actix_web::test]
+async fn a_new_alias_replaces_the_old_one() {
+    let store = Arc::new(MemoryStore::new());
+    let app = app!(store.clone());
+
+    for _ in 0..2 {
+        let req = test::TestRequest::post().uri("/temp-mail").insert_header(bearer("user_a")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+    assert_eq!(store.aliases().len(), 1);
+
+    let req = test::TestRequest::delete().uri("/temp-mail").insert_header(bearer("user_a")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(store.aliases().is_empty());
+}
+
+#[actix_web::test]
+async fn webhooks_for_unknown_aliases_are_not_stored() {
+    let store = Arc::new(MemoryStore::new());
+    let app = app!(store.clone());
+
+    let req = test::TestRequest::post()
+        .uri("/webhooks/email")
+        .insert_header(("X-Webhook-Secret", "hook-secret"))
+        .set_json(serde_json::json!({
+            "from": "a@example.com",
+            "to": "temp_nobody@mailpulse.net",
+            "subject": "Hi",
+            "body": "Hi",
+        }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    assert!(store.emails().is_empty());
+}
+
+/// Store `count` emails to `alias`, a minute apart, the newest with the highest number
+async fn fill_inbox(store: &MemoryStore, user_id: &str, alias: &str, count: i64) {
+    store.replace_personal(user_id, alias).await.unwrap();
+    let to = store.resolve(alias).await.unwrap().unwrap();
+    let start = chrono::Utc::now().timestamp() - count * 60;
+    for i in 0..count {
+        let email = ParsedEmail {
+            sender: format!("sender{}@shop.example", i % 2),
+            subject: format!("Order {}", i),
+            otp: (i % 3 == 0).then(|| format!("{:06}", i)),
+            ..Default::default()
+        };
+        store.store(&to, Some(&format!("m{}", i)), &email, start + i * 60).await.unwrap();
+    }
+}
+
+/// GET a listing as user_a
+macro_rules! get_page {
+    ($app:expr, $uri:expr) => {{
+        let req = test::TestRequest::get().uri($uri).insert_header(bearer("user_a")).to_request();
+        let page: serde_json::Value = test::call_and_read_body_json($app, req).await;
+        page
+    }};
+}
+
+fn subjects(page: &serde_json::Value) -> Vec<String> {
+    page["emails"].as_array().unwrap().iter().map(|e| e["subject"].as_str().unwrap().to_string()).collect()
+}
+
+#[actix_web::test]
+async fn pages_follow_each_other_without_gaps() {
+    let store = Arc::new(MemoryStore::new());
+    fill_inbox(&store, "user_a", "temp_shop", 7).await;
+    let app = app!(store.clone());
+
+    let mut seen = Vec::new();
+    let mut uri = "/emails/user_a?limit=3".to_string();
+    loop {
+        let page = get_page!(&app, &uri);
+        seen.extend(subjects(&page));
+        match page["next_cursor"].as_str() {
+            Some(cursor) => uri = format!("/emails/user_a?limit=3&cursor={}", cursor),
+            None => break,
+        }
+    }
+
+    let expected: Vec<String> = (0..7).rev().map(|i| format!("Order {}", i)).collect();
+    assert_eq!(seen, expected);
+}
+
+#[actix_web::test]
+async fn filters_combine() {
+    let store = Arc::new(MemoryStore::new());
+    fill_inbox(&store, "user_a", "temp_shop", 7).await;
+    store.store(&Recipient::mailbox("user_a"), Some("direct"), &ParsedEmail {
+        sender: "boss@work.example".to_string(),
+        subject: "Direct".to_string(),
+        ..Default::default()
+    }, chrono::Utc::now().timestamp()).await.unwrap();
+    let app = app!(store.clone());
+
+    let page = get_page!(&app, "/emails/user_a?alias=temp_shop&sender=SENDER0&has_otp=true");
+    assert_eq!(subjects(&page), vec!["Order 6", "Order 0"]);
+
+    let page = get_page!(&app, "/emails/user_a?subject=direct&read=false");
+    assert_eq!(subjects(&page), vec!["Direct"]);
+    assert!(page["emails"][0]["alias"].is_null());
+
+    let page = get_page!(&app, "/emails/user_a?sender=%25");
+    assert!(subjects(&page).is_empty(), "wildcards match literally");
+}
+
+#[actix_web::test]
+async fn bad_listing_parameters_are_rejected() {
+    let store = Arc::new(MemoryStore::new());
+    let app = app!(store);
+
+    for query in ["cursor=nonsense", "after=yesterday", "limit=0", "limit=10000"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/emails/user_a?{}", query))
+            .insert_header(bearer("user_a"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST, "{}", query);
+    }
+}
+
+const RAW: &str = "From: Shop <noreply@shop.example>\r\n\
+To: temp_shop@mailpulse.net\r\n\
+Subject: Receipt\r\n\
+X-Long: first\r\n second\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Thanks for your order\r\n\
+--b\r\n\
+Content-Type: text/csv; name=receipt.csv\r\n\
+Content-Disposition: attachment; filename=receipt.csv\r\n\
+\r\n\
+a,b\r\n\
+--b--\r\n";
+
+#[actix_web::test]
+async fn one_email_can_be_read_downloaded_and_deleted() {
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_a", "temp_shop").await.unwrap();
+    let to = store.resolve("temp_shop").await.unwrap().unwrap();
+    let email = mail_server::core::ingest::parse_email(RAW.as_bytes()).unwrap();
+    let id = store.store(&to, Some("r1"), &email, chrono::Utc::now().timestamp()).await.unwrap();
+    let app = app!(store.clone());
+
+    let detail = get_page!(&app, &format!("/emails/user_a/{}", id));
+    assert_eq!(detail["id"], id);
+    assert_eq!(detail["alias"], "temp_shop");
+    assert_eq!(detail["body_text"].as_str().unwrap().trim(), "Thanks for your order");
+    assert!(detail["headers"].as_array().unwrap().contains(&serde_json::json!({ "name": "X-Long", "value": "first second" })));
+    assert_eq!(detail["attachments"][0]["filename"], "receipt.csv");
+    assert_eq!(detail["attachments"][0]["content"], "YSxi"); // "a,b"
+
+    let req = test::TestRequest::get().uri(&format!("/emails/user_a/{}/raw", id)).insert_header(bearer("user_a")).to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.headers().get("Content-Type").unwrap(), "message/rfc822");
+    assert_eq!(test::read_body(resp).await, RAW.as_bytes());
+
+    // Other users' mail is out of reach, even with its id
+    let req = test::TestRequest::delete().uri(&format!("/emails/user_b/{}", id)).insert_header(bearer("user_b")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::delete().uri(&format!("/emails/user_a/{}", id)).insert_header(bearer("user_a")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = test::TestRequest::get().uri(&format!("/emails/user_a/{}", id)).insert_header(bearer("user_a")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn bulk_deletes_need_a_filter() {
+    let store = Arc::new(MemoryStore::new());
+    fill_inbox(&store, "user_a", "temp_shop", 6).await;
+    let app = app!(store.clone());
+
+    let delete = |query: &str| {
+        test::TestRequest::delete()
+            .uri(&format!("/emails/user_a{}", query))
+            .insert_header(bearer("user_a"))
+            .to_request()
+    };
+
+    assert_eq!(test::call_service(&app, delete("")).await.status(), StatusCode::BAD_REQUEST);
+
+    let body: serde_json::Value = test::call_and_read_body_json(&app, delete("?has_otp=true")).await;
+    assert_eq!(body["deleted"], 2);
+    assert_eq!(store.emails().len(), 4);
+
+    let body: serde_json::Value = test::call_and_read_body_json(&app, delete("?all=true")).await;
+    assert_eq!(body["deleted"], 4);
+    assert!(store.emails().is_empty());
+}
+
+#[actix_web::test]
+async fn search_combines_text_and_operators() {
+    let store = Arc::new(MemoryStore::new());
+    fill_inbox(&store, "user_a", "temp_shop", 4).await;
+    let to = store.resolve("temp_shop").await.unwrap().unwrap();
+    store.store(&to, Some("invoice"), &ParsedEmail {
+        sender: "billing@stripe.example".to_string(),
+        to: vec!["temp_shop@mailpulse.net".to_string()],
+        subject: "Your invoice".to_string(),
+        body_text: Some("Invoice <b>42</b>: paid. Keep this invoice.".to_string()),
+        ..Default::default()
+    }, chrono::Utc::now().timestamp()).await.unwrap();
+    let app = app!(store.clone());
+
+    let found = get_page!(&app, "/emails/user_a/search?q=invoice%20from:stripe%20to:temp_shop");
+    let results = found["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["subject"], "Your invoice");
+    assert!(results[0]["rank"].as_f64().unwrap() > 0.0);
+    assert_eq!(
+        results[0]["snippet"],
+        "<mark>Invoice</mark> &lt;b&gt;42&lt;/b&gt;: paid. Keep this <mark>invoice.</mark>"
+    );
+
+    let found = get_page!(&app, "/emails/user_a/search?q=has:otp%20subject:order");
+    assert_eq!(found["results"].as_array().unwrap().len(), 2); // Orders 0 and 3
+
+    let found = get_page!(&app, "/emails/user_a/search?q=invoice%20-paid");
+    assert!(found["results"].as_array().unwrap().is_empty());
+
+    for q in ["", "has:attachment", "after:soon"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/emails/user_a/search?q={}", q))
+            .insert_header(bearer("user_a"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST, "{}", q);
+    }
+}
+
+/// A message to temp_shop with threading headers
+fn message(id: &str, subject: &str, in_reply_to: Option<&str>, references: &[&str]) -> ParsedEmail {
+    ParsedEmail {
+        message_id: Some(id.to_string()),
+        in_reply_to: in_reply_to.map(str::to_string),
+        references: references.iter().map(|r| r.to_string()).collect(),
+        sender: format!("{}@example.com", id),
+        subject: subject.to_string(),
+        ..Default::default()
+    }
+}
+
+#[actix_web::test]
+async fn replies_are_grouped_into_threads() {
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_a", "temp_shop").await.unwrap();
+    let to = store.resolve("temp_shop").await.unwrap().unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let deliveries = [
+        // The reply arrives before the message it answers
+        message("b", "Re: Order shipped", Some("a"), &["a"]),
+        message("a", "Order shipped", None, &[]),
+        message("c", "Re: Order shipped", None, &["a", "b"]),
+        message("d", "RE: order  SHIPPED", None, &[]), // Client dropped the headers
+        message("e", "Order shipped", None, &[]),      // Same subject, but not a reply
+        message("f", "Welcome", None, &[]),
+    ];
+    for (i, email) in deliveries.iter().enumerate() {
+        store.store(&to, email.message_id.as_deref(), email, now - 600 + i as i64 * 60).await.unwrap();
+    }
+    let app = app!(store.clone());
+
+    let page = get_page!(&app, "/threads/user_a?limit=2");
+    let threads = page["threads"].as_array().unwrap();
+    assert_eq!(threads.len(), 2);
+    assert_eq!(threads[0]["subject"], "Welcome");
+    assert_eq!(threads[1]["subject"], "Order shipped");
+    assert_eq!(threads[1]["message_count"], 1);
+
+    let cursor = page["next_cursor"].as_str().unwrap();
+    let page = get_page!(&app, &format!("/threads/user_a?cursor={}", cursor));
+    assert!(page["next_cursor"].is_null());
+    let conversation = &page["threads"][0];
+    assert_eq!(conversation["message_count"], 4);
+    assert_eq!(conversation["unread_count"], 4);
+    assert_eq!(conversation["subject"], "Re: Order shipped"); // Of the first to arrive
+
+    let thread = get_page!(&app, &format!("/threads/user_a/{}", conversation["thread_id"]));
+    let senders: Vec<&str> = thread["messages"].as_array().unwrap().iter().map(|m| m["sender"].as_str().unwrap()).collect();
+    assert_eq!(senders, vec!["b@example.com", "a@example.com", "c@example.com", "d@example.com"]);
+
+    let req = test::TestRequest::get().uri("/threads/user_b/1").insert_header(bearer("user_b")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn read_star_and_label_state_is_kept_and_streamed() {
+    let store = Arc::new(MemoryStore::new());
+    fill_inbox(&store, "user_a", "temp_shop", 3).await;
+    store.store(&Recipient::mailbox("user_a"), Some("direct"), &ParsedEmail {
+        sender: "boss@work.example".to_string(),
+        ..Default::default()
+    }, chrono::Utc::now().timestamp()).await.unwrap();
+    let ids: Vec<i64> = store.emails().iter().map(|e| e.id).collect();
+    let app = app!(store.clone());
+
+    let req = test::TestRequest::get().uri("/events/user_a").insert_header(bearer("user_a")).to_request();
+    let stream = test::call_service(&app, req).await;
+    assert_eq!(stream.headers().get("Content-Type").unwrap(), "text/event-stream");
+    let mut events = stream.into_body();
+
+    let patch = |uri: String, body: serde_json::Value| {
+        test::TestRequest::patch().uri(&uri).insert_header(bearer("user_a")).set_json(body).to_request()
+    };
+    let email: serde_json::Value = test::call_and_read_body_json(&app, patch(
+        format!("/emails/user_a/{}", ids[0]),
+        serde_json::json!({ "read": true, "starred": true, "add_labels": ["receipts", " work "] }),
+    )).await;
+    assert_eq!(email["read"], true);
+    assert_eq!(email["starred"], true);
+    assert_eq!(email["labels"], serde_json::json!(["receipts", "work"]));
+
+    let frame = next_chunk(&mut events).await;
+    assert!(frame.starts_with("event: state\ndata: "), "{}", frame);
+    assert!(frame.contains(&format!("\"id\":{}", ids[0])) && frame.contains("\"starred\":true"), "{}", frame);
+
+    let body: serde_json::Value = test::call_and_read_body_json(&app, patch(
+        "/emails/user_a".to_string(),
+        serde_json::json!({ "ids": [ids[0], ids[1], 999], "read": true, "remove_labels": ["work"] }),
+    )).await;
+    assert_eq!(body["emails"].as_array().unwrap().len(), 2);
+    assert!(next_chunk(&mut events).await.contains(&format!("\"id\":{}", ids[1])));
+
+    let page = get_page!(&app, "/emails/user_a?starred=true&label=receipts");
+    assert_eq!(page["emails"].as_array().unwrap().len(), 1);
+    let page = get_page!(&app, "/emails/user_a?read=false");
+    assert_eq!(page["emails"].as_array().unwrap().len(), 2);
+
+    let unread = get_page!(&app, "/emails/user_a/unread");
+    assert_eq!(unread["total"], 2);
+    assert_eq!(unread["aliases"], serde_json::json!([
+        { "alias": null, "unread": 1 },
+        { "alias": "temp_shop", "unread": 1 },
+    ]));
+
+    let resp = test::call_service(&app, patch(format!("/emails/user_a/{}", ids[0]), serde_json::json!({}))).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let resp = test::call_service(&app, patch("/emails/user_a/999".to_string(), serde_json::json!({ "read": true }))).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+const NEWSLETTER: &str = "Received: from relay.shop.example (relay.shop.example [192.0.2.10])\r\n \
+by mx.mailpulse.net with ESMTPS id a1; Tue, 2 Jan 2024 10:00:40 +0000\r\n\
+Received: from app01 (unknown [10.0.0.5]) by relay.shop.example with ESMTP id b2;\r\n \
+Tue, 2 Jan 2024 10:00:05 +0000\r\n\
+Authentication-Results: relay.shop.example; dkim=pass header.d=shop.example; dmarc=pass header.from=shop.example\r\n\
+From: Shop <news@shop.example>\r\n\
+To: temp_shop@mailpulse.net\r\n\
+Subject: Deals\r\n\
+Date: Tue, 2 Jan 2024 10:00:00 +0000\r\n\
+Message-ID: <deals-1@shop.example>\r\n\
+List-Unsubscribe: <mailto:unsub@shop.example>, <https://shop.example/u/1>\r\n\
+X-Campaign: winter\r\n\
+\r\n\
+Deals inside\r\n";
+
+#[actix_web::test]
+async fn headers_are_structured_and_reported_on() {
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_a", "temp_shop").await.unwrap();
+    let to = store.resolve("temp_shop").await.unwrap().unwrap();
+    let mut email = mail_server::core::ingest::parse_email(NEWSLETTER.as_bytes()).unwrap();
+    // Our own results, as added on arrival
+    email.headers.insert(0, (
+        "Authentication-Results".to_string(),
+        "mailpulse.net; spf=pass smtp.mailfrom=shop.example; dkim=fail reason=\"body hash did not verify\" header.d=shop.example".to_string(),
+    ));
+    let id = store.store(&to, Some("n1"), &email, chrono::Utc::now().timestamp()).await.unwrap();
+    let app = app!(store.clone());
+
+    let headers = get_page!(&app, &format!("/emails/user_a/{}/headers", id));
+    let hops = headers["received"].as_array().unwrap();
+    assert_eq!(hops.len(), 2);
+    assert_eq!(hops[0]["by"], "relay.shop.example");
+    assert_eq!(hops[0]["delay_secs"], 5);
+    assert_eq!(hops[1]["by"], "mx.mailpulse.net");
+    assert_eq!(hops[1]["delay_secs"], 35);
+    assert_eq!(headers["total_delay_secs"], 40);
+    assert_eq!(headers["authentication_results"][0]["results"][1]["reason"], "body hash did not verify");
+    assert_eq!(headers["list_unsubscribe"]["http"][0], "https://shop.example/u/1");
+    assert_eq!(headers["x_headers"], serde_json::json!([{ "name": "X-Campaign", "value": "winter" }]));
+
+    let report = get_page!(&app, &format!("/emails/user_a/{}/deliverability", id));
+    assert_eq!(report["spf"], "pass");
+    assert_eq!(report["dkim"], "fail"); // The relay's pass is only its word
+    assert_eq!(report["dmarc"], "none");
+    assert_eq!(report["warnings"], serde_json::json!([
+        "DKIM fail (body hash did not verify)",
+        "List-Unsubscribe without one-click unsubscribe (List-Unsubscribe-Post, RFC 8058)",
+    ]));
+
+    let req = test::TestRequest::get().uri("/emails/user_a/999/deliverability").insert_header(bearer("user_a")).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn raw_webhook_mail_has_its_dkim_signatures_checked() {
+    use base64::Engine;
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_a", "temp_dkim").await.unwrap();
+    // Keys of tests/fixtures/dkim_rsa.pem; the ed25519 signer's key is not published
+    let rsa = "MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC43qlBKGK1sNh1pX1oSBloIIhwllt+uFXpXk+ey0HTCFjLaQkHop/d+7iHuoHq5utdfiP4fFHeBjs486jZjGaXplZylO4+lEX/MdlIgjOYKW8snfwiduAz65EBkv425OAt2cD2LFDR1IK72F2gK1evQ/q9OPoQQju160Go0pa+EwIDAQAB";
+    let zone = Zone::new()
+        .txt("2024a._domainkey.shop.example", &format!("v=DKIM1; p={}", rsa))
+        .txt("k1._domainkey.esp.example", &format!("v=DKIM1; p={}", rsa));
+    let app = app!(store.clone(), zone);
+
+    let raw = include_bytes!("fixtures/dkim_signed.eml");
+    let req = test::TestRequest::post()
+        .uri("/webhooks/email")
+        .insert_header(("X-Webhook-Secret", "hook-secret"))
+        .set_json(serde_json::json!({
+            "from": "noreply@shop.example",
+            "to": "temp_dkim@mailpulse.net",
+            "subject": "",
+            "body": "",
+            "message_id": "<dkim-1@shop.example>",
+            "raw": base64::engine::general_purpose::STANDARD.encode(raw),
+        }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let id = store.emails()[0].id;
+    let email = get_page!(&app, &format!("/emails/user_a/{}", id));
+    assert_eq!(email["subject"], "Your verification code is 482913"); // Parsed from the raw message
+    assert_eq!(email["otp"], "482913");
+    let results: Vec<_> = email["dkim"].as_array().unwrap().iter().map(|s| (s["domain"].as_str(), s["result"].as_str())).collect();
+    assert_eq!(results, vec![
+        (Some("esp.example"), Some("pass")),
+        (Some("shop.example"), Some("permerror")),
+        (Some("shop.example"), Some("pass")),
+    ]);
+    assert_eq!(email["dkim"][1]["reason"], "no key at ed1._domainkey.shop.example");
+    assert_eq!(email["dkim"][2]["signed_headers"], serde_json::json!(["from", "to", "subject", "date", "message-id", "from"]));
+
+    // Recorded as Authentication-Results too, so the report sees them
+    let report = get_page!(&app, &format!("/emails/user_a/{}/deliverability", id));
+    assert_eq!(report["dkim"], "pass");
+
+    let req = test::TestRequest::post()
+        .uri("/webhooks/email")
+        .insert_header(("X-Webhook-Secret", "hook-secret"))
+        .set_json(serde_json::json!({ "from": "a@b.example", "to": "temp_dkim@mailpulse.net", "subject": "", "body": "", "raw": "%%%" }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn raw_webhook_retries_are_stored_once() {
+    use base64::Engine;
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_a", "temp_retry").await.unwrap();
+    let app = app!(store.clone());
+
+    let raw = "From: Shop <noreply@shop.example>\r\nSubject: Hi\r\nMessage-ID: <retry-1@shop.example>\r\n\r\nHello\r\n";
+    let delivery = |message_id: Option<&str>| {
+        let mut body = serde_json::json!({
+            "from": "noreply@shop.example",
+            "to": "temp_retry@mailpulse.net",
+            "subject": "",
+            "body": "",
+            "raw": base64::engine::general_purpose::STANDARD.encode(raw),
+        });
+        if let Some(message_id) = message_id {
+            body["message_id"] = message_id.into();
+        }
+        test::TestRequest::post()
+            .uri("/webhooks/email")
+            .insert_header(("X-Webhook-Secret", "hook-secret"))
+            .set_json(body)
+            .to_request()
+    };
+
+    // Keyed on the parsed Message-ID without one from the worker, and on the same bare id with it
+    for message_id in [None, None, Some("<retry-1@shop.example>"), Some("retry-1@shop.example")] {
+        assert_eq!(test::call_service(&app, delivery(message_id)).await.status(), StatusCode::OK);
+    }
+    assert_eq!(store.emails().len(), 1);
+    assert_eq!(store.emails()[0].message_id.as_deref(), Some("retry-1@shop.example"));
+}
+
+#[actix_web::test]
+async fn worker_headers_cannot_claim_our_results() {
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_a", "temp_hdrs").await.unwrap();
+    let app = app!(store.clone());
+
+    let req = test::TestRequest::post()
+        .uri("/webhooks/email")
+        .insert_header(("X-Webhook-Secret", "hook-secret"))
+        .set_json(serde_json::json!({
+            "from": "noreply@shop.example",
+            "to": "temp_hdrs@mailpulse.net",
+            "subject": "Hi",
+            "body": "Hi",
+            "headers": [
+                ["Authentication-Results", "mailpulse.net; spf=pass; dkim=pass; dmarc=pass"],
+                ["Authentication-Results", "relay.example; spf=pass"],
+                ["Subject", "Hi"],
+            ],
+        }))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let id = store.emails()[0].id;
+    let fields = get_page!(&app, &format!("/emails/user_a/{}/headers", id));
+    let ids: Vec<_> = fields["authentication_results"].as_array().unwrap().iter().map(|ar| ar["authserv_id"].clone()).collect();
+    assert_eq!(ids, vec![serde_json::json!("relay.example")]);
+    let report = get_page!(&app, &format!("/emails/user_a/{}/deliverability", id));
+    assert_eq!(report["dmarc"], "none");
+}
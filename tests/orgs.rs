@@ -0,0 +1,120 @@
+//! Shared inboxes of organizations: membership lives in Postgres, mail in the
+//! in-memory repositories.
+
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::{http::StatusCode, test, web, App};
+use mail_server::api::routes;
+use mail_server::core::events::EventHub;
+use mail_server::core::ingest::ParsedEmail;
+use mail_server::core::orgs;
+use mail_server::db::{memory::MemoryStore, AliasRepo, EmailRepo, Repos};
+
+use common::{bearer, next_chunk};
+
+#[actix_web::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn members_change_shared_mail_state_and_everyone_sees_it() {
+    let pool = common::database().await;
+    let [owner, reader] = common::users(&pool).await;
+    let org = orgs::create(&pool, &owner, "Support").await.unwrap();
+    orgs::change_member(&pool, &org.id, &owner, &reader, Some(orgs::Role::ReadOnly)).await.unwrap();
+
+    let store = Arc::new(MemoryStore::new());
+    store.create_shared(&org.id, &owner, "temp_support").await.unwrap();
+    let to = store.resolve("temp_support").await.unwrap().unwrap();
+    let email = ParsedEmail { subject: "Ticket".to_string(), ..Default::default() };
+    store.store(&to, Some("ticket-1"), &email, chrono::Utc::now().timestamp()).await.unwrap();
+    let email_id = store.emails()[0].id;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(EventHub::new()))
+            .app_data(web::Data::new(Repos::in_memory(store)))
+            .configure(routes::config),
+    )
+    .await;
+
+    // Read-only members follow the inbox...
+    let req = test::TestRequest::get().uri(&format!("/orgs/{}/events", org.id)).insert_header(bearer(&reader)).to_request();
+    let stream = test::call_service(&app, req).await;
+    assert_eq!(stream.status(), StatusCode::OK);
+    let mut events = stream.into_body();
+
+    // ...but may not change it
+    let patch = |user_id: &str, uri: String, body: serde_json::Value| {
+        test::TestRequest::patch().uri(&uri).insert_header(bearer(user_id)).set_json(body).to_request()
+    };
+    let uri = format!("/orgs/{}/emails/{}", org.id, email_id);
+    let resp = test::call_service(&app, patch(&reader, uri.clone(), serde_json::json!({ "read": true }))).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let email: serde_json::Value = test::call_and_read_body_json(&app, patch(&owner, uri, serde_json::json!({ "starred": true }))).await;
+    assert_eq!(email["starred"], true);
+    let frame = next_chunk(&mut events).await;
+    assert!(frame.contains(&format!("\"id\":{}", email_id)) && frame.contains("\"starred\":true"), "{}", frame);
+
+    let body: serde_json::Value = test::call_and_read_body_json(&app, patch(
+        &owner,
+        format!("/orgs/{}/emails", org.id),
+        serde_json::json!({ "ids": [email_id, 999], "read": true }),
+    )).await;
+    assert_eq!(body["emails"].as_array().unwrap().len(), 1);
+    assert!(next_chunk(&mut events).await.contains("\"read\":true"));
+}
+
+#[actix_web::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn any_member_reads_a_shared_email_but_only_members_delete_it() {
+    let pool = common::database().await;
+    let [owner, reader, outsider] = common::users(&pool).await;
+    let org = orgs::create(&pool, &owner, "Support").await.unwrap();
+    orgs::change_member(&pool, &org.id, &owner, &reader, Some(orgs::Role::ReadOnly)).await.unwrap();
+
+    let store = Arc::new(MemoryStore::new());
+    store.create_shared(&org.id, &owner, "temp_support").await.unwrap();
+    let to = store.resolve("temp_support").await.unwrap().unwrap();
+    let raw = b"Subject: Ticket\r\nX-Ticket: 42\r\n\r\nHello\r\n".to_vec();
+    let email = ParsedEmail {
+        subject: "Ticket".to_string(),
+        headers: vec![("Subject".to_string(), "Ticket".to_string()), ("X-Ticket".to_string(), "42".to_string())],
+        raw: raw.clone(),
+        ..Default::default()
+    };
+    store.store(&to, Some("ticket-1"), &email, chrono::Utc::now().timestamp()).await.unwrap();
+    let email_id = store.emails()[0].id;
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(EventHub::new()))
+            .app_data(web::Data::new(Repos::in_memory(store)))
+            .configure(routes::config),
+    )
+    .await;
+    let uri = format!("/orgs/{}/emails/{}", org.id, email_id);
+    let get = |user_id: &str, uri: String| test::TestRequest::get().uri(&uri).insert_header(bearer(user_id)).to_request();
+    let delete = |user_id: &str| test::TestRequest::delete().uri(&uri).insert_header(bearer(user_id)).to_request();
+
+    let email: serde_json::Value = test::call_and_read_body_json(&app, get(&reader, uri.clone())).await;
+    assert_eq!(email["subject"], "Ticket");
+    let resp = test::call_service(&app, get(&reader, format!("{}/raw", uri))).await;
+    assert_eq!(resp.headers().get("Content-Type").unwrap(), "message/rfc822");
+    assert_eq!(test::read_body(resp).await, raw);
+    let analysis: serde_json::Value = test::call_and_read_body_json(&app, get(&reader, format!("{}/headers", uri))).await;
+    assert_eq!(analysis["x_headers"][0]["name"], "X-Ticket");
+    let report = test::call_service(&app, get(&reader, format!("{}/deliverability", uri))).await;
+    assert_eq!(report.status(), StatusCode::OK);
+
+    for path in ["", "/raw", "/headers", "/deliverability"] {
+        let resp = test::call_service(&app, get(&outsider, format!("{}{}", uri, path))).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN, "GET {}{} by a non-member", uri, path);
+    }
+
+    assert_eq!(test::call_service(&app, delete(&reader)).await.status(), StatusCode::FORBIDDEN);
+    assert_eq!(test::call_service(&app, delete(&owner)).await.status(), StatusCode::OK);
+    assert_eq!(test::call_service(&app, get(&owner, uri.clone())).await.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,74 @@
+//! Sealed user secrets and key rotation against Postgres.
+
+mod common;
+
+use mail_server::core::crypto::{self, KeyRing};
+use mail_server::core::workos_sessions;
+use sqlx::{PgPool, Row};
+
+const OLD_KEY: &str = "k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=";
+const NEW_KEY: &str = "k2:MTExMTExMTExMTExMTExMTExMTExMTExMTExMTExMTE=";
+
+async fn secrets_key_id(pool: &PgPool, user_id: &str) -> Option<String> {
+    sqlx::query("SELECT secrets_key_id FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .get("secrets_key_id")
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn the_key_id_follows_the_sealed_columns() {
+    let (pool, user_id) = common::user().await;
+    let old = KeyRing::parse(OLD_KEY, None).unwrap();
+    let rotated = KeyRing::parse(&format!("{},{}", OLD_KEY, NEW_KEY), None).unwrap();
+
+    let password = old.seal_column(&user_id, "imap_password", Some("app-password")).unwrap();
+    sqlx::query("UPDATE users SET imap_password = $1 WHERE id = $2").bind(&password).bind(&user_id).execute(&pool).await.unwrap();
+    assert_eq!(secrets_key_id(&pool, &user_id).await.as_deref(), Some("k1"));
+
+    // A token sealed with the new key leaves the row on two keys: no single id
+    let token = rotated.seal_column(&user_id, "access_token", Some("token")).unwrap();
+    sqlx::query("UPDATE users SET access_token = $1 WHERE id = $2").bind(&token).bind(&user_id).execute(&pool).await.unwrap();
+    assert_eq!(secrets_key_id(&pool, &user_id).await, None);
+
+    crypto::reencrypt_user_secrets(&pool, &rotated).await.unwrap();
+    assert_eq!(secrets_key_id(&pool, &user_id).await.as_deref(), Some("k2"));
+
+    let row = sqlx::query("SELECT imap_password, access_token FROM users WHERE id = $1").bind(&user_id).fetch_one(&pool).await.unwrap();
+    let ctx = |column| crypto::context(&user_id, column);
+    assert_eq!(rotated.open(row.get("imap_password"), &ctx("imap_password")).unwrap(), "app-password");
+    // Already on the active key: kept as it was
+    assert_eq!(row.get::<Option<String>, _>("access_token"), token);
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn workos_refresh_tokens_move_to_the_active_key() {
+    let (pool, user_id) = common::user().await;
+    let old = KeyRing::parse(OLD_KEY, None).unwrap();
+    let rotated = KeyRing::parse(&format!("{},{}", OLD_KEY, NEW_KEY), None).unwrap();
+
+    let access_token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &serde_json::json!({ "sid": format!("session_{}", uuid::Uuid::new_v4()) }),
+        &jsonwebtoken::EncodingKey::from_secret(b"workos"),
+    )
+    .unwrap();
+    let session_id = workos_sessions::store(&pool, &old, &user_id, &access_token, "workos-refresh", None).await.unwrap();
+
+    assert!(workos_sessions::reencrypt_refresh_tokens(&pool, &rotated).await.unwrap() >= 1);
+    // Once done, nothing is left on the old key
+    assert_eq!(workos_sessions::reencrypt_refresh_tokens(&pool, &rotated).await.unwrap(), 0);
+
+    let sealed: String = sqlx::query("SELECT refresh_token FROM workos_sessions WHERE id = $1")
+        .bind(&session_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .get("refresh_token");
+    let new_only = KeyRing::parse(NEW_KEY, None).unwrap();
+    assert_eq!(new_only.open(&sealed, &workos_sessions::context(&session_id)).unwrap(), "workos-refresh");
+}
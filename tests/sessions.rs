@@ -0,0 +1,38 @@
+//! Refresh token rotation against Postgres.
+
+mod common;
+
+use mail_server::core::crypto::KeyRing;
+use mail_server::core::sessions;
+
+fn keys() -> KeyRing {
+    KeyRing::parse("k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=", None).unwrap()
+}
+
+#[actix_web::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn a_rotated_out_token_coming_back_revokes_the_session() {
+    common::test_env();
+    let (pool, user_id) = common::user().await;
+    let first = sessions::start(&pool, &user_id, None).await.unwrap();
+
+    let second = sessions::refresh(&pool, &keys(), &first.refresh_token).await.unwrap();
+    assert!(matches!(sessions::refresh(&pool, &keys(), &first.refresh_token).await, Err(sessions::RefreshError::Reused)));
+
+    // The whole session is gone, the current token included
+    assert!(matches!(sessions::refresh(&pool, &keys(), &second.refresh_token).await, Err(sessions::RefreshError::Invalid)));
+}
+
+#[actix_web::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn a_wrong_token_is_refused_without_ending_the_session() {
+    common::test_env();
+    let (pool, user_id) = common::user().await;
+    let first = sessions::start(&pool, &user_id, None).await.unwrap();
+    let (session_id, _) = first.refresh_token.split_once('.').unwrap();
+
+    let guess = format!("{}.{}", session_id, "0".repeat(64));
+    assert!(matches!(sessions::refresh(&pool, &keys(), &guess).await, Err(sessions::RefreshError::Invalid)));
+
+    assert!(sessions::refresh(&pool, &keys(), &first.refresh_token).await.is_ok());
+}
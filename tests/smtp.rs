@@ -0,0 +1,279 @@
+//! The SMTP worker against in-memory repositories: no database or socket needed.
+
+use std::sync::Arc;
+
+use mail_server::core::dkim::DkimResult;
+use mail_server::core::dns::Zone;
+use mail_server::core::ingest::ParsedEmail;
+use mail_server::db::{memory::MemoryStore, AliasRepo, EmailRepo, Inbox, Recipient, Repos};
+use mail_server::workers::smtp;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+const MESSAGE: &str = "From: Shop <noreply@shop.example>\r\n\
+Subject: Your code\r\n\
+Message-ID: <abc@shop.example>\r\n\
+\r\n\
+Your verification code is 482913\r\n\
+..signature\r\n\
+.\r\n";
+
+/// Send one command and return the server's reply
+async fn send(client: &mut DuplexStream, line: &str) -> String {
+    client.write_all(line.as_bytes()).await.unwrap();
+    read_reply(client).await
+}
+
+async fn read_reply(client: &mut DuplexStream) -> String {
+    let mut buffer = [0; 512];
+    let n = client.read(&mut buffer).await.unwrap();
+    String::from_utf8_lossy(&buffer[..n]).into_owned()
+}
+
+/// Run a session with the worker as a client at 192.0.2.25, with DNS answered from `zone`
+async fn session(store: Arc<MemoryStore>, zone: Zone, script: impl AsyncFnOnce(&mut DuplexStream)) {
+    let repos = Repos::in_memory(store);
+    let (mut client, server) = tokio::io::duplex(4096);
+    let peer = "192.0.2.25".parse().unwrap();
+    let worker = tokio::spawn(async move { smtp::handle_connection(server, peer, &repos, &zone).await });
+
+    assert!(read_reply(&mut client).await.starts_with("220"));
+    script(&mut client).await;
+    drop(client);
+    worker.await.unwrap();
+}
+
+#[tokio::test]
+async fn mail_to_a_shared_alias_lands_in_the_org_inbox() {
+    let store = Arc::new(MemoryStore::new());
+    store.create_shared("org_1", "user_a", "temp_team").await.unwrap();
+
+    session(store.clone(), Zone::new(), async |client| {
+        assert!(send(client, "HELO sender.example\r\n").await.starts_with("250"));
+        assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<temp_team@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, MESSAGE).await.starts_with("250"));
+        assert!(send(client, "QUIT\r\n").await.starts_with("221"));
+    })
+    .await;
+
+    let emails = store.emails();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].user_id, "user_a");
+    assert_eq!(emails[0].org_id.as_deref(), Some("org_1"));
+    assert_eq!(emails[0].subject.as_deref(), Some("Your code"));
+    assert_eq!(emails[0].otp.as_deref(), Some("482913"));
+
+    // Kept as sent: without the end of data line, with the client's dot-stuffing undone
+    let raw = store.raw(Inbox::Shared("org_1"), emails[0].id).await.unwrap().unwrap();
+    assert!(String::from_utf8(raw).unwrap().ends_with("482913\r\n.signature\r\n"));
+}
+
+#[tokio::test]
+async fn unknown_names_are_taken_as_user_ids() {
+    let store = Arc::new(MemoryStore::new());
+
+    session(store.clone(), Zone::new(), async |client| {
+        assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<user_b@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, MESSAGE).await.starts_with("250"));
+    })
+    .await;
+
+    assert_eq!(store.emails()[0].user_id, "user_b");
+    assert_eq!(store.emails()[0].org_id, None);
+}
+
+#[tokio::test]
+async fn recipients_over_the_rate_limit_are_refused() {
+    let store = Arc::new(MemoryStore::new());
+    store.replace_personal("user_c", "temp_busy").await.unwrap();
+    let inbox = Recipient::mailbox("user_c");
+    let now = chrono::Utc::now().timestamp();
+    for i in 0..100 {
+        let email = ParsedEmail { sender: "bulk@example.com".to_string(), ..Default::default() };
+        store.store(&inbox, Some(&format!("m{}", i)), &email, now).await.unwrap();
+    }
+
+    session(store.clone(), Zone::new(), async |client| {
+        assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<temp_busy@mailpulse.net>\r\n").await.starts_with("450"));
+    })
+    .await;
+
+    assert_eq!(store.emails().len(), 100);
+}
+
+#[tokio::test]
+async fn the_envelope_sender_is_checked_with_spf() {
+    let store = Arc::new(MemoryStore::new());
+    let zone = Zone::new()
+        .txt("shop.example", "v=spf1 include:_spf.esp.example -all")
+        .txt("_spf.esp.example", "v=spf1 ip4:192.0.2.0/24 ~all")
+        .txt("bank.example", "v=spf1 mx -all");
+
+    session(store.clone(), zone, async |client| {
+        assert!(send(client, "EHLO out.esp.example\r\n").await.starts_with("250"));
+        assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<user_d@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, MESSAGE).await.starts_with("250"));
+        // A forged sender in the same session is still taken, and marked
+        assert!(send(client, "MAIL FROM:<ceo@bank.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, &MESSAGE.replace("abc@", "def@")).await.starts_with("250"));
+    })
+    .await;
+
+    let inbox = Inbox::Personal("user_d");
+    let mut results = Vec::new();
+    for email in store.emails() {
+        let raw = String::from_utf8(store.raw(inbox, email.id).await.unwrap().unwrap()).unwrap();
+        results.push(raw.lines().next().unwrap().to_string());
+    }
+    results.sort();
+    assert_eq!(
+        results,
+        vec![
+            "Authentication-Results: mailpulse.net; spf=fail (domain of ceo@bank.example does not designate 192.0.2.25 as permitted sender) \
+             smtp.mailfrom=ceo@bank.example",
+            "Authentication-Results: mailpulse.net; spf=pass (domain of noreply@shop.example designates 192.0.2.25 as permitted sender) \
+             smtp.mailfrom=noreply@shop.example",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn dkim_signatures_are_verified_and_recorded() {
+    let store = Arc::new(MemoryStore::new());
+    // Public halves of tests/fixtures/dkim_rsa.pem and dkim_ed25519.pem
+    let rsa = "MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC43qlBKGK1sNh1pX1oSBloIIhwllt+uFXpXk+ey0HTCFjLaQkHop/d+7iHuoHq5utdfiP4fFHeBjs486jZjGaXplZylO4+lEX/MdlIgjOYKW8snfwiduAz65EBkv425OAt2cD2LFDR1IK72F2gK1evQ/q9OPoQQju160Go0pa+EwIDAQAB";
+    let zone = Zone::new()
+        .txt("shop.example", "v=spf1 ip4:192.0.2.25 -all")
+        .txt("2024a._domainkey.shop.example", &format!("v=DKIM1; k=rsa; p={}", rsa))
+        .txt("ed1._domainkey.shop.example", "v=DKIM1; k=ed25519; p=68k/CInIdklF1kNMoKm/4gtxwdnDwRCPMTS+ZVZZ2fA=")
+        .txt("k1._domainkey.esp.example", &format!("v=DKIM1; k=rsa; p={}", rsa));
+    let message = format!("{}.\r\n", include_str!("fixtures/dkim_signed.eml"));
+
+    session(store.clone(), zone, async |client| {
+        assert!(send(client, "HELO out.shop.example\r\n").await.starts_with("250"));
+        assert!(send(client, "MAIL FROM:<bounces@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<user_e@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, &message).await.starts_with("250"));
+    })
+    .await;
+
+    let detail = store.get(Inbox::Personal("user_e"), store.emails()[0].id).await.unwrap().unwrap();
+    let dkim = detail.dkim.unwrap();
+    assert_eq!(dkim.len(), 3);
+    assert!(dkim.iter().all(|s| s.result == DkimResult::Pass));
+    assert_eq!(
+        detail.headers.unwrap()[0].1,
+        "mailpulse.net; spf=pass (domain of bounces@shop.example designates 192.0.2.25 as permitted sender) smtp.mailfrom=bounces@shop.example; \
+         dkim=pass header.d=esp.example header.i=@esp.example header.s=k1 header.a=rsa-sha256; \
+         dkim=pass header.d=shop.example header.i=@mail.shop.example header.s=ed1 header.a=ed25519-sha256; \
+         dkim=pass header.d=shop.example header.i=@shop.example header.s=2024a header.a=rsa-sha256"
+    );
+}
+
+#[tokio::test]
+async fn malformed_envelope_names_are_refused() {
+    let store = Arc::new(MemoryStore::new());
+
+    session(store.clone(), Zone::new(), async |client| {
+        assert!(send(client, "HELO bad;name\r\n").await.starts_with("501"));
+        assert!(send(client, "HELO [192.0.2.25]\r\n").await.starts_with("250"));
+        // Would forge a result in our own Authentication-Results, or a header of its own
+        assert!(send(client, "MAIL FROM:<x@y.example; dmarc=pass>\r\n").await.starts_with("501"));
+        assert!(send(client, "MAIL FROM:<x@y.example\r\nX-Injected: 1>\r\n").await.starts_with("501"));
+        assert!(send(client, "MAIL FROM:<first last@y.example>\r\n").await.starts_with("501"));
+        assert!(send(client, "MAIL FROM:<@relay.example:x.y@y.example> SIZE=100\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<user_f@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, MESSAGE).await.starts_with("250"));
+    })
+    .await;
+
+    let raw = store.raw(Inbox::Personal("user_f"), store.emails()[0].id).await.unwrap().unwrap();
+    let first = String::from_utf8(raw).unwrap().lines().next().unwrap().to_string();
+    assert_eq!(first, "Authentication-Results: mailpulse.net; spf=none (domain of x.y@y.example has no SPF record) smtp.mailfrom=x.y@y.example");
+}
+
+#[tokio::test]
+async fn a_reused_message_id_never_replaces_stored_mail() {
+    let store = Arc::new(MemoryStore::new());
+    let forged = MESSAGE.replace("482913", "000000");
+
+    session(store.clone(), Zone::new(), async |client| {
+        for message in [MESSAGE, forged.as_str()] {
+            assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+            assert!(send(client, "RCPT TO:<user_i@mailpulse.net>\r\n").await.starts_with("250"));
+            assert!(send(client, "DATA\r\n").await.starts_with("354"));
+            assert!(send(client, message).await.starts_with("250"));
+        }
+    })
+    .await;
+
+    let mut emails = store.emails();
+    emails.sort_by_key(|email| email.id);
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[0].otp.as_deref(), Some("482913"));
+    let first = store.get(Inbox::Personal("user_i"), emails[0].id).await.unwrap().unwrap();
+    assert!(first.body_text.unwrap().contains("482913"));
+    assert_eq!(emails[1].otp.as_deref(), Some("000000"));
+}
+
+#[tokio::test]
+async fn recipients_and_data_need_an_accepted_sender() {
+    let store = Arc::new(MemoryStore::new());
+
+    session(store.clone(), Zone::new(), async |client| {
+        assert!(send(client, "HELO sender.example\r\n").await.starts_with("250"));
+        assert!(send(client, "MAIL FROM:<first last@y.example>\r\n").await.starts_with("501"));
+        assert!(send(client, "RCPT TO:<user_h@mailpulse.net>\r\n").await.starts_with("503"));
+        assert!(send(client, "DATA\r\n").await.starts_with("503"));
+        assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<user_h@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, MESSAGE).await.starts_with("250"));
+        // The delivered message ended the transaction
+        assert!(send(client, "DATA\r\n").await.starts_with("503"));
+    })
+    .await;
+
+    assert_eq!(store.emails().len(), 1);
+}
+
+#[tokio::test]
+async fn results_claiming_to_be_ours_are_dropped() {
+    let store = Arc::new(MemoryStore::new());
+    let forged = format!(
+        "Authentication-Results: mailpulse.net;\r\n dmarc=pass header.from=bank.example\r\n\
+         Authentication-Results: mx.relay.example; spf=pass\r\n{}",
+        MESSAGE
+    );
+
+    session(store.clone(), Zone::new(), async |client| {
+        assert!(send(client, "MAIL FROM:<noreply@shop.example>\r\n").await.starts_with("250"));
+        assert!(send(client, "RCPT TO:<user_g@mailpulse.net>\r\n").await.starts_with("250"));
+        assert!(send(client, "DATA\r\n").await.starts_with("354"));
+        assert!(send(client, &forged).await.starts_with("250"));
+    })
+    .await;
+
+    let detail = store.get(Inbox::Personal("user_g"), store.emails()[0].id).await.unwrap().unwrap();
+    let results: Vec<_> = detail.headers.unwrap().into_iter().filter(|(name, _)| name == "Authentication-Results").collect();
+    assert_eq!(
+        results,
+        vec![
+            (
+                "Authentication-Results".to_string(),
+                "mailpulse.net; spf=none (domain of noreply@shop.example has no SPF record) smtp.mailfrom=noreply@shop.example".to_string()
+            ),
+            ("Authentication-Results".to_string(), "mx.relay.example; spf=pass".to_string()),
+        ]
+    );
+    assert!(!String::from_utf8(detail.raw_message.unwrap()).unwrap().contains("dmarc=pass"));
+}
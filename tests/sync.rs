@@ -0,0 +1,171 @@
+//! The sync path against in-memory repositories, and sync leases against Postgres.
+
+mod common;
+
+use std::sync::Arc;
+
+use mail_server::core::crypto::{self, KeyRing};
+use mail_server::core::provider::InMemoryProvider;
+use mail_server::core::sync;
+use mail_server::db::{memory::MemoryStore, Repos, SealedTokens};
+
+fn keys() -> KeyRing {
+    KeyRing::parse("k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=", None).unwrap()
+}
+
+fn raw(subject: &str) -> Vec<u8> {
+    format!("From: Shop <noreply@shop.example>\r\nSubject: {}\r\n\r\nHello\r\n", subject).into_bytes()
+}
+
+#[tokio::test]
+async fn synced_mail_is_stored_and_the_cursor_saved() {
+    let store = Arc::new(MemoryStore::new());
+    store.add_user("user_a", "a@example.com");
+    let repos = Repos::in_memory(store.clone());
+    let provider = InMemoryProvider::new();
+    provider.push("1", &raw("Welcome"));
+    provider.push("2", &raw("Your order"));
+
+    let account = repos.users.sync_account("user_a").await.unwrap().unwrap();
+    let report = sync::sync_account(&repos, &keys(), "user_a", &account, &provider).await.unwrap();
+    assert_eq!((report.provider, report.saved), ("memory", 2));
+    assert_eq!(store.emails().len(), 2);
+
+    // The next sync picks up where this one stopped
+    let account = repos.users.sync_account("user_a").await.unwrap().unwrap();
+    assert_eq!(account.cursor.as_deref(), Some("2"));
+    provider.push("3", &raw("Shipped"));
+    let report = sync::sync_account(&repos, &keys(), "user_a", &account, &provider).await.unwrap();
+    assert_eq!(report.latest.unwrap().subject, "Shipped");
+    assert_eq!(store.emails().len(), 3);
+}
+
+#[tokio::test]
+async fn tokens_of_unknown_expiry_are_refreshed_once_refused() {
+    let store = Arc::new(MemoryStore::new());
+    store.add_user("user_a", "a@example.com");
+    let repos = Repos::in_memory(store.clone());
+    let keys = keys();
+    let tokens = SealedTokens {
+        access_token: keys.seal("stale-access-token", &crypto::context("user_a", "access_token")).unwrap(),
+        refresh_token: keys.seal_column("user_a", "refresh_token", Some("refresh-token")).unwrap(),
+        expires_at: None,
+    };
+    repos.tokens.connect("user_a", "google", &tokens).await.unwrap();
+
+    let provider = InMemoryProvider::new();
+    provider.push("1", &raw("Welcome"));
+    provider.expire_token();
+
+    let account = repos.users.sync_account("user_a").await.unwrap().unwrap();
+    let report = sync::sync_account(&repos, &keys, "user_a", &account, &provider).await.unwrap();
+    assert_eq!(report.saved, 1);
+
+    // The new token is kept with its expiry, the refresh token as it was
+    let saved = store.user("user_a").unwrap().tokens.unwrap();
+    assert_eq!(keys.open(&saved.access_token, &crypto::context("user_a", "access_token")).unwrap(), "refreshed-access-token");
+    assert_eq!(saved.refresh_token, tokens.refresh_token);
+    assert!(saved.expires_at.is_some_and(|at| at > chrono::Utc::now()));
+}
+
+#[tokio::test]
+async fn a_message_that_always_fails_does_not_hold_the_cursor_back() {
+    let store = Arc::new(MemoryStore::new());
+    store.add_user("user_a", "a@example.com");
+    let repos = Repos::in_memory(store.clone());
+    let provider = InMemoryProvider::new();
+    provider.push("1", &raw("Deleted before it was fetched"));
+    provider.push("2", &raw("Welcome"));
+    provider.lose("1");
+
+    let account = repos.users.sync_account("user_a").await.unwrap().unwrap();
+    let report = sync::sync_account(&repos, &keys(), "user_a", &account, &provider).await.unwrap();
+    assert_eq!(report.saved, 1);
+    assert_eq!(report.failures.len(), 1);
+    assert!(report.failures[0].permanent);
+
+    // Newer mail is reached, and the lost message isn't listed again
+    let account = repos.users.sync_account("user_a").await.unwrap().unwrap();
+    assert_eq!(account.cursor.as_deref(), Some("2"));
+    provider.push("3", &raw("Shipped"));
+    let report = sync::sync_account(&repos, &keys(), "user_a", &account, &provider).await.unwrap();
+    assert_eq!((report.saved, report.failures.len()), (1, 0));
+}
+
+#[tokio::test]
+async fn unknown_users_are_not_synced() {
+    let repos = Repos::in_memory(Arc::new(MemoryStore::new()));
+    let result = sync::sync_user(&repos, &Arc::new(keys()), "user_missing").await;
+    assert!(matches!(result, Err(sync::SyncError::UserNotFound)));
+}
+
+/// A fresh user with an IMAP mailbox connected
+async fn connected_user() -> (sqlx::PgPool, String) {
+    let (pool, user_id) = common::user().await;
+    sqlx::query("UPDATE users SET imap_server = 'imap.example.com', imap_password = 'sealed' WHERE id = $1")
+        .bind(&user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    (pool, user_id)
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn an_account_is_synced_by_one_owner_at_a_time() {
+    let (pool, user_id) = connected_user().await;
+
+    assert!(sync::claim_account(&pool, &user_id, "manual-1", 60).await.unwrap());
+    assert!(!sync::claim_account(&pool, &user_id, "manual-2", 60).await.unwrap());
+    assert!(sync::status(&pool, &user_id).await.unwrap().unwrap().syncing);
+
+    // Recording the result releases the lease for the next sync
+    let result = Err(sync::SyncError::UserNotFound);
+    sync::record_result(&pool, &user_id, Some("manual-1"), &result, 0).await.unwrap();
+    assert!(sync::claim_account(&pool, &user_id, "manual-2", 60).await.unwrap());
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn unknown_users_are_never_claimed() {
+    let (pool, _) = common::user().await;
+
+    assert!(!sync::claim_account(&pool, "user_missing", "manual-1", 60).await.unwrap());
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn accounts_without_a_mailbox_are_never_claimed() {
+    let (pool, user_id) = connected_user().await;
+    let [never_connected] = common::users(&pool).await;
+
+    // Neither by hand...
+    assert!(!sync::claim_account(&pool, &never_connected, "manual-1", 60).await.unwrap());
+    assert!(!sync::status(&pool, &never_connected).await.unwrap().unwrap().syncing);
+
+    // ...nor by the scheduler once the mailbox is disconnected
+    assert!(sync::claim_account(&pool, &user_id, "manual-1", 60).await.unwrap());
+    sync::record_result(&pool, &user_id, Some("manual-1"), &Err(sync::SyncError::UserNotFound), 0).await.unwrap();
+    sqlx::query("UPDATE account_sync SET next_sync_at = NOW() - INTERVAL '1 minute' WHERE user_id = $1")
+        .bind(&user_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE users SET imap_password = NULL WHERE id = $1").bind(&user_id).execute(&pool).await.unwrap();
+    let claimed = sync::claim_due_accounts(&pool, "scheduler-1", 60, i64::MAX).await.unwrap();
+    assert!(!claimed.contains(&user_id));
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn connected_tokens_keep_their_expiry() {
+    let (pool, user_id) = common::user().await;
+    let repos = Repos::postgres(pool);
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(3600);
+    let tokens = SealedTokens { access_token: "sealed".to_string(), refresh_token: None, expires_at: Some(expires_at) };
+    repos.tokens.connect(&user_id, "google", &tokens).await.unwrap();
+
+    let account = repos.users.sync_account(&user_id).await.unwrap().unwrap();
+    let saved = account.tokens.unwrap().expires_at.unwrap();
+    assert_eq!(saved.timestamp(), expires_at.timestamp());
+}
@@ -0,0 +1,50 @@
+//! Threading and thread listings of the Postgres repository.
+
+mod common;
+
+use mail_server::core::ingest::ParsedEmail;
+use mail_server::db::{EmailCursor, Inbox, Recipient, Repos, ThreadQuery};
+
+fn email(subject: &str, message_id: &str, in_reply_to: Option<&str>) -> ParsedEmail {
+    ParsedEmail {
+        sender: "a@example.com".to_string(),
+        subject: subject.to_string(),
+        message_id: Some(message_id.to_string()),
+        in_reply_to: in_reply_to.map(str::to_string),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn replies_stored_first_are_merged_and_threads_page_by_activity() {
+    let (pool, user_id) = common::user().await;
+    let repos = Repos::postgres(pool);
+    let to = Recipient::mailbox(&user_id);
+    let store = |subject: &'static str, id: &'static str, parent: Option<&'static str>, at: i64| {
+        let (repos, to) = (repos.clone(), to.clone());
+        async move { repos.emails.store(&to, Some(id), &email(subject, id, parent), at).await.unwrap() }
+    };
+
+    let reply = store("Re: Order", "b", Some("a"), 1_000_200).await;
+    let other = store("Hello", "x", None, 1_000_300).await;
+    let parent = store("Order", "a", None, 1_000_100).await;
+    store("Re: Re: Order", "c", Some("b"), 1_000_400).await;
+
+    let inbox = Inbox::Personal(&user_id);
+    let first = repos.emails.threads(inbox, &ThreadQuery { cursor: None, limit: 1 }).await.unwrap();
+    assert_eq!(first.len(), 1);
+    assert_eq!((first[0].thread_id, first[0].message_count), (reply, 3));
+    assert_eq!(first[0].subject.as_deref(), Some("Order"));
+    assert_eq!(repos.emails.thread(inbox, reply).await.unwrap()[0].id, parent);
+
+    let cursor = EmailCursor { received_at: first[0].last_received_at, id: first[0].thread_id };
+    let second = repos.emails.threads(inbox, &ThreadQuery { cursor: Some(cursor), limit: 5 }).await.unwrap();
+    assert_eq!(second.iter().map(|t| t.thread_id).collect::<Vec<_>>(), vec![other]);
+
+    // Deleting the latest message moves the thread back
+    let latest = repos.emails.thread(inbox, reply).await.unwrap().pop().unwrap();
+    assert!(repos.emails.delete(inbox, latest.id).await.unwrap());
+    let all = repos.emails.threads(inbox, &ThreadQuery { cursor: None, limit: 5 }).await.unwrap();
+    assert_eq!(all.iter().map(|t| t.thread_id).collect::<Vec<_>>(), vec![other, reply]);
+}
@@ -0,0 +1,315 @@
+//! Enterprise SSO login against a local mock of the WorkOS API: explicit
+//! organizations and connections, organization discovery from the email domain, and
+//! (with Postgres) the callback joining SSO users to the linked organization.
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use actix_web::{http::StatusCode, test, web, App, HttpRequest, HttpResponse, HttpServer};
+use mail_server::api::routes;
+use mail_server::core::crypto::KeyRing;
+use mail_server::core::{oauth_state, orgs};
+use mail_server::db::{memory::MemoryStore, Repos};
+use sqlx::postgres::PgPoolOptions;
+
+/// `GET /organizations?domains[]=` as WorkOS answers it
+async fn mock_list_organizations(req: HttpRequest, query: web::Query<HashMap<String, String>>) -> HttpResponse {
+    let authorized = req.headers().get("Authorization").and_then(|v| v.to_str().ok()) == Some("Bearer sk_test_mock");
+    if !authorized {
+        return HttpResponse::Unauthorized().json(serde_json::json!({ "message": "Unauthorized" }));
+    }
+
+    match query.get("domains[]").map(String::as_str) {
+        Some("acme.com") => HttpResponse::Ok().json(serde_json::json!({
+            "object": "list",
+            "data": [{ "object": "organization", "id": "org_acme", "name": "Acme", "domains": [{ "domain": "acme.com" }] }],
+            "list_metadata": { "before": null, "after": null }
+        })),
+        Some("broken.example") => HttpResponse::InternalServerError().finish(),
+        _ => HttpResponse::Ok().json(serde_json::json!({
+            "object": "list",
+            "data": [],
+            "list_metadata": { "before": null, "after": null }
+        })),
+    }
+}
+
+/// `POST /user_management/authenticate`. Test codes are `<user id>:<WorkOS organization id>`,
+/// the organization left empty for logins outside SSO.
+async fn mock_authenticate(body: web::Json<serde_json::Value>) -> HttpResponse {
+    let code = body["code"].as_str().unwrap_or_default();
+    let Some((user_id, organization_id)) = code.split_once(':') else {
+        return HttpResponse::BadRequest().json(serde_json::json!({ "error": "invalid_grant" }));
+    };
+    let access_token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &serde_json::json!({ "sid": format!("session_{}", uuid::Uuid::new_v4()) }),
+        &jsonwebtoken::EncodingKey::from_secret(b"workos"),
+    )
+    .unwrap();
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "user": {
+            "id": user_id,
+            "email": format!("{}@acme.com", user_id),
+            "first_name": null,
+            "last_name": null,
+            "email_verified": true
+        },
+        "access_token": access_token,
+        "refresh_token": "refresh_mock",
+        "organization_id": Some(organization_id).filter(|id| !id.is_empty())
+    }))
+}
+
+/// Start the mock WorkOS API once for this test binary and point the server at it.
+/// Every test calls this before reading the environment, so the variables are written
+/// exactly once and never while another test thread reads them.
+fn mock_workos() -> &'static str {
+    static BASE: OnceLock<String> = OnceLock::new();
+    BASE.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        // Own runtime: every test gets its own, the mock has to outlive them
+        std::thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                let server = HttpServer::new(|| {
+                    App::new()
+                        .route("/organizations", web::get().to(mock_list_organizations))
+                        .route("/user_management/authenticate", web::post().to(mock_authenticate))
+                })
+                .workers(1)
+                .bind("127.0.0.1:0")
+                .unwrap();
+                tx.send(server.addrs()[0]).unwrap();
+                server.run().await.unwrap();
+            });
+        });
+        let base = format!("http://{}", rx.recv().unwrap());
+
+        std::env::set_var("WORKOS_API_URL", &base);
+        std::env::set_var("WORKOS_API_KEY", "sk_test_mock");
+        std::env::set_var("WORKOS_CLIENT_ID", "client_mock");
+        std::env::set_var("JWT_SECRET", "test-secret-test-secret-test-secret");
+        std::env::set_var("ENCRYPTION_KEYS", "k1:MDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDAwMDA=");
+        base
+    })
+}
+
+/// Follow `GET /auth/sso` and return the query of the WorkOS authorize URL it redirects to
+async fn authorize_params(query: &str) -> HashMap<String, String> {
+    authorize(query).await.0
+}
+
+/// The login cookie a response sets
+fn login_cookie(resp: &actix_web::dev::ServiceResponse) -> actix_web::cookie::Cookie<'static> {
+    resp.response().cookies().find(|c| c.name() == oauth_state::LOGIN_COOKIE).unwrap().into_owned()
+}
+
+/// `authorize_params`, with the login cookie set on the browser
+async fn authorize(query: &str) -> (HashMap<String, String>, actix_web::cookie::Cookie<'static>) {
+    let base = mock_workos();
+    // Never connects: starting a login doesn't touch the database
+    let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+    let app = test::init_service(App::new().app_data(web::Data::new(pool)).configure(routes::config)).await;
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&format!("/auth/sso?{}", query)).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::FOUND, "{}", query);
+
+    let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+    assert!(location.starts_with(&format!("{}/user_management/authorize?", base)), "{}", location);
+    (url::Url::parse(location).unwrap().query_pairs().into_owned().collect(), login_cookie(&resp))
+}
+
+#[actix_web::test]
+async fn plain_login_uses_authkit() {
+    let (params, cookie) = authorize("redirect_to=http://localhost:5173").await;
+
+    assert_eq!(params["provider"], "authkit");
+    assert_eq!(params["client_id"], "client_mock");
+    assert_eq!(oauth_state::verify_login(&params["state"], Some(cookie.value())).unwrap(), "http://localhost:5173");
+    assert_eq!(cookie.path(), Some("/auth/workos/callback"));
+    assert_eq!(cookie.http_only(), Some(true));
+    assert!(!params.contains_key("organization_id"));
+    assert!(!params.contains_key("connection_id"));
+}
+
+#[actix_web::test]
+async fn explicit_organization_or_connection_skips_authkit() {
+    let params = authorize_params("organization_id=org_explicit").await;
+    assert_eq!(params["organization_id"], "org_explicit");
+    assert!(!params.contains_key("provider"));
+
+    // A connection is the most specific choice and wins over everything else
+    let params = authorize_params("connection_id=conn_1&organization_id=org_explicit&email=jane@acme.com").await;
+    assert_eq!(params["connection_id"], "conn_1");
+    assert!(!params.contains_key("organization_id"));
+    assert!(!params.contains_key("provider"));
+}
+
+#[actix_web::test]
+async fn email_domain_discovers_the_workos_organization() {
+    let params = authorize_params("email=Jane@ACME.com").await;
+
+    assert_eq!(params["organization_id"], "org_acme");
+    assert_eq!(params["login_hint"], "Jane@ACME.com");
+    assert!(!params.contains_key("provider"));
+}
+
+#[actix_web::test]
+async fn unknown_or_unreachable_domains_fall_back_to_authkit() {
+    for email in ["someone@gmail.com", "someone@broken.example", "not-an-email"] {
+        let params = authorize_params(&format!("email={}", email)).await;
+
+        assert_eq!(params["provider"], "authkit", "{}", email);
+        assert_eq!(params["login_hint"], email);
+        assert!(!params.contains_key("organization_id"), "{}", email);
+    }
+}
+
+#[actix_web::test]
+async fn redirect_targets_are_still_checked() {
+    mock_workos();
+    let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+    let app = test::init_service(App::new().app_data(web::Data::new(pool)).configure(routes::config)).await;
+
+    let req = test::TestRequest::get().uri("/auth/sso?organization_id=org_acme&redirect_to=https://evil.example").to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Start a login redirecting to `redirect_to` and come back to the callback with `code`,
+/// from the same browser
+macro_rules! callback {
+    ($app:expr, $code:expr, $redirect_to:expr) => {{
+        let redirect_to = url::form_urlencoded::byte_serialize($redirect_to.as_bytes()).collect::<String>();
+        let resp = test::call_service($app, test::TestRequest::get().uri(&format!("/auth/sso?redirect_to={}", redirect_to)).to_request()).await;
+        let location = url::Url::parse(resp.headers().get("Location").unwrap().to_str().unwrap()).unwrap();
+        let (_, state) = location.query_pairs().into_owned().find(|(k, _)| k == "state").unwrap();
+
+        let state = url::form_urlencoded::byte_serialize(state.as_bytes()).collect::<String>();
+        let uri = format!("/auth/workos/callback?code={}&state={}", $code, state);
+        test::call_service($app, test::TestRequest::get().uri(&uri).cookie(login_cookie(&resp)).to_request()).await
+    }};
+}
+
+/// Finish a login through the callback and trade its code for a session token
+macro_rules! sign_in {
+    ($app:expr, $code:expr) => {{
+        let code: &str = $code;
+        let resp = callback!($app, code, "http://localhost:5173");
+        assert_eq!(resp.status(), StatusCode::FOUND, "{}", code);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        let (_, exchange_code) = url::Url::parse(location).unwrap().query_pairs().into_owned().find(|(k, _)| k == "code").unwrap();
+
+        let req = test::TestRequest::post().uri("/auth/exchange").set_json(serde_json::json!({ "code": exchange_code })).to_request();
+        let session: serde_json::Value = test::call_and_read_body_json($app, req).await;
+        session["token"].as_str().unwrap().to_string()
+    }};
+}
+
+#[actix_web::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn sso_logins_join_the_linked_organization() {
+    mock_workos();
+    let pool = common::database().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Repos::postgres(pool.clone())))
+            .app_data(web::Data::new(KeyRing::from_env().unwrap()))
+            .configure(routes::config),
+    )
+    .await;
+
+    let run = uuid::Uuid::new_v4().simple().to_string();
+    let (workos_org, owner, member) = (format!("org_{}", run), format!("owner_{}", run), format!("member_{}", run));
+    let bearer = |token: &str| ("Authorization", format!("Bearer {}", token));
+
+    // The owner signs in through the WorkOS organization before anything is linked
+    let owner_token = sign_in!(&app, &format!("{}:{}", owner, workos_org));
+    let req = test::TestRequest::post().uri("/orgs").insert_header(bearer(&owner_token)).set_json(serde_json::json!({ "name": "Acme" })).to_request();
+    let org: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    let org_id = org["id"].as_str().unwrap().to_string();
+
+    // Another WorkOS organization can't be claimed, nor this one without having signed in through it
+    let link = |token: &str, workos_org: &str| {
+        test::TestRequest::put()
+            .uri(&format!("/orgs/{}/sso", org_id))
+            .insert_header(bearer(token))
+            .set_json(serde_json::json!({ "workos_organization_id": workos_org, "role": "member" }))
+            .to_request()
+    };
+    let resp = test::call_service(&app, link(&owner_token, &format!("org_other_{}", run))).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let plain_token = sign_in!(&app, &format!("{}:", owner));
+    let resp = test::call_service(&app, link(&plain_token, &workos_org)).await;
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = test::call_service(&app, link(&owner_token, &workos_org)).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // From now on SSO users of the WorkOS organization join with the linked role
+    sign_in!(&app, &format!("{}:{}", member, workos_org));
+    assert_eq!(orgs::role_of(&pool, &org_id, &member).await.unwrap(), Some(orgs::Role::Member));
+    assert_eq!(orgs::role_of(&pool, &org_id, &owner).await.unwrap(), Some(orgs::Role::Owner));
+}
+
+#[actix_web::test]
+#[ignore = "needs Postgres: set DATABASE_URL and run with --include-ignored"]
+async fn exchange_codes_keep_the_redirect_targets_own_query() {
+    mock_workos();
+    let pool = common::database().await;
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Repos::postgres(pool)))
+            .app_data(web::Data::new(KeyRing::from_env().unwrap()))
+            .configure(routes::config),
+    )
+    .await;
+
+    let user = format!("user_{}", uuid::Uuid::new_v4().simple());
+    let resp = callback!(&app, format!("{}:", user), "http://localhost:5173/inbox?tab=all");
+    assert_eq!(resp.status(), StatusCode::FOUND);
+    let cleared = login_cookie(&resp);
+    assert_eq!(cleared.max_age(), Some(actix_web::cookie::time::Duration::ZERO));
+
+    let location = url::Url::parse(resp.headers().get("Location").unwrap().to_str().unwrap()).unwrap();
+    assert_eq!(location.path(), "/inbox");
+    let query: Vec<(String, String)> = location.query_pairs().into_owned().collect();
+    assert_eq!(query[0], ("tab".to_string(), "all".to_string()));
+    assert_eq!(query[1].0, "code");
+}
+
+#[actix_web::test]
+async fn logins_finish_only_in_the_browser_that_started_them() {
+    mock_workos();
+    // Never connects: the callback refuses before touching the database
+    let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(pool))
+            .app_data(web::Data::new(Repos::in_memory(std::sync::Arc::new(MemoryStore::new()))))
+            .app_data(web::Data::new(KeyRing::from_env().unwrap()))
+            .configure(routes::config),
+    )
+    .await;
+
+    // An attacker starts a login of their own and sends the victim its callback link
+    let (params, attacker_cookie) = authorize("redirect_to=http://localhost:5173").await;
+    let state = url::form_urlencoded::byte_serialize(params["state"].as_bytes()).collect::<String>();
+    let uri = format!("/auth/workos/callback?code=attacker:&state={}", state);
+
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let (_, victim_cookie) = authorize("redirect_to=http://localhost:5173").await;
+    let resp = test::call_service(&app, test::TestRequest::get().uri(&uri).cookie(victim_cookie).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // The old unsigned state is refused too
+    let uri = "/auth/workos/callback?code=attacker:&state=authkit_login|http://localhost:5173";
+    let resp = test::call_service(&app, test::TestRequest::get().uri(uri).cookie(attacker_cookie).to_request()).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}